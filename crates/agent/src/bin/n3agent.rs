@@ -3,14 +3,20 @@ use std::{
     net::{IpAddr, SocketAddr},
     ops::Range,
     path::PathBuf,
+    thread,
     time::Duration,
 };
 
 use clap::{Parser, Subcommand};
 use color_print::ceprintln;
 use futures::executor::block_on;
-use n3agent::Agent;
+use n3agent::{
+    Agent,
+    config::{AgentConfig, ListenerConfig, ListenerMode, PortRange},
+};
 use n3io::reactor::{Reactor, set_global_reactor};
+use n3quic::{QuicCongestionControl, QuicShutdown, QuicTuning};
+use signal_hook::{consts::SIGTERM, iterator::Signals};
 
 fn parse_port_range(arg: &str) -> std::result::Result<Range<u16>, String> {
     let parts = arg.split(":").collect::<Vec<_>>();
@@ -52,12 +58,17 @@ struct Cli {
     protos: Vec<String>,
 
     /// Specify n3 server listening address.
-    #[arg(short = 'i', long, value_name = "ADDR")]
-    n3_ip: IpAddr,
+    #[arg(
+        short = 'i',
+        long,
+        value_name = "ADDR",
+        required_unless_present = "config"
+    )]
+    n3_ip: Option<IpAddr>,
 
     /// Specify the n3 server listening port range.
-    #[arg(short = 'p', long, value_name = "PORT", value_parser=parse_port_range)]
-    n3_port_range: Range<u16>,
+    #[arg(short = 'p', long, value_name = "PORT", value_parser=parse_port_range, required_unless_present = "config")]
+    n3_port_range: Option<Range<u16>>,
 
     /// Configure the certificate chain file(PEM).
     #[arg(short, long, value_name = "PEM_FILE")]
@@ -91,6 +102,38 @@ struct Cli {
     #[arg(long, value_name = "SIZE", default_value_t = 3)]
     ack_frequency_exponent: u64,
 
+    /// The congestion control algorithm: `cubic`, `reno` or `bbr2`, quiche uses `cubic` if not set.
+    #[arg(long, value_name = "ALGORITHM")]
+    cc_algorithm: Option<QuicCongestionControl>,
+
+    /// Sets the initial congestion window, in `1..=1000` packets.
+    #[arg(long, value_name = "PACKETS", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=1000))]
+    initial_congestion_window_packets: Option<usize>,
+
+    /// Sets the maximum outgoing and incoming UDP payload size, in bytes.
+    #[arg(long, value_name = "SIZE", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1200..=65527))]
+    max_udp_payload_size: Option<usize>,
+
+    /// Enables the path MTU discovery, probing up to `--max-udp-payload-size`.
+    #[arg(long, value_name = "BOOL")]
+    discover_pmtu: Option<bool>,
+
+    /// Enables the HyStart++ slow start of the congestion controller.
+    #[arg(long, value_name = "BOOL")]
+    hystart: Option<bool>,
+
+    /// Enables the pacing of quiche, which sets the send time of each packet.
+    #[arg(long, value_name = "BOOL")]
+    enable_pacing: Option<bool>,
+
+    /// Sets the maximum pacing rate, in bytes per second.
+    #[arg(long, value_name = "RATE", value_parser = clap::value_parser!(u64).range(1..))]
+    max_pacing_rate: Option<u64>,
+
+    /// Sets the `active_connection_id_limit` transport parameter.
+    #[arg(long, value_name = "LIMIT", value_parser = clap::value_parser!(u64).range(2..))]
+    active_connection_id_limit: Option<u64>,
+
     /// Sets the quiche `initial_max_streams_bidi` transport parameter.
     ///
     /// When set to a non-zero value quiche will only allow v number of concurrent remotely-initiated bidirectional
@@ -102,12 +145,50 @@ struct Cli {
     #[arg(long, value_name = "INTERVAL", default_value_t = 20)]
     io_timer_tick_interval: u64,
 
+    /// The grace period of the shutdown on `SIGTERM`, in seconds.
+    ///
+    /// In-flight streams are closed when the grace period expires.
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    shutdown_timeout: u64,
+
+    /// Ask the n3 server to forward the streams to `HOST:PORT` by a stream preamble.
+    #[arg(long, value_name = "HOST:PORT")]
+    remote: Option<String>,
+
+    /// The authentication token sent in the stream preamble, requires `--remote` or `proxy`.
+    #[arg(long, value_name = "TOKEN")]
+    token: Option<String>,
+
+    /// Serve prometheus metrics on `http://ADDRESS/metrics`, e.g. `127.0.0.1:9091`.
+    #[arg(long, value_name = "ADDRESS")]
+    metrics: Option<SocketAddr>,
+
+    /// Persist the TLS sessions of the n3 servers to FILE, so a restarted agent resumes them.
+    #[arg(long, value_name = "FILE")]
+    session_file: Option<PathBuf>,
+
+    /// Send the first streams of the resumed connections as 0-RTT data.
+    ///
+    /// The n3 servers must enable 0-RTT, see the `--zero-rtt` option of n3.
+    #[arg(long)]
+    zero_rtt: bool,
+
+    /// The delay before racing the next n3 address, in milliseconds.
+    ///
+    /// The addresses of `--n3-port-range` are tried in turn, a failed address is tried last for 10s.
+    #[arg(long, value_name = "MILLIS", default_value_t = 250)]
+    attempt_delay: u64,
+
     /// Debug mode, print verbose output informations.
     #[arg(short, long, default_value_t = false, action)]
     debug: bool,
 
+    /// Load all settings from a `toml` config file, other flags are ignored.
+    #[arg(long, value_name = "TOML_FILE")]
+    config: Option<PathBuf>,
+
     #[command(subcommand)]
-    commands: Commands,
+    commands: Option<Commands>,
 }
 
 #[derive(Subcommand)]
@@ -117,106 +198,146 @@ enum Commands {
         /// Specify the redirect target address
         target: Option<SocketAddr>,
     },
+    /// Expose a local service through the n3 server(reverse tunnel).
+    Tunnel {
+        /// Specify the local service address
+        target: SocketAddr,
+    },
+    /// Relay the local udp datagrams to the n3 server in QUIC DATAGRAM frames.
+    Udp {
+        /// Specify the local udp listening address
+        target: Option<SocketAddr>,
+    },
+    /// Run as a local SOCKS5 and `HTTP CONNECT` proxy, the n3 server must read the stream
+    /// preambles(`n3 preamble`).
+    Proxy {
+        /// Specify the local proxy listening address
+        target: Option<SocketAddr>,
+    },
 }
 
-fn parse_n3_addrs(cli: &Cli) -> Result<Vec<SocketAddr>> {
-    let mut laddrs: Vec<SocketAddr> = vec![];
-
-    for port in cli.n3_port_range.clone() {
-        laddrs.push(SocketAddr::new(cli.n3_ip, port));
+impl Cli {
+    /// Convert the command line flags to the equivalent config file.
+    fn into_config(self) -> Result<AgentConfig> {
+        let (Some(n3_ip), Some(n3_port_range), Some(commands)) =
+            (self.n3_ip, self.n3_port_range, self.commands)
+        else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "`--n3-ip`, `--n3-port-range` and a subcommand are required without `--config`",
+            ));
+        };
+
+        let (mode, laddr) = match commands {
+            Commands::Listen { target } => (
+                ListenerMode::Listen,
+                target.unwrap_or("[::]:1812".parse().map_err(Error::other)?),
+            ),
+            Commands::Tunnel { target } => (ListenerMode::Tunnel, target),
+            Commands::Udp { target } => (
+                ListenerMode::Udp,
+                target.unwrap_or("[::]:1812".parse().map_err(Error::other)?),
+            ),
+            Commands::Proxy { target } => (
+                ListenerMode::Proxy,
+                target.unwrap_or("127.0.0.1:1080".parse().map_err(Error::other)?),
+            ),
+        };
+
+        let config = AgentConfig {
+            protos: self.protos,
+            cert: self.cert,
+            key: self.key,
+            io_timer_tick_interval: self.io_timer_tick_interval,
+            debug: self.debug,
+            shutdown_timeout: self.shutdown_timeout,
+            metrics: self.metrics,
+            session_file: self.session_file,
+            zero_rtt: self.zero_rtt,
+            attempt_delay: self.attempt_delay,
+            quic: QuicTuning {
+                initial_max_streams: Some(self.initial_max_streams),
+                initial_max_stream_data: Some(self.initial_max_stream_data),
+                max_idle_timeout: Some(self.max_idle_timeout),
+                max_ack_delay: Some(self.max_ack_delay),
+                ack_delay_exponent: Some(self.ack_frequency_exponent),
+                dgram_queue_len: None,
+                cc_algorithm: self.cc_algorithm,
+                initial_congestion_window_packets: self.initial_congestion_window_packets,
+                max_udp_payload_size: self.max_udp_payload_size,
+                discover_pmtu: self.discover_pmtu,
+                hystart: self.hystart,
+                enable_pacing: self.enable_pacing,
+                max_pacing_rate: self.max_pacing_rate,
+                active_connection_id_limit: self.active_connection_id_limit,
+            },
+            listeners: vec![ListenerConfig {
+                mode,
+                laddr,
+                n3_ip,
+                n3_ports: PortRange(n3_port_range),
+                server_name: None,
+                remote: self.remote,
+                token: self.token,
+                protos: None,
+                quic: QuicTuning::default(),
+            }],
+        };
+
+        config.validate()?;
+
+        Ok(config)
     }
-
-    Ok(laddrs)
 }
 
-async fn run_agent(cli: Cli, laddr: SocketAddr) -> Result<()> {
-    let n3_addrs = parse_n3_addrs(&cli)?;
-
-    if n3_addrs.is_empty() {
-        return Err(Error::new(ErrorKind::InvalidInput, "`n3_addrs` ."));
-    }
-
-    let protos = cli
-        .protos
-        .iter()
-        .map(|proto| proto.as_bytes())
-        .collect::<Vec<_>>();
-
-    Agent::new(n3_addrs.as_slice())
-        .connector(|connector| {
-            connector.quiche_config(|config| {
-                config.set_initial_max_data(cli.initial_max_stream_data * cli.initial_max_streams);
-                config.set_initial_max_stream_data_bidi_local(cli.initial_max_stream_data);
-                config.set_initial_max_stream_data_bidi_remote(cli.initial_max_stream_data);
-                config.set_initial_max_stream_data_uni(cli.initial_max_stream_data);
-                config.set_initial_max_streams_bidi(cli.initial_max_streams);
-                config.set_initial_max_streams_uni(cli.initial_max_streams);
-                config.set_max_idle_timeout(cli.max_idle_timeout);
-                config.set_max_ack_delay(cli.max_ack_delay);
-                config.set_ack_delay_exponent(cli.ack_frequency_exponent);
-
-                if let Some(cert) = &cli.cert {
-                    config
-                        .load_cert_chain_from_pem_file(cert.to_str().unwrap())
-                        .map_err(|err| {
-                            Error::new(
-                                ErrorKind::NotFound,
-                                format!(
-                                    "Unable to load certificate chain file {:?}, {}",
-                                    cli.cert, err
-                                ),
-                            )
-                        })?;
-                }
+async fn run_n3_agent() -> Result<()> {
+    let mut cli = Cli::parse();
 
-                config
-                    .load_priv_key_from_pem_file(cli.key.to_str().unwrap())
-                    .map_err(|err| {
-                        Error::new(
-                            ErrorKind::NotFound,
-                            format!("Unable to load key file {:?}, {}", cli.key, err),
-                        )
-                    })?;
-
-                config.set_application_protos(&protos).map_err(|err| {
-                    Error::new(
-                        ErrorKind::InvalidInput,
-                        format!(
-                            "failed to set application protos as {:?}, {}",
-                            cli.protos, err
-                        ),
-                    )
-                })?;
-
-                Ok(())
-            })
-        })
-        .bind(laddr)
-        .await
-}
+    let debug = cli.debug;
 
-async fn run_n3_agent() -> Result<()> {
-    let cli = Cli::parse();
+    let config = if let Some(path) = cli.config.take() {
+        AgentConfig::from_file(path)?
+    } else {
+        cli.into_config()?
+    };
 
-    let io_timer_tick_interval = cli.io_timer_tick_interval;
+    let io_timer_tick_interval = config.io_timer_tick_interval;
 
     set_global_reactor(move || {
         Reactor::new(1024, Duration::from_millis(io_timer_tick_interval)).unwrap()
     });
 
-    if cli.debug {
+    if debug || config.debug {
         pretty_env_logger::try_init_timed().map_err(Error::other)?;
     }
 
-    match cli.commands {
-        Commands::Listen { target } => {
-            run_agent(
-                cli,
-                target.unwrap_or("[::]:1812".parse().map_err(Error::other)?),
-            )
-            .await?;
-        }
-    }
+    let agents = config.build();
+
+    let shutdowns = agents
+        .iter()
+        .map(Agent::shutdown_handle)
+        .collect::<Vec<_>>();
+
+    spawn_signal_watcher(Duration::from_secs(config.shutdown_timeout), shutdowns)?;
+
+    config.run(agents).await
+}
+
+/// Shutdown the agents gracefully on `SIGTERM`.
+fn spawn_signal_watcher(grace: Duration, shutdowns: Vec<QuicShutdown>) -> Result<()> {
+    let mut signals = Signals::new([SIGTERM])?;
+
+    thread::Builder::new()
+        .name("n3agent-signal".to_owned())
+        .spawn(move || {
+            for _ in signals.forever() {
+                log::info!("shutdown, grace={:?}", grace);
+
+                for shutdown in &shutdowns {
+                    shutdown.shutdown(grace);
+                }
+            }
+        })?;
 
     Ok(())
 }
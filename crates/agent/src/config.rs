@@ -0,0 +1,663 @@
+//! Declarative configuration file for `n3agent`.
+//!
+//! ```toml
+//! cert = "n3agent.crt"
+//! key = "n3agent.key"
+//! metrics = "127.0.0.1:9091"
+//! shutdown_timeout = 30
+//! session_file = "n3agent.sessions"
+//! zero_rtt = true
+//!
+//! [quic]
+//! initial_max_stream_data = 1048576
+//! cc_algorithm = "bbr2"
+//! initial_congestion_window_packets = 20
+//!
+//! [[listener]]
+//! laddr = "[::]:1812"
+//! n3_ip = "10.0.0.1"
+//! n3_ports = "443:446"
+//!
+//! [[listener]]
+//! laddr = "[::]:1813"
+//! n3_ip = "10.0.0.1"
+//! n3_ports = 8443
+//! server_name = "api.example.com"
+//! remote = "db.internal:5432"
+//! token = "secret"
+//!
+//! [listener.quic]
+//! max_idle_timeout = 10000
+//!
+//! [[listener]]
+//! mode = "tunnel"
+//! laddr = "127.0.0.1:8080"
+//! n3_ip = "10.0.0.1"
+//! n3_ports = 9443
+//!
+//! [[listener]]
+//! mode = "udp"
+//! laddr = "127.0.0.1:5353"
+//! n3_ip = "10.0.0.1"
+//! n3_ports = 8443
+//!
+//! [[listener]]
+//! mode = "proxy"
+//! laddr = "127.0.0.1:1080"
+//! n3_ip = "10.0.0.1"
+//! n3_ports = 8443
+//! token = "secret"
+//! ```
+
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, SocketAddr},
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::pin,
+    time::Duration,
+};
+
+use futures::future::{Either, pending, select, try_join_all};
+use n3_metrics::Registry;
+use n3_proto::Preamble;
+use n3quic::{QuicSessionCache, QuicTuning};
+use serde::Deserialize;
+
+use crate::Agent;
+
+/// Root of the `n3agent` configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentConfig {
+    /// Application protos, the default is `["n3"]`.
+    #[serde(default = "default_protos")]
+    pub protos: Vec<String>,
+    /// The certificate chain file(PEM).
+    pub cert: Option<PathBuf>,
+    /// The private key file(PEM).
+    #[serde(default = "default_key")]
+    pub key: PathBuf,
+    /// The io timer tick interval, in milliseconds.
+    #[serde(default = "default_io_timer_tick_interval")]
+    pub io_timer_tick_interval: u64,
+    /// Print verbose output informations.
+    #[serde(default)]
+    pub debug: bool,
+    /// The grace period of the shutdown on `SIGTERM`, in seconds.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// Serve prometheus metrics on `http://{metrics}/metrics`.
+    pub metrics: Option<SocketAddr>,
+    /// Persist the TLS sessions of the n3 servers to this file, so restarted agents resume them.
+    pub session_file: Option<PathBuf>,
+    /// Send the first streams of the resumed connections as `0-RTT` data.
+    #[serde(default)]
+    pub zero_rtt: bool,
+    /// The delay before racing the next n3 address, in milliseconds.
+    #[serde(default = "default_attempt_delay")]
+    pub attempt_delay: u64,
+    /// Transport parameters shared by all listeners.
+    #[serde(default)]
+    pub quic: QuicTuning,
+    /// Listeners, at least one is required.
+    #[serde(default, rename = "listener")]
+    pub listeners: Vec<ListenerConfig>,
+}
+
+fn default_protos() -> Vec<String> {
+    vec!["n3".to_owned()]
+}
+
+fn default_key() -> PathBuf {
+    "n3.key".into()
+}
+
+fn default_io_timer_tick_interval() -> u64 {
+    20
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_attempt_delay() -> u64 {
+    250
+}
+
+/// A local tcp listener and the n3 servers its streams are forwarded to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    #[serde(default)]
+    pub mode: ListenerMode,
+    /// The local listening address, or the local service address in `tunnel` mode.
+    pub laddr: SocketAddr,
+    /// The n3 server listening address.
+    pub n3_ip: IpAddr,
+    /// The n3 server listening port range: `from:to` or `port`.
+    pub n3_ports: PortRange,
+    /// The TLS SNI sent to the n3 server.
+    pub server_name: Option<String>,
+    /// Ask the n3 server to forward the streams to `host:port` by a stream preamble.
+    pub remote: Option<String>,
+    /// The authentication token sent in the stream preamble.
+    pub token: Option<String>,
+    /// Application protos, override the global ones.
+    pub protos: Option<Vec<String>>,
+    /// Transport parameters, override the global ones.
+    #[serde(default)]
+    pub quic: QuicTuning,
+}
+
+/// The direction a listener forwards the connections.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerMode {
+    /// Forward the tcp connections accepted on `laddr` to the n3 servers.
+    #[default]
+    Listen,
+    /// Keep a connection to the n3 server, forward the streams it opens to `laddr`.
+    Tunnel,
+    /// Run a local SOCKS5 and `HTTP CONNECT` proxy on `laddr`, the destination requested by
+    /// each client is sent in the stream preamble.
+    Proxy,
+    /// Relay the udp datagrams received on `laddr` to the n3 server in QUIC DATAGRAM frames.
+    Udp,
+}
+
+impl Display for ListenerMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Listen => write!(f, "listen"),
+            Self::Tunnel => write!(f, "tunnel"),
+            Self::Proxy => write!(f, "proxy"),
+            Self::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// A port range, deserialized from `port` or `"from:to"`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "PortRangeRepr")]
+pub struct PortRange(pub Range<u16>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRangeRepr {
+    Port(u16),
+    Range(String),
+}
+
+impl TryFrom<PortRangeRepr> for PortRange {
+    type Error = String;
+
+    fn try_from(value: PortRangeRepr) -> std::result::Result<Self, Self::Error> {
+        match value {
+            PortRangeRepr::Port(port) => Ok(Self(port..port.saturating_add(1))),
+            PortRangeRepr::Range(range) => {
+                let Some((from, to)) = range.split_once(':') else {
+                    let port = range
+                        .parse::<u16>()
+                        .map_err(|err| format!("failed to parse port: {}", err))?;
+
+                    return Ok(Self(port..port.saturating_add(1)));
+                };
+
+                let from = from
+                    .parse::<u16>()
+                    .map_err(|err| format!("failed to parse port(from): {}", err))?;
+
+                let to = to
+                    .parse::<u16>()
+                    .map_err(|err| format!("failed to parse port(to): {}", err))?;
+
+                if to <= from {
+                    return Err("failed to parse port range: ensure `to > from`".to_owned());
+                }
+
+                Ok(Self(from..to))
+            }
+        }
+    }
+}
+
+fn invalid(reason: impl Display) -> Error {
+    Error::new(ErrorKind::InvalidData, reason.to_string())
+}
+
+impl AgentConfig {
+    /// The transport parameters used when neither the listener nor the global `quic` set them.
+    pub fn default_quic() -> QuicTuning {
+        QuicTuning {
+            initial_max_streams: Some(100),
+            initial_max_stream_data: Some(1024 * 1024),
+            max_idle_timeout: Some(60 * 1000),
+            max_ack_delay: Some(25),
+            ack_delay_exponent: Some(3),
+            dgram_queue_len: Some(1024),
+            ..Default::default()
+        }
+    }
+
+    /// Load and validate the configuration from a `toml` file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+
+        let content = std::fs::read_to_string(path).map_err(|err| {
+            Error::new(
+                err.kind(),
+                format!("Unable to read config file {:?}, {}", path, err),
+            )
+        })?;
+
+        Self::from_toml(&content)
+            .map_err(|err| Error::new(err.kind(), format!("config file {:?}: {}", path, err)))
+    }
+
+    /// Parse and validate the configuration from `toml` string.
+    pub fn from_toml(content: &str) -> Result<Self> {
+        let config: Self = toml::from_str(content).map_err(invalid)?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Check the semantic constraints which can't be expressed by the file format.
+    pub fn validate(&self) -> Result<()> {
+        if self.listeners.is_empty() {
+            return Err(invalid("at least one `[[listener]]` is required"));
+        }
+
+        if self.io_timer_tick_interval == 0 {
+            return Err(invalid("`io_timer_tick_interval` must be greater than 0"));
+        }
+
+        if self.protos.is_empty() {
+            return Err(invalid("`protos` is empty"));
+        }
+
+        self.quic
+            .validate()
+            .map_err(|err| invalid(format!("quic: {}", err)))?;
+
+        for (index, listener) in self.listeners.iter().enumerate() {
+            listener
+                .quic
+                .validate()
+                .map_err(|err| invalid(format!("listener[{}]: {}", index, err)))?;
+
+            if listener
+                .protos
+                .as_ref()
+                .is_some_and(|protos| protos.is_empty())
+            {
+                return Err(invalid(format!("listener[{}]: `protos` is empty", index)));
+            }
+
+            if listener
+                .server_name
+                .as_ref()
+                .is_some_and(|name| name.is_empty())
+            {
+                return Err(invalid(format!(
+                    "listener[{}]: `server_name` is empty",
+                    index
+                )));
+            }
+
+            if matches!(listener.mode, ListenerMode::Tunnel | ListenerMode::Udp)
+                && (listener.remote.is_some() || listener.token.is_some())
+            {
+                return Err(invalid(format!(
+                    "listener[{}]: `remote` and `token` are not allowed in `{}` mode",
+                    index, listener.mode
+                )));
+            }
+
+            if listener.mode == ListenerMode::Tunnel && self.cert.is_none() {
+                return Err(invalid(format!(
+                    "listener[{}]: `cert` is required in `tunnel` mode",
+                    index
+                )));
+            }
+
+            if listener.mode == ListenerMode::Proxy && listener.remote.is_some() {
+                return Err(invalid(format!(
+                    "listener[{}]: `remote` is not allowed in `proxy` mode",
+                    index
+                )));
+            }
+
+            if listener.mode != ListenerMode::Proxy
+                && listener.token.is_some()
+                && listener.remote.is_none()
+            {
+                return Err(invalid(format!(
+                    "listener[{}]: `token` requires `remote`",
+                    index
+                )));
+            }
+
+            if let Some(preamble) = listener.preamble() {
+                // the target of `proxy` mode is read from the client handshake.
+                if listener.mode != ListenerMode::Proxy {
+                    preamble
+                        .host_port()
+                        .map_err(|err| invalid(format!("listener[{}]: {}", index, err)))?;
+                }
+
+                preamble
+                    .encode()
+                    .map_err(|err| invalid(format!("listener[{}]: {}", index, err)))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Create the `Agent` instances of the listeners, in the order of `listeners`.
+    pub fn build(&self) -> Vec<Agent> {
+        let base = self.quic.or(&Self::default_quic());
+
+        // one cache for all the listeners, they may connect to the same servers.
+        let sessions = match &self.session_file {
+            Some(path) => QuicSessionCache::with_file(path),
+            None => QuicSessionCache::new(),
+        };
+
+        self.listeners
+            .iter()
+            .map(|listener| listener.agent(self, &base, &sessions))
+            .collect()
+    }
+
+    /// Bind the agents created by [`build`](Self::build) and run them until one of them fails
+    /// or all of them are shut down.
+    ///
+    /// The metrics of the listeners are labeled by `listener="{index}"`.
+    pub async fn run(&self, agents: Vec<Agent>) -> Result<()> {
+        let registry = Registry::new();
+
+        for (index, agent) in agents.iter().enumerate() {
+            registry.register(&[("listener", &index.to_string())], agent.metrics());
+        }
+
+        let metrics = async {
+            match self.metrics {
+                Some(laddr) => {
+                    // the metrics endpoint is auxiliary, never stop the listeners because of it.
+                    if let Err(err) = n3_metrics::serve(laddr, registry).await {
+                        log::error!("metrics, laddr={}, err={}", laddr, err);
+                    }
+
+                    pending().await
+                }
+                None => pending().await,
+            }
+        };
+
+        let listeners = try_join_all(
+            self.listeners
+                .iter()
+                .zip(agents)
+                .map(|(listener, agent)| listener.run(agent)),
+        );
+
+        match select(pin!(listeners), pin!(metrics)).await {
+            Either::Left((result, _)) => result.map(|_| ()),
+            Either::Right((never, _)) => never,
+        }
+    }
+}
+
+impl ListenerConfig {
+    async fn run(&self, agent: Agent) -> Result<()> {
+        match self.mode {
+            ListenerMode::Listen => agent.bind(self.laddr).await,
+            ListenerMode::Tunnel => agent.tunnel(self.laddr).await,
+            ListenerMode::Proxy => agent.proxy().bind(self.laddr).await,
+            ListenerMode::Udp => agent.bind_udp(self.laddr).await,
+        }
+    }
+
+    /// Returns the n3 server addresses.
+    pub fn n3_addrs(&self) -> Vec<SocketAddr> {
+        self.n3_ports
+            .0
+            .clone()
+            .map(|port| SocketAddr::new(self.n3_ip, port))
+            .collect()
+    }
+
+    /// Create the `Agent` instance of this listener, `base` is the global transport parameters.
+    pub fn agent(
+        &self,
+        config: &AgentConfig,
+        base: &QuicTuning,
+        sessions: &QuicSessionCache,
+    ) -> Agent {
+        let tuning = self.quic.or(base);
+
+        let protos = self.protos.as_ref().unwrap_or(&config.protos);
+
+        let agent = Agent::new(self.n3_addrs().as_slice()).connector(|connector| {
+            let connector = if let Some(server_name) = &self.server_name {
+                connector.server_name(server_name)
+            } else {
+                connector
+            };
+
+            let connector = connector
+                .session_cache(Some(sessions.clone()))
+                .early_data(config.zero_rtt)
+                .attempt_delay(Duration::from_millis(config.attempt_delay));
+
+            connector.quiche_config(|quiche_config| {
+                tuning.apply(quiche_config);
+
+                if let Some(cert) = &config.cert {
+                    quiche_config
+                        .load_cert_chain_from_pem_file(&cert.to_string_lossy())
+                        .map_err(|err| {
+                            Error::new(
+                                ErrorKind::NotFound,
+                                format!(
+                                    "Unable to load certificate chain file {:?}, {}",
+                                    cert, err
+                                ),
+                            )
+                        })?;
+                }
+
+                quiche_config
+                    .load_priv_key_from_pem_file(&config.key.to_string_lossy())
+                    .map_err(|err| {
+                        Error::new(
+                            ErrorKind::NotFound,
+                            format!("Unable to load key file {:?}, {}", config.key, err),
+                        )
+                    })?;
+
+                let wire_protos = protos
+                    .iter()
+                    .map(|proto| proto.as_bytes())
+                    .collect::<Vec<_>>();
+
+                quiche_config
+                    .set_application_protos(&wire_protos)
+                    .map_err(|err| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("failed to set application protos as {:?}, {}", protos, err),
+                        )
+                    })?;
+
+                Ok(())
+            })
+        });
+
+        if let Some(preamble) = self.preamble() {
+            agent.preamble(preamble)
+        } else {
+            agent
+        }
+    }
+
+    /// Returns the stream preamble of this listener, if `remote` is set or in `proxy` mode.
+    ///
+    /// The target is empty in `proxy` mode, it's replaced by the destination of each client.
+    pub fn preamble(&self) -> Option<Preamble> {
+        let preamble = match self.mode {
+            ListenerMode::Proxy => Preamble::default(),
+            _ => Preamble::new(self.remote.as_ref()?),
+        };
+
+        Some(match &self.token {
+            Some(token) => preamble.token(token),
+            None => preamble,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use n3quic::QuicCongestionControl;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = AgentConfig::from_toml(
+            r#"
+            cert = "n3agent.crt"
+            metrics = "127.0.0.1:9091"
+            shutdown_timeout = 10
+
+            [quic]
+            max_idle_timeout = 1000
+            cc_algorithm = "reno"
+            enable_pacing = false
+
+            [[listener]]
+            laddr = "[::]:1812"
+            n3_ip = "10.0.0.1"
+            n3_ports = "443:445"
+
+            [[listener]]
+            laddr = "[::]:1813"
+            n3_ip = "10.0.0.2"
+            n3_ports = 8443
+            server_name = "api.example.com"
+            remote = "db.internal:5432"
+            token = "secret"
+
+            [listener.quic]
+            initial_max_streams = 1000
+            max_pacing_rate = 1000000
+
+            [[listener]]
+            mode = "tunnel"
+            laddr = "127.0.0.1:8080"
+            n3_ip = "10.0.0.2"
+            n3_ports = 9443
+
+            [[listener]]
+            mode = "udp"
+            laddr = "127.0.0.1:5353"
+            n3_ip = "10.0.0.2"
+            n3_ports = 8443
+
+            [[listener]]
+            mode = "proxy"
+            laddr = "127.0.0.1:1080"
+            n3_ip = "10.0.0.2"
+            n3_ports = 8443
+            token = "secret"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.protos, vec!["n3".to_owned()]);
+        assert_eq!(config.metrics, Some("127.0.0.1:9091".parse().unwrap()));
+        assert_eq!(config.shutdown_timeout, 10);
+        assert_eq!(config.listeners[0].mode, ListenerMode::Listen);
+        assert_eq!(config.listeners[2].mode, ListenerMode::Tunnel);
+        assert_eq!(config.listeners[3].mode, ListenerMode::Udp);
+        assert_eq!(config.listeners[3].preamble(), None);
+        assert_eq!(config.listeners[4].mode, ListenerMode::Proxy);
+        assert_eq!(
+            config.listeners[4].preamble(),
+            Some(Preamble::default().token("secret"))
+        );
+        assert_eq!(config.listeners[0].preamble(), None);
+        assert_eq!(
+            config.listeners[1].preamble(),
+            Some(Preamble::new("db.internal:5432").token("secret"))
+        );
+        assert_eq!(
+            config.listeners[0].n3_addrs(),
+            vec![
+                "10.0.0.1:443".parse::<SocketAddr>().unwrap(),
+                "10.0.0.1:444".parse().unwrap()
+            ]
+        );
+
+        let base = config.quic.or(&AgentConfig::default_quic());
+        let tuning = config.listeners[1].quic.or(&base);
+
+        assert_eq!(tuning.max_idle_timeout, Some(1000));
+        assert_eq!(tuning.initial_max_streams, Some(1000));
+        assert_eq!(tuning.initial_max_stream_data, Some(1024 * 1024));
+        assert_eq!(tuning.cc_algorithm, Some(QuicCongestionControl::Reno));
+        assert_eq!(tuning.enable_pacing, Some(false));
+        assert_eq!(tuning.max_pacing_rate, Some(1000000));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let invalid = |content: &str| AgentConfig::from_toml(content).unwrap_err().to_string();
+
+        assert!(invalid("").contains("at least one `[[listener]]`"));
+        assert!(invalid("protos = []\n[[listener]]\nladdr = \"[::]:1812\"\nn3_ip = \"::1\"\nn3_ports = 443\n")
+            .contains("`protos` is empty"));
+        assert!(
+            invalid("[[listener]]\nladdr = \"[::]:1812\"\nn3_ip = \"::1\"\nn3_ports = \"1:0\"\n")
+                .contains("`to > from`")
+        );
+        assert!(
+            invalid("[[listener]]\nladdr = \"[::]:1812\"\nn3_ip = \"::1\"\n")
+                .contains("missing field `n3_ports`")
+        );
+        assert!(
+            invalid("[[listener]]\nladdr = \"[::]:1812\"\nn3_ip = \"::1\"\nn3_ports = 443\nremote = \"db.internal\"\n")
+                .contains("has no port")
+        );
+        assert!(
+            invalid("[[listener]]\nladdr = \"[::]:1812\"\nn3_ip = \"::1\"\nn3_ports = 443\ntoken = \"secret\"\n")
+                .contains("`token` requires `remote`")
+        );
+        assert!(
+            invalid("[[listener]]\nmode = \"proxy\"\nladdr = \"[::]:1080\"\nn3_ip = \"::1\"\nn3_ports = 443\nremote = \"db.internal:5432\"\n")
+                .contains("`remote` is not allowed in `proxy` mode")
+        );
+        assert!(
+            invalid("[[listener]]\nmode = \"udp\"\nladdr = \"[::]:5353\"\nn3_ip = \"::1\"\nn3_ports = 443\ntoken = \"secret\"\n")
+                .contains("not allowed in `udp` mode")
+        );
+        assert!(
+            invalid("[[listener]]\nmode = \"tunnel\"\nladdr = \"127.0.0.1:8080\"\nn3_ip = \"::1\"\nn3_ports = 443\n")
+                .contains("`cert` is required in `tunnel` mode")
+        );
+        assert!(
+            invalid("[quic]\ninitial_congestion_window_packets = 0\n[[listener]]\nladdr = \"[::]:1812\"\nn3_ip = \"::1\"\nn3_ports = 443\n")
+                .contains("quic: `initial_congestion_window_packets`")
+        );
+        assert!(
+            invalid("[[listener]]\nladdr = \"[::]:1812\"\nn3_ip = \"::1\"\nn3_ports = 443\n[listener.quic]\nmax_pacing_rate = 0\n")
+                .contains("listener[0]: `max_pacing_rate`")
+        );
+    }
+}
@@ -2,26 +2,54 @@ use std::{
     collections::HashMap,
     io::{Error, ErrorKind, Result},
     net::{SocketAddr, ToSocketAddrs},
-    time::Duration,
+    pin::pin,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use futures::AsyncWriteExt;
+use futures::{
+    AsyncWriteExt,
+    future::{Either, select},
+    lock::Mutex,
+};
 
+use n3_proto::{Preamble, PreambleStatus};
 use n3_spawner::spawn;
-use n3io::{copy::copy, net::TcpListener, timeout::TimeoutExt as _};
-use n3quic::{QuicConn, QuicConnExt, QuicConnector, QuicStream};
-
-#[derive(Debug, Default)]
-struct Metrics {
-    conns: usize,
-    streams: usize,
-    closed: usize,
-}
+use n3io::{
+    copy::copy,
+    net::{TcpListener, TcpStream, UdpSocket},
+    timeout::{TimeoutExt as _, sleep},
+};
+use n3quic::{QuicConn, QuicConnExt, QuicConnector, QuicShutdown, QuicStream};
+
+pub mod config;
+
+mod metrics;
+pub use metrics::*;
+
+mod proxy;
+use proxy::{ProxyReply, ProxyRequest};
+
+mod udp;
+
+/// The interval of checking whether the in-flight streams are finished during shutdown.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The timeout of waiting for the n3 server to answer a proxy request.
+const PREAMBLE_REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The initial delay before reconnecting a closed tunnel, doubled on each failure.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+
+/// The maximum delay before reconnecting a closed tunnel.
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
 
 struct QuicPool {
     conns: HashMap<String, QuicConn>,
     /// Configure for quic client connection.
     connector: QuicConnector,
+    /// agent counters.
+    metrics: Arc<AgentMetrics>,
 }
 
 impl QuicPool {
@@ -29,15 +57,13 @@ impl QuicPool {
         let mut closed = vec![];
         let mut stream = None;
 
-        let mut metrics = Metrics::default();
+        let mut streams = 0;
 
         for (trace_id, conn) in &self.conns {
-            metrics.conns += 1;
-            metrics.streams += conn.active_outbound_streams().unwrap_or(0) as usize;
+            streams += conn.active_outbound_streams().unwrap_or(0);
 
             if conn.is_closed() {
                 closed.push(trace_id.to_owned());
-                metrics.closed += 1;
                 continue;
             }
 
@@ -54,7 +80,6 @@ impl QuicPool {
                         conn.active_outbound_streams()
                     );
                     stream = Some((trace_id.clone(), outbound));
-                    metrics.streams += 1;
                 }
                 Err(err) if err.kind() == ErrorKind::WouldBlock => {
                     log::warn!(
@@ -74,13 +99,20 @@ impl QuicPool {
             }
         }
 
-        log::info!("{:?}", metrics);
+        log::info!(
+            "quic pool, conns={}, streams={}, closed={}",
+            self.conns.len(),
+            streams,
+            closed.len()
+        );
 
         for id in closed {
             log::info!("clearup closed connection, quic_conn_id={}", id);
             self.conns.remove(&id);
         }
 
+        self.metrics.active_quic_conns.set(self.conns.len() as i64);
+
         if let Some(stream) = stream {
             return Ok(stream);
         }
@@ -95,14 +127,21 @@ impl QuicPool {
         {
             Ok(conn) => conn,
             Err(err) if err.kind() == ErrorKind::TimedOut => {
+                self.metrics.quic_connect_errors.inc();
+
                 return Err(Error::new(
                     ErrorKind::TimedOut,
                     "quic connect to server timeout.",
                 ));
             }
-            Err(err) => return Err(err),
+            Err(err) => {
+                self.metrics.quic_connect_errors.inc();
+                return Err(err);
+            }
         };
 
+        self.metrics.quic_conns.inc();
+
         let stream = conn.open().await?;
 
         let trace_id = conn.quiche_conn(|conn| conn.trace_id().to_owned());
@@ -116,6 +155,8 @@ impl QuicPool {
 
         self.conns.insert(trace_id.clone(), conn);
 
+        self.metrics.active_quic_conns.set(self.conns.len() as i64);
+
         Ok((trace_id, stream))
     }
 }
@@ -124,6 +165,14 @@ impl QuicPool {
 pub struct Agent {
     /// Configure for quic client connection.
     connector: QuicConnector,
+    /// agent counters.
+    metrics: Arc<AgentMetrics>,
+    /// graceful shutdown handle.
+    shutdown: QuicShutdown,
+    /// written first on each stream, selects the target on the n3 server.
+    preamble: Option<Arc<Preamble>>,
+    /// read the target of each tcp connection from a SOCKS5 or `HTTP CONNECT` handshake.
+    proxy: bool,
 }
 
 impl Agent {
@@ -131,9 +180,29 @@ impl Agent {
     pub fn new<S: ToSocketAddrs>(raddrs: S) -> Self {
         Self {
             connector: QuicConnector::new(raddrs),
+            metrics: Default::default(),
+            shutdown: QuicShutdown::new(),
+            preamble: None,
+            proxy: false,
         }
     }
 
+    /// Write `preamble` first on each stream, the n3 server forwards the stream to its target.
+    pub fn preamble(mut self, preamble: Preamble) -> Self {
+        self.preamble = Some(Arc::new(preamble));
+        self
+    }
+
+    /// Run as a local SOCKS5 and `HTTP CONNECT` proxy.
+    ///
+    /// The destination requested by each client replaces the target of the [`preamble`](Self::preamble),
+    /// the n3 server must read the stream preambles to forward them. The client is answered
+    /// with the result of the n3 server, once the destination is connected or rejected.
+    pub fn proxy(mut self) -> Self {
+        self.proxy = true;
+        self
+    }
+
     /// Update quic connector configuration.
     pub fn connector<F>(mut self, f: F) -> Self
     where
@@ -143,108 +212,600 @@ impl Agent {
         self
     }
 
+    /// Returns the counters of this agent.
+    pub fn metrics(&self) -> Arc<AgentMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Returns the handle to shutdown this agent gracefully.
+    ///
+    /// After the shutdown is started, no more tcp connections are accepted, [`bind`](Self::bind)
+    /// returns once the in-flight streams are finished or the grace period expires.
+    pub fn shutdown_handle(&self) -> QuicShutdown {
+        self.shutdown.clone()
+    }
+
     /// Bind `agent` to `laddr` and run it.
     pub async fn bind(self, laddr: SocketAddr) -> Result<()> {
         let listener = TcpListener::bind(laddr).await?;
 
-        let mut pool = QuicPool {
+        let metrics = self.metrics;
+
+        let pool = Arc::new(Mutex::new(QuicPool {
             connector: self.connector,
             conns: Default::default(),
-        };
+            metrics: metrics.clone(),
+        }));
 
-        loop {
-            let (inbound, from) = listener.accept().await?;
+        // cloned by each stream, released when both directions are closed.
+        let inflight = Arc::new(());
 
-            let (trace_id, outbound) = match pool.connect().await {
-                Ok((trace_id, outbound)) => {
-                    log::info!("in: {}, out: ({},{})", from, trace_id, outbound.id());
-                    (trace_id, outbound)
+        let deadline = loop {
+            let (inbound, from) =
+                match select(pin!(listener.accept()), pin!(self.shutdown.wait())).await {
+                    Either::Left((accepted, _)) => accepted?,
+                    Either::Right((deadline, _)) => break deadline,
+                };
+
+            metrics.tcp_conns.inc();
+
+            let pool = pool.clone();
+            let preamble = self.preamble.clone();
+            let proxy = self.proxy;
+            let metrics = metrics.clone();
+            let inflight = inflight.clone();
+
+            spawn(async move {
+                if let Err(err) =
+                    Self::forward(pool, inbound, from, preamble, proxy, metrics, inflight).await
+                {
+                    log::error!("failed to forward tcp({}), err={}", from, err);
                 }
-                Err(err) => {
-                    log::error!(
-                        "Failed to open quic stream for inbound, from={}, err={}",
-                        from,
-                        err
-                    );
-                    continue;
+            })?;
+        };
+
+        drop(listener);
+
+        log::info!(
+            "shutdown, laddr={}, inflight={}",
+            laddr,
+            Arc::strong_count(&inflight) - 1
+        );
+
+        while Arc::strong_count(&inflight) > 1 && Instant::now() < deadline {
+            sleep(DRAIN_CHECK_INTERVAL).await;
+        }
+
+        // dropping `pool` closes the quic connections.
+        log::info!(
+            "shutdown completed, laddr={}, inflight={}",
+            laddr,
+            Arc::strong_count(&inflight) - 1
+        );
+
+        Ok(())
+    }
+
+    /// Forward the tcp connection `inbound` through a quic stream of `pool`.
+    async fn forward(
+        pool: Arc<Mutex<QuicPool>>,
+        mut inbound: TcpStream,
+        from: SocketAddr,
+        preamble: Option<Arc<Preamble>>,
+        proxy: bool,
+        metrics: Arc<AgentMetrics>,
+        inflight: Arc<()>,
+    ) -> Result<()> {
+        // in proxy mode, the target of each stream is read from the client handshake.
+        let request = if proxy {
+            let request = ProxyRequest::read(&mut inbound).await?;
+
+            log::info!("proxy request, from={}, target={}", from, request.target);
+
+            Some(request)
+        } else {
+            None
+        };
+
+        let connected = pool.lock().await.connect().await;
+
+        let (trace_id, mut outbound) = match connected {
+            Ok((trace_id, outbound)) => {
+                log::info!("in: {}, out: ({},{})", from, trace_id, outbound.id());
+                (trace_id, outbound)
+            }
+            Err(err) => {
+                metrics.stream_open_errors.inc();
+
+                log::error!(
+                    "Failed to open quic stream for inbound, from={}, err={}",
+                    from,
+                    err
+                );
+
+                if let Some(request) = &request {
+                    request.reply(&mut inbound, ProxyReply::Failure).await?;
                 }
-            };
 
-            let stream_id = outbound.id();
+                return Ok(());
+            }
+        };
 
-            let (mut inbound_writer, inbound_reader) = inbound.split();
-            let (mut outbound_writer, outbound_reader) = outbound.split();
+        let stream_id = outbound.id();
 
-            let trace_id_cloned = trace_id.clone();
+        // in proxy mode, the client is answered once the n3 server has connected the target, the
+        // preamble is written here instead of by the forward direction.
+        let preamble = match &request {
+            Some(request) => {
+                let preamble = Preamble {
+                    target: request.target.clone(),
+                    ..preamble.as_deref().cloned().unwrap_or_default()
+                }
+                .reply();
 
-            spawn(async move {
-                let id = format!("tcp({}) <- quic({},{})", from, trace_id_cloned, stream_id,);
-
-                match copy(Some(&id), outbound_reader, &mut inbound_writer, 65535).await {
-                    Ok(len) => {
-                        log::info!(
-                            "stream(backward) is closed, tcp({}) <== quic({},{}), transferred={}",
-                            from,
-                            trace_id_cloned,
-                            stream_id,
-                            len
-                        );
-                    }
+                let reply = match Self::handshake(&mut outbound, &preamble)
+                    .timeout(PREAMBLE_REPLY_TIMEOUT)
+                    .await
+                {
+                    Ok(status) => status.into(),
                     Err(err) => {
                         log::error!(
-                            "stream(backward) is closed, tcp({}) <== quic({},{}), err={}",
-                            from,
-                            trace_id_cloned,
+                            "failed to handshake preamble, quic({},{}), err={}",
+                            trace_id,
                             stream_id,
                             err
                         );
+
+                        ProxyReply::Failure
                     }
+                };
+
+                request.reply(&mut inbound, reply).await?;
+
+                if reply != ProxyReply::Succeeded {
+                    log::error!(
+                        "proxy request is rejected, from={}, target={}, reply={:?}",
+                        from,
+                        request.target,
+                        reply
+                    );
+
+                    return Ok(());
                 }
 
-                if let Err(err) = inbound_writer.close().await {
-                    log::trace!(
-                        "stream(backward) close writer, tcp({}) ==> quic({},{}), err={}",
+                None
+            }
+            None => preamble,
+        };
+
+        metrics.streams.inc();
+
+        let (mut inbound_writer, inbound_reader) = inbound.split();
+        let (mut outbound_writer, outbound_reader) = outbound.split();
+
+        let trace_id_cloned = trace_id.clone();
+
+        // decreased when both directions are closed.
+        let active_stream = Arc::new((ActiveStream::new(metrics.clone()), inflight.clone()));
+        let active_stream_cloned = active_stream.clone();
+        let metrics_cloned = metrics.clone();
+
+        spawn(async move {
+            let _active_stream = active_stream_cloned;
+
+            let id = format!("tcp({}) <- quic({},{})", from, trace_id_cloned, stream_id,);
+
+            match copy(Some(&id), outbound_reader, &mut inbound_writer, 65535).await {
+                Ok(len) => {
+                    metrics_cloned.backward_bytes.add(len as u64);
+
+                    log::info!(
+                        "stream(backward) is closed, tcp({}) <== quic({},{}), transferred={}",
+                        from,
+                        trace_id_cloned,
+                        stream_id,
+                        len
+                    );
+                }
+                Err(err) => {
+                    log::error!(
+                        "stream(backward) is closed, tcp({}) <== quic({},{}), err={}",
                         from,
                         trace_id_cloned,
                         stream_id,
                         err
                     );
                 }
-            })?;
+            }
 
-            spawn(async move {
-                let id = format!("tcp({}) -> quic({},{})", from, trace_id, stream_id,);
-                match copy(Some(&id), inbound_reader, &mut outbound_writer, 65535).await {
-                    Ok(len) => {
-                        log::info!(
-                            "stream(forward) is closed, tcp({}) ==> quic({},{}), transferred={}",
-                            from,
-                            trace_id,
-                            stream_id,
-                            len
-                        );
+            if let Err(err) = inbound_writer.close().await {
+                log::trace!(
+                    "stream(backward) close writer, tcp({}) ==> quic({},{}), err={}",
+                    from,
+                    trace_id_cloned,
+                    stream_id,
+                    err
+                );
+            }
+        })?;
+
+        let metrics_cloned = metrics.clone();
+
+        spawn(async move {
+            let _active_stream = active_stream;
+
+            if let Some(preamble) = preamble
+                && let Err(err) = preamble.write(&mut outbound_writer).await
+            {
+                log::error!(
+                    "failed to write preamble, quic({},{}), err={}",
+                    trace_id,
+                    stream_id,
+                    err
+                );
+                return;
+            }
+
+            let id = format!("tcp({}) -> quic({},{})", from, trace_id, stream_id,);
+            match copy(Some(&id), inbound_reader, &mut outbound_writer, 65535).await {
+                Ok(len) => {
+                    metrics_cloned.forward_bytes.add(len as u64);
+
+                    log::info!(
+                        "stream(forward) is closed, tcp({}) ==> quic({},{}), transferred={}",
+                        from,
+                        trace_id,
+                        stream_id,
+                        len
+                    );
+                }
+                Err(err) => {
+                    log::error!(
+                        "stream(forward) is closed, tcp({}) ==> quic({},{}), err={}",
+                        from,
+                        trace_id,
+                        stream_id,
+                        err
+                    );
+                }
+            }
+
+            if let Err(err) = outbound_writer.close().await {
+                log::trace!(
+                    "stream(forward) close writer, tcp({}) <== quic({},{}), err={}",
+                    from,
+                    trace_id,
+                    stream_id,
+                    err
+                );
+            }
+        })?;
+
+        Ok(())
+    }
+
+    /// Write `preamble` to `outbound`, returns the answer of the n3 server.
+    async fn handshake(outbound: &mut QuicStream, preamble: &Preamble) -> Result<PreambleStatus> {
+        preamble.write(outbound).await?;
+
+        PreambleStatus::read(outbound).await
+    }
+
+    /// Keep a quic connection to the n3 server and connect the server-initiated streams to
+    /// `target`, the agent side of a reverse tunnel.
+    ///
+    /// The connection is reestablished with an exponential backoff when it's closed.
+    pub async fn tunnel(mut self, target: SocketAddr) -> Result<()> {
+        let metrics = self.metrics.clone();
+
+        // cloned by each stream, released when both directions are closed.
+        let inflight = Arc::new(());
+
+        let mut backoff = RECONNECT_BACKOFF_MIN;
+
+        // kept alive until the in-flight streams are drained.
+        let mut tunnel = None;
+
+        let deadline = 'tunnel: loop {
+            let connect = self.connector.connect().timeout(Duration::from_secs(5));
+
+            let conn = match select(pin!(connect), pin!(self.shutdown.wait())).await {
+                Either::Left((Ok(conn), _)) => conn,
+                Either::Left((Err(err), _)) => {
+                    metrics.quic_connect_errors.inc();
+
+                    log::error!(
+                        "failed to connect tunnel, target={}, retry_in={:?}, err={}",
+                        target,
+                        backoff,
+                        err
+                    );
+
+                    if let Either::Right((deadline, _)) =
+                        select(pin!(sleep(backoff)), pin!(self.shutdown.wait())).await
+                    {
+                        break deadline;
                     }
-                    Err(err) => {
+
+                    backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+
+                    continue;
+                }
+                Either::Right((deadline, _)) => break deadline,
+            };
+
+            backoff = RECONNECT_BACKOFF_MIN;
+
+            metrics.quic_conns.inc();
+            metrics.active_quic_conns.set(1);
+
+            let trace_id = conn.quiche_conn(|conn| conn.trace_id().to_owned());
+
+            log::info!("tunnel is connected, id={}, target={}", trace_id, target);
+
+            let conn = tunnel.insert(conn);
+
+            loop {
+                let inbound = match select(conn.accept(), pin!(self.shutdown.wait())).await {
+                    Either::Left((Ok(inbound), _)) => inbound,
+                    Either::Left((Err(err), _)) => {
+                        log::error!("tunnel is closed, id={}, err={}", trace_id, err);
+                        break;
+                    }
+                    Either::Right((deadline, _)) => break 'tunnel deadline,
+                };
+
+                metrics.streams.inc();
+
+                let trace_id = trace_id.clone();
+                let metrics = metrics.clone();
+                let inflight = inflight.clone();
+
+                spawn(async move {
+                    let stream_id = inbound.id();
+
+                    let outbound = match TcpStream::connect(target).await {
+                        Ok(outbound) => outbound,
+                        Err(err) => {
+                            metrics.stream_open_errors.inc();
+
+                            log::error!(
+                                "failed to connect tunnel target, quic({},{}), target={}, err={}",
+                                trace_id,
+                                stream_id,
+                                target,
+                                err
+                            );
+                            return;
+                        }
+                    };
+
+                    if let Err(err) = Self::tunnel_pipe(
+                        inbound,
+                        outbound,
+                        target,
+                        trace_id.clone(),
+                        metrics,
+                        inflight,
+                    ) {
                         log::error!(
-                            "stream(forward) is closed, tcp({}) ==> quic({},{}), err={}",
-                            from,
+                            "failed to create tunnel pipe, quic({},{}), err={}",
                             trace_id,
                             stream_id,
                             err
                         );
                     }
+                })?;
+            }
+
+            tunnel = None;
+
+            metrics.active_quic_conns.set(0);
+        };
+
+        log::info!(
+            "tunnel shutdown, target={}, inflight={}",
+            target,
+            Arc::strong_count(&inflight) - 1
+        );
+
+        while Arc::strong_count(&inflight) > 1
+            && tunnel.as_ref().is_some_and(|conn| !conn.is_closed())
+            && Instant::now() < deadline
+        {
+            sleep(DRAIN_CHECK_INTERVAL).await;
+        }
+
+        drop(tunnel);
+
+        metrics.active_quic_conns.set(0);
+
+        log::info!("tunnel shutdown completed, target={}", target);
+
+        Ok(())
+    }
+
+    /// Bind a udp socket to `laddr`, relay its datagrams to the n3 server in QUIC DATAGRAM
+    /// frames.
+    ///
+    /// Each peer address of the socket is a flow, the n3 server relays it to the upstream by its
+    /// own udp socket. The connection is reestablished with an exponential backoff when it's
+    /// closed.
+    pub async fn bind_udp(mut self, laddr: SocketAddr) -> Result<()> {
+        let socket = UdpSocket::bind(laddr).await?;
+
+        let metrics = self.metrics.clone();
+
+        let mut backoff = RECONNECT_BACKOFF_MIN;
+
+        loop {
+            let connect = self.connector.connect().timeout(Duration::from_secs(5));
+
+            let conn = match select(pin!(connect), pin!(self.shutdown.wait())).await {
+                Either::Left((Ok(conn), _)) => conn,
+                Either::Left((Err(err), _)) => {
+                    metrics.quic_connect_errors.inc();
+
+                    log::error!(
+                        "failed to connect udp relay, laddr={}, retry_in={:?}, err={}",
+                        laddr,
+                        backoff,
+                        err
+                    );
+
+                    if let Either::Right(_) =
+                        select(pin!(sleep(backoff)), pin!(self.shutdown.wait())).await
+                    {
+                        break;
+                    }
+
+                    backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+
+                    continue;
                 }
+                Either::Right(_) => break,
+            };
 
-                if let Err(err) = outbound_writer.close().await {
-                    log::trace!(
-                        "stream(forward) close writer, tcp({}) <== quic({},{}), err={}",
-                        from,
+            backoff = RECONNECT_BACKOFF_MIN;
+
+            metrics.quic_conns.inc();
+            metrics.active_quic_conns.set(1);
+
+            let trace_id = conn.quiche_conn(|conn| conn.trace_id().to_owned());
+
+            log::info!("udp relay is connected, id={}, laddr={}", trace_id, laddr);
+
+            let relay = udp::relay(&conn, &socket, &metrics, &trace_id);
+
+            let shutdown = match select(pin!(relay), pin!(self.shutdown.wait())).await {
+                Either::Left((result, _)) => {
+                    if let Err(err) = result {
+                        log::error!("udp relay is closed, id={}, err={}", trace_id, err);
+                    }
+
+                    false
+                }
+                Either::Right(_) => true,
+            };
+
+            metrics.active_quic_conns.set(0);
+
+            if shutdown {
+                break;
+            }
+        }
+
+        // datagrams have no in-flight state, dropping `conn` closes the relay.
+        log::info!("udp relay shutdown completed, laddr={}", laddr);
+
+        Ok(())
+    }
+
+    fn tunnel_pipe(
+        inbound: QuicStream,
+        outbound: TcpStream,
+        target: SocketAddr,
+        trace_id: String,
+        metrics: Arc<AgentMetrics>,
+        inflight: Arc<()>,
+    ) -> Result<()> {
+        let stream_id = inbound.id();
+
+        log::info!(
+            "new tunnel pipe quic({},{}) => tcp({})",
+            trace_id,
+            stream_id,
+            target
+        );
+
+        // released when both directions are closed.
+        let active_stream = Arc::new((ActiveStream::new(metrics.clone()), inflight));
+        let active_stream_cloned = active_stream.clone();
+        let metrics_cloned = metrics.clone();
+
+        let (mut inbound_writer, inbound_reader) = inbound.split();
+        let (mut outbound_writer, outbound_reader) = outbound.split();
+
+        let trace_id_cloned = trace_id.clone();
+
+        spawn(async move {
+            let _active_stream = active_stream_cloned;
+
+            let id = format!("quic({},{}) <- tcp({})", trace_id_cloned, stream_id, target);
+
+            match copy(Some(&id), outbound_reader, &mut inbound_writer, 65535).await {
+                Ok(len) => {
+                    metrics_cloned.forward_bytes.add(len as u64);
+
+                    log::info!(
+                        "tunnel(backward) is closed, quic({},{}) <== tcp({}), transferred={}",
+                        trace_id_cloned,
+                        stream_id,
+                        target,
+                        len
+                    );
+                }
+                Err(err) => {
+                    log::error!(
+                        "tunnel(backward) is closed, quic({},{}) <== tcp({}), err={}",
+                        trace_id_cloned,
+                        stream_id,
+                        target,
+                        err
+                    );
+                }
+            }
+
+            if let Err(err) = inbound_writer.close().await {
+                log::trace!(
+                    "tunnel(backward) close writer, quic({},{}) ==> tcp({}), err={}",
+                    trace_id_cloned,
+                    stream_id,
+                    target,
+                    err
+                );
+            }
+        })?;
+
+        spawn(async move {
+            let _active_stream = active_stream;
+
+            let id = format!("quic({},{}) -> tcp({})", trace_id, stream_id, target);
+
+            match copy(Some(&id), inbound_reader, &mut outbound_writer, 65535).await {
+                Ok(len) => {
+                    metrics.backward_bytes.add(len as u64);
+
+                    log::info!(
+                        "tunnel(forward) is closed, quic({},{}) ==> tcp({}), transferred={}",
                         trace_id,
                         stream_id,
+                        target,
+                        len
+                    );
+                }
+                Err(err) => {
+                    log::error!(
+                        "tunnel(forward) is closed, quic({},{}) ==> tcp({}), err={}",
+                        trace_id,
+                        stream_id,
+                        target,
                         err
                     );
                 }
-            })?;
-        }
+            }
+
+            if let Err(err) = outbound_writer.close().await {
+                log::trace!(
+                    "tunnel(forward) close writer, quic({},{}) <== tcp({}), err={}",
+                    trace_id,
+                    stream_id,
+                    target,
+                    err
+                );
+            }
+        })?;
+
+        Ok(())
     }
 }
@@ -0,0 +1,146 @@
+use std::sync::Arc;
+
+use n3_metrics::{Collector, Counter, Encoder, Gauge};
+
+/// Counters of an [`Agent`](crate::Agent).
+#[derive(Debug, Default)]
+pub struct AgentMetrics {
+    /// Accepted tcp connections.
+    pub tcp_conns: Counter,
+    /// Established quic connections to the n3 servers.
+    pub quic_conns: Counter,
+    /// Failures of connecting to the n3 servers.
+    pub quic_connect_errors: Counter,
+    /// Quic connections in the pool.
+    pub active_quic_conns: Gauge,
+    /// Opened quic streams.
+    pub streams: Counter,
+    /// Streams that are being forwarded.
+    pub active_streams: Gauge,
+    /// Tcp connections dropped because no quic stream can be opened.
+    pub stream_open_errors: Counter,
+    /// Bytes copied from the tcp connections to the n3 servers.
+    pub forward_bytes: Counter,
+    /// Bytes copied from the n3 servers to the tcp connections.
+    pub backward_bytes: Counter,
+    /// Local udp peers that are being relayed.
+    pub udp_flows: Gauge,
+    /// Datagrams dropped because they are invalid, too large or the send queue is full.
+    pub dropped_datagrams: Counter,
+    /// Udp payload bytes relayed from the local peers to the n3 servers.
+    pub udp_forward_bytes: Counter,
+    /// Udp payload bytes relayed from the n3 servers to the local peers.
+    pub udp_backward_bytes: Counter,
+}
+
+impl Collector for AgentMetrics {
+    fn collect(&self, encoder: &mut Encoder) {
+        encoder.counter(
+            "n3agent_tcp_conns_total",
+            "Accepted tcp connections.",
+            &[],
+            self.tcp_conns.get(),
+        );
+
+        encoder.counter(
+            "n3agent_quic_conns_total",
+            "Established quic connections to the n3 servers.",
+            &[],
+            self.quic_conns.get(),
+        );
+
+        encoder.counter(
+            "n3agent_quic_connect_errors_total",
+            "Failures of connecting to the n3 servers.",
+            &[],
+            self.quic_connect_errors.get(),
+        );
+
+        encoder.gauge(
+            "n3agent_active_quic_conns",
+            "Quic connections in the pool.",
+            &[],
+            self.active_quic_conns.get(),
+        );
+
+        encoder.counter(
+            "n3agent_streams_total",
+            "Opened quic streams.",
+            &[],
+            self.streams.get(),
+        );
+
+        encoder.gauge(
+            "n3agent_active_streams",
+            "Streams that are being forwarded.",
+            &[],
+            self.active_streams.get(),
+        );
+
+        encoder.counter(
+            "n3agent_stream_open_errors_total",
+            "Tcp connections dropped because no quic stream can be opened.",
+            &[],
+            self.stream_open_errors.get(),
+        );
+
+        encoder.counter(
+            "n3agent_stream_bytes_total",
+            "Bytes copied between the tcp connections and the n3 servers.",
+            &[("direction", "forward")],
+            self.forward_bytes.get(),
+        );
+
+        encoder.counter(
+            "n3agent_stream_bytes_total",
+            "Bytes copied between the tcp connections and the n3 servers.",
+            &[("direction", "backward")],
+            self.backward_bytes.get(),
+        );
+
+        encoder.gauge(
+            "n3agent_udp_flows",
+            "Local udp peers that are being relayed.",
+            &[],
+            self.udp_flows.get(),
+        );
+
+        encoder.counter(
+            "n3agent_dropped_datagrams_total",
+            "Datagrams dropped because they are invalid, too large or the send queue is full.",
+            &[],
+            self.dropped_datagrams.get(),
+        );
+
+        encoder.counter(
+            "n3agent_udp_bytes_total",
+            "Udp payload bytes relayed between the local peers and the n3 servers.",
+            &[("direction", "forward")],
+            self.udp_forward_bytes.get(),
+        );
+
+        encoder.counter(
+            "n3agent_udp_bytes_total",
+            "Udp payload bytes relayed between the local peers and the n3 servers.",
+            &[("direction", "backward")],
+            self.udp_backward_bytes.get(),
+        );
+    }
+}
+
+/// A forwarded stream, `active_streams` is decreased when it's dropped.
+pub(crate) struct ActiveStream(Arc<AgentMetrics>);
+
+impl ActiveStream {
+    pub(crate) fn new(metrics: Arc<AgentMetrics>) -> Self {
+        metrics.active_streams.inc();
+
+        Self(metrics)
+    }
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.0.active_streams.dec();
+    }
+}
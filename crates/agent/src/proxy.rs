@@ -0,0 +1,414 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use n3_proto::{Preamble, PreambleStatus};
+
+/// The protocol version byte of SOCKS5.
+const SOCKS5_VERSION: u8 = 5;
+
+/// The maximum size of a `HTTP CONNECT` request head.
+const MAX_REQUEST_HEAD_LEN: usize = 8 * 1024;
+
+/// The maximum number of headers of a `HTTP CONNECT` request.
+const MAX_REQUEST_HEADERS: usize = 64;
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, reason.into())
+}
+
+/// The handshake protocol of a local proxy client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ProxyKind {
+    Socks5,
+    HttpConnect,
+}
+
+/// The result of a proxy request, sent as the final handshake reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ProxyReply {
+    /// The target is connected.
+    Succeeded,
+    /// The tunnel to the n3 server failed.
+    Failure,
+    /// The n3 server denied the target.
+    NotAllowed,
+    /// The target can't be resolved or connected by the n3 server.
+    HostUnreachable,
+    /// The target is not a valid `host:port`.
+    InvalidTarget,
+}
+
+impl From<PreambleStatus> for ProxyReply {
+    fn from(status: PreambleStatus) -> Self {
+        match status {
+            PreambleStatus::Connected => Self::Succeeded,
+            PreambleStatus::Denied => Self::NotAllowed,
+            PreambleStatus::InvalidTarget => Self::InvalidTarget,
+            PreambleStatus::Unreachable => Self::HostUnreachable,
+        }
+    }
+}
+
+/// The destination requested by a local proxy client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ProxyRequest {
+    pub(crate) kind: ProxyKind,
+    /// The requested target, `host:port`.
+    pub(crate) target: String,
+}
+
+impl ProxyRequest {
+    /// Read a SOCKS5 or `HTTP CONNECT` handshake from `stream`, detected by the first byte.
+    ///
+    /// Unsupported requests are answered with an error reply before returning the error.
+    pub(crate) async fn read<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<Self> {
+        let mut first = [0u8; 1];
+
+        stream.read_exact(&mut first).await?;
+
+        let request = if first[0] == SOCKS5_VERSION {
+            Self::read_socks5(stream).await?
+        } else {
+            Self::read_http_connect(stream, first[0]).await?
+        };
+
+        if let Err(err) = Preamble::new(request.target.as_str()).host_port() {
+            request.reply(stream, ProxyReply::InvalidTarget).await?;
+            return Err(err);
+        }
+
+        Ok(request)
+    }
+
+    async fn read_socks5<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<Self> {
+        let mut nmethods = [0u8; 1];
+
+        stream.read_exact(&mut nmethods).await?;
+
+        let mut methods = vec![0u8; nmethods[0] as usize];
+
+        stream.read_exact(&mut methods).await?;
+
+        // only `NO AUTHENTICATION REQUIRED` is supported.
+        if !methods.contains(&0) {
+            stream.write_all(&[SOCKS5_VERSION, 0xff]).await?;
+            stream.flush().await?;
+
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "socks5: no acceptable authentication method",
+            ));
+        }
+
+        stream.write_all(&[SOCKS5_VERSION, 0]).await?;
+        stream.flush().await?;
+
+        let mut header = [0u8; 4];
+
+        stream.read_exact(&mut header).await?;
+
+        if header[0] != SOCKS5_VERSION {
+            return Err(invalid(format!("socks5: invalid version {}", header[0])));
+        }
+
+        let target = match header[3] {
+            1 => {
+                let mut buf = [0u8; 6];
+                stream.read_exact(&mut buf).await?;
+
+                let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
+
+                SocketAddr::from((ip, u16::from_be_bytes([buf[4], buf[5]]))).to_string()
+            }
+            3 => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len).await?;
+
+                let mut buf = vec![0u8; len[0] as usize + 2];
+                stream.read_exact(&mut buf).await?;
+
+                let port = u16::from_be_bytes([buf[buf.len() - 2], buf[buf.len() - 1]]);
+
+                let host = std::str::from_utf8(&buf[..buf.len() - 2])
+                    .map_err(|err| invalid(format!("socks5: invalid domain name, {}", err)))?;
+
+                format!("{}:{}", host, port)
+            }
+            4 => {
+                let mut buf = [0u8; 18];
+                stream.read_exact(&mut buf).await?;
+
+                let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&buf[..16]).unwrap());
+
+                SocketAddr::from((ip, u16::from_be_bytes([buf[16], buf[17]]))).to_string()
+            }
+            atyp => {
+                Self::socks5_reply(stream, 0x08).await?;
+
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("socks5: unsupported address type {}", atyp),
+                ));
+            }
+        };
+
+        // only `CONNECT` is supported.
+        if header[1] != 1 {
+            Self::socks5_reply(stream, 0x07).await?;
+
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("socks5: unsupported command {}", header[1]),
+            ));
+        }
+
+        Ok(Self {
+            kind: ProxyKind::Socks5,
+            target,
+        })
+    }
+
+    async fn read_http_connect<S: AsyncRead + AsyncWrite + Unpin>(
+        stream: &mut S,
+        first: u8,
+    ) -> Result<Self> {
+        let mut head = vec![first];
+
+        // read byte by byte, the bytes after the request head belong to the tunnel.
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_REQUEST_HEAD_LEN {
+                Self::http_reply(stream, "431 Request Header Fields Too Large").await?;
+
+                return Err(invalid("http connect: request head is too long"));
+            }
+
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).await?;
+
+            head.push(byte[0]);
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+
+        let (method, path) = match request.parse(&head) {
+            Ok(httparse::Status::Complete(_)) => (request.method, request.path),
+            _ => (None, None),
+        };
+
+        let (Some(method), Some(path)) = (method, path) else {
+            Self::http_reply(stream, "400 Bad Request").await?;
+
+            return Err(invalid("http connect: invalid request"));
+        };
+
+        if !method.eq_ignore_ascii_case("CONNECT") {
+            Self::http_reply(stream, "405 Method Not Allowed").await?;
+
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("http connect: unsupported method {}", method),
+            ));
+        }
+
+        Ok(Self {
+            kind: ProxyKind::HttpConnect,
+            target: path.to_owned(),
+        })
+    }
+
+    /// Send the final handshake reply, the client starts sending payload after a success reply.
+    pub(crate) async fn reply<S: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        reply: ProxyReply,
+    ) -> Result<()> {
+        match self.kind {
+            ProxyKind::Socks5 => {
+                let rep = match reply {
+                    ProxyReply::Succeeded => 0,
+                    ProxyReply::Failure => 0x01,
+                    ProxyReply::NotAllowed => 0x02,
+                    ProxyReply::HostUnreachable => 0x04,
+                    ProxyReply::InvalidTarget => 0x08,
+                };
+
+                Self::socks5_reply(stream, rep).await
+            }
+            ProxyKind::HttpConnect => {
+                let status = match reply {
+                    ProxyReply::Succeeded => "200 Connection Established",
+                    ProxyReply::Failure | ProxyReply::HostUnreachable => "502 Bad Gateway",
+                    ProxyReply::NotAllowed => "403 Forbidden",
+                    ProxyReply::InvalidTarget => "400 Bad Request",
+                };
+
+                Self::http_reply(stream, status).await
+            }
+        }
+    }
+
+    async fn socks5_reply<S: AsyncWrite + Unpin>(stream: &mut S, rep: u8) -> Result<()> {
+        // the bound address is unknown to the agent, reply `0.0.0.0:0`.
+        stream
+            .write_all(&[SOCKS5_VERSION, rep, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await?;
+        stream.flush().await
+    }
+
+    async fn http_reply<S: AsyncWrite + Unpin>(stream: &mut S, status: &str) -> Result<()> {
+        stream
+            .write_all(format!("HTTP/1.1 {}\r\n\r\n", status).as_bytes())
+            .await?;
+        stream.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use futures::{executor::block_on, io::Cursor};
+
+    use super::*;
+
+    /// A client connection with a scripted input, records the replies.
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl MockStream {
+        fn new(input: &[u8]) -> Self {
+            Self {
+                input: Cursor::new(input.to_vec()),
+                output: vec![],
+            }
+        }
+    }
+
+    impl AsyncRead for MockStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<Result<usize>> {
+            Pin::new(&mut self.input).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for MockStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize>> {
+            Pin::new(&mut self.output).poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_socks5() {
+        let read = |input: &[u8]| {
+            let mut stream = MockStream::new(input);
+            let request = block_on(ProxyRequest::read(&mut stream));
+            (request, stream.output)
+        };
+
+        let (request, output) = read(&[
+            5, 1, 0, 5, 1, 0, 3, 11, b'd', b'b', b'.', b'i', b'n', b't', b'e', b'r', b'n', b'a',
+            b'l', 0x15, 0x38,
+        ]);
+
+        assert_eq!(request.unwrap().target, "db.internal:5432");
+        assert_eq!(output, [5, 0]);
+
+        let (request, _) = read(&[5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80]);
+        assert_eq!(request.unwrap().target, "127.0.0.1:80");
+
+        let mut input = vec![5, 1, 0, 5, 1, 0, 4];
+        input.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        input.extend_from_slice(&[0, 80]);
+
+        let (request, _) = read(&input);
+        assert_eq!(request.unwrap().target, "[::1]:80");
+
+        // username/password only.
+        let (request, output) = read(&[5, 1, 2]);
+        assert_eq!(request.unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(output, [5, 0xff]);
+
+        // BIND
+        let (request, output) = read(&[5, 1, 0, 5, 2, 0, 1, 127, 0, 0, 1, 0, 80]);
+        assert_eq!(request.unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(output, [5, 0, 5, 7, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        // empty domain name.
+        let (request, output) = read(&[5, 1, 0, 5, 1, 0, 3, 0, 0, 80]);
+        assert_eq!(request.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(output, [5, 0, 5, 8, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        let request = ProxyRequest {
+            kind: ProxyKind::Socks5,
+            target: "db.internal:5432".to_owned(),
+        };
+
+        let mut stream = MockStream::new(b"");
+        block_on(request.reply(&mut stream, PreambleStatus::Unreachable.into())).unwrap();
+        assert_eq!(stream.output, [5, 4, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_http_connect() {
+        let mut stream = MockStream::new(
+            b"CONNECT db.internal:5432 HTTP/1.1\r\nHost: db.internal:5432\r\n\r\npayload",
+        );
+
+        let request = block_on(ProxyRequest::read(&mut stream)).unwrap();
+
+        assert_eq!(request.kind, ProxyKind::HttpConnect);
+        assert_eq!(request.target, "db.internal:5432");
+
+        block_on(request.reply(&mut stream, ProxyReply::Succeeded)).unwrap();
+
+        assert_eq!(
+            stream.output,
+            b"HTTP/1.1 200 Connection Established\r\n\r\n"
+        );
+
+        let mut rest = vec![];
+        block_on(stream.input.read_to_end(&mut rest)).unwrap();
+        assert_eq!(rest, b"payload");
+
+        let mut stream = MockStream::new(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+
+        assert!(block_on(ProxyRequest::read(&mut stream)).is_err());
+        assert!(stream.output.starts_with(b"HTTP/1.1 405"));
+
+        let mut stream = MockStream::new(b"CONNECT db.internal HTTP/1.1\r\n\r\n");
+
+        assert!(block_on(ProxyRequest::read(&mut stream)).is_err());
+        assert!(stream.output.starts_with(b"HTTP/1.1 400"));
+
+        let mut stream = MockStream::new(b"");
+
+        block_on(request.reply(&mut stream, PreambleStatus::Denied.into())).unwrap();
+
+        assert_eq!(stream.output, b"HTTP/1.1 403 Forbidden\r\n\r\n");
+    }
+}
@@ -0,0 +1,227 @@
+//! Udp relay: the datagrams of the local udp peers are carried to the n3 server in QUIC DATAGRAM
+//! frames, each peer is a flow.
+
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Result},
+    net::SocketAddr,
+    pin::pin,
+    time::{Duration, Instant},
+};
+
+use futures::future::{Either, select};
+use n3_proto::FlowDatagram;
+use n3io::net::UdpSocket;
+use n3quic::{QuicConn, QuicConnExt};
+
+use crate::AgentMetrics;
+
+/// A flow is removed after no datagram is relayed in either direction for this duration.
+const UDP_FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The maximum size of a udp datagram.
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// The flow ids of the local udp peers.
+#[derive(Default)]
+struct UdpFlows {
+    /// peer address to `(flow id, last active time)`.
+    ids: HashMap<SocketAddr, (u32, Instant)>,
+    /// flow id to peer address.
+    peers: HashMap<u32, SocketAddr>,
+    /// generator of flow ids.
+    next_id: u32,
+}
+
+impl UdpFlows {
+    /// Returns the flow id of `peer`, a new flow is allocated if it's unknown or expired.
+    fn flow_id(&mut self, peer: SocketAddr, now: Instant) -> u32 {
+        if let Some((id, last_active)) = self.ids.get_mut(&peer)
+            && now.duration_since(*last_active) < UDP_FLOW_IDLE_TIMEOUT
+        {
+            *last_active = now;
+            return *id;
+        }
+
+        self.expire(now);
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        self.ids.insert(peer, (id, now));
+        self.peers.insert(id, peer);
+
+        id
+    }
+
+    /// Returns the peer address of flow `id`, if it's not expired.
+    fn peer(&mut self, id: u32, now: Instant) -> Option<SocketAddr> {
+        let peer = *self.peers.get(&id)?;
+
+        let (_, last_active) = self.ids.get_mut(&peer)?;
+
+        if now.duration_since(*last_active) >= UDP_FLOW_IDLE_TIMEOUT {
+            return None;
+        }
+
+        *last_active = now;
+
+        Some(peer)
+    }
+
+    /// Remove the idle flows.
+    fn expire(&mut self, now: Instant) {
+        self.ids.retain(|_, (id, last_active)| {
+            let active = now.duration_since(*last_active) < UDP_FLOW_IDLE_TIMEOUT;
+
+            if !active {
+                self.peers.remove(id);
+            }
+
+            active
+        });
+    }
+
+    fn len(&self) -> usize {
+        self.ids.len()
+    }
+}
+
+/// Relay the datagrams between `socket` and `conn` until either of them fails.
+pub(crate) async fn relay(
+    conn: &QuicConn,
+    socket: &UdpSocket,
+    metrics: &AgentMetrics,
+    trace_id: &str,
+) -> Result<()> {
+    let sender = conn.dgram_sender();
+
+    let mut flows = UdpFlows::default();
+
+    let mut local_buf = vec![0u8; MAX_DATAGRAM_LEN];
+    let mut quic_buf = vec![0u8; MAX_DATAGRAM_LEN];
+
+    let result = loop {
+        // the pending receiving is dropped before the buffers are used.
+        let received = match select(
+            pin!(socket.recv_from(&mut local_buf)),
+            pin!(conn.dgram_recv(&mut quic_buf)),
+        )
+        .await
+        {
+            Either::Left((received, _)) => Either::Left(received),
+            Either::Right((received, _)) => Either::Right(received),
+        };
+
+        match received {
+            Either::Left(received) => {
+                let (len, from) = match received {
+                    Ok(received) => received,
+                    Err(err) => break Err(err),
+                };
+
+                let flow_id = flows.flow_id(from, Instant::now());
+
+                metrics.udp_flows.set(flows.len() as i64);
+
+                match sender.send(&FlowDatagram::new(flow_id, &local_buf[..len]).encode()) {
+                    Ok(()) => metrics.udp_forward_bytes.add(len as u64),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                        metrics.dropped_datagrams.inc();
+                    }
+                    // only this datagram is dropped, the other flows keep relaying.
+                    Err(err) if err.kind() == ErrorKind::InvalidInput => {
+                        metrics.dropped_datagrams.inc();
+                        log::trace!(
+                            "drop datagram, id={}, flow_id={}, err={}",
+                            trace_id,
+                            flow_id,
+                            err
+                        );
+                    }
+                    Err(err) => break Err(err),
+                }
+            }
+            Either::Right(received) => {
+                let len = match received {
+                    Ok(len) => len,
+                    Err(err) => break Err(err),
+                };
+
+                let datagram = match FlowDatagram::decode(&quic_buf[..len]) {
+                    Ok(datagram) => datagram,
+                    Err(err) => {
+                        metrics.dropped_datagrams.inc();
+                        log::error!("drop datagram, id={}, err={}", trace_id, err);
+                        continue;
+                    }
+                };
+
+                let Some(peer) = flows.peer(datagram.flow_id, Instant::now()) else {
+                    metrics.dropped_datagrams.inc();
+                    log::trace!(
+                        "drop datagram of expired flow, id={}, flow_id={}",
+                        trace_id,
+                        datagram.flow_id
+                    );
+                    continue;
+                };
+
+                match socket.send_to(datagram.payload, peer).await {
+                    Ok(len) => metrics.udp_backward_bytes.add(len as u64),
+                    Err(err) => {
+                        metrics.dropped_datagrams.inc();
+
+                        log::error!(
+                            "failed to relay datagram, id={}, flow_id={}, peer={}, err={}",
+                            trace_id,
+                            datagram.flow_id,
+                            peer,
+                            err
+                        );
+                    }
+                }
+            }
+        }
+    };
+
+    metrics.udp_flows.set(0);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp_flows() {
+        let mut flows = UdpFlows::default();
+
+        let now = Instant::now();
+
+        let a = "127.0.0.1:1000".parse().unwrap();
+        let b = "127.0.0.1:1001".parse().unwrap();
+
+        assert_eq!(flows.flow_id(a, now), 0);
+        assert_eq!(flows.flow_id(b, now), 1);
+        assert_eq!(flows.flow_id(a, now), 0);
+        assert_eq!(flows.peer(1, now), Some(b));
+        assert_eq!(flows.peer(2, now), None);
+
+        // `a` is kept alive by a response.
+        let later = now + UDP_FLOW_IDLE_TIMEOUT / 2;
+
+        assert_eq!(flows.peer(0, later), Some(a));
+
+        let expired = now + UDP_FLOW_IDLE_TIMEOUT;
+
+        assert_eq!(flows.peer(1, expired), None);
+        assert_eq!(flows.flow_id(a, expired), 0);
+
+        // `b` is expired, a new flow is allocated.
+        assert_eq!(flows.flow_id(b, expired), 2);
+        assert_eq!(flows.len(), 2);
+        assert_eq!(flows.peer(1, expired), None);
+    }
+}
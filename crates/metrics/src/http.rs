@@ -0,0 +1,170 @@
+use std::{io::Result, net::SocketAddr, time::Duration};
+
+use futures::{AsyncReadExt, AsyncWriteExt};
+use n3_spawner::spawn;
+use n3io::{
+    net::{TcpListener, TcpStream},
+    timeout::TimeoutExt,
+};
+
+use crate::Registry;
+
+/// The maximum length of the request header section.
+const MAX_REQUEST_HEADER_SIZE: usize = 8 * 1024;
+
+/// Serve `registry` in prometheus text format on `http://{laddr}/metrics`.
+///
+/// Only fails if `laddr` can't be bound, accept errors are logged and skipped.
+pub async fn serve(laddr: SocketAddr, registry: Registry) -> Result<()> {
+    let listener = TcpListener::bind(laddr).await?;
+
+    serve_listener(listener, registry).await
+}
+
+/// Serve `registry` on the bound `listener`, see [`serve`].
+async fn serve_listener(listener: TcpListener, registry: Registry) -> Result<()> {
+    log::info!(
+        "metrics, listening on http://{}/metrics",
+        listener.mio_socket().local_addr()?
+    );
+
+    loop {
+        let (stream, from) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::error!("metrics, accept, err={}", err);
+                continue;
+            }
+        };
+
+        let registry = registry.clone();
+
+        spawn(async move {
+            if let Err(err) = handle(stream, &registry)
+                .timeout(Duration::from_secs(10))
+                .await
+            {
+                log::trace!("metrics, from={}, err={}", from, err);
+            }
+        })?;
+    }
+}
+
+/// Read the request head, returns the request line.
+async fn read_request_line(stream: &mut TcpStream) -> Result<Option<String>> {
+    let mut buf = vec![0; MAX_REQUEST_HEADER_SIZE];
+    let mut len = 0;
+
+    loop {
+        if let Some(end) = buf[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&buf[..end]);
+
+            return Ok(head.lines().next().map(str::to_owned));
+        }
+
+        if len == buf.len() {
+            return Ok(None);
+        }
+
+        let read_size = stream.read(&mut buf[len..]).await?;
+
+        if read_size == 0 {
+            return Ok(None);
+        }
+
+        len += read_size;
+    }
+}
+
+async fn handle(mut stream: TcpStream, registry: &Registry) -> Result<()> {
+    let Some(request_line) = read_request_line(&mut stream).await? else {
+        return Ok(());
+    };
+
+    let mut parts = request_line.split_whitespace();
+
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            registry.encode(),
+        ),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "not found\n".to_owned()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_owned(),
+        ),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        sync::Arc,
+    };
+
+    use crate::{Collector, Counter, Encoder};
+
+    use super::*;
+
+    struct Requests(Counter);
+
+    impl Collector for Requests {
+        fn collect(&self, encoder: &mut Encoder) {
+            encoder.counter("requests_total", "Requests.", &[], self.0.get());
+        }
+    }
+
+    fn get(laddr: SocketAddr, path: &str) -> String {
+        let mut stream = std::net::TcpStream::connect(laddr).unwrap();
+
+        write!(stream, "GET {} HTTP/1.1\r\nhost: localhost\r\n\r\n", path).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[futures_test::test]
+    async fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let laddr = listener.mio_socket().local_addr().unwrap();
+
+        let registry = Registry::new();
+
+        let requests = Arc::new(Requests(Counter::default()));
+        requests.0.add(2);
+
+        registry.register(&[], requests);
+
+        spawn(async move {
+            serve_listener(listener, registry).await.unwrap();
+        })
+        .unwrap();
+
+        let response = get(laddr, "/metrics");
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\nrequests_total 2\n"));
+
+        assert!(get(laddr, "/").starts_with("HTTP/1.1 404"));
+    }
+}
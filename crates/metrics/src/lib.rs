@@ -0,0 +1,16 @@
+//! n3 metrics primitives and prometheus exporter.
+
+#![cfg_attr(docsrs, feature(doc_cfg))]
+
+mod metric;
+pub use metric::*;
+
+mod registry;
+pub use registry::*;
+
+#[cfg(feature = "global_reactor")]
+#[cfg_attr(docsrs, doc(cfg(feature = "global_reactor")))]
+mod http;
+
+#[cfg(feature = "global_reactor")]
+pub use http::*;
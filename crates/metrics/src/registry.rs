@@ -0,0 +1,205 @@
+use std::{
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+/// A set of metrics which can be exported.
+pub trait Collector: Send + Sync {
+    /// Write the current values of the metrics into `encoder`.
+    fn collect(&self, encoder: &mut Encoder);
+}
+
+/// Samples of one metric family.
+#[derive(Debug)]
+struct Family {
+    name: String,
+    help: String,
+    ty: &'static str,
+    samples: Vec<String>,
+}
+
+/// Prometheus text format(`0.0.4`) encoder.
+///
+/// Samples of the same family are grouped together, so that several collectors may export the
+/// same metric with different labels.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    families: Vec<Family>,
+    /// Labels attached to every sample, set by the [`Registry`].
+    const_labels: Vec<(String, String)>,
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Encoder {
+    /// Write a sample of the counter `name`.
+    pub fn counter(&mut self, name: &str, help: &str, labels: &[(&str, &str)], value: u64) {
+        self.sample("counter", name, help, labels, value);
+    }
+
+    /// Write a sample of the gauge `name`.
+    pub fn gauge(&mut self, name: &str, help: &str, labels: &[(&str, &str)], value: i64) {
+        self.sample("gauge", name, help, labels, value);
+    }
+
+    fn sample(
+        &mut self,
+        ty: &'static str,
+        name: &str,
+        help: &str,
+        labels: &[(&str, &str)],
+        value: impl std::fmt::Display,
+    ) {
+        let index = match self.families.iter().position(|family| family.name == name) {
+            Some(index) => index,
+            None => {
+                self.families.push(Family {
+                    name: name.to_owned(),
+                    help: help.replace('\\', "\\\\").replace('\n', "\\n"),
+                    ty,
+                    samples: vec![],
+                });
+
+                self.families.len() - 1
+            }
+        };
+
+        let labels = self
+            .const_labels
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .chain(labels.iter().copied())
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+            .collect::<Vec<_>>();
+
+        let sample = if labels.is_empty() {
+            format!("{} {}", name, value)
+        } else {
+            format!("{}{{{}}} {}", name, labels.join(","), value)
+        };
+
+        self.families[index].samples.push(sample);
+    }
+
+    /// Returns the encoded text.
+    pub fn finish(self) -> String {
+        let mut text = String::new();
+
+        for family in self.families {
+            _ = writeln!(text, "# HELP {} {}", family.name, family.help);
+            _ = writeln!(text, "# TYPE {} {}", family.name, family.ty);
+
+            for sample in family.samples {
+                _ = writeln!(text, "{}", sample);
+            }
+        }
+
+        text
+    }
+}
+
+struct Registered {
+    labels: Vec<(String, String)>,
+    collector: Arc<dyn Collector>,
+}
+
+/// A shared list of [`Collector`]s.
+#[derive(Clone, Default)]
+pub struct Registry(Arc<Mutex<Vec<Registered>>>);
+
+impl std::fmt::Debug for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Registry")
+            .field("collectors", &self.0.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Registry {
+    /// Create a new empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `collector`, `labels` are attached to all of its samples.
+    pub fn register(&self, labels: &[(&str, &str)], collector: Arc<dyn Collector>) {
+        let labels = labels
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        self.0
+            .lock()
+            .unwrap()
+            .push(Registered { labels, collector });
+    }
+
+    /// Returns the current values of all the registered metrics in prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut encoder = Encoder::default();
+
+        for registered in self.0.lock().unwrap().iter() {
+            encoder.const_labels.clone_from(&registered.labels);
+            registered.collector.collect(&mut encoder);
+        }
+
+        encoder.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Counter, Gauge};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Conns {
+        total: Counter,
+        active: Gauge,
+    }
+
+    impl Collector for Conns {
+        fn collect(&self, encoder: &mut Encoder) {
+            encoder.counter("conns_total", "Accepted conns.", &[], self.total.get());
+            encoder.gauge("active_conns", "Active conns.", &[], self.active.get());
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let registry = Registry::new();
+
+        let first = Arc::new(Conns::default());
+        let second = Arc::new(Conns::default());
+
+        registry.register(&[("listener", "0")], first.clone());
+        registry.register(&[("listener", "a\"b")], second.clone());
+
+        first.total.add(3);
+        let _guard = first.active.track();
+        second.total.inc();
+
+        assert_eq!(
+            registry.encode(),
+            r#"# HELP conns_total Accepted conns.
+# TYPE conns_total counter
+conns_total{listener="0"} 3
+conns_total{listener="a\"b"} 1
+# HELP active_conns Active conns.
+# TYPE active_conns gauge
+active_conns{listener="0"} 1
+active_conns{listener="a\"b"} 0
+"#
+        );
+
+        drop(_guard);
+
+        assert_eq!(first.active.get(), 0);
+    }
+}
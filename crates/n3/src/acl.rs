@@ -0,0 +1,270 @@
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind, Result},
+    net::IpAddr,
+    ops::RangeInclusive,
+    str::FromStr,
+};
+
+use n3_proto::Preamble;
+use serde::Deserialize;
+
+use crate::match_server_name;
+
+/// The host part of an [`AllowRule`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostPattern {
+    /// `*`, any host.
+    Any,
+    /// `db.internal` or `*.internal`, lowercase.
+    Name(String),
+    /// `10.0.0.0/8` or an ip literal(`/32` or `/128`).
+    Cidr(IpAddr, u8),
+}
+
+impl HostPattern {
+    fn matches(&self, host: &str) -> bool {
+        match (self, host.parse::<IpAddr>()) {
+            (Self::Any, _) => true,
+            (Self::Cidr(net, prefix), Ok(ip)) => in_cidr(*net, *prefix, ip),
+            (Self::Cidr(..), Err(_)) => false,
+            // names never match ip literals.
+            (_, Ok(_)) => false,
+            (Self::Name(pattern), Err(_)) => match_server_name(pattern, &host.to_ascii_lowercase()),
+        }
+    }
+}
+
+fn in_cidr(net: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    match (net, ip.to_canonical()) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(net) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(net) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// An allowed preamble target: `HOST:PORTS`.
+///
+/// `HOST` is `*`, `*.example.com`, a host name, an ip or a cidr(`10.0.0.0/8`, `[fd00::/8]`),
+/// `PORTS` is `*`, a port or a range(`8000-8100`). Names and ips are matched against the host
+/// literal of the preamble, a name never matches an ip literal.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct AllowRule {
+    host: HostPattern,
+    ports: RangeInclusive<u16>,
+}
+
+impl AllowRule {
+    /// Returns true if `host:port` is allowed by this rule.
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        self.ports.contains(&port) && self.host.matches(host)
+    }
+}
+
+impl FromStr for AllowRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = |reason: &str| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid allow rule `{}`: {}", s, reason),
+            )
+        };
+
+        let Some((host, ports)) = s.rsplit_once(':') else {
+            return Err(invalid("expect `HOST:PORTS`"));
+        };
+
+        let ports = match ports.split_once('-') {
+            _ if ports == "*" => 0..=u16::MAX,
+            Some((from, to)) => {
+                let from = from.parse::<u16>().map_err(|_| invalid("invalid port"))?;
+                let to = to.parse::<u16>().map_err(|_| invalid("invalid port"))?;
+
+                if to < from {
+                    return Err(invalid("ensure `to >= from`"));
+                }
+
+                from..=to
+            }
+            None => {
+                let port = ports.parse::<u16>().map_err(|_| invalid("invalid port"))?;
+                port..=port
+            }
+        };
+
+        let host = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+
+        let host = if host == "*" {
+            HostPattern::Any
+        } else if let Some((net, prefix)) = host.split_once('/') {
+            let net = net.parse::<IpAddr>().map_err(|_| invalid("invalid cidr"))?;
+            let prefix = prefix.parse::<u8>().map_err(|_| invalid("invalid cidr"))?;
+
+            if prefix > if net.is_ipv4() { 32 } else { 128 } {
+                return Err(invalid("invalid cidr prefix"));
+            }
+
+            HostPattern::Cidr(net, prefix)
+        } else if let Ok(ip) = host.parse::<IpAddr>() {
+            HostPattern::Cidr(ip, if ip.is_ipv4() { 32 } else { 128 })
+        } else {
+            let name = host.strip_prefix("*.").unwrap_or(host);
+
+            if name.is_empty() || name.contains(['*', '/']) {
+                return Err(invalid("invalid host"));
+            }
+
+            HostPattern::Name(host.to_ascii_lowercase())
+        };
+
+        Ok(Self { host, ports })
+    }
+}
+
+impl TryFrom<String> for AllowRule {
+    type Error = Error;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// The targets and tokens accepted in stream preambles.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AllowList {
+    /// Allowed targets, a preamble must match one of them.
+    #[serde(default)]
+    allow: Vec<AllowRule>,
+    /// Accepted tokens, no token is required if empty.
+    #[serde(default)]
+    tokens: Vec<String>,
+}
+
+impl AllowList {
+    /// Create an empty allow-list, which rejects all the targets.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append an allowed target.
+    pub fn allow(mut self, rule: AllowRule) -> Self {
+        self.allow.push(rule);
+        self
+    }
+
+    /// Append an accepted token.
+    pub fn token<T: Into<String>>(mut self, token: T) -> Self {
+        self.tokens.push(token.into());
+        self
+    }
+
+    /// Returns the allowed targets.
+    pub fn rules(&self) -> &[AllowRule] {
+        &self.allow
+    }
+
+    /// Check the token and the target of `preamble`, returns the target host and port.
+    pub fn check<'a>(&self, preamble: &'a Preamble) -> Result<(&'a str, u16)> {
+        let denied = |reason: &dyn Display| {
+            Error::new(
+                ErrorKind::PermissionDenied,
+                format!("preamble to `{}` is denied: {}", preamble.target, reason),
+            )
+        };
+
+        if !self.tokens.is_empty() {
+            let token = preamble.token.as_deref().unwrap_or_default();
+
+            if !self.tokens.iter().any(|accepted| token_eq(accepted, token)) {
+                return Err(denied(&"invalid token"));
+            }
+        }
+
+        let (host, port) = preamble.host_port()?;
+
+        if !self.allow.iter().any(|rule| rule.matches(host, port)) {
+            return Err(denied(&"not in the allow-list"));
+        }
+
+        Ok((host, port))
+    }
+}
+
+/// Compare tokens in constant time of the token length.
+fn token_eq(lhs: &str, rhs: &str) -> bool {
+    lhs.len() == rhs.len()
+        && lhs
+            .bytes()
+            .zip(rhs.bytes())
+            .fold(0u8, |diff, (l, r)| diff | (l ^ r))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow_rule() {
+        let rule = |s: &str| s.parse::<AllowRule>().unwrap();
+
+        assert!(rule("*:*").matches("example.com", 80));
+        assert!(rule("*.internal:5432").matches("db.Internal", 5432));
+        assert!(!rule("*.internal:5432").matches("internal", 5432));
+        assert!(!rule("*.internal:5432").matches("db.internal", 5433));
+        assert!(rule("db.internal:8000-8100").matches("DB.internal", 8080));
+        assert!(!rule("db.internal:8000-8100").matches("db.internal", 8101));
+        assert!(rule("10.0.0.0/8:22").matches("10.1.2.3", 22));
+        assert!(!rule("10.0.0.0/8:22").matches("11.1.2.3", 22));
+        assert!(rule("127.0.0.1:*").matches("127.0.0.1", 1));
+        assert!(rule("[fd00::/8]:*").matches("fd12::1", 80));
+        assert!(rule("[::1]:80").matches("::1", 80));
+        assert!(!rule("localhost:80").matches("127.0.0.1", 80));
+
+        assert!("db.internal".parse::<AllowRule>().is_err());
+        assert!("10.0.0.0/33:22".parse::<AllowRule>().is_err());
+        assert!("a.*.internal:22".parse::<AllowRule>().is_err());
+        assert!("db.internal:90-80".parse::<AllowRule>().is_err());
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let list = AllowList::new()
+            .allow("*.internal:*".parse().unwrap())
+            .token("secret");
+
+        assert_eq!(
+            list.check(&Preamble::new("db.internal:5432").token("secret"))
+                .unwrap(),
+            ("db.internal", 5432)
+        );
+
+        let denied = |preamble: Preamble| list.check(&preamble).unwrap_err().kind();
+
+        assert_eq!(
+            denied(Preamble::new("db.internal:5432")),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            denied(Preamble::new("db.internal:5432").token("secreT")),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            denied(Preamble::new("example.com:80").token("secret")),
+            ErrorKind::PermissionDenied
+        );
+    }
+}
@@ -1,17 +1,27 @@
 use std::{
+    fs,
     io::{Error, ErrorKind, Result},
-    net::{IpAddr, Ipv6Addr, SocketAddr},
+    net::{IpAddr, SocketAddr},
     ops::Range,
-    path::PathBuf,
-    time::Duration,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
 };
 
 use clap::{Parser, Subcommand};
 use color_print::ceprintln;
 use futures::executor::block_on;
+use signal_hook::{
+    consts::{SIGHUP, SIGTERM},
+    iterator::Signals,
+};
 
 use n3io::reactor::{Reactor, set_global_reactor};
-use n3server::N3;
+use n3quic::{QuicCongestionControl, QuicShutdown, QuicTuning, ZeroRttPolicy};
+use n3server::{
+    AllowList, AllowRule, N3, N3Reloader, Route, Target, Upstream,
+    config::{ListenerConfig, ListenerMode, N3Config, PortRange},
+};
 
 fn parse_port_range(arg: &str) -> std::result::Result<Range<u16>, String> {
     let parts = arg.split(":").collect::<Vec<_>>();
@@ -57,8 +67,8 @@ struct Cli {
     interfaces: Option<Vec<IpAddr>>,
 
     /// Specify the listening port range: `from:to` or `port`
-    #[arg(short, long, value_name = "PORT-RANGE", value_parser=parse_port_range)]
-    ports: Range<u16>,
+    #[arg(short, long, value_name = "PORT-RANGE", value_parser=parse_port_range, required_unless_present = "config")]
+    ports: Option<Range<u16>>,
 
     /// Configure the certificate chain file(PEM).
     #[arg(short, long, value_name = "PEM_FILE", default_value = "n3.crt")]
@@ -103,140 +113,346 @@ struct Cli {
     #[arg(long, value_name = "SIZE", default_value_t = 3)]
     ack_frequency_exponent: u64,
 
+    /// The congestion control algorithm: `cubic`, `reno` or `bbr2`, quiche uses `cubic` if not set.
+    #[arg(long, value_name = "ALGORITHM")]
+    cc_algorithm: Option<QuicCongestionControl>,
+
+    /// Sets the initial congestion window, in `1..=1000` packets.
+    #[arg(long, value_name = "PACKETS", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=1000))]
+    initial_congestion_window_packets: Option<usize>,
+
+    /// Sets the maximum outgoing and incoming UDP payload size, in bytes.
+    #[arg(long, value_name = "SIZE", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1200..=65527))]
+    max_udp_payload_size: Option<usize>,
+
+    /// Enables the path MTU discovery, probing up to `--max-udp-payload-size`.
+    #[arg(long, value_name = "BOOL")]
+    discover_pmtu: Option<bool>,
+
+    /// Enables the HyStart++ slow start of the congestion controller.
+    #[arg(long, value_name = "BOOL")]
+    hystart: Option<bool>,
+
+    /// Enables the pacing of quiche, which sets the send time of each packet.
+    #[arg(long, value_name = "BOOL")]
+    enable_pacing: Option<bool>,
+
+    /// Sets the maximum pacing rate, in bytes per second.
+    #[arg(long, value_name = "RATE", value_parser = clap::value_parser!(u64).range(1..))]
+    max_pacing_rate: Option<u64>,
+
+    /// Sets the `active_connection_id_limit` transport parameter.
+    #[arg(long, value_name = "LIMIT", value_parser = clap::value_parser!(u64).range(2..))]
+    active_connection_id_limit: Option<u64>,
+
     /// Set the io timer tick interval, in milliseconds.
     #[arg(long, value_name = "INTERVAL", default_value_t = 20)]
     io_timer_tick_interval: u64,
 
+    /// The interval of checking the config and certificate files for modification, in seconds.
+    ///
+    /// Certificates and routes are also reloaded on `SIGHUP`, set to `0` to disable file checking.
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    reload_interval: u64,
+
+    /// The grace period of the shutdown on `SIGTERM`, in seconds.
+    ///
+    /// In-flight streams are closed when the grace period expires.
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    shutdown_timeout: u64,
+
+    /// Serve prometheus metrics on `http://ADDRESS/metrics`, e.g. `127.0.0.1:9090`.
+    #[arg(long, value_name = "ADDRESS")]
+    metrics: Option<SocketAddr>,
+
+    /// Derive the stateless reset tokens from the content of the secret FILE.
+    ///
+    /// Share the file between restarts, so the clients of a restarted server fail fast.
+    #[arg(long, value_name = "FILE")]
+    stateless_reset_key: Option<PathBuf>,
+
+    /// The handling of the 0-RTT data of the resumed connections: `disabled`, `deferred` or `accept`.
+    ///
+    /// `deferred` serves the connection after the handshake completes, `accept` serves it at once
+    /// and drops the replayed handshakes within the retry token timeout.
+    #[arg(long, value_name = "POLICY", default_value = "disabled")]
+    zero_rtt: ZeroRttPolicy,
+
+    /// Receive the datagrams of each listener on N tasks, with `SO_REUSEPORT` sockets(linux only).
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    shards: usize,
+
+    /// Add a routing rule: `[sni=HOST][,alpn=PROTO][,laddr=ADDR]@TARGET`.
+    ///
+    /// Rules are matched in order, the subcommand `target` is used when none of them matches.
+    /// `sni=*.example.com` matches any subdomain of `example.com`.
+    #[arg(short, long, value_name = "RULE")]
+    route: Vec<Route>,
+
     /// Debug mode, print verbose output informations.
     #[arg(short, long, default_value_t = false, action)]
     debug: bool,
 
+    /// Load all settings from a `toml` config file, other flags are ignored.
+    #[arg(long, value_name = "TOML_FILE")]
+    config: Option<PathBuf>,
+
     #[command(subcommand)]
-    commands: Commands,
+    commands: Option<Commands>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Configure the static redirection function
     Redirect {
-        /// Specify the redirect target address
-        target: SocketAddr,
+        /// Specify the redirect target address: `IP:PORT` or `HOST:PORT`
+        target: Target,
+    },
+    /// Run as a `HTTP/3` reverse proxy, forward requests to upstream as `HTTP/1.1`
+    Http3 {
+        /// Specify the upstream `HTTP/1.1` server address: `IP:PORT` or `HOST:PORT`
+        target: Target,
+
+        /// Accept `connect-udp` requests to an allowed target: `HOST:PORTS`, e.g. `*.internal:53`
+        #[arg(long, value_name = "RULE")]
+        connect_udp_allow: Vec<AllowRule>,
+
+        /// Add an accepted `connect-udp` bearer token of `proxy-authorization`
+        #[arg(long, value_name = "TOKEN", requires = "connect_udp_allow")]
+        connect_udp_token: Vec<String>,
+    },
+    /// Read the target of each quic stream from its preamble, redirect it if allowed
+    Preamble {
+        /// Add an allowed target: `HOST:PORTS`, e.g. `*.internal:*`, `10.0.0.0/8:22`
+        #[arg(long, value_name = "RULE", required = true)]
+        allow: Vec<AllowRule>,
+
+        /// Add an accepted token, no token is required if not set
+        #[arg(long, value_name = "TOKEN")]
+        token: Vec<String>,
+    },
+    /// Run as the server side of a reverse tunnel, forward the tcp connections accepted on
+    /// `expose` to the connected agents
+    Tunnel {
+        /// Specify the public tcp listening address
+        expose: SocketAddr,
     },
 }
 
-fn parse_laddrs(cli: &Cli) -> Result<Vec<SocketAddr>> {
-    let interfaces = if let Some(interfaces) = cli.interfaces.clone() {
-        interfaces
-    } else {
-        vec![IpAddr::V6(Ipv6Addr::UNSPECIFIED)]
-    };
+impl Cli {
+    /// Convert the command line flags to the equivalent config file.
+    fn into_config(self) -> Result<N3Config> {
+        let (Some(ports), Some(commands)) = (self.ports, self.commands) else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "`--ports` and a subcommand are required without `--config`",
+            ));
+        };
+
+        let mut preamble = None;
+        let mut connect_udp = None;
+
+        let (mode, protos, target, expose) = match commands {
+            Commands::Redirect { target } => (
+                ListenerMode::Redirect,
+                Some(self.protos),
+                Some(Upstream::new([target])),
+                None,
+            ),
+            Commands::Http3 {
+                target,
+                connect_udp_allow,
+                connect_udp_token,
+            } => {
+                if !connect_udp_allow.is_empty() {
+                    let allow_list = connect_udp_allow
+                        .into_iter()
+                        .fold(AllowList::new(), AllowList::allow);
+
+                    connect_udp = Some(
+                        connect_udp_token
+                            .into_iter()
+                            .fold(allow_list, AllowList::token),
+                    );
+                }
+
+                (
+                    ListenerMode::Http3,
+                    None,
+                    Some(Upstream::new([target])),
+                    None,
+                )
+            }
+            Commands::Preamble { allow, token } => {
+                let allow_list = allow.into_iter().fold(AllowList::new(), AllowList::allow);
 
-    let mut laddrs: Vec<SocketAddr> = vec![];
+                preamble = Some(token.into_iter().fold(allow_list, AllowList::token));
 
-    for port in cli.ports.clone() {
-        for ip in &interfaces {
-            laddrs.push(SocketAddr::new(*ip, port));
-        }
+                (ListenerMode::Redirect, Some(self.protos), None, None)
+            }
+            Commands::Tunnel { expose } => {
+                (ListenerMode::Tunnel, Some(self.protos), None, Some(expose))
+            }
+        };
+
+        let config = N3Config {
+            cert: self.cert,
+            key: self.key,
+            verify_peer: self.verify_peer,
+            io_timer_tick_interval: self.io_timer_tick_interval,
+            debug: self.debug,
+            reload_interval: self.reload_interval,
+            shutdown_timeout: self.shutdown_timeout,
+            metrics: self.metrics,
+            stateless_reset_key: self.stateless_reset_key,
+            zero_rtt: self.zero_rtt,
+            shards: self.shards,
+            quic: QuicTuning {
+                initial_max_streams: Some(self.initial_max_streams),
+                initial_max_stream_data: Some(self.initial_max_stream_data),
+                max_idle_timeout: Some(self.max_idle_timeout),
+                max_ack_delay: Some(self.max_ack_delay),
+                ack_delay_exponent: Some(self.ack_frequency_exponent),
+                dgram_queue_len: None,
+                cc_algorithm: self.cc_algorithm,
+                initial_congestion_window_packets: self.initial_congestion_window_packets,
+                max_udp_payload_size: self.max_udp_payload_size,
+                discover_pmtu: self.discover_pmtu,
+                hystart: self.hystart,
+                enable_pacing: self.enable_pacing,
+                max_pacing_rate: self.max_pacing_rate,
+                active_connection_id_limit: self.active_connection_id_limit,
+            },
+            listeners: vec![ListenerConfig {
+                mode,
+                interfaces: self.interfaces.unwrap_or_default(),
+                ports: PortRange(ports),
+                protos,
+                target,
+                expose,
+                preamble,
+                connect_udp,
+                routes: self.route,
+                quic: QuicTuning::default(),
+            }],
+        };
+
+        config.validate()?;
+
+        Ok(config)
     }
-
-    Ok(laddrs)
 }
 
 async fn run_n3() -> Result<()> {
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
+
+    let debug = cli.debug;
 
-    let io_timer_tick_interval = cli.io_timer_tick_interval;
+    let path = cli.config.take();
+
+    let config = if let Some(path) = &path {
+        N3Config::from_file(path)?
+    } else {
+        cli.into_config()?
+    };
+
+    let io_timer_tick_interval = config.io_timer_tick_interval;
 
     set_global_reactor(move || {
         Reactor::new(1024, Duration::from_millis(io_timer_tick_interval)).unwrap()
     });
 
-    if cli.debug {
+    if debug || config.debug {
         pretty_env_logger::try_init_timed().map_err(Error::other)?;
     }
 
-    match cli.commands {
-        Commands::Redirect { target } => {
-            run_static_redirect(cli, target).await?;
-        }
-    }
+    let n3s = config.build()?;
 
-    Ok(())
+    let reloaders = n3s.iter().map(N3::reloader).collect::<Vec<_>>();
+    let shutdowns = n3s.iter().map(N3::shutdown_handle).collect::<Vec<_>>();
+
+    spawn_signal_watcher(path, config.clone(), reloaders, shutdowns)?;
+
+    config.run(n3s).await
 }
 
-async fn run_static_redirect(cli: Cli, target: SocketAddr) -> Result<()> {
-    let laddrs = parse_laddrs(&cli)?;
-
-    let protos = cli
-        .protos
-        .iter()
-        .map(|proto| proto.as_bytes())
-        .collect::<Vec<_>>();
-
-    N3::new(target)
-        .quic_server(|quic_server| {
-            quic_server
-                .verify_peer(cli.verify_peer.is_some())
-                .quiche_config(|config| {
-                    config.set_initial_max_data(
-                        cli.initial_max_streams * cli.initial_max_stream_data,
-                    );
-                    config.set_initial_max_stream_data_bidi_local(cli.initial_max_stream_data);
-                    config.set_initial_max_stream_data_bidi_remote(cli.initial_max_stream_data);
-                    config.set_initial_max_stream_data_uni(cli.initial_max_stream_data);
-                    config.set_initial_max_streams_bidi(cli.initial_max_streams);
-                    config.set_initial_max_streams_uni(cli.initial_max_streams);
-                    config.set_max_idle_timeout(cli.max_idle_timeout);
-                    config.set_max_ack_delay(cli.max_ack_delay);
-                    config.set_ack_delay_exponent(cli.ack_frequency_exponent);
-
-                    config
-                        .load_cert_chain_from_pem_file(cli.cert.to_str().unwrap())
-                        .map_err(|err| {
-                            Error::new(
-                                ErrorKind::NotFound,
-                                format!(
-                                    "Unable to load certificate chain file {:?}, {}",
-                                    cli.cert, err
-                                ),
-                            )
-                        })?;
-
-                    config
-                        .load_priv_key_from_pem_file(cli.key.to_str().unwrap())
-                        .map_err(|err| {
-                            Error::new(
-                                ErrorKind::NotFound,
-                                format!("Unable to load key file {:?}, {}", cli.cert, err),
-                            )
-                        })?;
-
-                    if let Some(ca) = &cli.verify_peer {
-                        config
-                            .load_verify_locations_from_file(ca.to_str().unwrap())
-                            .map_err(|err| {
-                                Error::new(
-                                    ErrorKind::NotFound,
-                                    format!("Unable to trusted CA file {:?}, {}", cli.cert, err),
-                                )
-                            })?;
+/// Returns the modification times of the files `config` depends on.
+fn watched_files(path: Option<&Path>, config: &N3Config) -> Vec<Option<SystemTime>> {
+    path.into_iter()
+        .chain([config.cert.as_path(), config.key.as_path()])
+        .chain(config.verify_peer.as_deref())
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+/// Reload certificates and routes on `SIGHUP` or when the watched files are modified,
+/// shutdown the listeners gracefully on `SIGTERM`.
+///
+/// Without `path`, the running config is reapplied to pick up the rotated certificates.
+fn spawn_signal_watcher(
+    path: Option<PathBuf>,
+    mut running: N3Config,
+    reloaders: Vec<N3Reloader>,
+    shutdowns: Vec<QuicShutdown>,
+) -> Result<()> {
+    let mut signals = Signals::new([SIGHUP, SIGTERM])?;
+
+    thread::Builder::new()
+        .name("n3-reload".to_owned())
+        .spawn(move || {
+            let mut modified = watched_files(path.as_deref(), &running);
+
+            for tick in 1u64.. {
+                // poll signals every second.
+                thread::sleep(Duration::from_secs(1));
+
+                let mut sighup = false;
+
+                for signal in signals.pending() {
+                    match signal {
+                        SIGTERM => {
+                            log::info!("shutdown, grace={}s", running.shutdown_timeout);
+
+                            for shutdown in &shutdowns {
+                                shutdown.shutdown(Duration::from_secs(running.shutdown_timeout));
+                            }
+                        }
+                        _ => sighup = true,
+                    }
+                }
+
+                if !sighup {
+                    if running.reload_interval == 0 || !tick.is_multiple_of(running.reload_interval)
+                    {
+                        continue;
                     }
 
-                    config.set_application_protos(&protos).map_err(|err| {
-                        Error::new(
-                            ErrorKind::InvalidInput,
-                            format!(
-                                "failed to set application protos as {:?}, {}",
-                                cli.protos, err
-                            ),
-                        )
-                    })?;
-
-                    Ok(())
-                })
-        })
-        .bind(laddrs.as_slice())
-        .await
+                    if watched_files(path.as_deref(), &running) == modified {
+                        continue;
+                    }
+                }
+
+                let config = match &path {
+                    Some(path) => N3Config::from_file(path),
+                    None => Ok(running.clone()),
+                };
+
+                match config.and_then(|config| config.reload(&running, &reloaders).map(|_| config))
+                {
+                    Ok(config) => {
+                        log::info!("reload config, sighup={}, path={:?}", sighup, path);
+                        running = config;
+                    }
+                    Err(err) => {
+                        log::error!("failed to reload config, path={:?}, err={}", path, err);
+                    }
+                }
+
+                modified = watched_files(path.as_deref(), &running);
+            }
+        })?;
+
+    Ok(())
 }
 
 fn main() {
@@ -0,0 +1,1075 @@
+//! Declarative configuration file for `n3`.
+//!
+//! ```toml
+//! cert = "n3.crt"
+//! key = "n3.key"
+//! verify_peer = "ca.crt"
+//! metrics = "127.0.0.1:9090"
+//! shutdown_timeout = 30
+//! stateless_reset_key = "n3.reset.key"
+//! zero_rtt = "deferred"
+//! shards = 4
+//!
+//! [quic]
+//! max_idle_timeout = 60000
+//! cc_algorithm = "bbr2"
+//! max_udp_payload_size = 1472
+//! discover_pmtu = true
+//!
+//! [[listener]]
+//! ports = "443:446"
+//! target = "127.0.0.1:8080"
+//!
+//! [[listener.route]]
+//! sni = "*.example.com"
+//! target = ["127.0.0.1:8081", "127.0.0.1:8082"]
+//!
+//! [[listener.route]]
+//! sni = "api.example.com"
+//! target = { targets = ["10.0.0.1:80", "10.0.0.2:80"], policy = "least_conn", health_check_interval = 5 }
+//!
+//! [[listener]]
+//! mode = "http3"
+//! interfaces = ["0.0.0.0"]
+//! ports = 8443
+//! target = "web.internal:80"
+//!
+//! [listener.quic]
+//! initial_max_streams = 1000
+//!
+//! [listener.connect_udp]
+//! allow = ["*.internal:53", "10.0.0.0/8:*"]
+//!
+//! [[listener]]
+//! mode = "tunnel"
+//! ports = 9443
+//! expose = "0.0.0.0:8080"
+//!
+//! [[listener]]
+//! ports = 9444
+//!
+//! [listener.preamble]
+//! allow = ["*.internal:*", "10.0.0.0/8:22"]
+//! tokens = ["secret"]
+//! ```
+//!
+//! Certificates, protos, quic tuning and routes can be reloaded at runtime by
+//! [`N3Config::reload`], listening addresses can't.
+//!
+//! Transport parameters are exchanged during the handshake, before a route is selected,
+//! so the `quic` tuning is set per listener, a route that needs its own tuning should be
+//! served by its own listener.
+
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::pin,
+    time::Duration,
+};
+
+use futures::future::{Either, pending, select, try_join_all};
+use n3_metrics::Registry;
+use n3quic::{QuicServer, QuicTuning, StatelessResetKey, ZeroRttPolicy, quiche};
+use serde::Deserialize;
+
+use crate::{AllowList, N3, N3Reloader, Policy, Route, Router, Target, Upstream};
+
+/// Root of the `n3` configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct N3Config {
+    /// The certificate chain file(PEM).
+    #[serde(default = "default_cert")]
+    pub cert: PathBuf,
+    /// The private key file(PEM).
+    #[serde(default = "default_key")]
+    pub key: PathBuf,
+    /// Trusted CA certificates file for the peer's certificate verification.
+    pub verify_peer: Option<PathBuf>,
+    /// The io timer tick interval, in milliseconds.
+    #[serde(default = "default_io_timer_tick_interval")]
+    pub io_timer_tick_interval: u64,
+    /// Print verbose output informations.
+    #[serde(default)]
+    pub debug: bool,
+    /// The interval of checking the config and certificate files for modification, in seconds.
+    ///
+    /// Set to `0` to reload on `SIGHUP` only.
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+    /// The grace period of the shutdown on `SIGTERM`, in seconds.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// Serve prometheus metrics on `http://{metrics}/metrics`.
+    pub metrics: Option<SocketAddr>,
+    /// The secret file of the stateless reset tokens, shared by the restarts and instances.
+    ///
+    /// A random key is used if not set, the peers of a restarted server then wait for idle timeout.
+    pub stateless_reset_key: Option<PathBuf>,
+    /// The handling of the `0-RTT` data of the resumed connections.
+    #[serde(default)]
+    pub zero_rtt: ZeroRttPolicy,
+    /// The number of `SO_REUSEPORT` shards of each listener, see [`QuicServer::shards`].
+    #[serde(default = "default_shards")]
+    pub shards: usize,
+    /// Transport parameters shared by all listeners.
+    #[serde(default)]
+    pub quic: QuicTuning,
+    /// Listeners, at least one is required.
+    #[serde(default, rename = "listener")]
+    pub listeners: Vec<ListenerConfig>,
+}
+
+fn default_cert() -> PathBuf {
+    "n3.crt".into()
+}
+
+fn default_key() -> PathBuf {
+    "n3.key".into()
+}
+
+fn default_io_timer_tick_interval() -> u64 {
+    20
+}
+
+fn default_reload_interval() -> u64 {
+    5
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_shards() -> usize {
+    1
+}
+
+/// The way a listener forwards the accepted connections.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerMode {
+    /// Redirect quic streams to tcp streams.
+    #[default]
+    Redirect,
+    /// Forward `HTTP/3` requests as `HTTP/1.1` requests.
+    Http3,
+    /// Accept agents and forward the tcp connections accepted on `expose` to them.
+    Tunnel,
+}
+
+/// One group of listening addresses sharing the same routing table.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    #[serde(default)]
+    pub mode: ListenerMode,
+    /// Listening interfaces, the default is `[::]`.
+    #[serde(default)]
+    pub interfaces: Vec<IpAddr>,
+    /// Listening port range: `from:to` or `port`.
+    pub ports: PortRange,
+    /// Application protos, the default is `["n3"]`, not allowed in `http3` mode.
+    pub protos: Option<Vec<String>>,
+    /// The upstream used when no route matches.
+    pub target: Option<Upstream>,
+    /// The public tcp listening address, required in `tunnel` mode.
+    pub expose: Option<SocketAddr>,
+    /// Read the target of each stream from its preamble, only allowed in `redirect` mode.
+    pub preamble: Option<AllowList>,
+    /// Accept `connect-udp` requests to the allowed targets, only allowed in `http3` mode.
+    pub connect_udp: Option<AllowList>,
+    /// Routing rules, matched in order.
+    #[serde(default, rename = "route")]
+    pub routes: Vec<Route>,
+    /// Transport parameters, override the global ones.
+    #[serde(default)]
+    pub quic: QuicTuning,
+}
+
+/// The file representation of [`Route`].
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RouteRepr {
+    sni: Option<String>,
+    alpn: Option<String>,
+    laddr: Option<SocketAddr>,
+    target: Upstream,
+}
+
+impl TryFrom<RouteRepr> for Route {
+    type Error = String;
+
+    fn try_from(value: RouteRepr) -> std::result::Result<Self, Self::Error> {
+        let mut route = Route::with_upstream(value.target);
+
+        if let Some(sni) = value.sni {
+            if sni.is_empty() {
+                return Err("`sni` is empty".to_owned());
+            }
+
+            route = route.server_name(sni);
+        }
+
+        if let Some(alpn) = value.alpn {
+            if alpn.is_empty() {
+                return Err("`alpn` is empty".to_owned());
+            }
+
+            route = route.alpn(alpn);
+        }
+
+        if let Some(laddr) = value.laddr {
+            route = route.laddr(laddr);
+        }
+
+        Ok(route)
+    }
+}
+
+/// The file representation of [`Upstream`]: an address, a list of addresses or a table.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum UpstreamRepr {
+    Addr(Target),
+    List(Vec<Target>),
+    Table(UpstreamTable),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct UpstreamTable {
+    targets: Vec<Target>,
+    #[serde(default)]
+    policy: Policy,
+    max_fails: Option<u32>,
+    /// In seconds.
+    fail_timeout: Option<u64>,
+    /// In seconds.
+    connect_timeout: Option<u64>,
+    /// In seconds, active health checks are disabled if unset.
+    health_check_interval: Option<u64>,
+    /// In seconds, the default is `connect_timeout`.
+    health_check_timeout: Option<u64>,
+}
+
+impl TryFrom<UpstreamRepr> for Upstream {
+    type Error = String;
+
+    fn try_from(value: UpstreamRepr) -> std::result::Result<Self, Self::Error> {
+        let table = match value {
+            UpstreamRepr::Addr(addr) => return Ok(Upstream::new([addr])),
+            UpstreamRepr::List(targets) => UpstreamTable {
+                targets,
+                policy: Policy::default(),
+                max_fails: None,
+                fail_timeout: None,
+                connect_timeout: None,
+                health_check_interval: None,
+                health_check_timeout: None,
+            },
+            UpstreamRepr::Table(table) => table,
+        };
+
+        if table.targets.is_empty() {
+            return Err("`targets` is empty".to_owned());
+        }
+
+        let mut upstream = Upstream::new(table.targets).policy(table.policy);
+
+        if table.max_fails.is_some() || table.fail_timeout.is_some() {
+            upstream = upstream.max_fails(
+                table.max_fails.unwrap_or(3),
+                Duration::from_secs(table.fail_timeout.unwrap_or(10)),
+            );
+        }
+
+        let connect_timeout = Duration::from_secs(table.connect_timeout.unwrap_or(5));
+
+        if connect_timeout.is_zero() {
+            return Err("`connect_timeout` must be greater than 0".to_owned());
+        }
+
+        upstream = upstream.connect_timeout(connect_timeout);
+
+        if let Some(interval) = table.health_check_interval {
+            if interval == 0 {
+                return Err("`health_check_interval` must be greater than 0".to_owned());
+            }
+
+            let timeout = table
+                .health_check_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(connect_timeout);
+
+            upstream = upstream.health_check(Duration::from_secs(interval), timeout);
+        }
+
+        Ok(upstream)
+    }
+}
+
+/// A port range, deserialized from `port` or `"from:to"`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "PortRangeRepr")]
+pub struct PortRange(pub Range<u16>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRangeRepr {
+    Port(u16),
+    Range(String),
+}
+
+impl TryFrom<PortRangeRepr> for PortRange {
+    type Error = String;
+
+    fn try_from(value: PortRangeRepr) -> std::result::Result<Self, Self::Error> {
+        match value {
+            PortRangeRepr::Port(port) => Ok(Self(port..port.saturating_add(1))),
+            PortRangeRepr::Range(range) => {
+                let Some((from, to)) = range.split_once(':') else {
+                    let port = range
+                        .parse::<u16>()
+                        .map_err(|err| format!("failed to parse port: {}", err))?;
+
+                    return Ok(Self(port..port.saturating_add(1)));
+                };
+
+                let from = from
+                    .parse::<u16>()
+                    .map_err(|err| format!("failed to parse port(from): {}", err))?;
+
+                let to = to
+                    .parse::<u16>()
+                    .map_err(|err| format!("failed to parse port(to): {}", err))?;
+
+                if to <= from {
+                    return Err("failed to parse port range: ensure `to > from`".to_owned());
+                }
+
+                Ok(Self(from..to))
+            }
+        }
+    }
+}
+
+fn invalid(reason: impl Display) -> Error {
+    Error::new(ErrorKind::InvalidData, reason.to_string())
+}
+
+impl N3Config {
+    /// The transport parameters used when neither the listener nor the global `quic` set them.
+    pub fn default_quic() -> QuicTuning {
+        QuicTuning {
+            initial_max_streams: Some(100),
+            initial_max_stream_data: Some(1024 * 1024 * 10),
+            max_idle_timeout: Some(60 * 1000),
+            max_ack_delay: Some(25),
+            ack_delay_exponent: Some(3),
+            dgram_queue_len: Some(1024),
+            ..Default::default()
+        }
+    }
+
+    /// Load and validate the configuration from a `toml` file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+
+        let content = std::fs::read_to_string(path).map_err(|err| {
+            Error::new(
+                err.kind(),
+                format!("Unable to read config file {:?}, {}", path, err),
+            )
+        })?;
+
+        Self::from_toml(&content)
+            .map_err(|err| Error::new(err.kind(), format!("config file {:?}: {}", path, err)))
+    }
+
+    /// Parse and validate the configuration from `toml` string.
+    pub fn from_toml(content: &str) -> Result<Self> {
+        let config: Self = toml::from_str(content).map_err(invalid)?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Check the semantic constraints which can't be expressed by the file format.
+    pub fn validate(&self) -> Result<()> {
+        if self.listeners.is_empty() {
+            return Err(invalid("at least one `[[listener]]` is required"));
+        }
+
+        if self.io_timer_tick_interval == 0 {
+            return Err(invalid("`io_timer_tick_interval` must be greater than 0"));
+        }
+
+        if self.shards == 0 {
+            return Err(invalid("`shards` must be greater than 0"));
+        }
+
+        self.quic
+            .validate()
+            .map_err(|err| invalid(format!("quic: {}", err)))?;
+
+        for (index, listener) in self.listeners.iter().enumerate() {
+            listener
+                .validate()
+                .map_err(|err| invalid(format!("listener[{}]: {}", index, err)))?;
+
+            // the agents are authenticated by their client certificates.
+            if listener.mode == ListenerMode::Tunnel && self.verify_peer.is_none() {
+                return Err(invalid(format!(
+                    "listener[{}]: `verify_peer` is required in `tunnel` mode",
+                    index
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Create the `N3` instances of all the listeners, in order.
+    pub fn build(&self) -> Result<Vec<N3>> {
+        let base = self.quic.or(&Self::default_quic());
+
+        self.listeners
+            .iter()
+            .map(|listener| listener.n3(self, &base))
+            .collect()
+    }
+
+    /// Apply the certificates, protos, quic tuning and routes of this config to the
+    /// listeners which were built from `running`.
+    ///
+    /// Listening addresses, modes and the process wide settings(`shards`, `zero_rtt`,
+    /// `stateless_reset_key`, `metrics`, `io_timer_tick_interval` and `debug`) can't be changed
+    /// at runtime, nothing is applied if they differ, or any listener fails to load its
+    /// certificates or start its health checks. `shutdown_timeout` and `reload_interval` are
+    /// read by the caller from the new config.
+    pub fn reload(&self, running: &N3Config, reloaders: &[N3Reloader]) -> Result<()> {
+        if self.shards != running.shards
+            || self.zero_rtt != running.zero_rtt
+            || self.stateless_reset_key != running.stateless_reset_key
+            || self.metrics != running.metrics
+            || self.io_timer_tick_interval != running.io_timer_tick_interval
+            || self.debug != running.debug
+        {
+            return Err(invalid(
+                "`shards`, `zero_rtt`, `stateless_reset_key`, `metrics`, `io_timer_tick_interval` or `debug` has changed, restart is required",
+            ));
+        }
+
+        if self.listeners.len() != running.listeners.len()
+            || reloaders.len() != self.listeners.len()
+        {
+            return Err(invalid(
+                "the number of listeners has changed, restart is required",
+            ));
+        }
+
+        for (index, (listener, running)) in
+            self.listeners.iter().zip(&running.listeners).enumerate()
+        {
+            if listener.mode != running.mode
+                || listener.laddrs() != running.laddrs()
+                || listener.expose != running.expose
+            {
+                return Err(invalid(format!(
+                    "listener[{}]: `mode`, `interfaces`, `ports` or `expose` has changed, restart is required",
+                    index
+                )));
+            }
+        }
+
+        let base = self.quic.or(&Self::default_quic());
+
+        let quiche_configs = self
+            .listeners
+            .iter()
+            .map(|listener| listener.quiche_config(self, &base))
+            .collect::<Result<Vec<_>>>()?;
+
+        let routers = self
+            .listeners
+            .iter()
+            .map(ListenerConfig::router)
+            .collect::<Vec<_>>();
+
+        for router in &routers {
+            router.start_health_checks()?;
+        }
+
+        // the health checks are started, replacing the routers doesn't fail from here.
+        for ((router, quiche_config), reloader) in
+            routers.into_iter().zip(quiche_configs).zip(reloaders)
+        {
+            reloader.quiche_config(quiche_config);
+            reloader.router(router)?;
+        }
+
+        Ok(())
+    }
+
+    /// Run the listeners created by [`build`](Self::build) until one of them fails or all of
+    /// them are shut down.
+    ///
+    /// The metrics of the listeners are labeled by `listener="{index}"`.
+    pub async fn run(&self, n3s: Vec<N3>) -> Result<()> {
+        let registry = Registry::new();
+
+        for (index, n3) in n3s.iter().enumerate() {
+            registry.register(&[("listener", &index.to_string())], n3.metrics());
+        }
+
+        let metrics = async {
+            match self.metrics {
+                Some(laddr) => {
+                    // the metrics endpoint is auxiliary, never stop the listeners because of it.
+                    if let Err(err) = n3_metrics::serve(laddr, registry).await {
+                        log::error!("metrics, laddr={}, err={}", laddr, err);
+                    }
+
+                    pending().await
+                }
+                None => pending().await,
+            }
+        };
+
+        let listeners = try_join_all(
+            self.listeners
+                .iter()
+                .zip(n3s)
+                .map(|(listener, n3)| listener.run(n3)),
+        );
+
+        match select(pin!(listeners), pin!(metrics)).await {
+            Either::Left((result, _)) => result.map(|_| ()),
+            Either::Right((never, _)) => never,
+        }
+    }
+}
+
+impl ListenerConfig {
+    fn validate(&self) -> Result<()> {
+        if self.mode == ListenerMode::Tunnel {
+            if self.expose.is_none() {
+                return Err(invalid("`expose` is required in `tunnel` mode"));
+            }
+
+            if self.target.is_some() || !self.routes.is_empty() {
+                return Err(invalid(
+                    "`target` and `[[listener.route]]` are not allowed in `tunnel` mode",
+                ));
+            }
+        } else if self.expose.is_some() {
+            return Err(invalid("`expose` is only allowed in `tunnel` mode"));
+        } else if self.preamble.is_some() {
+            if self.target.is_some() || !self.routes.is_empty() {
+                return Err(invalid(
+                    "`target` and `[[listener.route]]` are not allowed with `preamble`",
+                ));
+            }
+        } else if self.target.is_none() && self.routes.is_empty() {
+            return Err(invalid(
+                "either `target` or `[[listener.route]]` is required",
+            ));
+        }
+
+        if self.preamble.is_some() && self.mode != ListenerMode::Redirect {
+            return Err(invalid("`preamble` is only allowed in `redirect` mode"));
+        }
+
+        if self.connect_udp.is_some() && self.mode != ListenerMode::Http3 {
+            return Err(invalid("`connect_udp` is only allowed in `http3` mode"));
+        }
+
+        if let Some(protos) = &self.protos {
+            if self.mode == ListenerMode::Http3 {
+                return Err(invalid("`protos` is not allowed in `http3` mode"));
+            }
+
+            if protos.is_empty() {
+                return Err(invalid("`protos` is empty"));
+            }
+        }
+
+        self.quic.validate()?;
+
+        Ok(())
+    }
+
+    /// Returns the listening addresses.
+    pub fn laddrs(&self) -> Vec<SocketAddr> {
+        let interfaces = if self.interfaces.is_empty() {
+            vec![IpAddr::V6(Ipv6Addr::UNSPECIFIED)]
+        } else {
+            self.interfaces.clone()
+        };
+
+        let mut laddrs = vec![];
+
+        for port in self.ports.0.clone() {
+            for ip in &interfaces {
+                laddrs.push(SocketAddr::new(*ip, port));
+            }
+        }
+
+        laddrs
+    }
+
+    /// Returns the routing table of this listener.
+    pub fn router(&self) -> Router {
+        let router = self
+            .routes
+            .iter()
+            .cloned()
+            .fold(Router::new(), |router, route| router.route(route));
+
+        let router = if let Some(target) = &self.target {
+            router.fallback_upstream(target.clone())
+        } else {
+            router
+        };
+
+        let router = if let Some(allow_list) = &self.connect_udp {
+            router.connect_udp(allow_list.clone())
+        } else {
+            router
+        };
+
+        if let Some(allow_list) = &self.preamble {
+            router.preamble(allow_list.clone())
+        } else {
+            router
+        }
+    }
+
+    /// Create a new `quiche::Config` of this listener, `base` is the global transport parameters.
+    pub fn quiche_config(&self, config: &N3Config, base: &QuicTuning) -> Result<quiche::Config> {
+        let mut quiche_config =
+            quiche::Config::new(quiche::PROTOCOL_VERSION).map_err(Error::other)?;
+
+        quiche_config.verify_peer(config.verify_peer.is_some());
+
+        self.quic.or(base).apply(&mut quiche_config);
+
+        quiche_config
+            .load_cert_chain_from_pem_file(&config.cert.to_string_lossy())
+            .map_err(|err| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!(
+                        "Unable to load certificate chain file {:?}, {}",
+                        config.cert, err
+                    ),
+                )
+            })?;
+
+        quiche_config
+            .load_priv_key_from_pem_file(&config.key.to_string_lossy())
+            .map_err(|err| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("Unable to load key file {:?}, {}", config.key, err),
+                )
+            })?;
+
+        if let Some(ca) = &config.verify_peer {
+            quiche_config
+                .load_verify_locations_from_file(&ca.to_string_lossy())
+                .map_err(|err| {
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!("Unable to trusted CA file {:?}, {}", ca, err),
+                    )
+                })?;
+        }
+
+        let protos = match self.mode {
+            ListenerMode::Redirect | ListenerMode::Tunnel => self
+                .protos
+                .clone()
+                .unwrap_or_else(|| vec!["n3".to_owned()])
+                .into_iter()
+                .map(|proto| proto.into_bytes())
+                .collect::<Vec<_>>(),
+            ListenerMode::Http3 => quiche::h3::APPLICATION_PROTOCOL
+                .iter()
+                .map(|proto| proto.to_vec())
+                .collect(),
+        };
+
+        let protos = protos
+            .iter()
+            .map(|proto| proto.as_slice())
+            .collect::<Vec<_>>();
+
+        quiche_config
+            .set_application_protos(&protos)
+            .map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "failed to set application protos as {:?}, {}",
+                        protos
+                            .iter()
+                            .map(|proto| String::from_utf8_lossy(proto))
+                            .collect::<Vec<_>>(),
+                        err
+                    ),
+                )
+            })?;
+
+        Ok(quiche_config)
+    }
+
+    /// Create the `N3` instance of this listener, `base` is the global transport parameters.
+    pub fn n3(&self, config: &N3Config, base: &QuicTuning) -> Result<N3> {
+        let quiche_config = self.quiche_config(config, base)?;
+
+        let stateless_reset_key = config
+            .stateless_reset_key
+            .as_ref()
+            .map(|path| {
+                std::fs::read(path)
+                    .map(StatelessResetKey::new)
+                    .map_err(|err| {
+                        Error::new(
+                            err.kind(),
+                            format!(
+                                "Unable to read stateless reset key file {:?}, {}",
+                                path, err
+                            ),
+                        )
+                    })
+            })
+            .transpose()?;
+
+        Ok(N3::with_router(self.router()).quic_server(|_| {
+            let server = QuicServer::with_quiche_config(quiche_config)
+                .verify_peer(config.verify_peer.is_some())
+                .zero_rtt(config.zero_rtt)
+                .shards(config.shards);
+
+            match stateless_reset_key {
+                Some(key) => server.stateless_reset_key(key),
+                None => server,
+            }
+        }))
+    }
+
+    async fn run(&self, n3: N3) -> Result<()> {
+        let laddrs = self.laddrs();
+
+        match self.mode {
+            ListenerMode::Redirect => n3.bind(laddrs.as_slice()).await,
+            ListenerMode::Http3 => n3.bind_http3(laddrs.as_slice()).await,
+            ListenerMode::Tunnel => {
+                n3.bind_tunnel(laddrs.as_slice(), self.expose.expect("expose"))
+                    .await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use n3_proto::Preamble;
+    use n3quic::QuicCongestionControl;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = N3Config::from_toml(
+            r#"
+            metrics = "127.0.0.1:9090"
+            shutdown_timeout = 10
+
+            [quic]
+            max_idle_timeout = 1000
+            cc_algorithm = "bbr2"
+            hystart = false
+
+            [[listener]]
+            ports = "443:445"
+            target = "127.0.0.1:8080"
+
+            [[listener.route]]
+            sni = "*.example.com"
+            target = "127.0.0.1:8081"
+
+            [[listener]]
+            mode = "http3"
+            interfaces = ["0.0.0.0"]
+            ports = 8443
+
+            [[listener.route]]
+            alpn = "h3"
+            target = "127.0.0.1:80"
+
+            [listener.quic]
+            initial_max_streams = 1000
+            max_udp_payload_size = 1350
+
+            [listener.connect_udp]
+            allow = ["*.internal:53"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.cert, PathBuf::from("n3.crt"));
+        assert_eq!(config.metrics, Some("127.0.0.1:9090".parse().unwrap()));
+        assert_eq!(config.shutdown_timeout, 10);
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(
+            config.listeners[0].laddrs(),
+            vec![
+                "[::]:443".parse::<SocketAddr>().unwrap(),
+                "[::]:444".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.listeners[0].router().routes().len(), 1);
+        assert_eq!(config.listeners[1].mode, ListenerMode::Http3);
+        assert_eq!(
+            config.listeners[1].laddrs(),
+            vec!["0.0.0.0:8443".parse::<SocketAddr>().unwrap()]
+        );
+
+        assert!(
+            config.listeners[0]
+                .router()
+                .connect_udp_allow_list()
+                .is_none()
+        );
+        assert_eq!(
+            config.listeners[1]
+                .router()
+                .connect_udp_allow_list()
+                .unwrap()
+                .rules()
+                .len(),
+            1
+        );
+
+        let base = config.quic.or(&N3Config::default_quic());
+        let tuning = config.listeners[1].quic.or(&base);
+
+        assert_eq!(tuning.max_idle_timeout, Some(1000));
+        assert_eq!(tuning.initial_max_streams, Some(1000));
+        assert_eq!(tuning.max_ack_delay, Some(25));
+        assert_eq!(tuning.cc_algorithm, Some(QuicCongestionControl::Bbr2));
+        assert_eq!(tuning.hystart, Some(false));
+        assert_eq!(tuning.max_udp_payload_size, Some(1350));
+        assert_eq!(tuning.enable_pacing, None);
+    }
+
+    #[test]
+    fn test_reload_listener_changed() {
+        let running =
+            N3Config::from_toml("[[listener]]\nports = 443\ntarget = \"127.0.0.1:80\"\n").unwrap();
+
+        let config = N3Config::from_toml(
+            "[[listener]]\nports = 443\ntarget = \"127.0.0.1:80\"\n[[listener]]\nports = 8443\ntarget = \"127.0.0.1:80\"\n",
+        )
+        .unwrap();
+
+        assert!(config.reload(&running, &[]).is_err());
+
+        let config =
+            N3Config::from_toml("[[listener]]\nports = 444\ntarget = \"127.0.0.1:80\"\n").unwrap();
+
+        assert!(
+            config
+                .reload(&running, &[])
+                .unwrap_err()
+                .to_string()
+                .contains("restart is required")
+        );
+    }
+
+    #[test]
+    fn test_reload_global_changed() {
+        let running =
+            N3Config::from_toml("[[listener]]\nports = 443\ntarget = \"127.0.0.1:80\"\n").unwrap();
+
+        for global in [
+            "shards = 2",
+            "zero_rtt = \"accept\"",
+            "stateless_reset_key = \"n3.reset.key\"",
+            "metrics = \"127.0.0.1:9090\"",
+            "io_timer_tick_interval = 10",
+            "debug = true",
+        ] {
+            let config = N3Config::from_toml(&format!(
+                "{}\n[[listener]]\nports = 443\ntarget = \"127.0.0.1:80\"\n",
+                global
+            ))
+            .unwrap();
+
+            assert!(
+                config
+                    .reload(&running, &[])
+                    .unwrap_err()
+                    .to_string()
+                    .contains("`shards`, `zero_rtt`"),
+                "{}",
+                global
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let invalid = |content: &str| N3Config::from_toml(content).unwrap_err().to_string();
+
+        assert!(invalid("").contains("at least one `[[listener]]`"));
+        assert!(invalid("[[listener]]\nports = 443\n").contains("listener[0]"));
+        assert!(
+            invalid("[[listener]]\nports = \"444:443\"\ntarget = \"127.0.0.1:80\"\n")
+                .contains("`to > from`")
+        );
+        assert!(
+            invalid("[[listener]]\nport = 443\ntarget = \"127.0.0.1:80\"\n")
+                .contains("unknown field")
+        );
+        assert!(
+            invalid("[[listener]]\nmode = \"http3\"\nports = 443\ntarget = \"127.0.0.1:80\"\nprotos = [\"n3\"]\n")
+                .contains("not allowed")
+        );
+        assert!(invalid("[[listener]]\nports = 443\ntarget = []\n").contains("`targets` is empty"));
+        assert!(
+            invalid("[[listener]]\nmode = \"tunnel\"\nports = 443\n")
+                .contains("`expose` is required")
+        );
+        assert!(
+            invalid("[[listener]]\nmode = \"http3\"\nports = 443\n[listener.preamble]\nallow = [\"*:*\"]\n")
+                .contains("only allowed in `redirect` mode")
+        );
+        assert!(
+            invalid("[[listener]]\nmode = \"tunnel\"\nports = 443\nexpose = \"0.0.0.0:80\"\n[listener.preamble]\nallow = [\"*:*\"]\n")
+                .contains("`preamble` is only allowed in `redirect` mode")
+        );
+        assert!(
+            invalid("[[listener]]\nports = 443\ntarget = \"127.0.0.1:80\"\n[listener.connect_udp]\nallow = [\"*:*\"]\n")
+                .contains("only allowed in `http3` mode")
+        );
+        assert!(
+            invalid("[[listener]]\nports = 443\n[listener.preamble]\nallow = [\"db.internal\"]\n")
+                .contains("invalid allow rule")
+        );
+        assert!(
+            invalid("[[listener]]\nmode = \"tunnel\"\nports = 443\nexpose = \"0.0.0.0:80\"\ntarget = \"127.0.0.1:80\"\n")
+                .contains("not allowed in `tunnel` mode")
+        );
+        assert!(
+            invalid("[[listener]]\nmode = \"tunnel\"\nports = 443\nexpose = \"0.0.0.0:80\"\n")
+                .contains("`verify_peer` is required in `tunnel` mode")
+        );
+        assert!(
+            invalid(
+                "[[listener]]\nports = 443\nexpose = \"0.0.0.0:80\"\ntarget = \"127.0.0.1:80\"\n"
+            )
+            .contains("only allowed in `tunnel` mode")
+        );
+        assert!(
+            invalid("shards = 0\n[[listener]]\nports = 443\ntarget = \"127.0.0.1:80\"\n")
+                .contains("`shards` must be greater than 0")
+        );
+        assert!(
+            invalid("[quic]\ncc_algorithm = \"bbr\"\n[[listener]]\nports = 443\ntarget = \"127.0.0.1:80\"\n")
+                .contains("unknown variant")
+        );
+        assert!(
+            invalid("[[listener]]\nports = 443\ntarget = \"127.0.0.1:80\"\n[listener.quic]\nmax_udp_payload_size = 100\n")
+                .contains("listener[0]: `max_udp_payload_size`")
+        );
+        assert!(
+            invalid("[quic]\nactive_connection_id_limit = 1\n[[listener]]\nports = 443\ntarget = \"127.0.0.1:80\"\n")
+                .contains("quic: `active_connection_id_limit`")
+        );
+    }
+
+    #[test]
+    fn test_parse_preamble() {
+        let config = N3Config::from_toml(
+            r#"
+            [[listener]]
+            ports = 443
+
+            [listener.preamble]
+            allow = ["*.internal:*", "10.0.0.0/8:22"]
+            tokens = ["secret"]
+            "#,
+        )
+        .unwrap();
+
+        let allow_list = config.listeners[0].router().allow_list().unwrap();
+
+        assert_eq!(allow_list.rules().len(), 2);
+        assert!(
+            allow_list
+                .check(&Preamble::new("10.0.0.1:22").token("secret"))
+                .is_ok()
+        );
+        assert!(allow_list.check(&Preamble::new("10.0.0.1:22")).is_err());
+    }
+
+    #[test]
+    fn test_parse_upstream() {
+        let config = N3Config::from_toml(
+            r#"
+            [[listener]]
+            ports = 443
+            target = ["127.0.0.1:8080", "127.0.0.1:8081"]
+
+            [[listener.route]]
+            sni = "api.example.com"
+            target = { targets = ["127.0.0.1:80"], policy = "least_conn", health_check_interval = 5 }
+
+            [[listener.route]]
+            alpn = "h3"
+            target = "web.internal:80"
+            "#,
+        )
+        .unwrap();
+
+        let listener = &config.listeners[0];
+
+        assert_eq!(
+            listener.target,
+            Some(Upstream::new([
+                "127.0.0.1:8080".parse().unwrap(),
+                "127.0.0.1:8081".parse().unwrap()
+            ]))
+        );
+
+        assert_eq!(
+            listener.routes[0].upstream(),
+            &Upstream::new(["127.0.0.1:80".parse().unwrap()])
+                .policy(Policy::LeastConn)
+                .health_check(Duration::from_secs(5), Duration::from_secs(5))
+        );
+
+        assert_eq!(
+            listener.routes[1].upstream(),
+            &Upstream::new([Target::Host("web.internal".to_owned(), 80)])
+        );
+
+        assert!(
+            N3Config::from_toml(
+                r#"
+                [[listener]]
+                ports = 443
+                target = "web.internal"
+                "#,
+            )
+            .is_err()
+        );
+    }
+}
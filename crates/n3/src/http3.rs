@@ -0,0 +1,1029 @@
+//! `HTTP/3` to `HTTP/1.1` forwarding.
+
+use std::{
+    io::{Error, ErrorKind, Result},
+    pin::pin,
+    sync::Arc,
+};
+
+use futures::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    future::{Either, pending, select},
+    io::BufReader,
+};
+use n3_spawner::spawn;
+use n3io::{copy::copy, timeout::sleep};
+use n3quic::{
+    H3Conn, QuicConn, QuicShutdown,
+    quiche::h3::{self, Header, NameValue},
+};
+
+use crate::{
+    ActiveStream, AllowList, DRAIN_CHECK_INTERVAL, N3Metrics, RouteKey, Upstream,
+    masque::{ConnectUdp, is_connect_udp},
+};
+
+/// The maximum length of the upstream response header section.
+const MAX_RESPONSE_HEADER_SIZE: usize = 64 * 1024;
+
+/// The maximum number of the upstream response headers.
+const MAX_RESPONSE_HEADERS: usize = 128;
+
+/// Hop-by-hop headers, which must not be forwarded.
+const HOP_BY_HOP_HEADERS: &[&[u8]] = &[
+    b"connection",
+    b"keep-alive",
+    b"proxy-connection",
+    b"transfer-encoding",
+    b"upgrade",
+    b"te",
+];
+
+fn is_hop_by_hop(name: &[u8]) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|header| header.eq_ignore_ascii_case(name))
+}
+
+/// RFC 9110 5.6.2: `tchar`, the characters of a token.
+fn is_tchar(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+fn is_token(value: &[u8]) -> bool {
+    !value.is_empty() && value.iter().all(|c| is_tchar(*c))
+}
+
+/// RFC 9114 4.2: field names are lower-case tokens.
+fn is_field_name(name: &[u8]) -> bool {
+    is_token(name) && !name.iter().any(u8::is_ascii_uppercase)
+}
+
+/// RFC 9114 4.2: field values must not contain `CR`, `LF` or `NUL`, which would split the
+/// `HTTP/1.1` request head.
+fn is_field_value(value: &[u8]) -> bool {
+    !value.iter().any(|c| matches!(c, b'\r' | b'\n' | b'\0'))
+}
+
+/// The request target of `HTTP/1.1` contains no whitespace or control characters.
+fn is_request_target(path: &[u8]) -> bool {
+    !path.is_empty() && !path.iter().any(|c| *c <= b' ' || *c == 0x7f)
+}
+
+/// The routed upstream of a `HTTP/3` connection.
+struct Forwarding {
+    upstream: Upstream,
+    key: Arc<RouteKey>,
+    metrics: Arc<N3Metrics>,
+}
+
+/// Serve `HTTP/3` requests on `conn`, forward them to `upstream`.
+///
+/// `connect-udp` requests are accepted if `connect_udp` is set, the others are forwarded.
+pub(crate) async fn serve(
+    conn: QuicConn,
+    upstream: Upstream,
+    key: Arc<RouteKey>,
+    metrics: Arc<N3Metrics>,
+    connect_udp: Option<Arc<AllowList>>,
+    shutdown: QuicShutdown,
+    trace_id: &str,
+) -> Result<()> {
+    let mut config = h3::Config::new().map_err(Error::other)?;
+
+    let connect_udp = connect_udp.map(|allow_list| {
+        // RFC 9220: `:protocol` is only sent by clients after the server enables it.
+        config.enable_extended_connect(true);
+
+        Arc::new(ConnectUdp::new(allow_list, metrics.clone()))
+    });
+
+    let forwarding = Arc::new(Forwarding {
+        upstream,
+        key,
+        metrics,
+    });
+
+    let h3_conn = H3Conn::new(conn, &config)?;
+
+    let requests = serve_requests(&h3_conn, &forwarding, &connect_udp, &shutdown, trace_id);
+
+    let datagrams = async {
+        match &connect_udp {
+            Some(connect_udp) => connect_udp.relay(h3_conn.quic_conn(), trace_id).await,
+            // no HTTP datagram is expected, the receive queue of quiche drops them.
+            None => pending().await,
+        }
+    };
+
+    match select(pin!(requests), pin!(datagrams)).await {
+        Either::Left((result, _)) => result,
+        Either::Right((result, _)) => result,
+    }
+}
+
+async fn serve_requests(
+    h3_conn: &H3Conn,
+    forwarding: &Arc<Forwarding>,
+    connect_udp: &Option<Arc<ConnectUdp>>,
+    shutdown: &QuicShutdown,
+    trace_id: &str,
+) -> Result<()> {
+    // cloned by each request, released when the response is forwarded.
+    let inflight = Arc::new(());
+
+    // the first request stream id not to be processed after `GOAWAY`.
+    let mut next_stream_id = 0;
+
+    let mut goaway = false;
+
+    loop {
+        if goaway && Arc::strong_count(&inflight) == 1 {
+            log::info!("http3 conn is drained, id={}", trace_id);
+            return Ok(());
+        }
+
+        let event = {
+            let event = h3_conn.event();
+
+            if goaway {
+                // keep polling events, which drive the request bodies of the in-flight requests.
+                match select(pin!(event), pin!(sleep(DRAIN_CHECK_INTERVAL))).await {
+                    Either::Left((event, _)) => Some(event?),
+                    Either::Right(_) => None,
+                }
+            } else {
+                match select(pin!(event), pin!(shutdown.wait())).await {
+                    Either::Left((event, _)) => Some(event?),
+                    Either::Right(_) => {
+                        h3_conn.send_goaway(next_stream_id)?;
+
+                        log::info!(
+                            "http3 send goaway, id={}, stream_id={}, inflight={}",
+                            trace_id,
+                            next_stream_id,
+                            Arc::strong_count(&inflight) - 1
+                        );
+
+                        goaway = true;
+
+                        None
+                    }
+                }
+            }
+        };
+
+        let Some((stream_id, event)) = event else {
+            continue;
+        };
+
+        match event {
+            h3::Event::Headers { .. } if goaway => {
+                log::trace!(
+                    "http3 reject request after goaway, h3({},{})",
+                    trace_id,
+                    stream_id
+                );
+
+                // RFC 9114 4.1.1: the client can retry a rejected request on a new connection.
+                h3_conn.reset_stream(stream_id, h3::WireErrorCode::RequestRejected as u64)?;
+            }
+            h3::Event::Headers { list, .. } if connect_udp.is_some() && is_connect_udp(&list) => {
+                next_stream_id = stream_id + 4;
+
+                let h3_conn = h3_conn.clone();
+                let trace_id = trace_id.to_owned();
+                let connect_udp = connect_udp.clone().expect("connect_udp");
+                let inflight = inflight.clone();
+
+                spawn(async move {
+                    let _inflight = inflight;
+
+                    if let Err(err) = connect_udp.serve(h3_conn, stream_id, list, &trace_id).await {
+                        log::error!(
+                            "http3 connect-udp, h3({},{}), err={}",
+                            trace_id,
+                            stream_id,
+                            err
+                        );
+                    }
+                })?;
+            }
+            h3::Event::Headers { list, more_frames } => {
+                next_stream_id = stream_id + 4;
+
+                let h3_conn = h3_conn.clone();
+                let trace_id = trace_id.to_owned();
+                let forwarding = forwarding.clone();
+                let inflight = inflight.clone();
+
+                spawn(async move {
+                    let _inflight = inflight;
+
+                    if let Err(err) = forward(
+                        h3_conn,
+                        stream_id,
+                        list,
+                        more_frames,
+                        &forwarding,
+                        &trace_id,
+                    )
+                    .await
+                    {
+                        log::error!(
+                            "http3 forward, h3({},{}) => http({}), err={}",
+                            trace_id,
+                            stream_id,
+                            forwarding.upstream,
+                            err
+                        );
+                    }
+                })?;
+            }
+            h3::Event::GoAway => {
+                log::info!("http3 goaway, id={}", trace_id);
+                return Ok(());
+            }
+            // body events are dispatched by `H3Conn` itself.
+            _ => {}
+        }
+    }
+}
+
+/// A parsed `HTTP/3` request head.
+struct RequestHead {
+    method: Vec<u8>,
+    path: Vec<u8>,
+    authority: Option<Vec<u8>>,
+    headers: Vec<(Vec<u8>, Vec<u8>)>,
+    content_length: Option<u64>,
+}
+
+impl RequestHead {
+    fn parse(list: &[Header]) -> Result<Self> {
+        let mut method = None;
+        let mut path = None;
+        let mut authority = None;
+        let mut headers = vec![];
+        let mut cookies: Vec<&[u8]> = vec![];
+        let mut content_length = None;
+
+        for header in list {
+            if !is_field_value(header.value()) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "invalid value of header `{}`",
+                        String::from_utf8_lossy(header.name())
+                    ),
+                ));
+            }
+
+            match header.name() {
+                b":method" => method = Some(header.value().to_vec()),
+                b":path" => path = Some(header.value().to_vec()),
+                b":authority" => authority = Some(header.value().to_vec()),
+                b":scheme" | b":protocol" => {}
+                b"cookie" => cookies.push(header.value()),
+                name if name.starts_with(b":") => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unknown pseudo header `{}`", String::from_utf8_lossy(name)),
+                    ));
+                }
+                name if !is_field_name(name) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid header name `{}`", String::from_utf8_lossy(name)),
+                    ));
+                }
+                name if is_hop_by_hop(name) => {}
+                name => {
+                    if name == b"content-length" {
+                        let len = std::str::from_utf8(header.value())
+                            .ok()
+                            .and_then(|value| value.trim().parse::<u64>().ok())
+                            .ok_or_else(|| {
+                                Error::new(ErrorKind::InvalidData, "invalid `content-length`")
+                            })?;
+
+                        if content_length.is_some_and(|content_length| content_length != len) {
+                            return Err(Error::new(
+                                ErrorKind::InvalidData,
+                                "conflicting `content-length`",
+                            ));
+                        }
+
+                        content_length = Some(len);
+                    }
+
+                    headers.push((name.to_vec(), header.value().to_vec()));
+                }
+            }
+        }
+
+        // RFC9114 4.2.1: multiple cookie fields are concatenated before passing to `HTTP/1.1`.
+        if !cookies.is_empty() {
+            headers.push((b"cookie".to_vec(), cookies.join(&b"; "[..])));
+        }
+
+        let method =
+            method.ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing `:method` header"))?;
+
+        if !is_token(&method) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "invalid `:method` header",
+            ));
+        }
+
+        if method == b"CONNECT" {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "`CONNECT` method is not supported",
+            ));
+        }
+
+        let path =
+            path.ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing `:path` header"))?;
+
+        if !is_request_target(&path) {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid `:path` header"));
+        }
+
+        Ok(Self {
+            method,
+            path,
+            authority,
+            headers,
+            content_length,
+        })
+    }
+
+    /// Encode the request head as `HTTP/1.1` format.
+    fn encode(&self, chunked: bool) -> Vec<u8> {
+        let mut buf = vec![];
+
+        buf.extend_from_slice(&self.method);
+        buf.push(b' ');
+        buf.extend_from_slice(&self.path);
+        buf.extend_from_slice(b" HTTP/1.1\r\n");
+
+        let has_host = self.headers.iter().any(|(name, _)| name == b"host");
+
+        if let (false, Some(authority)) = (has_host, &self.authority) {
+            buf.extend_from_slice(b"host: ");
+            buf.extend_from_slice(authority);
+            buf.extend_from_slice(b"\r\n");
+        }
+
+        for (name, value) in &self.headers {
+            buf.extend_from_slice(name);
+            buf.extend_from_slice(b": ");
+            buf.extend_from_slice(value);
+            buf.extend_from_slice(b"\r\n");
+        }
+
+        if chunked {
+            buf.extend_from_slice(b"transfer-encoding: chunked\r\n");
+        }
+
+        buf.extend_from_slice(b"connection: close\r\n\r\n");
+
+        buf
+    }
+}
+
+/// The framing of upstream response body.
+enum BodyLength {
+    None,
+    Fixed(u64),
+    Chunked,
+    UntilEof,
+}
+
+/// Send a response without body.
+pub(crate) async fn send_status(h3_conn: &H3Conn, stream_id: u64, status: u16) -> Result<()> {
+    let status = status.to_string();
+
+    h3_conn
+        .send_response(
+            stream_id,
+            &[
+                Header::new(b":status", status.as_bytes()),
+                Header::new(b"content-length", b"0"),
+            ],
+            true,
+        )
+        .await
+}
+
+async fn forward(
+    h3_conn: H3Conn,
+    stream_id: u64,
+    list: Vec<Header>,
+    more_frames: bool,
+    forwarding: &Forwarding,
+    trace_id: &str,
+) -> Result<()> {
+    let metrics = &forwarding.metrics;
+
+    // RFC 9114 4.1.2: a request whose body does not match its `content-length` is malformed.
+    let head = match RequestHead::parse(&list).and_then(|head| {
+        if !more_frames && head.content_length.is_some_and(|len| len > 0) {
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                "request body is shorter than `content-length`",
+            ))
+        } else {
+            Ok(head)
+        }
+    }) {
+        Ok(head) => head,
+        Err(err) => {
+            let status = if err.kind() == ErrorKind::Unsupported {
+                501
+            } else {
+                400
+            };
+
+            send_status(&h3_conn, stream_id, status).await?;
+            return Err(err);
+        }
+    };
+
+    let _active_stream = ActiveStream::new(metrics.clone());
+
+    // held until the response is forwarded.
+    let (outbound, upstream_conn) = match forwarding.upstream.connect(&forwarding.key).await {
+        Ok(connected) => connected,
+        Err(err) => {
+            metrics.upstream_connect_errors.inc();
+            send_status(&h3_conn, stream_id, 502).await?;
+            return Err(err);
+        }
+    };
+
+    let raddr = upstream_conn.addr();
+
+    let laddr = outbound.mio_socket().local_addr()?;
+
+    log::info!(
+        "new http3 request h3({},{}) => http({},{}), method={}, path={}",
+        trace_id,
+        stream_id,
+        laddr,
+        raddr,
+        String::from_utf8_lossy(&head.method),
+        String::from_utf8_lossy(&head.path)
+    );
+
+    let is_head = head.method == b"HEAD";
+    let chunked = more_frames && head.content_length.is_none();
+    let content_length = head.content_length;
+
+    let (mut upstream_writer, upstream_reader) = outbound.split();
+
+    upstream_writer.write_all(&head.encode(chunked)).await?;
+
+    if more_frames {
+        let body = h3_conn.stream(stream_id);
+        let h3_conn = h3_conn.clone();
+        let trace_id = trace_id.to_owned();
+        let metrics = metrics.clone();
+
+        spawn(async move {
+            match copy_request_body(body, &mut upstream_writer, chunked, content_length).await {
+                Ok(len) => {
+                    metrics.forward_bytes.add(len as u64);
+
+                    log::trace!(
+                        "http3 request body is sent, h3({},{}) ==> http({}), trans_size={}",
+                        trace_id,
+                        stream_id,
+                        raddr,
+                        len
+                    );
+                }
+                Err(err) => {
+                    log::error!(
+                        "http3 request body is broken, h3({},{}) ==> http({}), err={}",
+                        trace_id,
+                        stream_id,
+                        raddr,
+                        err
+                    );
+
+                    // the upstream must not read the rest as another request.
+                    if err.kind() == ErrorKind::InvalidData {
+                        _ = upstream_writer.close().await;
+                        _ = h3_conn.reset_stream(stream_id, h3::WireErrorCode::MessageError as u64);
+                    }
+                }
+            }
+        })?;
+    } else {
+        upstream_writer.flush().await?;
+    }
+
+    let mut upstream_reader = BufReader::new(upstream_reader);
+
+    let (status, headers, body_length) = match read_final_response_head(&mut upstream_reader).await
+    {
+        Ok(head) => head,
+        Err(err) => {
+            send_status(&h3_conn, stream_id, 502).await?;
+            return Err(err);
+        }
+    };
+
+    let body_length = if is_head || status == 204 || status == 304 {
+        BodyLength::None
+    } else {
+        body_length
+    };
+
+    let status = status.to_string();
+
+    let mut response = vec![Header::new(b":status", status.as_bytes())];
+
+    for (name, value) in &headers {
+        response.push(Header::new(name, value));
+    }
+
+    let fin = matches!(body_length, BodyLength::None | BodyLength::Fixed(0));
+
+    h3_conn.send_response(stream_id, &response, fin).await?;
+
+    if fin {
+        return Ok(());
+    }
+
+    let mut body = h3_conn.stream(stream_id);
+
+    let id = format!(
+        "h3({},{}) <- http({},{})",
+        trace_id, stream_id, laddr, raddr
+    );
+
+    let len = match body_length {
+        BodyLength::Fixed(len) => {
+            copy(
+                Some(&id),
+                (&mut upstream_reader).take(len),
+                &mut body,
+                65535,
+            )
+            .await?
+        }
+        BodyLength::Chunked => copy_chunked(&id, &mut upstream_reader, &mut body).await?,
+        _ => copy(Some(&id), upstream_reader, &mut body, 65535).await?,
+    };
+
+    body.close().await?;
+
+    metrics.backward_bytes.add(len as u64);
+
+    log::info!(
+        "http3 response is closed, h3({},{}) <== http({},{}), status={}, trans_size={}",
+        trace_id,
+        stream_id,
+        laddr,
+        raddr,
+        status,
+        len
+    );
+
+    Ok(())
+}
+
+/// Copy the request body to `writer`, re-framed as `chunked` if `chunked` is set.
+///
+/// Returns an `InvalidData` error before writing any byte beyond `content_length`, or at the end
+/// of a shorter body.
+async fn copy_request_body<R, W>(
+    mut body: R,
+    writer: &mut W,
+    chunked: bool,
+    content_length: Option<u64>,
+) -> Result<usize>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut trans = 0;
+    let mut buf = vec![0; 65535];
+
+    loop {
+        let read_size = body.read(&mut buf).await?;
+
+        if let Some(len) = content_length
+            && (trans + read_size) as u64 > len
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "request body is longer than `content-length`",
+            ));
+        }
+
+        if read_size == 0 {
+            if let Some(len) = content_length
+                && (trans as u64) < len
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "request body is shorter than `content-length`",
+                ));
+            }
+
+            if chunked {
+                writer.write_all(b"0\r\n\r\n").await?;
+            }
+
+            writer.flush().await?;
+
+            return Ok(trans);
+        }
+
+        if chunked {
+            writer
+                .write_all(format!("{:x}\r\n", read_size).as_bytes())
+                .await?;
+            writer.write_all(&buf[..read_size]).await?;
+            writer.write_all(b"\r\n").await?;
+        } else {
+            writer.write_all(&buf[..read_size]).await?;
+        }
+
+        trans += read_size;
+    }
+}
+
+/// Read the final response head of `HTTP/1.1`, skipping the informational responses.
+///
+/// `101 Switching Protocols` is an error, `HTTP/3` has no connection upgrade.
+async fn read_final_response_head<R>(
+    reader: &mut BufReader<R>,
+) -> Result<(u16, Vec<(Vec<u8>, Vec<u8>)>, BodyLength)>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let (status, headers, body_length) = read_response_head(reader).await?;
+
+        if status == 101 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "upstream switched protocols",
+            ));
+        }
+
+        // e.g. `100 Continue`.
+        if (100..200).contains(&status) {
+            continue;
+        }
+
+        return Ok((status, headers, body_length));
+    }
+}
+
+/// Read the response head of `HTTP/1.1`, returns `(status, headers, body_length)`.
+async fn read_response_head<R>(
+    reader: &mut BufReader<R>,
+) -> Result<(u16, Vec<(Vec<u8>, Vec<u8>)>, BodyLength)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = vec![];
+
+    loop {
+        let offset = buf.len();
+
+        if reader.read_until(b'\n', &mut buf).await? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "upstream closed before sending response head",
+            ));
+        }
+
+        if buf.len() > MAX_RESPONSE_HEADER_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "upstream response head is too large",
+            ));
+        }
+
+        if &buf[offset..] == b"\r\n" || &buf[offset..] == b"\n" {
+            break;
+        }
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_RESPONSE_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+
+    if response.parse(&buf).map_err(Error::other)?.is_partial() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "incomplete upstream response head",
+        ));
+    }
+
+    let status = response
+        .code
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing response status"))?;
+
+    let mut body_length = BodyLength::UntilEof;
+    let mut forward_headers = vec![];
+
+    for header in response.headers.iter() {
+        let name = header.name.to_ascii_lowercase();
+
+        if name == "transfer-encoding" {
+            if header
+                .value
+                .split(|c| *c == b',')
+                .any(|coding| coding.trim_ascii().eq_ignore_ascii_case(b"chunked"))
+            {
+                body_length = BodyLength::Chunked;
+            }
+
+            continue;
+        }
+
+        if is_hop_by_hop(name.as_bytes()) {
+            continue;
+        }
+
+        if name == "content-length" && !matches!(body_length, BodyLength::Chunked) {
+            let len = std::str::from_utf8(header.value)
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, "invalid upstream `content-length`")
+                })?;
+
+            body_length = BodyLength::Fixed(len);
+        }
+
+        forward_headers.push((name.into_bytes(), header.value.to_vec()));
+    }
+
+    // `transfer-encoding` overrides `content-length`.
+    if matches!(body_length, BodyLength::Chunked) {
+        forward_headers.retain(|(name, _)| name != b"content-length");
+    }
+
+    Ok((status, forward_headers, body_length))
+}
+
+/// Decode a `chunked` body and copy it to `writer`.
+async fn copy_chunked<R, W>(id: &str, reader: &mut BufReader<R>, writer: &mut W) -> Result<usize>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut trans = 0;
+    let mut line = vec![];
+
+    loop {
+        line.clear();
+
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "upstream closed in the middle of chunked body",
+            ));
+        }
+
+        let size = line
+            .split(|c| *c == b';')
+            .next()
+            .and_then(|size| std::str::from_utf8(size).ok())
+            .and_then(|size| u64::from_str_radix(size.trim(), 16).ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid chunk size"))?;
+
+        if size == 0 {
+            // skip trailers.
+            loop {
+                line.clear();
+
+                if reader.read_until(b'\n', &mut line).await? == 0
+                    || line == b"\r\n"
+                    || line == b"\n"
+                {
+                    writer.flush().await?;
+                    return Ok(trans);
+                }
+            }
+        }
+
+        trans += copy(Some(id), (&mut *reader).take(size), writer, 65535).await?;
+
+        // chunk data CRLF.
+        line.clear();
+        reader.read_until(b'\n', &mut line).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_encode_request_head() {
+        let head = RequestHead::parse(&[
+            Header::new(b":method", b"POST"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"example.com"),
+            Header::new(b":path", b"/index.html"),
+            Header::new(b"cookie", b"a=1"),
+            Header::new(b"connection", b"keep-alive"),
+            Header::new(b"cookie", b"b=2"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            head.encode(true),
+            b"POST /index.html HTTP/1.1\r\nhost: example.com\r\ncookie: a=1; b=2\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n"
+        );
+
+        assert_eq!(
+            RequestHead::parse(&[
+                Header::new(b":method", b"CONNECT"),
+                Header::new(b":authority", b"example.com:443"),
+            ])
+            .err()
+            .unwrap()
+            .kind(),
+            ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_reject_malformed_request_head() {
+        let request = |name: &[u8], value: &[u8]| {
+            let mut list = vec![
+                Header::new(b":method", b"GET"),
+                Header::new(b":scheme", b"https"),
+                Header::new(b":authority", b"example.com"),
+                Header::new(b":path", b"/"),
+            ];
+
+            match list.iter_mut().find(|header| header.name() == name) {
+                Some(header) => *header = Header::new(name, value),
+                None => list.push(Header::new(name, value)),
+            }
+
+            RequestHead::parse(&list)
+        };
+
+        assert!(request(b"x-test", b"1").is_ok());
+
+        let malformed: &[(&[u8], &[u8])] = &[
+            (b"x-test", b"1\r\nhost: evil.com"),
+            (b"x-test", b"1\nx-evil: 1"),
+            (b"x-test", b"1\0"),
+            (b":authority", b"example.com\r\nx-evil: 1"),
+            (b"X-Test", b"1"),
+            (b"x test", b"1"),
+            (b"x-test:", b"1"),
+            (b"", b"1"),
+            (b":method", b"GET /evil HTTP/1.1"),
+            (b":method", b""),
+            (b":path", b"/ HTTP/1.1"),
+            (b":path", b"/\x7f"),
+            (b":path", b"/\t"),
+            (b":path", b""),
+        ];
+
+        for &(name, value) in malformed {
+            assert_eq!(
+                request(name, value).err().unwrap().kind(),
+                ErrorKind::InvalidData,
+                "{}: {}",
+                String::from_utf8_lossy(name),
+                String::from_utf8_lossy(value)
+            );
+        }
+
+        assert_eq!(
+            RequestHead::parse(&[
+                Header::new(b":method", b"POST"),
+                Header::new(b":path", b"/"),
+                Header::new(b"content-length", b"5"),
+                Header::new(b"content-length", b"6"),
+            ])
+            .err()
+            .unwrap()
+            .kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[futures_test::test]
+    async fn test_copy_request_body() {
+        let mut upstream = vec![];
+
+        assert_eq!(
+            copy_request_body(Cursor::new(b"hello"), &mut upstream, false, Some(5))
+                .await
+                .unwrap(),
+            5
+        );
+
+        assert_eq!(upstream, b"hello");
+
+        // the extra bytes would be parsed by the upstream as another request.
+        let mut upstream = vec![];
+
+        assert_eq!(
+            copy_request_body(
+                Cursor::new(b"helloGET /evil HTTP/1.1\r\n\r\n"),
+                &mut upstream,
+                false,
+                Some(5)
+            )
+            .await
+            .err()
+            .unwrap()
+            .kind(),
+            ErrorKind::InvalidData
+        );
+
+        assert!(upstream.is_empty());
+
+        let mut upstream = vec![];
+
+        assert_eq!(
+            copy_request_body(Cursor::new(b"hel"), &mut upstream, false, Some(5))
+                .await
+                .err()
+                .unwrap()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+
+        let mut upstream = vec![];
+
+        assert_eq!(
+            copy_request_body(Cursor::new(b"hello"), &mut upstream, true, None)
+                .await
+                .unwrap(),
+            5
+        );
+
+        assert_eq!(upstream, b"5\r\nhello\r\n0\r\n\r\n");
+    }
+
+    #[futures_test::test]
+    async fn test_read_response() {
+        let mut reader = BufReader::new(Cursor::new(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\nTransfer-Encoding: chunked\r\nConnection: close\r\nX-Test: 1\r\n\r\n5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: 1\r\n\r\n"
+                .to_vec(),
+        ));
+
+        let (status, headers, body_length) = read_response_head(&mut reader).await.unwrap();
+
+        assert_eq!(status, 200);
+        assert_eq!(headers, vec![(b"x-test".to_vec(), b"1".to_vec())]);
+        assert!(matches!(body_length, BodyLength::Chunked));
+
+        let mut body = vec![];
+
+        assert_eq!(
+            copy_chunked("test", &mut reader, &mut body).await.unwrap(),
+            11
+        );
+
+        assert_eq!(body, b"hello world");
+    }
+
+    #[futures_test::test]
+    async fn test_read_final_response() {
+        let mut reader = BufReader::new(Cursor::new(
+            b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 103 Early Hints\r\nLink: </a.css>\r\n\r\nHTTP/1.1 204 No Content\r\n\r\n"
+                .to_vec(),
+        ));
+
+        let (status, headers, body_length) = read_final_response_head(&mut reader).await.unwrap();
+
+        assert_eq!(status, 204);
+        assert!(headers.is_empty());
+        assert!(matches!(body_length, BodyLength::UntilEof));
+
+        let mut reader = BufReader::new(Cursor::new(
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n".to_vec(),
+        ));
+
+        assert_eq!(
+            read_final_response_head(&mut reader)
+                .await
+                .err()
+                .unwrap()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+    }
+}
@@ -3,32 +3,167 @@
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
 use std::{
-    io::Result,
+    fmt::Display,
+    io::{Error, ErrorKind, Result},
     net::{SocketAddr, ToSocketAddrs},
+    pin::pin,
+    sync::{Arc, RwLock},
+    time::Duration,
 };
 
-use futures::AsyncWriteExt;
+use futures::{
+    AsyncWriteExt,
+    future::{Either, pending, select, try_join},
+};
+use n3_proto::{Preamble, PreambleStatus};
 use n3_spawner::spawn;
-use n3io::{copy::copy, net::TcpStream};
-use n3quic::{QuicConn, QuicConnExt, QuicServer, QuicStream};
+use n3io::{
+    copy::copy,
+    dns::global_resolver,
+    net::{TcpListener, TcpStream},
+    timeout::{TimeoutExt as _, sleep},
+};
+use n3quic::{
+    QuicConfigReloader, QuicConn, QuicConnExt, QuicListener, QuicServer, QuicShutdown, QuicStream,
+    quiche,
+};
+
+mod http3;
+mod masque;
+mod tunnel;
+mod udp;
+
+mod router;
+pub use router::*;
+
+mod acl;
+pub use acl::*;
+
+mod upstream;
+pub use upstream::*;
+
+mod metrics;
+pub use metrics::*;
+
+pub mod config;
+
+/// The interval of checking whether the in-flight streams are finished during shutdown.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The timeout of reading the preamble of a stream.
+const PREAMBLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The timeout of connecting to the target of a stream preamble.
+const PREAMBLE_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the streams of a redirected connection are forwarded to.
+#[derive(Clone)]
+enum Forward {
+    /// The routed upstream pool.
+    Upstream(Upstream, Arc<RouteKey>),
+    /// The target of each stream preamble, checked against the allow-list.
+    Preamble(Arc<AllowList>),
+}
+
+impl Display for Forward {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Upstream(upstream, _) => upstream.fmt(f),
+            Self::Preamble(_) => write!(f, "preamble"),
+        }
+    }
+}
+
+/// A handle to update the routing table and the `quiche::Config` of a running [`N3`].
+///
+/// Established connections keep the upstream and the config they were accepted with.
+#[derive(Debug, Clone)]
+pub struct N3Reloader {
+    router: Arc<RwLock<Router>>,
+    quic: QuicConfigReloader,
+}
+
+impl N3Reloader {
+    /// Replace the routing table, and start its health checks.
+    pub fn router(&self, router: Router) -> Result<()> {
+        router.start_health_checks()?;
+
+        *self.router.write().unwrap() = router;
+
+        Ok(())
+    }
+
+    /// Replace the `quiche::Config` used by the subsequent handshakes.
+    pub fn quiche_config(&self, config: quiche::Config) {
+        self.quic.reload(config);
+    }
+}
 
 /// Reverse proxy server.
 pub struct N3 {
-    /// Redirection target for tcp stream.
-    redirect_to: SocketAddr,
+    /// Selects the redirection target for incoming connections.
+    router: Arc<RwLock<Router>>,
     /// the QUIC server configuration.
     quic_server: QuicServer,
+    /// runtime `quiche::Config` replacement.
+    quic_reloader: QuicConfigReloader,
+    /// server counters.
+    metrics: Arc<N3Metrics>,
+    /// graceful shutdown handle.
+    shutdown: QuicShutdown,
 }
 
 impl N3 {
     /// Create a new `N3` configuration with `redirect_to` target.
     pub fn new(redirect_to: SocketAddr) -> Self {
+        Self::with_router(Router::new().fallback(redirect_to))
+    }
+
+    /// Create a new `N3` configuration with routing table.
+    pub fn with_router(router: Router) -> Self {
         Self {
-            redirect_to,
+            router: Arc::new(RwLock::new(router)),
             quic_server: QuicServer::new(),
+            quic_reloader: QuicConfigReloader::new(),
+            metrics: Default::default(),
+            shutdown: QuicShutdown::new(),
         }
     }
 
+    /// Update `router` config.
+    pub fn router<F>(self, f: F) -> Self
+    where
+        F: FnOnce(Router) -> Router,
+    {
+        let mut router = self.router.write().unwrap();
+        *router = f(std::mem::take(&mut *router));
+        drop(router);
+
+        self
+    }
+
+    /// Returns the handle to reload this server at runtime.
+    pub fn reloader(&self) -> N3Reloader {
+        N3Reloader {
+            router: self.router.clone(),
+            quic: self.quic_reloader.clone(),
+        }
+    }
+
+    /// Returns the counters of this server.
+    pub fn metrics(&self) -> Arc<N3Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Returns the handle to shutdown this server gracefully.
+    ///
+    /// After the shutdown is started, no more connections or streams are accepted, `HTTP/3`
+    /// clients receive a `GOAWAY` frame, [`bind`](Self::bind) returns once the in-flight streams
+    /// are finished or the grace period expires.
+    pub fn shutdown_handle(&self) -> QuicShutdown {
+        self.shutdown.clone()
+    }
+
     // Update `quic_server` config.
     pub fn quic_server<F>(mut self, f: F) -> Self
     where
@@ -43,17 +178,45 @@ impl N3 {
     where
         S: ToSocketAddrs,
     {
-        let mut listener = self.quic_server.bind(laddrs).await?;
+        let mut listener = self
+            .quic_server
+            .config_reloader(self.quic_reloader)
+            .metrics(self.metrics.quic.clone())
+            .shutdown_handle(self.shutdown.clone())
+            .bind(laddrs)
+            .await?;
+
+        let router = self.router;
+
+        router.read().unwrap().start_health_checks()?;
 
         loop {
-            let conn = listener.accept().await?;
+            let Some(conn) = Self::accept(&mut listener, &self.shutdown).await? else {
+                return Ok(());
+            };
+
+            let allow_list = router.read().unwrap().allow_list();
+
+            let forward = if let Some(allow_list) = allow_list {
+                self.metrics.conns.inc();
+                Forward::Preamble(allow_list)
+            } else if let Some((upstream, key)) = Self::route(&router, &conn, &self.metrics) {
+                Forward::Upstream(upstream, key)
+            } else {
+                continue;
+            };
+
+            let metrics = self.metrics.clone();
+            let shutdown = self.shutdown.clone();
 
             spawn(async move {
                 let trace_id = conn.quiche_conn(|conn| conn.trace_id().to_owned());
 
-                log::info!("redirect, id={}, to={}", trace_id, self.redirect_to);
+                log::info!("redirect, id={}, to={}", trace_id, forward);
 
-                if let Err(err) = Self::redirect_loop(conn, self.redirect_to, &trace_id).await {
+                if let Err(err) =
+                    Self::redirect_loop(conn, forward, metrics, shutdown, &trace_id).await
+                {
                     log::error!("pipe is broken, id={}, err={}", trace_id, err);
                 } else {
                     log::info!("pipe is broken, id={}", trace_id);
@@ -62,27 +225,366 @@ impl N3 {
         }
     }
 
-    async fn redirect_loop(conn: QuicConn, raddr: SocketAddr, trace_id: &str) -> Result<()> {
+    /// Bind `n3` to `laddrs` and run it as a `HTTP/3` reverse proxy.
+    ///
+    /// The incoming `HTTP/3` requests are forwarded to the routed target as `HTTP/1.1` requests,
+    /// `connect-udp` requests are proxied if [`Router::connect_udp`] is set.
+    pub async fn bind_http3<S>(self, laddrs: S) -> Result<()>
+    where
+        S: ToSocketAddrs,
+    {
+        let mut listener = self
+            .quic_server
+            .config_reloader(self.quic_reloader)
+            .metrics(self.metrics.quic.clone())
+            .shutdown_handle(self.shutdown.clone())
+            .bind(laddrs)
+            .await?;
+
+        let router = self.router;
+
+        router.read().unwrap().start_health_checks()?;
+
         loop {
-            let inbound = conn.accept().await?;
+            let Some(conn) = Self::accept(&mut listener, &self.shutdown).await? else {
+                return Ok(());
+            };
+
+            let Some((upstream, key)) = Self::route(&router, &conn, &self.metrics) else {
+                continue;
+            };
+
+            let connect_udp = router.read().unwrap().connect_udp_allow_list();
+
+            let metrics = self.metrics.clone();
+            let shutdown = self.shutdown.clone();
 
-            let trace_id = trace_id.to_owned();
             spawn(async move {
-                let stream_id = inbound.id();
+                let trace_id = conn.quiche_conn(|conn| conn.trace_id().to_owned());
 
-                if let Err(err) = Self::create_channel(inbound, raddr, trace_id.clone()).await {
-                    log::error!("create channel ({},{}), err={}", trace_id, stream_id, err);
+                log::info!("http3, id={}, to={}", trace_id, upstream);
+
+                if let Err(err) = http3::serve(
+                    conn,
+                    upstream,
+                    key,
+                    metrics,
+                    connect_udp,
+                    shutdown,
+                    &trace_id,
+                )
+                .await
+                {
+                    log::error!("http3 conn is broken, id={}, err={}", trace_id, err);
+                } else {
+                    log::info!("http3 conn is closed, id={}", trace_id);
                 }
             })?;
         }
     }
 
+    /// Bind `n3` to `laddrs` as the server side of a reverse tunnel.
+    ///
+    /// The agents connect to `laddrs`, the tcp connections accepted on `expose` are forwarded to
+    /// them as server-initiated quic streams, the routing table is not used in this mode.
+    ///
+    /// Only the agents presenting a client certificate are accepted, so the `quiche::Config`
+    /// must verify the peer against the trusted CA certificates.
+    pub async fn bind_tunnel<S>(self, laddrs: S, expose: SocketAddr) -> Result<()>
+    where
+        S: ToSocketAddrs,
+    {
+        let listener = self
+            .quic_server
+            .config_reloader(self.quic_reloader)
+            .metrics(self.metrics.quic.clone())
+            .shutdown_handle(self.shutdown.clone())
+            .bind(laddrs)
+            .await?;
+
+        let exposed = TcpListener::bind(expose).await?;
+
+        Self::tunnel(listener, exposed, self.metrics, self.shutdown).await
+    }
+
+    /// Run the reverse tunnel of [`bind_tunnel`](Self::bind_tunnel) on the bound listeners.
+    async fn tunnel(
+        mut listener: QuicListener,
+        exposed: TcpListener,
+        metrics: Arc<N3Metrics>,
+        shutdown: QuicShutdown,
+    ) -> Result<()> {
+        let expose = exposed.mio_socket().local_addr()?;
+
+        let agents = Arc::new(tunnel::Agents::default());
+
+        let exposed = tunnel::serve(exposed, agents.clone(), metrics.clone(), shutdown.clone());
+
+        let accept = async {
+            loop {
+                let Some(conn) = Self::accept(&mut listener, &shutdown).await? else {
+                    return Ok(());
+                };
+
+                let (trace_id, has_peer_cert) = conn
+                    .quiche_conn(|conn| (conn.trace_id().to_owned(), conn.peer_cert().is_some()));
+
+                // a verified client certificate is the only credential of an agent.
+                if !has_peer_cert {
+                    log::error!(
+                        "tunnel agent without client certificate, id={}, expose={}",
+                        trace_id,
+                        expose
+                    );
+
+                    if let Err(err) = conn.close(0x0, b"client certificate required") {
+                        log::trace!("close conn, id={}, err={}", trace_id, err);
+                    }
+
+                    continue;
+                }
+
+                metrics.conns.inc();
+
+                log::info!(
+                    "tunnel agent is connected, id={}, expose={}",
+                    trace_id,
+                    expose
+                );
+
+                agents.insert(trace_id, conn, &metrics);
+            }
+        };
+
+        try_join(exposed, accept).await?;
+
+        Ok(())
+    }
+
+    /// Accept a new connection, returns `None` once `listener` is drained after the shutdown.
+    async fn accept(
+        listener: &mut QuicListener,
+        shutdown: &QuicShutdown,
+    ) -> Result<Option<QuicConn>> {
+        match listener.accept().await {
+            Ok(conn) => Ok(Some(conn)),
+            Err(_) if shutdown.is_shutdown() => {
+                let laddrs = listener.local_addrs().copied().collect::<Vec<_>>();
+
+                log::info!(
+                    "shutdown, laddrs={:?}, active_conns={}",
+                    laddrs,
+                    listener.active_conns()
+                );
+
+                listener.wait_closed().await;
+
+                log::info!("shutdown completed, laddrs={:?}", laddrs);
+
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Select the upstream for `conn`, close `conn` if no route matches.
+    fn route(
+        router: &RwLock<Router>,
+        conn: &QuicConn,
+        metrics: &N3Metrics,
+    ) -> Option<(Upstream, Arc<RouteKey>)> {
+        let key = RouteKey::from_conn(conn);
+
+        if let Some(upstream) = router.read().unwrap().lookup(&key) {
+            metrics.conns.inc();
+            return Some((upstream, Arc::new(key)));
+        }
+
+        metrics.no_route_conns.inc();
+
+        log::error!(
+            "no route, id={}, server_name={:?}, alpn={}, laddr={:?}",
+            key.conn_id,
+            key.server_name,
+            String::from_utf8_lossy(&key.alpn),
+            key.laddr
+        );
+
+        if let Err(err) = conn.close(0x0, b"no route") {
+            log::trace!("close conn, id={}, err={}", key.conn_id, err);
+        }
+
+        None
+    }
+
+    async fn redirect_loop(
+        conn: QuicConn,
+        forward: Forward,
+        metrics: Arc<N3Metrics>,
+        shutdown: QuicShutdown,
+        trace_id: &str,
+    ) -> Result<()> {
+        // cloned by each pipe, released when both directions are closed.
+        let inflight = Arc::new(());
+
+        let streams = async {
+            loop {
+                let inbound = match select(conn.accept(), pin!(shutdown.wait())).await {
+                    Either::Left((inbound, _)) => inbound?,
+                    Either::Right(_) => return Ok::<_, Error>(()),
+                };
+
+                let trace_id = trace_id.to_owned();
+                let forward = forward.clone();
+                let metrics = metrics.clone();
+                let inflight = inflight.clone();
+
+                spawn(async move {
+                    let stream_id = inbound.id();
+
+                    if let Err(err) =
+                        Self::create_channel(inbound, &forward, metrics, inflight, trace_id.clone())
+                            .await
+                    {
+                        log::error!("create channel ({},{}), err={}", trace_id, stream_id, err);
+                    }
+                })?;
+            }
+        };
+
+        let datagrams = async {
+            match &forward {
+                Forward::Upstream(upstream, key) => {
+                    udp::relay(&conn, upstream, key, &metrics, trace_id).await
+                }
+                // the datagrams have no target, the receive queue of quiche drops them.
+                Forward::Preamble(_) => pending().await,
+            }
+        };
+
+        match select(pin!(streams), pin!(datagrams)).await {
+            Either::Left((result, _)) => result?,
+            Either::Right((result, _)) => result?,
+        }
+
+        log::info!(
+            "stop accepting streams, id={}, inflight={}",
+            trace_id,
+            Arc::strong_count(&inflight) - 1
+        );
+
+        // the listener closes `conn` when the grace period expires.
+        while Arc::strong_count(&inflight) > 1 && !conn.is_closed() {
+            sleep(DRAIN_CHECK_INTERVAL).await;
+        }
+
+        Ok(())
+    }
+
+    /// Read the preamble of `inbound`, connect to its target if it's allowed by `allow_list`.
+    ///
+    /// The result is written back to `inbound` if the preamble asks for a reply.
+    async fn connect_preamble(
+        inbound: &mut QuicStream,
+        allow_list: &AllowList,
+        metrics: &N3Metrics,
+    ) -> Result<(TcpStream, SocketAddr)> {
+        let preamble = Preamble::read(inbound)
+            .timeout(PREAMBLE_TIMEOUT)
+            .await
+            .inspect_err(|_| metrics.rejected_preambles.inc())?;
+
+        let connected = Self::connect_preamble_target(&preamble, allow_list, metrics).await;
+
+        if preamble.wants_reply() {
+            let status = match &connected {
+                Ok(_) => PreambleStatus::Connected,
+                Err((status, _)) => *status,
+            };
+
+            status.write(inbound).await?;
+        }
+
+        connected.map_err(|(_, err)| err)
+    }
+
+    /// Connect to the target of `preamble`, the error is paired with the status of the reply.
+    async fn connect_preamble_target(
+        preamble: &Preamble,
+        allow_list: &AllowList,
+        metrics: &N3Metrics,
+    ) -> std::result::Result<(TcpStream, SocketAddr), (PreambleStatus, Error)> {
+        let (host, port) = allow_list.check(preamble).map_err(|err| {
+            metrics.rejected_preambles.inc();
+
+            match err.kind() {
+                ErrorKind::PermissionDenied => (PreambleStatus::Denied, err),
+                _ => (PreambleStatus::InvalidTarget, err),
+            }
+        })?;
+
+        let raddrs = global_resolver()
+            .resolve(host, port)
+            .await
+            .map_err(|err| (PreambleStatus::Unreachable, err))?;
+
+        let mut last_err = Error::new(
+            ErrorKind::NotFound,
+            format!("`{}` has no address", preamble.target),
+        );
+
+        for raddr in raddrs {
+            match TcpStream::connect(raddr)
+                .timeout(PREAMBLE_CONNECT_TIMEOUT)
+                .await
+            {
+                Ok(outbound) => return Ok((outbound, raddr)),
+                Err(err) => {
+                    log::error!(
+                        "failed to connect preamble target, target={}, raddr={}, err={}",
+                        preamble.target,
+                        raddr,
+                        err
+                    );
+                    last_err = err;
+                }
+            }
+        }
+
+        metrics.upstream_connect_errors.inc();
+
+        Err((PreambleStatus::Unreachable, last_err))
+    }
+
     async fn create_channel(
-        inbound: QuicStream,
-        raddr: SocketAddr,
+        mut inbound: QuicStream,
+        forward: &Forward,
+        metrics: Arc<N3Metrics>,
+        inflight: Arc<()>,
         trace_id: String,
     ) -> Result<()> {
-        let outbound = TcpStream::connect(raddr).await?;
+        let (outbound, upstream_conn, raddr) = match forward {
+            Forward::Upstream(upstream, key) => match upstream.connect(key).await {
+                Ok((outbound, upstream_conn)) => {
+                    let raddr = upstream_conn.addr();
+                    (outbound, Some(upstream_conn), raddr)
+                }
+                Err(err) => {
+                    metrics.upstream_connect_errors.inc();
+                    return Err(err);
+                }
+            },
+            Forward::Preamble(allow_list) => {
+                let (outbound, raddr) =
+                    Self::connect_preamble(&mut inbound, allow_list, &metrics).await?;
+                (outbound, None, raddr)
+            }
+        };
+
+        // released when both directions are closed.
+        let upstream_conn = Arc::new((upstream_conn, ActiveStream::new(metrics.clone()), inflight));
+        let upstream_conn_cloned = upstream_conn.clone();
+        let metrics_cloned = metrics.clone();
 
         let stream_id = inbound.id();
 
@@ -102,12 +604,16 @@ impl N3 {
         let trace_id_owned = trace_id.to_owned();
 
         spawn(async move {
+            let _upstream_conn = upstream_conn_cloned;
+
             let id = format!(
                 "quic({},{}) <- tcp({},{})",
                 trace_id_owned, stream_id, laddr, raddr
             );
             match copy(Some(&id), outbound_reader, &mut inbound_writer, 65535).await {
                 Ok(len) => {
+                    metrics_cloned.backward_bytes.add(len as u64);
+
                     log::info!(
                         "stream(backward) is closed, quic({},{}) <== tcp({},{}), trans_size={}",
                         trace_id_owned,
@@ -142,6 +648,8 @@ impl N3 {
         })?;
 
         spawn(async move {
+            let _upstream_conn = upstream_conn;
+
             let id = format!(
                 "quic({},{}) -> tcp({},{})",
                 trace_id, stream_id, laddr, raddr
@@ -149,6 +657,8 @@ impl N3 {
 
             match copy(Some(&id), inbound_reader, &mut outbound_writer, 65545).await {
                 Ok(len) => {
+                    metrics.forward_bytes.add(len as u64);
+
                     log::info!(
                         "stream(forward) is closed, quic({},{}) ==> tcp({},{}), trans_size={}",
                         trace_id,
@@ -0,0 +1,514 @@
+//! `connect-udp`(RFC 9298): proxy udp over `HTTP/3`, the udp payloads are carried in HTTP
+//! datagrams(RFC 9297) over QUIC DATAGRAM frames.
+
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::pin,
+    sync::{Arc, Mutex},
+};
+
+use futures::{
+    AsyncWriteExt,
+    future::{Either, select},
+    io,
+};
+use n3_proto::Preamble;
+use n3io::{dns::global_resolver, net::UdpSocket};
+use n3quic::{
+    H3Conn, QuicConn, QuicConnExt, QuicDgramSender,
+    quiche::h3::{Header, NameValue},
+};
+
+use crate::{AllowList, N3Metrics, http3::send_status};
+
+/// The value of the `:protocol` pseudo header of `connect-udp` requests.
+const CONNECT_UDP_PROTOCOL: &[u8] = b"connect-udp";
+
+/// The path prefix of the default URI template `/.well-known/masque/udp/{host}/{port}/`.
+const WELL_KNOWN_PREFIX: &str = "/.well-known/masque/udp/";
+
+/// The context id of the udp payload datagrams.
+const UDP_PAYLOAD_CONTEXT_ID: u64 = 0;
+
+/// The maximum size of a udp datagram.
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// Returns true if `list` is the header list of a `connect-udp` request.
+pub(crate) fn is_connect_udp(list: &[Header]) -> bool {
+    let mut method = None;
+    let mut protocol = None;
+
+    for header in list {
+        match header.name() {
+            b":method" => method = Some(header.value()),
+            b":protocol" => protocol = Some(header.value()),
+            _ => {}
+        }
+    }
+
+    method == Some(b"CONNECT") && protocol == Some(CONNECT_UDP_PROTOCOL)
+}
+
+/// Encode `value` as a QUIC variable-length integer.
+fn encode_varint(value: u64, buf: &mut Vec<u8>) {
+    match value {
+        0..=0x3f => buf.push(value as u8),
+        0x40..=0x3fff => buf.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => buf.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes()),
+        _ => buf.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+/// Decode a QUIC variable-length integer, returns the value and its length.
+fn decode_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let len = 1 << (first >> 6);
+
+    let bytes = buf.get(..len)?;
+
+    let value = bytes[1..]
+        .iter()
+        .fold((first & 0x3f) as u64, |value, byte| {
+            value << 8 | *byte as u64
+        });
+
+    Some((value, len))
+}
+
+/// A HTTP datagram of a `connect-udp` request.
+#[derive(Debug, PartialEq, Eq)]
+struct HttpDatagram<'a> {
+    /// The request stream id divided by 4.
+    quarter_stream_id: u64,
+    context_id: u64,
+    payload: &'a [u8],
+}
+
+impl<'a> HttpDatagram<'a> {
+    /// Create a udp payload datagram of request `stream_id`.
+    fn udp_payload(stream_id: u64, payload: &'a [u8]) -> Self {
+        Self {
+            quarter_stream_id: stream_id / 4,
+            context_id: UDP_PAYLOAD_CONTEXT_ID,
+            payload,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(16 + self.payload.len());
+
+        encode_varint(self.quarter_stream_id, &mut buf);
+        encode_varint(self.context_id, &mut buf);
+        buf.extend_from_slice(self.payload);
+
+        buf
+    }
+
+    fn decode(buf: &'a [u8]) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "truncated http datagram");
+
+        let (quarter_stream_id, len) = decode_varint(buf).ok_or_else(invalid)?;
+        let buf = &buf[len..];
+
+        let (context_id, len) = decode_varint(buf).ok_or_else(invalid)?;
+
+        Ok(Self {
+            quarter_stream_id,
+            context_id,
+            payload: &buf[len..],
+        })
+    }
+}
+
+/// Parse the target of a `connect-udp` request from its `:path`, returns `host:port`.
+fn parse_target(path: &[u8]) -> Result<String> {
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "invalid connect-udp path `{}`",
+                String::from_utf8_lossy(path)
+            ),
+        )
+    };
+
+    let path = std::str::from_utf8(path).map_err(|_| invalid())?;
+
+    let rest = path.strip_prefix(WELL_KNOWN_PREFIX).ok_or_else(invalid)?;
+
+    let (host, port) = rest
+        .strip_suffix('/')
+        .unwrap_or(rest)
+        .split_once('/')
+        .ok_or_else(invalid)?;
+
+    let host = percent_decode(host).ok_or_else(invalid)?;
+    let port = port.parse::<u16>().map_err(|_| invalid())?;
+
+    if host.is_empty() || port == 0 {
+        return Err(invalid());
+    }
+
+    // ipv6 literals are percent-encoded by the template expansion, e.g. `2001%3Adb8%3A%3A1`.
+    if host.contains(':') {
+        Ok(format!("[{}]:{}", host, port))
+    } else {
+        Ok(format!("{}:{}", host, port))
+    }
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut buf = vec![];
+    let mut bytes = s.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+
+            buf.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            buf.push(byte);
+        }
+    }
+
+    String::from_utf8(buf).ok()
+}
+
+/// The udp socket of a `connect-udp` request.
+struct Flow {
+    socket: UdpSocket,
+    raddr: SocketAddr,
+}
+
+/// The `connect-udp` requests of a `HTTP/3` connection.
+pub(crate) struct ConnectUdp {
+    allow_list: Arc<AllowList>,
+    metrics: Arc<N3Metrics>,
+    /// quarter stream id to the flow of the request.
+    flows: Mutex<HashMap<u64, Arc<Flow>>>,
+}
+
+impl ConnectUdp {
+    pub(crate) fn new(allow_list: Arc<AllowList>, metrics: Arc<N3Metrics>) -> Self {
+        Self {
+            allow_list,
+            metrics,
+            flows: Default::default(),
+        }
+    }
+
+    /// Dispatch the HTTP datagrams of `conn` to the udp sockets of the requests until `conn`
+    /// is closed.
+    pub(crate) async fn relay(&self, conn: &QuicConn, trace_id: &str) -> Result<()> {
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+
+        loop {
+            let len = conn.dgram_recv(&mut buf).await?;
+
+            let datagram = match HttpDatagram::decode(&buf[..len]) {
+                Ok(datagram) => datagram,
+                Err(err) => {
+                    self.metrics.dropped_datagrams.inc();
+                    log::error!("drop http datagram, id={}, err={}", trace_id, err);
+                    continue;
+                }
+            };
+
+            // unknown contexts are dropped silently(RFC 9298 4).
+            if datagram.context_id != UDP_PAYLOAD_CONTEXT_ID {
+                self.metrics.dropped_datagrams.inc();
+                continue;
+            }
+
+            let flow = self
+                .flows
+                .lock()
+                .unwrap()
+                .get(&datagram.quarter_stream_id)
+                .cloned();
+
+            // the request may be closed, or its response is not sent yet.
+            let Some(flow) = flow else {
+                self.metrics.dropped_datagrams.inc();
+                log::trace!(
+                    "drop http datagram of unknown request, id={}, quarter_stream_id={}",
+                    trace_id,
+                    datagram.quarter_stream_id
+                );
+                continue;
+            };
+
+            match flow.socket.send_to(datagram.payload, flow.raddr).await {
+                Ok(len) => self.metrics.udp_forward_bytes.add(len as u64),
+                Err(err) => {
+                    self.metrics.dropped_datagrams.inc();
+
+                    log::error!(
+                        "failed to relay http datagram, id={}, quarter_stream_id={}, raddr={}, err={}",
+                        trace_id,
+                        datagram.quarter_stream_id,
+                        flow.raddr,
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    /// Open the udp socket of request `stream_id`, relay its responses until the request stream
+    /// is closed.
+    pub(crate) async fn serve(
+        &self,
+        h3_conn: H3Conn,
+        stream_id: u64,
+        list: Vec<Header>,
+        trace_id: &str,
+    ) -> Result<()> {
+        let flow = match self.open(&list).await {
+            Ok(flow) => Arc::new(flow),
+            Err(err) => {
+                let status = match err.kind() {
+                    ErrorKind::InvalidData => 400,
+                    ErrorKind::PermissionDenied => 403,
+                    _ => 502,
+                };
+
+                send_status(&h3_conn, stream_id, status).await?;
+                return Err(err);
+            }
+        };
+
+        h3_conn
+            .send_response(
+                stream_id,
+                &[
+                    Header::new(b":status", b"200"),
+                    Header::new(b"capsule-protocol", b"?1"),
+                ],
+                false,
+            )
+            .await?;
+
+        log::info!(
+            "new connect-udp request, h3({},{}) => udp({}), laddr={}",
+            trace_id,
+            stream_id,
+            flow.raddr,
+            flow.socket.mio_socket().local_addr()?
+        );
+
+        self.flows
+            .lock()
+            .unwrap()
+            .insert(stream_id / 4, flow.clone());
+
+        self.metrics.udp_flows.inc();
+
+        let sender = h3_conn.quic_conn().dgram_sender();
+
+        // capsules are not supported, the request body is discarded until the end.
+        let result = match select(
+            pin!(self.backward(&flow, stream_id, &sender, trace_id)),
+            pin!(io::copy(h3_conn.stream(stream_id), &mut io::sink())),
+        )
+        .await
+        {
+            Either::Left((result, _)) => result,
+            Either::Right((result, _)) => result.map(|_| ()),
+        };
+
+        self.flows.lock().unwrap().remove(&(stream_id / 4));
+
+        self.metrics.udp_flows.dec();
+
+        h3_conn.stream(stream_id).close().await?;
+
+        log::info!(
+            "connect-udp request is closed, h3({},{}) => udp({})",
+            trace_id,
+            stream_id,
+            flow.raddr
+        );
+
+        result
+    }
+
+    /// Check the target of the request, bind the udp socket.
+    async fn open(&self, list: &[Header]) -> Result<Flow> {
+        let mut path = None;
+        let mut token = None;
+
+        for header in list {
+            match header.name() {
+                b":path" => path = Some(header.value()),
+                b"proxy-authorization" => {
+                    token = std::str::from_utf8(header.value())
+                        .ok()
+                        .and_then(|value| value.strip_prefix("Bearer "))
+                }
+                _ => {}
+            }
+        }
+
+        let target = parse_target(path.unwrap_or_default())
+            .inspect_err(|_| self.metrics.rejected_connect_udp.inc())?;
+
+        let preamble = match token {
+            Some(token) => Preamble::new(target).token(token),
+            None => Preamble::new(target),
+        };
+
+        let (host, port) = self
+            .allow_list
+            .check(&preamble)
+            .map_err(|err| {
+                let kind = if err.kind() == ErrorKind::PermissionDenied {
+                    err.kind()
+                } else {
+                    ErrorKind::InvalidData
+                };
+
+                Error::new(kind, err)
+            })
+            .inspect_err(|_| self.metrics.rejected_connect_udp.inc())?;
+
+        let raddr = global_resolver()
+            .resolve(host, port)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("`{}` has no address", preamble.target),
+                )
+            })?;
+
+        let laddr = if raddr.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+
+        let socket = UdpSocket::bind(laddr).await?;
+
+        Ok(Flow { socket, raddr })
+    }
+
+    /// Relay the responses of the target as HTTP datagrams.
+    async fn backward(
+        &self,
+        flow: &Flow,
+        stream_id: u64,
+        sender: &QuicDgramSender,
+        trace_id: &str,
+    ) -> Result<()> {
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+
+        loop {
+            let (len, from) = flow.socket.recv_from(&mut buf).await?;
+
+            // only the responses of the target are relayed.
+            if from != flow.raddr {
+                continue;
+            }
+
+            match sender.send(&HttpDatagram::udp_payload(stream_id, &buf[..len]).encode()) {
+                Ok(()) => self.metrics.udp_backward_bytes.add(len as u64),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    self.metrics.dropped_datagrams.inc();
+                }
+                // larger than a DATAGRAM frame, only this response is dropped.
+                Err(err) if err.kind() == ErrorKind::InvalidInput => {
+                    self.metrics.dropped_datagrams.inc();
+                    log::trace!(
+                        "connect-udp drop datagram, h3({},{}), err={}",
+                        trace_id,
+                        stream_id,
+                        err
+                    );
+                }
+                Err(err) => {
+                    log::error!(
+                        "connect-udp request is broken, h3({},{}), err={}",
+                        trace_id,
+                        stream_id,
+                        err
+                    );
+
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        for value in [
+            0,
+            37,
+            0x3f,
+            0x40,
+            15293,
+            0x3fff,
+            0x4000,
+            494878333,
+            151288809941952652,
+        ] {
+            let mut buf = vec![];
+            encode_varint(value, &mut buf);
+
+            assert_eq!(decode_varint(&buf), Some((value, buf.len())));
+        }
+
+        // RFC 9000 A.1
+        assert_eq!(decode_varint(&[0x7b, 0xbd]), Some((15293, 2)));
+        assert_eq!(
+            decode_varint(&[0x9d, 0x7f, 0x3e, 0x7d]),
+            Some((494878333, 4))
+        );
+        assert_eq!(decode_varint(&[0x9d, 0x7f]), None);
+
+        let datagram = HttpDatagram::udp_payload(400, b"hello");
+        let buf = datagram.encode();
+
+        assert_eq!(buf, [0x40, 100, 0, b'h', b'e', b'l', b'l', b'o']);
+        assert_eq!(HttpDatagram::decode(&buf).unwrap(), datagram);
+        assert!(HttpDatagram::decode(&[0x40]).is_err());
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            parse_target(b"/.well-known/masque/udp/192.0.2.6/443/").unwrap(),
+            "192.0.2.6:443"
+        );
+        assert_eq!(
+            parse_target(b"/.well-known/masque/udp/dns.internal/53/").unwrap(),
+            "dns.internal:53"
+        );
+        assert_eq!(
+            parse_target(b"/.well-known/masque/udp/2001%3Adb8%3A%3A42/53/").unwrap(),
+            "[2001:db8::42]:53"
+        );
+
+        assert!(parse_target(b"/masque/udp/192.0.2.6/443/").is_err());
+        assert!(parse_target(b"/.well-known/masque/udp/192.0.2.6/").is_err());
+        assert!(parse_target(b"/.well-known/masque/udp/192.0.2.6/0/").is_err());
+        assert!(parse_target(b"/.well-known/masque/udp/%3/53/").is_err());
+
+        assert!(is_connect_udp(&[
+            Header::new(b":method", b"CONNECT"),
+            Header::new(b":protocol", b"connect-udp"),
+        ]));
+        assert!(!is_connect_udp(&[Header::new(b":method", b"CONNECT")]));
+    }
+}
@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use n3_metrics::{Collector, Counter, Encoder, Gauge};
+use n3quic::QuicServerMetrics;
+
+/// Counters of a [`N3`](crate::N3) server.
+#[derive(Debug, Default)]
+pub struct N3Metrics {
+    /// Counters of the underlying quic listener.
+    pub quic: Arc<QuicServerMetrics>,
+    /// Routed connections.
+    pub conns: Counter,
+    /// Connections closed because no route matches.
+    pub no_route_conns: Counter,
+    /// Forwarded quic streams or `HTTP/3` requests.
+    pub streams: Counter,
+    /// Streams that are being forwarded.
+    pub active_streams: Gauge,
+    /// Failures of connecting to the upstream.
+    pub upstream_connect_errors: Counter,
+    /// Streams closed because the preamble is invalid or not allowed.
+    pub rejected_preambles: Counter,
+    /// `connect-udp` requests rejected because the target is invalid or not allowed.
+    pub rejected_connect_udp: Counter,
+    /// Agent connections of the reverse tunnel.
+    pub tunnel_agents: Gauge,
+    /// Bytes copied from the client to the upstream.
+    pub forward_bytes: Counter,
+    /// Bytes copied from the upstream to the client.
+    pub backward_bytes: Counter,
+    /// Udp flows that are being relayed.
+    pub udp_flows: Gauge,
+    /// Datagrams dropped because they are invalid, too large, over the flow limit or the send
+    /// queue is full.
+    pub dropped_datagrams: Counter,
+    /// Udp payload bytes relayed from the client to the upstream.
+    pub udp_forward_bytes: Counter,
+    /// Udp payload bytes relayed from the upstream to the client.
+    pub udp_backward_bytes: Counter,
+}
+
+impl Collector for N3Metrics {
+    fn collect(&self, encoder: &mut Encoder) {
+        self.quic.collect(encoder);
+
+        encoder.counter(
+            "n3_conns_total",
+            "Routed connections.",
+            &[],
+            self.conns.get(),
+        );
+
+        encoder.counter(
+            "n3_no_route_conns_total",
+            "Connections closed because no route matches.",
+            &[],
+            self.no_route_conns.get(),
+        );
+
+        encoder.counter(
+            "n3_streams_total",
+            "Forwarded quic streams or HTTP/3 requests.",
+            &[],
+            self.streams.get(),
+        );
+
+        encoder.gauge(
+            "n3_active_streams",
+            "Streams that are being forwarded.",
+            &[],
+            self.active_streams.get(),
+        );
+
+        encoder.counter(
+            "n3_upstream_connect_errors_total",
+            "Failures of connecting to the upstream.",
+            &[],
+            self.upstream_connect_errors.get(),
+        );
+
+        encoder.counter(
+            "n3_rejected_preambles_total",
+            "Streams closed because the preamble is invalid or not allowed.",
+            &[],
+            self.rejected_preambles.get(),
+        );
+
+        encoder.counter(
+            "n3_rejected_connect_udp_total",
+            "connect-udp requests rejected because the target is invalid or not allowed.",
+            &[],
+            self.rejected_connect_udp.get(),
+        );
+
+        encoder.gauge(
+            "n3_tunnel_agents",
+            "Agent connections of the reverse tunnel.",
+            &[],
+            self.tunnel_agents.get(),
+        );
+
+        encoder.counter(
+            "n3_stream_bytes_total",
+            "Bytes copied between the clients and the upstreams.",
+            &[("direction", "forward")],
+            self.forward_bytes.get(),
+        );
+
+        encoder.counter(
+            "n3_stream_bytes_total",
+            "Bytes copied between the clients and the upstreams.",
+            &[("direction", "backward")],
+            self.backward_bytes.get(),
+        );
+
+        encoder.gauge(
+            "n3_udp_flows",
+            "Udp flows that are being relayed.",
+            &[],
+            self.udp_flows.get(),
+        );
+
+        encoder.counter(
+            "n3_dropped_datagrams_total",
+            "Datagrams dropped because they are invalid, too large, over the flow limit or the send queue is full.",
+            &[],
+            self.dropped_datagrams.get(),
+        );
+
+        encoder.counter(
+            "n3_udp_bytes_total",
+            "Udp payload bytes relayed between the clients and the upstreams.",
+            &[("direction", "forward")],
+            self.udp_forward_bytes.get(),
+        );
+
+        encoder.counter(
+            "n3_udp_bytes_total",
+            "Udp payload bytes relayed between the clients and the upstreams.",
+            &[("direction", "backward")],
+            self.udp_backward_bytes.get(),
+        );
+    }
+}
+
+/// An active stream, `active_streams` is decreased when it's dropped.
+pub(crate) struct ActiveStream(Arc<N3Metrics>);
+
+impl ActiveStream {
+    pub(crate) fn new(metrics: Arc<N3Metrics>) -> Self {
+        metrics.streams.inc();
+        metrics.active_streams.inc();
+
+        Self(metrics)
+    }
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.0.active_streams.dec();
+    }
+}
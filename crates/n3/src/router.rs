@@ -0,0 +1,376 @@
+//! Upstream routing table.
+
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+};
+
+use n3quic::QuicConn;
+use serde::Deserialize;
+
+use crate::{AllowList, Target, Upstream, config::RouteRepr};
+
+/// The connection properties used to select a [`Route`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RouteKey {
+    /// The TLS SNI sent by the client.
+    pub server_name: Option<String>,
+    /// The negotiated application protocol.
+    pub alpn: Vec<u8>,
+    /// The local address the connection was accepted on.
+    pub laddr: Option<SocketAddr>,
+    /// The client address.
+    pub raddr: Option<SocketAddr>,
+    /// The trace id of the connection.
+    pub conn_id: String,
+}
+
+impl RouteKey {
+    /// Extract route key from an established `conn`.
+    pub fn from_conn(conn: &QuicConn) -> Self {
+        conn.quiche_conn(|conn| {
+            let path = conn.path_stats().find(|stats| stats.active);
+
+            Self {
+                server_name: conn.server_name().map(|name| name.to_ascii_lowercase()),
+                alpn: conn.application_proto().to_vec(),
+                laddr: path.as_ref().map(|stats| stats.local_addr),
+                raddr: path.as_ref().map(|stats| stats.peer_addr),
+                conn_id: conn.trace_id().to_owned(),
+            }
+        })
+    }
+}
+
+/// A routing rule, all of the specified conditions must match.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RouteRepr")]
+pub struct Route {
+    /// Match the TLS SNI, `*.example.com` matches any subdomain of `example.com`.
+    server_name: Option<String>,
+    /// Match the negotiated application protocol.
+    alpn: Option<Vec<u8>>,
+    /// Match the listening address.
+    laddr: Option<SocketAddr>,
+    /// The upstream pool.
+    upstream: Upstream,
+}
+
+impl Route {
+    /// Create a route to `target` that matches any connection.
+    pub fn new(target: SocketAddr) -> Self {
+        Self::with_upstream(Upstream::new([target]))
+    }
+
+    /// Create a route to `upstream` pool that matches any connection.
+    pub fn with_upstream(upstream: Upstream) -> Self {
+        Self {
+            server_name: None,
+            alpn: None,
+            laddr: None,
+            upstream,
+        }
+    }
+
+    /// Only match connections with TLS SNI `server_name`.
+    pub fn server_name<S: AsRef<str>>(mut self, server_name: S) -> Self {
+        self.server_name = Some(server_name.as_ref().to_ascii_lowercase());
+        self
+    }
+
+    /// Only match connections negotiated application protocol `alpn`.
+    pub fn alpn<A: AsRef<[u8]>>(mut self, alpn: A) -> Self {
+        self.alpn = Some(alpn.as_ref().to_vec());
+        self
+    }
+
+    /// Only match connections accepted on `laddr`.
+    pub fn laddr(mut self, laddr: SocketAddr) -> Self {
+        self.laddr = Some(laddr);
+        self
+    }
+
+    /// Returns the upstream pool of this route.
+    pub fn upstream(&self) -> &Upstream {
+        &self.upstream
+    }
+
+    /// Returns true if this route matches the `key`.
+    pub fn matches(&self, key: &RouteKey) -> bool {
+        if let Some(pattern) = &self.server_name {
+            let Some(server_name) = &key.server_name else {
+                return false;
+            };
+
+            if !match_server_name(pattern, server_name) {
+                return false;
+            }
+        }
+
+        if let Some(alpn) = &self.alpn
+            && *alpn != key.alpn
+        {
+            return false;
+        }
+
+        if let Some(laddr) = &self.laddr {
+            let Some(key_laddr) = &key.laddr else {
+                return false;
+            };
+
+            // a route bound to a port on a wildcard interface matches any local ip.
+            if laddr.port() != key_laddr.port()
+                || (!laddr.ip().is_unspecified() && laddr.ip() != key_laddr.ip())
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+pub(crate) fn match_server_name(pattern: &str, server_name: &str) -> bool {
+    if let Some(suffix) = pattern.strip_prefix("*.") {
+        server_name
+            .strip_suffix(suffix)
+            .and_then(|prefix| prefix.strip_suffix('.'))
+            .is_some_and(|prefix| !prefix.is_empty())
+    } else {
+        pattern == server_name
+    }
+}
+
+/// Parse route from `[sni=HOST][,alpn=PROTO][,laddr=ADDR][,lb=POLICY]@TARGET[,TARGET...]`,
+/// e.g. `sni=api.example.com,alpn=h3,lb=least_conn@127.0.0.1:8080,127.0.0.1:8081`.
+impl FromStr for Route {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: &dyn Display| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid route `{}`, {}", s, reason),
+            )
+        };
+
+        let (conds, targets) = s
+            .rsplit_once('@')
+            .ok_or_else(|| invalid(&"expect `[CONDS]@TARGET`"))?;
+
+        let targets = targets
+            .split(',')
+            .map(|target| target.parse::<Target>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|err| invalid(&format!("target: {}", err)))?;
+
+        let mut route = Route::with_upstream(Upstream::new(targets));
+
+        for cond in conds.split(',').filter(|cond| !cond.is_empty()) {
+            let (key, value) = cond
+                .split_once('=')
+                .ok_or_else(|| invalid(&format!("expect `KEY=VALUE`, got `{}`", cond)))?;
+
+            route = match key {
+                "sni" => route.server_name(value),
+                "alpn" => route.alpn(value),
+                "laddr" => route.laddr(
+                    value
+                        .parse()
+                        .map_err(|err| invalid(&format!("laddr: {}", err)))?,
+                ),
+                "lb" => {
+                    let policy = value.parse().map_err(|err| invalid(&err))?;
+                    route.upstream = route.upstream.policy(policy);
+                    route
+                }
+                _ => return Err(invalid(&format!("unknown key `{}`", key))),
+            };
+        }
+
+        Ok(route)
+    }
+}
+
+/// An ordered routing table, the first matched route wins.
+#[derive(Debug, Default, Clone)]
+pub struct Router {
+    routes: Vec<Route>,
+    /// Used when none of `routes` matches.
+    fallback: Option<Upstream>,
+    /// Read the target of each stream from its preamble instead of routing.
+    allow_list: Option<Arc<AllowList>>,
+    /// The targets of `HTTP/3` `connect-udp` requests.
+    connect_udp: Option<Arc<AllowList>>,
+}
+
+impl Router {
+    /// Create an empty routing table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a `route` to the table.
+    pub fn route(mut self, route: Route) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// Set the upstream used when no route matches.
+    pub fn fallback(self, target: SocketAddr) -> Self {
+        self.fallback_upstream(Upstream::new([target]))
+    }
+
+    /// Set the upstream pool used when no route matches.
+    pub fn fallback_upstream(mut self, upstream: Upstream) -> Self {
+        self.fallback = Some(upstream);
+        self
+    }
+
+    /// Read the target of each stream from its [`Preamble`](n3_proto::Preamble), the routes are
+    /// ignored, the targets not in `allow_list` are rejected.
+    pub fn preamble(mut self, allow_list: AllowList) -> Self {
+        self.allow_list = Some(Arc::new(allow_list));
+        self
+    }
+
+    /// Returns the allow-list of the stream preambles, if set.
+    pub fn allow_list(&self) -> Option<Arc<AllowList>> {
+        self.allow_list.clone()
+    }
+
+    /// Accept `HTTP/3` `connect-udp` requests(RFC 9298) to the targets in `allow_list`.
+    pub fn connect_udp(mut self, allow_list: AllowList) -> Self {
+        self.connect_udp = Some(Arc::new(allow_list));
+        self
+    }
+
+    /// Returns the allow-list of the `connect-udp` requests, if set.
+    pub fn connect_udp_allow_list(&self) -> Option<Arc<AllowList>> {
+        self.connect_udp.clone()
+    }
+
+    /// Returns the routes in matching order.
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// Select the upstream for `key`.
+    pub fn lookup(&self, key: &RouteKey) -> Option<Upstream> {
+        self.routes
+            .iter()
+            .find(|route| route.matches(key))
+            .map(|route| &route.upstream)
+            .or(self.fallback.as_ref())
+            .cloned()
+    }
+
+    /// Start the health check tasks of all the upstreams.
+    pub fn start_health_checks(&self) -> Result<()> {
+        for upstream in self
+            .routes
+            .iter()
+            .map(|route| &route.upstream)
+            .chain(self.fallback.as_ref())
+        {
+            upstream.start_health_check()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Policy;
+
+    use super::*;
+
+    fn key(server_name: Option<&str>, alpn: &str, laddr: &str) -> RouteKey {
+        RouteKey {
+            server_name: server_name.map(|name| name.to_owned()),
+            alpn: alpn.as_bytes().to_vec(),
+            laddr: Some(laddr.parse().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_route_parse() {
+        let route: Route = "sni=API.example.com,alpn=h3,laddr=[::]:443@127.0.0.1:8080"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            route,
+            Route::new("127.0.0.1:8080".parse().unwrap())
+                .server_name("api.example.com")
+                .alpn("h3")
+                .laddr("[::]:443".parse().unwrap())
+        );
+
+        let route: Route = "@[::1]:80".parse().unwrap();
+        assert_eq!(route, Route::new("[::1]:80".parse().unwrap()));
+
+        let route: Route = "lb=least_conn@127.0.0.1:80,127.0.0.1:81".parse().unwrap();
+        assert_eq!(
+            route,
+            Route::with_upstream(
+                Upstream::new([
+                    "127.0.0.1:80".parse().unwrap(),
+                    "127.0.0.1:81".parse().unwrap()
+                ])
+                .policy(Policy::LeastConn)
+            )
+        );
+
+        assert!("lb=random@127.0.0.1:80".parse::<Route>().is_err());
+
+        assert!("127.0.0.1:80".parse::<Route>().is_err());
+        assert!("host=a@127.0.0.1:80".parse::<Route>().is_err());
+        assert!("sni@127.0.0.1:80".parse::<Route>().is_err());
+    }
+
+    #[test]
+    fn test_server_name() {
+        assert!(match_server_name("a.com", "a.com"));
+        assert!(!match_server_name("a.com", "b.a.com"));
+        assert!(match_server_name("*.a.com", "b.a.com"));
+        assert!(match_server_name("*.a.com", "c.b.a.com"));
+        assert!(!match_server_name("*.a.com", "a.com"));
+        assert!(!match_server_name("*.a.com", "ba.com"));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let router = Router::new()
+            .route("sni=*.a.com,alpn=h3@127.0.0.1:1".parse().unwrap())
+            .route("sni=*.a.com@127.0.0.1:2".parse().unwrap())
+            .route("laddr=10.0.0.1:443@127.0.0.1:3".parse().unwrap())
+            .route("laddr=[::]:8443@127.0.0.1:4".parse().unwrap());
+
+        let lookup = |key: RouteKey| {
+            router
+                .lookup(&key)
+                .map(|upstream| upstream.targets().next().unwrap().port())
+        };
+
+        assert_eq!(lookup(key(Some("b.a.com"), "h3", "[::1]:443")), Some(1));
+        assert_eq!(lookup(key(Some("b.a.com"), "n3", "[::1]:443")), Some(2));
+        assert_eq!(lookup(key(None, "h3", "10.0.0.1:443")), Some(3));
+        assert_eq!(lookup(key(None, "h3", "10.0.0.2:443")), None);
+        assert_eq!(lookup(key(None, "h3", "10.0.0.2:8443")), Some(4));
+
+        let router = router.fallback("127.0.0.1:5".parse().unwrap());
+        let lookup = |key: RouteKey| {
+            router
+                .lookup(&key)
+                .map(|upstream| upstream.targets().next().unwrap().port())
+        };
+
+        assert_eq!(lookup(key(None, "h3", "10.0.0.2:443")), Some(5));
+    }
+}
@@ -0,0 +1,425 @@
+//! Reverse tunnel: the tcp connections accepted by a public listener are forwarded to the
+//! agents as server-initiated quic streams.
+
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Result},
+    net::SocketAddr,
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use futures::{
+    AsyncWriteExt,
+    future::{Either, select},
+};
+use n3_spawner::spawn;
+use n3io::{
+    copy::copy,
+    net::{TcpListener, TcpStream},
+    timeout::sleep,
+};
+use n3quic::{QuicConn, QuicShutdown, QuicStream};
+
+use crate::{ActiveStream, DRAIN_CHECK_INTERVAL, N3Metrics};
+
+/// The quic connections of the agents, streams are opened on them in round-robin order.
+#[derive(Default)]
+pub(crate) struct Agents(Mutex<VecDeque<(String, QuicConn)>>);
+
+impl Agents {
+    /// Add a new agent connection.
+    pub(crate) fn insert(&self, trace_id: String, conn: QuicConn, metrics: &N3Metrics) {
+        let mut conns = self.0.lock().unwrap();
+
+        conns.retain(|(_, conn)| !conn.is_closed());
+        conns.push_back((trace_id, conn));
+
+        metrics.tunnel_agents.set(conns.len() as i64);
+    }
+
+    /// Open a new stream on the next agent connection, closed connections are removed.
+    fn open(&self, metrics: &N3Metrics) -> Option<(String, QuicStream)> {
+        let mut conns = self.0.lock().unwrap();
+
+        let mut opened = None;
+
+        for _ in 0..conns.len() {
+            let Some((trace_id, conn)) = conns.pop_front() else {
+                break;
+            };
+
+            if conn.is_closed() {
+                log::info!("tunnel agent is closed, id={}", trace_id);
+                continue;
+            }
+
+            match conn.try_open() {
+                Ok(stream) => {
+                    opened = Some((trace_id.clone(), stream));
+                    conns.push_back((trace_id, conn));
+                    break;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    log::warn!(
+                        "failed to open tunnel stream, id={}, err=WOULD_BLOCK",
+                        trace_id
+                    );
+                    conns.push_back((trace_id, conn));
+                }
+                Err(err) => {
+                    log::error!("failed to open tunnel stream, id={}, err={}", trace_id, err);
+                }
+            }
+        }
+
+        metrics.tunnel_agents.set(conns.len() as i64);
+
+        opened
+    }
+}
+
+/// Accept tcp connections on `listener` and forward them to `agents` until the shutdown.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    agents: Arc<Agents>,
+    metrics: Arc<N3Metrics>,
+    shutdown: QuicShutdown,
+) -> Result<()> {
+    let expose = listener.mio_socket().local_addr()?;
+
+    log::info!("tunnel is exposed, laddr={}", expose);
+
+    // cloned by each pipe, released when both directions are closed.
+    let inflight = Arc::new(());
+
+    loop {
+        let (inbound, from) = match select(pin!(listener.accept()), pin!(shutdown.wait())).await {
+            Either::Left((accepted, _)) => accepted?,
+            Either::Right(_) => break,
+        };
+
+        let Some((trace_id, outbound)) = agents.open(&metrics) else {
+            metrics.upstream_connect_errors.inc();
+            log::error!("no tunnel agent is available, from={}", from);
+            continue;
+        };
+
+        metrics.streams.inc();
+
+        pipe(
+            inbound,
+            from,
+            outbound,
+            trace_id,
+            metrics.clone(),
+            inflight.clone(),
+        )?;
+    }
+
+    drop(listener);
+
+    log::info!(
+        "tunnel stops accepting, laddr={}, inflight={}",
+        expose,
+        Arc::strong_count(&inflight) - 1
+    );
+
+    // the quic listener closes the agent connections when the grace period expires.
+    while Arc::strong_count(&inflight) > 1
+        && shutdown
+            .deadline()
+            .is_some_and(|deadline| deadline > Instant::now())
+    {
+        sleep(DRAIN_CHECK_INTERVAL).await;
+    }
+
+    Ok(())
+}
+
+fn pipe(
+    inbound: TcpStream,
+    from: SocketAddr,
+    outbound: QuicStream,
+    trace_id: String,
+    metrics: Arc<N3Metrics>,
+    inflight: Arc<()>,
+) -> Result<()> {
+    let stream_id = outbound.id();
+
+    log::info!(
+        "new tunnel pipe tcp({}) => quic({},{})",
+        from,
+        trace_id,
+        stream_id
+    );
+
+    // released when both directions are closed.
+    let active_stream = Arc::new((ActiveStream::new(metrics.clone()), inflight));
+    let active_stream_cloned = active_stream.clone();
+    let metrics_cloned = metrics.clone();
+
+    let (mut inbound_writer, inbound_reader) = inbound.split();
+    let (mut outbound_writer, outbound_reader) = outbound.split();
+
+    let trace_id_cloned = trace_id.clone();
+
+    spawn(async move {
+        let _active_stream = active_stream_cloned;
+
+        let id = format!("tcp({}) <- quic({},{})", from, trace_id_cloned, stream_id);
+
+        match copy(Some(&id), outbound_reader, &mut inbound_writer, 65535).await {
+            Ok(len) => {
+                metrics_cloned.backward_bytes.add(len as u64);
+
+                log::info!(
+                    "tunnel(backward) is closed, tcp({}) <== quic({},{}), trans_size={}",
+                    from,
+                    trace_id_cloned,
+                    stream_id,
+                    len
+                );
+            }
+            Err(err) => {
+                log::error!(
+                    "tunnel(backward) is broken, tcp({}) <== quic({},{}), err={}",
+                    from,
+                    trace_id_cloned,
+                    stream_id,
+                    err
+                );
+            }
+        }
+
+        if let Err(err) = inbound_writer.close().await {
+            log::trace!(
+                "tunnel(backward) close writer, tcp({}) ==> quic({},{}), err={}",
+                from,
+                trace_id_cloned,
+                stream_id,
+                err
+            );
+        }
+    })?;
+
+    spawn(async move {
+        let _active_stream = active_stream;
+
+        let id = format!("tcp({}) -> quic({},{})", from, trace_id, stream_id);
+
+        match copy(Some(&id), inbound_reader, &mut outbound_writer, 65535).await {
+            Ok(len) => {
+                metrics.forward_bytes.add(len as u64);
+
+                log::info!(
+                    "tunnel(forward) is closed, tcp({}) ==> quic({},{}), trans_size={}",
+                    from,
+                    trace_id,
+                    stream_id,
+                    len
+                );
+            }
+            Err(err) => {
+                log::error!(
+                    "tunnel(forward) is broken, tcp({}) ==> quic({},{}), err={}",
+                    from,
+                    trace_id,
+                    stream_id,
+                    err
+                );
+            }
+        }
+
+        if let Err(err) = outbound_writer.close().await {
+            log::trace!(
+                "tunnel(forward) close writer, tcp({}) <== quic({},{}), err={}",
+                from,
+                trace_id,
+                stream_id,
+                err
+            );
+        }
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use futures::AsyncReadExt;
+    use n3agent::{Agent, AgentMetrics};
+    use n3io::timeout::TimeoutExt;
+    use n3quic::{QuicServer, quiche};
+
+    use crate::N3;
+
+    use super::*;
+
+    /// Loads `{name}.crt` and `{name}.key` of the test certificates if `name` is set.
+    fn mock_config(name: Option<&str>) -> quiche::Config {
+        let path = |file: String| {
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("../quic/cert")
+                .join(file)
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+
+        config.set_initial_max_data(10_000_000);
+        config.set_initial_max_stream_data_bidi_local(1024 * 1024);
+        config.set_initial_max_stream_data_bidi_remote(1024 * 1024);
+        config.set_initial_max_streams_bidi(100);
+        config.set_max_idle_timeout(60000);
+        config.verify_peer(true);
+
+        if let Some(name) = name {
+            config
+                .load_cert_chain_from_pem_file(&path(format!("{}.crt", name)))
+                .unwrap();
+
+            config
+                .load_priv_key_from_pem_file(&path(format!("{}.key", name)))
+                .unwrap();
+        }
+
+        config
+            .load_verify_locations_from_file(&path("rasi_ca.pem".to_owned()))
+            .unwrap();
+
+        config.set_application_protos(&[b"n3"]).unwrap();
+
+        config
+    }
+
+    /// Start an echo server, the n3 tunnel and an agent of the echo server presenting the
+    /// `agent_cert`, returns the exposed address and the counters of n3 and the agent.
+    async fn mock_tunnel(
+        agent_cert: Option<&str>,
+    ) -> (SocketAddr, Arc<N3Metrics>, Arc<AgentMetrics>) {
+        let target = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let target_addr = target.mio_socket().local_addr().unwrap();
+
+        spawn(async move {
+            while let Ok((conn, _)) = target.accept().await {
+                _ = futures::io::copy(&conn, &mut &conn).await;
+            }
+        })
+        .unwrap();
+
+        let listener = QuicServer::with_quiche_config(mock_config(Some("server")))
+            .verify_peer(true)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+
+        let laddr = *listener.local_addrs().next().unwrap();
+
+        let exposed = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let expose = exposed.mio_socket().local_addr().unwrap();
+
+        let metrics = Arc::new(N3Metrics::default());
+
+        let shutdown = QuicShutdown::new();
+
+        spawn({
+            let metrics = metrics.clone();
+
+            async move {
+                N3::tunnel(listener, exposed, metrics, shutdown)
+                    .await
+                    .unwrap();
+            }
+        })
+        .unwrap();
+
+        let agent_config = mock_config(agent_cert);
+
+        let agent = Agent::new(laddr).connector(|connector| {
+            connector.quiche_config(|config| {
+                *config = agent_config;
+                Ok(())
+            })
+        });
+
+        let agent_metrics = agent.metrics();
+
+        spawn(async move {
+            agent.tunnel(target_addr).await.unwrap();
+        })
+        .unwrap();
+
+        (expose, metrics, agent_metrics)
+    }
+
+    /// Wait until `f` returns `true`, panics after 10 seconds.
+    async fn wait_until<F>(f: F)
+    where
+        F: Fn() -> bool,
+    {
+        // a rejected handshake fails after the 5 seconds connect timeout of the agent.
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while !f() {
+            assert!(Instant::now() < deadline, "timeout");
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[futures_test::test]
+    async fn test_tunnel() {
+        let (expose, metrics, _) = mock_tunnel(Some("client")).await;
+
+        wait_until(|| metrics.tunnel_agents.get() > 0).await;
+
+        for _ in 0..3 {
+            let mut conn = TcpStream::connect(expose).await.unwrap();
+
+            conn.write_all(b"hello world").await.unwrap();
+
+            let mut buf = vec![0; 11];
+            conn.read_exact(&mut buf)
+                .timeout(Duration::from_secs(5))
+                .await
+                .unwrap();
+
+            assert_eq!(buf, b"hello world");
+        }
+    }
+
+    #[futures_test::test]
+    async fn test_tunnel_anonymous_agent() {
+        let (expose, metrics, agent_metrics) = mock_tunnel(None).await;
+
+        // the server has answered the handshake of the agent.
+        wait_until(|| agent_metrics.quic_connect_errors.get() + agent_metrics.quic_conns.get() > 0)
+            .await;
+
+        assert_eq!(metrics.tunnel_agents.get(), 0);
+
+        let mut conn = TcpStream::connect(expose).await.unwrap();
+
+        _ = conn.write_all(b"hello world").await;
+
+        let mut buf = vec![];
+
+        // closed at once, no agent is accepted.
+        _ = conn
+            .read_to_end(&mut buf)
+            .timeout(Duration::from_secs(5))
+            .await;
+
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,376 @@
+//! Udp relay: the udp datagrams of the agents are carried in QUIC DATAGRAM frames, each flow is
+//! relayed to the upstream by its own udp socket.
+
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Result},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use n3_proto::FlowDatagram;
+use n3_spawner::spawn;
+use n3io::{net::UdpSocket, timeout::TimeoutExt as _};
+use n3quic::{QuicConn, QuicConnExt, QuicDgramSender};
+
+use crate::{N3Metrics, RouteKey, Upstream};
+
+/// A flow is closed after no datagram is relayed in either direction for this duration.
+pub(crate) const UDP_FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The maximum size of a udp datagram.
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// The maximum number of flows relayed for one connection, each flow holds an upstream socket.
+const MAX_UDP_FLOWS: usize = 256;
+
+/// A udp flow of an agent and its upstream socket.
+struct Flow {
+    socket: UdpSocket,
+    /// the upstream address.
+    raddr: SocketAddr,
+    /// the time of the last relayed datagram.
+    last_active: Mutex<Instant>,
+    /// set when the backward task exits.
+    closed: AtomicBool,
+}
+
+impl Flow {
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    fn is_idle(&self) -> bool {
+        self.last_active.lock().unwrap().elapsed() >= UDP_FLOW_IDLE_TIMEOUT
+    }
+
+    fn is_expired(&self) -> bool {
+        self.closed.load(Ordering::Acquire) || self.is_idle()
+    }
+}
+
+/// Relay the DATAGRAM frames of `conn` to `upstream` until `conn` is closed.
+///
+/// At most [`MAX_UDP_FLOWS`] flows are open at once, the datagrams of further flows are dropped.
+pub(crate) async fn relay(
+    conn: &QuicConn,
+    upstream: &Upstream,
+    key: &RouteKey,
+    metrics: &Arc<N3Metrics>,
+    trace_id: &str,
+) -> Result<()> {
+    let sender = conn.dgram_sender();
+
+    let mut flows: HashMap<u32, Arc<Flow>> = HashMap::new();
+
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+
+    loop {
+        let len = conn.dgram_recv(&mut buf).await?;
+
+        let datagram = match FlowDatagram::decode(&buf[..len]) {
+            Ok(datagram) => datagram,
+            Err(err) => {
+                metrics.dropped_datagrams.inc();
+                log::error!("drop datagram, id={}, err={}", trace_id, err);
+                continue;
+            }
+        };
+
+        let flow = match flows.get(&datagram.flow_id) {
+            Some(flow) if !flow.is_expired() => flow.clone(),
+            _ => {
+                flows.retain(|_, flow| !flow.is_expired());
+
+                if flows.len() >= MAX_UDP_FLOWS {
+                    metrics.dropped_datagrams.inc();
+
+                    log::warn!(
+                        "too many udp flows, id={}, flow_id={}, max={}",
+                        trace_id,
+                        datagram.flow_id,
+                        MAX_UDP_FLOWS
+                    );
+                    continue;
+                }
+
+                let flow =
+                    match open(datagram.flow_id, upstream, key, &sender, metrics, trace_id).await {
+                        Ok(flow) => flow,
+                        Err(err) => {
+                            metrics.dropped_datagrams.inc();
+
+                            log::error!(
+                                "failed to open udp flow, id={}, flow_id={}, err={}",
+                                trace_id,
+                                datagram.flow_id,
+                                err
+                            );
+                            continue;
+                        }
+                    };
+
+                flows.insert(datagram.flow_id, flow.clone());
+
+                flow
+            }
+        };
+
+        flow.touch();
+
+        match flow.socket.send_to(datagram.payload, flow.raddr).await {
+            Ok(len) => metrics.udp_forward_bytes.add(len as u64),
+            Err(err) => {
+                metrics.dropped_datagrams.inc();
+
+                log::error!(
+                    "failed to relay datagram, id={}, flow_id={}, raddr={}, err={}",
+                    trace_id,
+                    datagram.flow_id,
+                    flow.raddr,
+                    err
+                );
+            }
+        }
+    }
+}
+
+/// Create the upstream socket of flow `flow_id`, spawn the task relaying its responses.
+async fn open(
+    flow_id: u32,
+    upstream: &Upstream,
+    key: &RouteKey,
+    sender: &QuicDgramSender,
+    metrics: &Arc<N3Metrics>,
+    trace_id: &str,
+) -> Result<Arc<Flow>> {
+    let Some(upstream_conn) = upstream.pick(key).await else {
+        return Err(ErrorKind::NotFound.into());
+    };
+
+    let raddr = upstream_conn.addr();
+
+    let laddr = if raddr.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+
+    let socket = UdpSocket::bind(laddr).await?;
+
+    metrics.udp_flows.inc();
+
+    let flow = Arc::new(Flow {
+        socket,
+        raddr,
+        last_active: Mutex::new(Instant::now()),
+        closed: AtomicBool::new(false),
+    });
+
+    log::info!(
+        "new udp flow, id={}, flow_id={}, raddr={}",
+        trace_id,
+        flow_id,
+        raddr
+    );
+
+    let backward = flow.clone();
+    let sender = sender.clone();
+    let metrics = metrics.clone();
+    let trace_id = trace_id.to_owned();
+
+    spawn(async move {
+        // the backend stays counted as active until the flow is closed.
+        let _upstream_conn = upstream_conn;
+
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+
+        loop {
+            let (len, from) = match backward
+                .socket
+                .recv_from(&mut buf)
+                .timeout(UDP_FLOW_IDLE_TIMEOUT)
+                .await
+            {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::TimedOut => {
+                    if backward.is_idle() {
+                        break;
+                    }
+
+                    continue;
+                }
+                Err(err) => {
+                    log::error!(
+                        "udp flow is broken, id={}, flow_id={}, err={}",
+                        trace_id,
+                        flow_id,
+                        err
+                    );
+                    break;
+                }
+            };
+
+            // only the responses of the upstream are relayed.
+            if from != backward.raddr {
+                continue;
+            }
+
+            backward.touch();
+
+            match sender.send(&FlowDatagram::new(flow_id, &buf[..len]).encode()) {
+                Ok(()) => metrics.udp_backward_bytes.add(len as u64),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    metrics.dropped_datagrams.inc();
+                }
+                // e.g. a large EDNS answer, only this response is dropped.
+                Err(err) if err.kind() == ErrorKind::InvalidInput => {
+                    metrics.dropped_datagrams.inc();
+                    log::trace!(
+                        "drop datagram, id={}, flow_id={}, err={}",
+                        trace_id,
+                        flow_id,
+                        err
+                    );
+                }
+                Err(err) => {
+                    log::error!(
+                        "udp flow is broken, id={}, flow_id={}, err={}",
+                        trace_id,
+                        flow_id,
+                        err
+                    );
+                    break;
+                }
+            }
+        }
+
+        backward.closed.store(true, Ordering::Release);
+
+        metrics.udp_flows.dec();
+
+        log::info!("udp flow is closed, id={}, flow_id={}", trace_id, flow_id);
+    })?;
+
+    Ok(flow)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use n3quic::{QuicConnector, QuicServer, quiche};
+
+    use super::*;
+
+    fn mock_config(is_server: bool) -> quiche::Config {
+        let path = |file: &str| {
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("../quic/cert")
+                .join(file)
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+
+        config.set_initial_max_data(10_000_000);
+        config.set_initial_max_streams_bidi(100);
+        config.set_max_idle_timeout(60000);
+        config.enable_dgram(true, 10, 10);
+
+        if is_server {
+            config
+                .load_cert_chain_from_pem_file(&path("server.crt"))
+                .unwrap();
+
+            config
+                .load_priv_key_from_pem_file(&path("server.key"))
+                .unwrap();
+        } else {
+            config
+                .load_verify_locations_from_file(&path("rasi_ca.pem"))
+                .unwrap();
+        }
+
+        config.set_application_protos(&[b"n3"]).unwrap();
+
+        config
+    }
+
+    #[futures_test::test]
+    async fn test_drop_oversized_datagram() {
+        // answers each request with a datagram larger than a DATAGRAM frame, then a small one.
+        let target = UdpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let target_addr = target.mio_socket().local_addr().unwrap();
+
+        spawn(async move {
+            let mut buf = vec![0; MAX_DATAGRAM_LEN];
+
+            while let Ok((_, from)) = target.recv_from(&mut buf).await {
+                _ = target.send_to(&[0; 4096], from).await;
+                _ = target.send_to(b"pong", from).await;
+            }
+        })
+        .unwrap();
+
+        let mut listener = QuicServer::with_quiche_config(mock_config(true))
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+
+        let raddrs = listener.local_addrs().copied().collect::<Vec<_>>();
+
+        let outbound = QuicConnector::new_with_config(raddrs.as_slice(), mock_config(false))
+            .connect()
+            .await
+            .unwrap();
+
+        let inbound = listener.accept().await.unwrap();
+
+        let metrics = Arc::new(N3Metrics::default());
+
+        spawn({
+            let metrics = metrics.clone();
+
+            async move {
+                let key = RouteKey::from_conn(&inbound);
+                let upstream = Upstream::new([target_addr]);
+
+                _ = relay(&inbound, &upstream, &key, &metrics, "test").await;
+            }
+        })
+        .unwrap();
+
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+
+        for dropped in 1..=2 {
+            outbound
+                .dgram_sender()
+                .send(&FlowDatagram::new(7, b"ping").encode())
+                .unwrap();
+
+            let len = outbound
+                .dgram_recv(&mut buf)
+                .timeout(Duration::from_secs(5))
+                .await
+                .unwrap();
+
+            let datagram = FlowDatagram::decode(&buf[..len]).unwrap();
+
+            assert_eq!(datagram.flow_id, 7);
+            assert_eq!(datagram.payload, b"pong");
+
+            assert_eq!(metrics.dropped_datagrams.get(), dropped);
+        }
+
+        assert_eq!(metrics.udp_flows.get(), 1);
+    }
+}
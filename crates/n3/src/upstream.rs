@@ -0,0 +1,672 @@
+//! Upstream pools with load balancing and health checks.
+
+use std::{
+    fmt::Display,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use n3_spawner::spawn;
+use n3io::{
+    dns::global_resolver,
+    net::TcpStream,
+    timeout::{TimeoutExt, sleep},
+};
+use serde::Deserialize;
+
+use crate::{RouteKey, config::UpstreamRepr};
+
+/// The number of virtual nodes per backend on the consistent hash ring.
+const VIRTUAL_NODES: usize = 160;
+
+/// Backend selection policy of an [`Upstream`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// Select backends in turn.
+    #[default]
+    RoundRobin,
+    /// Select the backend with the fewest active connections.
+    LeastConn,
+    /// Consistent hash by the client address.
+    HashClientAddr,
+    /// Consistent hash by the quic connection id.
+    HashConnId,
+}
+
+impl FromStr for Policy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "round_robin" => Ok(Self::RoundRobin),
+            "least_conn" => Ok(Self::LeastConn),
+            "hash_client_addr" => Ok(Self::HashClientAddr),
+            "hash_conn_id" => Ok(Self::HashConnId),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "unknown policy `{}`, expect `round_robin`, `least_conn`, `hash_client_addr` or `hash_conn_id`",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+/// The address of a backend, a host name is resolved again when the TTL of its records expires.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Target {
+    /// A socket address.
+    Addr(SocketAddr),
+    /// A `(host name, port)` pair.
+    Host(String, u16),
+}
+
+impl Target {
+    /// Returns the port of this target.
+    pub fn port(&self) -> u16 {
+        match self {
+            Target::Addr(addr) => addr.port(),
+            Target::Host(_, port) => *port,
+        }
+    }
+
+    /// Returns the addresses of this target, the host name is resolved by the global resolver.
+    pub async fn resolve(&self) -> Result<Vec<SocketAddr>> {
+        match self {
+            Target::Addr(addr) => Ok(vec![*addr]),
+            Target::Host(host, port) => global_resolver().resolve(host, *port).await,
+        }
+    }
+}
+
+impl From<SocketAddr> for Target {
+    fn from(addr: SocketAddr) -> Self {
+        Target::Addr(addr)
+    }
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Target::Addr(addr));
+        }
+
+        let invalid = |reason: &str| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid target `{}`, {}", s, reason),
+            )
+        };
+
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| invalid("expect `HOST:PORT`"))?;
+
+        let port = port.parse::<u16>().map_err(|_| invalid("invalid port"))?;
+
+        if host.is_empty() || host.contains(':') || host.parse::<IpAddr>().is_ok() {
+            return Err(invalid("invalid host"));
+        }
+
+        Ok(Target::Host(host.to_owned(), port))
+    }
+}
+
+impl TryFrom<String> for Target {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Addr(addr) => write!(f, "{}", addr),
+            Target::Host(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Backend {
+    target: Target,
+    /// The number of active connections.
+    active: AtomicUsize,
+    /// Consecutive connect failures.
+    fails: AtomicU32,
+    /// Passive ejection deadline.
+    ejected_until: Mutex<Option<Instant>>,
+    /// Result of the last active health check.
+    healthy: AtomicBool,
+}
+
+impl Backend {
+    fn available(&self, now: Instant) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+            && self
+                .ejected_until
+                .lock()
+                .unwrap()
+                .is_none_or(|deadline| now >= deadline)
+    }
+}
+
+/// A pool of backends, cloned instances share the same connection counters and health states.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "UpstreamRepr")]
+pub struct Upstream {
+    backends: Arc<[Backend]>,
+    /// Consistent hash ring, sorted `(hash, backend index)` pairs.
+    ring: Arc<[(u64, usize)]>,
+    /// Round robin cursor.
+    next: Arc<AtomicUsize>,
+    policy: Policy,
+    /// Eject a backend after `max_fails` consecutive connect failures, `0` disables ejection.
+    max_fails: u32,
+    /// How long an ejected backend is out of rotation.
+    fail_timeout: Duration,
+    /// Timeout of connecting to a backend.
+    connect_timeout: Duration,
+    /// Active health check `(interval, timeout)`.
+    health_check: Option<(Duration, Duration)>,
+    health_check_started: Arc<AtomicBool>,
+}
+
+impl PartialEq for Upstream {
+    fn eq(&self, other: &Self) -> bool {
+        self.targets().eq(other.targets())
+            && self.policy == other.policy
+            && self.max_fails == other.max_fails
+            && self.fail_timeout == other.fail_timeout
+            && self.connect_timeout == other.connect_timeout
+            && self.health_check == other.health_check
+    }
+}
+
+impl Eq for Upstream {}
+
+impl Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, target) in self.targets().enumerate() {
+            if index > 0 {
+                write!(f, ",")?;
+            }
+
+            write!(f, "{}", target)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn hash<T: Hash>(value: T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Upstream {
+    /// Create a round robin pool of `targets`.
+    pub fn new<I, T>(targets: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Target>,
+    {
+        let backends = targets
+            .into_iter()
+            .map(|target| Backend {
+                target: target.into(),
+                active: AtomicUsize::new(0),
+                fails: AtomicU32::new(0),
+                ejected_until: Mutex::new(None),
+                healthy: AtomicBool::new(true),
+            })
+            .collect::<Arc<[Backend]>>();
+
+        let mut ring = backends
+            .iter()
+            .enumerate()
+            .flat_map(|(index, backend)| {
+                (0..VIRTUAL_NODES).map(move |node| (hash((&backend.target, node)), index))
+            })
+            .collect::<Vec<_>>();
+
+        ring.sort_unstable();
+
+        Self {
+            backends,
+            ring: ring.into(),
+            next: Default::default(),
+            policy: Policy::default(),
+            max_fails: 3,
+            fail_timeout: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(5),
+            health_check: None,
+            health_check_started: Default::default(),
+        }
+    }
+
+    /// Set the backend selection policy, the default is [`Policy::RoundRobin`].
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Eject a backend for `fail_timeout` after `max_fails` consecutive connect failures.
+    ///
+    /// The default is `3` failures and `10s`, set `max_fails` to `0` to disable passive ejection.
+    pub fn max_fails(mut self, max_fails: u32, fail_timeout: Duration) -> Self {
+        self.max_fails = max_fails;
+        self.fail_timeout = fail_timeout;
+        self
+    }
+
+    /// Set the timeout of connecting to a backend, the default is `5s`.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Enable active tcp health checks, every `interval` each backend is probed by a tcp connect.
+    pub fn health_check(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.health_check = Some((interval, timeout));
+        self
+    }
+
+    /// Returns the backend targets.
+    pub fn targets(&self) -> impl Iterator<Item = &Target> + '_ {
+        self.backends.iter().map(|backend| &backend.target)
+    }
+
+    /// Spawn the active health check task, if it is enabled and not running.
+    ///
+    /// The task stops when all the clones of this upstream are dropped.
+    pub fn start_health_check(&self) -> Result<()> {
+        let Some((interval, timeout)) = self.health_check else {
+            return Ok(());
+        };
+
+        if self.health_check_started.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        let backends = Arc::downgrade(&self.backends);
+
+        spawn(async move {
+            loop {
+                sleep(interval).await;
+
+                let Some(backends) = backends.upgrade() else {
+                    return;
+                };
+
+                for backend in backends.iter() {
+                    let healthy = async {
+                        let raddrs = backend.target.resolve().await?;
+
+                        let raddr = raddrs.first().ok_or(ErrorKind::NotFound)?;
+
+                        TcpStream::connect(*raddr).await
+                    }
+                    .timeout(timeout)
+                    .await
+                    .is_ok();
+
+                    if backend.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                        if healthy {
+                            log::info!("upstream is healthy, target={}", backend.target);
+                        } else {
+                            log::warn!("upstream is unhealthy, target={}", backend.target);
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Select a backend for `key`, skip the backends in `tried`.
+    ///
+    /// When none of the backends is available, all of them are candidates.
+    fn select(&self, key: &RouteKey, tried: &[usize]) -> Option<usize> {
+        let now = Instant::now();
+
+        let untried = |index: &usize| !tried.contains(index);
+
+        let mut candidates = (0..self.backends.len())
+            .filter(untried)
+            .filter(|index| self.backends[*index].available(now))
+            .collect::<Vec<_>>();
+
+        if candidates.is_empty() {
+            candidates = (0..self.backends.len()).filter(untried).collect();
+        }
+
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let len = self.backends.len();
+
+        match self.policy {
+            Policy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+
+                candidates
+                    .into_iter()
+                    .min_by_key(|index| (index + len - start) % len)
+            }
+            Policy::LeastConn => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+
+                candidates.into_iter().min_by_key(|index| {
+                    (
+                        self.backends[*index].active.load(Ordering::Relaxed),
+                        (index + len - start) % len,
+                    )
+                })
+            }
+            Policy::HashClientAddr | Policy::HashConnId => {
+                let value = if self.policy == Policy::HashClientAddr {
+                    hash(key.raddr.map(|raddr| raddr.ip()))
+                } else {
+                    hash(&key.conn_id)
+                };
+
+                let start = self.ring.partition_point(|(node, _)| *node < value);
+
+                self.ring[start..]
+                    .iter()
+                    .chain(&self.ring[..start])
+                    .map(|(_, index)| *index)
+                    .find(|index| candidates.contains(index))
+            }
+        }
+    }
+
+    fn record_failure(&self, index: usize) {
+        if self.max_fails == 0 {
+            return;
+        }
+
+        let backend = &self.backends[index];
+
+        if backend.fails.fetch_add(1, Ordering::Relaxed) + 1 >= self.max_fails {
+            backend.fails.store(0, Ordering::Relaxed);
+
+            *backend.ejected_until.lock().unwrap() = Some(Instant::now() + self.fail_timeout);
+
+            log::warn!(
+                "upstream is ejected, target={}, fail_timeout={:?}",
+                backend.target,
+                self.fail_timeout
+            );
+        }
+    }
+
+    /// Select a backend for a udp flow, which is counted as an active connection.
+    ///
+    /// The backends whose host name can't be resolved are skipped.
+    pub async fn pick(&self, key: &RouteKey) -> Option<UpstreamConn> {
+        let mut tried = vec![];
+
+        while let Some(index) = self.select(key, &tried) {
+            tried.push(index);
+
+            let backend = &self.backends[index];
+
+            let addr = match backend.target.resolve().await {
+                Ok(raddrs) if !raddrs.is_empty() => raddrs[0],
+                Ok(_) => continue,
+                Err(err) => {
+                    log::error!(
+                        "failed to resolve upstream, target={}, conn_id={}, err={}",
+                        backend.target,
+                        key.conn_id,
+                        err
+                    );
+
+                    self.record_failure(index);
+                    continue;
+                }
+            };
+
+            backend.active.fetch_add(1, Ordering::Relaxed);
+
+            return Some(UpstreamConn {
+                backends: self.backends.clone(),
+                index,
+                addr,
+            });
+        }
+
+        None
+    }
+
+    /// Connect to a backend selected by the policy.
+    ///
+    /// On failure, the other backends are tried in turn.
+    pub async fn connect(&self, key: &RouteKey) -> Result<(TcpStream, UpstreamConn)> {
+        let mut tried = vec![];
+        let mut last_err = None;
+
+        while let Some(index) = self.select(key, &tried) {
+            tried.push(index);
+
+            let backend = &self.backends[index];
+
+            match self.connect_backend(backend).await {
+                Ok((stream, addr)) => {
+                    backend.fails.store(0, Ordering::Relaxed);
+                    backend.active.fetch_add(1, Ordering::Relaxed);
+
+                    return Ok((
+                        stream,
+                        UpstreamConn {
+                            backends: self.backends.clone(),
+                            index,
+                            addr,
+                        },
+                    ));
+                }
+                Err(err) => {
+                    log::error!(
+                        "failed to connect upstream, target={}, conn_id={}, err={}",
+                        backend.target,
+                        key.conn_id,
+                        err
+                    );
+
+                    self.record_failure(index);
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| Error::new(ErrorKind::NotFound, "upstream has no backend")))
+    }
+
+    /// Resolve `backend` and connect to its addresses in order.
+    async fn connect_backend(&self, backend: &Backend) -> Result<(TcpStream, SocketAddr)> {
+        let mut last_err = Error::new(
+            ErrorKind::NotFound,
+            format!("`{}` has no address", backend.target),
+        );
+
+        for addr in backend.target.resolve().await? {
+            match TcpStream::connect(addr).timeout(self.connect_timeout).await {
+                Ok(stream) => return Ok((stream, addr)),
+                Err(err) => last_err = err,
+            }
+        }
+
+        Err(last_err)
+    }
+}
+
+/// An active connection to a backend, released when dropped.
+#[derive(Debug)]
+pub struct UpstreamConn {
+    backends: Arc<[Backend]>,
+    index: usize,
+    /// The resolved address of the backend.
+    addr: SocketAddr,
+}
+
+impl UpstreamConn {
+    /// Returns the backend address.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for UpstreamConn {
+    fn drop(&mut self) {
+        self.backends[self.index]
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(policy: Policy) -> Upstream {
+        Upstream::new((1..=4).map(|port| SocketAddr::from(([127, 0, 0, 1], port)))).policy(policy)
+    }
+
+    fn key(raddr: &str, conn_id: &str) -> RouteKey {
+        RouteKey {
+            raddr: Some(raddr.parse().unwrap()),
+            conn_id: conn_id.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            "127.0.0.1:80".parse::<Target>().unwrap(),
+            Target::Addr("127.0.0.1:80".parse().unwrap())
+        );
+
+        assert_eq!(
+            "[::1]:80".parse::<Target>().unwrap(),
+            Target::Addr("[::1]:80".parse().unwrap())
+        );
+
+        assert_eq!(
+            "web.internal:80".parse::<Target>().unwrap(),
+            Target::Host("web.internal".to_owned(), 80)
+        );
+
+        assert_eq!(
+            "web.internal:80".parse::<Target>().unwrap().to_string(),
+            "web.internal:80"
+        );
+
+        for invalid in [
+            "web.internal",
+            ":80",
+            "::1:80",
+            "10.0.0.1:http",
+            "127.0.0.1:70000",
+        ] {
+            assert!(invalid.parse::<Target>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let upstream = upstream(Policy::RoundRobin);
+
+        let selected = (0..8)
+            .map(|_| upstream.select(&RouteKey::default(), &[]).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(selected, vec![0, 1, 2, 3, 0, 1, 2, 3]);
+
+        assert_eq!(upstream.select(&RouteKey::default(), &[0, 1, 2]), Some(3));
+        assert_eq!(upstream.select(&RouteKey::default(), &[0, 1, 2, 3]), None);
+    }
+
+    #[test]
+    fn test_least_conn() {
+        let upstream = upstream(Policy::LeastConn);
+
+        upstream.backends[0].active.store(2, Ordering::Relaxed);
+        upstream.backends[1].active.store(1, Ordering::Relaxed);
+        upstream.backends[2].active.store(1, Ordering::Relaxed);
+        upstream.backends[3].active.store(3, Ordering::Relaxed);
+
+        for _ in 0..4 {
+            let index = upstream.select(&RouteKey::default(), &[]).unwrap();
+            assert!(index == 1 || index == 2);
+        }
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let upstream = upstream(Policy::HashClientAddr);
+
+        let index = upstream.select(&key("10.0.0.1:1000", "a"), &[]).unwrap();
+
+        // the client port and the conn id are ignored.
+        assert_eq!(
+            upstream.select(&key("10.0.0.1:2000", "b"), &[]),
+            Some(index)
+        );
+
+        // only the keys of the ejected backend are remapped.
+        *upstream.backends[index].ejected_until.lock().unwrap() =
+            Some(Instant::now() + Duration::from_secs(10));
+
+        let remapped = upstream.select(&key("10.0.0.1:1000", "a"), &[]).unwrap();
+        assert_ne!(remapped, index);
+
+        let upstream = upstream.policy(Policy::HashConnId);
+
+        let index = upstream.select(&key("10.0.0.1:1000", "a"), &[]).unwrap();
+
+        assert_eq!(
+            upstream.select(&key("10.0.0.2:1000", "a"), &[]),
+            Some(index)
+        );
+    }
+
+    #[test]
+    fn test_passive_ejection() {
+        let upstream = upstream(Policy::RoundRobin).max_fails(2, Duration::from_secs(10));
+
+        upstream.record_failure(0);
+        assert!(upstream.backends[0].available(Instant::now()));
+
+        upstream.record_failure(0);
+        assert!(!upstream.backends[0].available(Instant::now()));
+
+        for _ in 0..8 {
+            assert_ne!(upstream.select(&RouteKey::default(), &[]), Some(0));
+        }
+
+        // all the backends are down, fail open.
+        for backend in upstream.backends.iter() {
+            backend.healthy.store(false, Ordering::Relaxed);
+        }
+
+        assert!(upstream.select(&RouteKey::default(), &[]).is_some());
+    }
+}
@@ -0,0 +1,787 @@
+//! An asynchronous DNS stub resolver over udp, with a TTL based cache.
+//!
+//! Only `A` and `AAAA` lookups are supported, truncated responses are used as is.
+
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{net::UdpSocket, reactor::Reactor, timeout::TimeoutExt};
+
+/// Record type `A`.
+const TYPE_A: u16 = 1;
+/// Record type `AAAA`.
+const TYPE_AAAA: u16 = 28;
+/// Class `IN`.
+const CLASS_IN: u16 = 1;
+
+/// The maximum size of a udp DNS message without EDNS.
+const MAX_MESSAGE_LEN: usize = 512;
+
+/// The validity of the addresses that are not resolved by DNS, e.g. ip literals and hosts file.
+const STATIC_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The result of [`Resolver::lookup`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lookup {
+    /// Resolved addresses, IPv4 first.
+    pub addrs: Vec<IpAddr>,
+    /// Resolve again after this instant, the minimum TTL of the records.
+    pub valid_until: Instant,
+}
+
+struct ResolverImpl {
+    /// name servers, queried in order.
+    nameservers: Vec<SocketAddr>,
+    /// domains appended to the relative names.
+    search: Vec<String>,
+    /// names with fewer dots are tried with the `search` domains first.
+    ndots: usize,
+    /// static addresses from the hosts file.
+    hosts: HashMap<String, Vec<IpAddr>>,
+    /// timeout of a single query.
+    timeout: Duration,
+    /// query attempts per name server.
+    attempts: usize,
+    /// maximum TTL of the cached records.
+    max_ttl: Duration,
+    /// cached lookups, keyed by lowercase host name.
+    cache: Mutex<HashMap<String, Lookup>>,
+    /// seed of the random query ids.
+    random: RandomState,
+    reactor: Reactor,
+}
+
+/// An asynchronous DNS resolver based on the [`Reactor`], cloned instances share the cache.
+#[derive(Clone)]
+pub struct Resolver(Arc<ResolverImpl>);
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resolver")
+            .field("nameservers", &self.0.nameservers)
+            .field("timeout", &self.0.timeout)
+            .finish()
+    }
+}
+
+impl Resolver {
+    /// Create a resolver that queries `nameservers` in order.
+    pub fn new(nameservers: Vec<SocketAddr>, reactor: Reactor) -> Self {
+        Self::with_hosts(nameservers, Default::default(), reactor)
+    }
+
+    fn with_hosts(
+        nameservers: Vec<SocketAddr>,
+        hosts: HashMap<String, Vec<IpAddr>>,
+        reactor: Reactor,
+    ) -> Self {
+        Self(Arc::new(ResolverImpl {
+            nameservers,
+            search: vec![],
+            ndots: 1,
+            hosts,
+            timeout: Duration::from_secs(2),
+            attempts: 2,
+            max_ttl: Duration::from_secs(60 * 60),
+            cache: Default::default(),
+            random: RandomState::new(),
+            reactor,
+        }))
+    }
+
+    /// Create a resolver from `/etc/resolv.conf` and `/etc/hosts`.
+    ///
+    /// The `search`, `domain` and `ndots` options are applied as the system resolver does.
+    pub fn with_system_conf(reactor: Reactor) -> Result<Self> {
+        let conf = parse_resolv_conf(&std::fs::read_to_string("/etc/resolv.conf")?);
+
+        if conf.nameservers.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "no nameserver in /etc/resolv.conf",
+            ));
+        }
+
+        let hosts = read_hosts("/etc/hosts");
+
+        Ok(Self::with_hosts(conf.nameservers, hosts, reactor).search(conf.search, conf.ndots))
+    }
+
+    /// Set the timeout of a single query, the default is `2s`.
+    ///
+    /// Must be called before the resolver is cloned.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        if let Some(inner) = Arc::get_mut(&mut self.0) {
+            inner.timeout = timeout;
+        }
+
+        self
+    }
+
+    /// Set the domains appended to the relative names, the `search` option of `resolv.conf`.
+    ///
+    /// Names with fewer than `ndots` dots are tried with the `search` domains first, the others
+    /// as is first, names ending with `.` are never searched. Must be called before the resolver
+    /// is cloned.
+    pub fn search(mut self, search: Vec<String>, ndots: usize) -> Self {
+        if let Some(inner) = Arc::get_mut(&mut self.0) {
+            inner.search = search
+                .into_iter()
+                .map(|domain| domain.trim_matches('.').to_ascii_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect();
+            inner.ndots = ndots;
+        }
+
+        self
+    }
+
+    /// Returns the addresses of `host`, from the cache if the TTL has not expired.
+    pub async fn lookup(&self, host: &str) -> Result<Lookup> {
+        let key = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase();
+
+        let host = key.trim_end_matches('.');
+
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Lookup {
+                addrs: vec![ip],
+                valid_until: Instant::now() + STATIC_TTL,
+            });
+        }
+
+        if let Some(addrs) = self.0.hosts.get(host) {
+            return Ok(Lookup {
+                addrs: addrs.clone(),
+                valid_until: Instant::now() + STATIC_TTL,
+            });
+        }
+
+        if let Some(lookup) = self.0.cache.lock().unwrap().get(&key)
+            && lookup.valid_until > Instant::now()
+        {
+            return Ok(lookup.clone());
+        }
+
+        let mut last_err = None;
+
+        for name in self.search_names(host, key.ends_with('.')) {
+            match self.lookup_name(&name).await {
+                Ok(lookup) => {
+                    self.0.cache.lock().unwrap().insert(key, lookup.clone());
+
+                    return Ok(lookup);
+                }
+                // the name does not exist, try the next domain.
+                Err(err) if err.kind() == ErrorKind::NotFound => last_err = Some(err),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(last_err.expect("at least one name"))
+    }
+
+    /// Returns the names to query for `host` in order, see [`search`](Self::search).
+    fn search_names(&self, host: &str, absolute: bool) -> Vec<String> {
+        if absolute || self.0.search.is_empty() {
+            return vec![host.to_owned()];
+        }
+
+        let searched = self
+            .0
+            .search
+            .iter()
+            .map(|domain| format!("{}.{}", host, domain));
+
+        if host.matches('.').count() >= self.0.ndots {
+            std::iter::once(host.to_owned()).chain(searched).collect()
+        } else {
+            searched.chain(std::iter::once(host.to_owned())).collect()
+        }
+    }
+
+    /// Query the `A` and `AAAA` records of the fully qualified `host`.
+    async fn lookup_name(&self, host: &str) -> Result<Lookup> {
+        let (ipv4, ipv6) = futures::join!(self.query(host, TYPE_A), self.query(host, TYPE_AAAA));
+
+        let mut addrs = vec![];
+        let mut ttl = self.0.max_ttl;
+
+        let mut last_err = None;
+
+        for result in [ipv4, ipv6] {
+            match result {
+                Ok(records) => {
+                    for (addr, record_ttl) in records {
+                        addrs.push(addr);
+                        ttl = ttl.min(Duration::from_secs(record_ttl as u64));
+                    }
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        if addrs.is_empty() {
+            return Err(last_err.unwrap_or_else(|| {
+                Error::new(ErrorKind::NotFound, format!("`{}` has no address", host))
+            }));
+        }
+
+        let lookup = Lookup {
+            addrs,
+            valid_until: Instant::now() + ttl,
+        };
+
+        log::trace!(
+            "Resolver: resolved, host={}, addrs={:?}, ttl={:?}",
+            host,
+            lookup.addrs,
+            ttl
+        );
+
+        Ok(lookup)
+    }
+
+    /// Returns the socket addresses of `host` and `port`, see [`lookup`](Self::lookup).
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let lookup = self.lookup(host).await?;
+
+        Ok(lookup
+            .addrs
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    /// Query the records of `qtype`, try the name servers in order.
+    async fn query(&self, host: &str, qtype: u16) -> Result<Vec<(IpAddr, u32)>> {
+        let mut last_err = Error::new(ErrorKind::NotFound, "no nameserver");
+
+        for nameserver in &self.0.nameservers {
+            for _ in 0..self.0.attempts {
+                match self
+                    .query_prv(*nameserver, host, qtype)
+                    .timeout_with(self.0.timeout, self.0.reactor.clone())
+                    .await
+                {
+                    Ok(records) => return Ok(records),
+                    // the name server answers, the others have the same records.
+                    Err(err) if err.kind() == ErrorKind::NotFound => return Err(err),
+                    Err(err) => {
+                        log::warn!(
+                            "Resolver: query failed, nameserver={}, host={}, type={}, err={}",
+                            nameserver,
+                            host,
+                            qtype,
+                            err
+                        );
+
+                        last_err = err;
+                    }
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    async fn query_prv(
+        &self,
+        nameserver: SocketAddr,
+        host: &str,
+        qtype: u16,
+    ) -> Result<Vec<(IpAddr, u32)>> {
+        let id = self.0.random.hash_one((host, qtype, Instant::now())) as u16;
+
+        let query = encode_query(id, host, qtype)?;
+
+        let laddr = if nameserver.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+
+        let socket = UdpSocket::bind_with(laddr, self.0.reactor.clone()).await?;
+
+        let result = async {
+            socket.send_to(&query, nameserver).await?;
+
+            let mut buf = vec![0; MAX_MESSAGE_LEN];
+
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await?;
+
+                // ignore the spoofed or stale responses.
+                if from != nameserver || len < 2 || u16::from_be_bytes([buf[0], buf[1]]) != id {
+                    continue;
+                }
+
+                return decode_response(id, qtype, &buf[..len]);
+            }
+        }
+        .await;
+
+        _ = socket.shutdown();
+
+        result
+    }
+}
+
+/// Encode a recursive query of `host`.
+fn encode_query(id: u16, host: &str, qtype: u16) -> Result<Vec<u8>> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid host `{}`", host));
+
+    if host.is_empty() || host.len() > 253 {
+        return Err(invalid());
+    }
+
+    let mut buf = Vec::with_capacity(18 + host.len());
+
+    buf.extend_from_slice(&id.to_be_bytes());
+    // flags: RD
+    buf.extend_from_slice(&0x0100u16.to_be_bytes());
+    // QDCOUNT, ANCOUNT, NSCOUNT, ARCOUNT
+    buf.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in host.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(invalid());
+        }
+
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+
+    buf.push(0);
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(buf)
+}
+
+/// Decode the `qtype` records of the answer section, returns `(address, ttl)` pairs.
+fn decode_response(id: u16, qtype: u16, buf: &[u8]) -> Result<Vec<(IpAddr, u32)>> {
+    let malformed = || Error::new(ErrorKind::InvalidData, "malformed DNS response");
+
+    let u16_at = |pos: usize| -> Result<u16> {
+        buf.get(pos..pos + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .ok_or_else(malformed)
+    };
+
+    if u16_at(0)? != id {
+        return Err(malformed());
+    }
+
+    let flags = u16_at(2)?;
+
+    // QR
+    if flags & 0x8000 == 0 {
+        return Err(malformed());
+    }
+
+    match flags & 0x000f {
+        0 => {}
+        3 => return Err(Error::new(ErrorKind::NotFound, "NXDOMAIN")),
+        rcode => {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("DNS server failure, rcode={}", rcode),
+            ));
+        }
+    }
+
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut pos = 12;
+
+    for _ in 0..questions {
+        pos = skip_name(buf, pos)? + 4;
+    }
+
+    let mut records = vec![];
+
+    for _ in 0..answers {
+        pos = skip_name(buf, pos)?;
+
+        let ty = u16_at(pos)?;
+        let class = u16_at(pos + 2)?;
+        let ttl = buf
+            .get(pos + 4..pos + 8)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .ok_or_else(malformed)?;
+        let len = u16_at(pos + 8)? as usize;
+
+        pos += 10;
+
+        let data = buf.get(pos..pos + len).ok_or_else(malformed)?;
+
+        pos += len;
+
+        // CNAME records are skipped, the recursive server appends the records of the target.
+        if ty != qtype || class != CLASS_IN {
+            continue;
+        }
+
+        let addr = match (ty, len) {
+            (TYPE_A, 4) => IpAddr::from(<[u8; 4]>::try_from(data).unwrap()),
+            (TYPE_AAAA, 16) => IpAddr::from(<[u8; 16]>::try_from(data).unwrap()),
+            _ => return Err(malformed()),
+        };
+
+        records.push((addr, ttl));
+    }
+
+    Ok(records)
+}
+
+/// Returns the position after the name at `pos`.
+fn skip_name(buf: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        let len = *buf
+            .get(pos)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "malformed DNS name"))?
+            as usize;
+
+        match len {
+            0 => return Ok(pos + 1),
+            // compression pointer.
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + len,
+        }
+    }
+}
+
+/// The options of `resolv.conf` used by the [`Resolver`].
+#[derive(Debug, PartialEq, Eq)]
+struct ResolvConf {
+    nameservers: Vec<SocketAddr>,
+    search: Vec<String>,
+    ndots: usize,
+}
+
+/// Parse the `nameserver`, `search`, `domain` and `options ndots:n` lines of `resolv.conf`.
+fn parse_resolv_conf(content: &str) -> ResolvConf {
+    let mut conf = ResolvConf {
+        nameservers: vec![],
+        search: vec![],
+        ndots: 1,
+    };
+
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+
+        match fields.next() {
+            Some("nameserver") => {
+                // drop the IPv6 zone id.
+                if let Some(ip) = fields
+                    .next()
+                    .and_then(|ip| ip.split('%').next())
+                    .and_then(|ip| ip.parse::<IpAddr>().ok())
+                {
+                    conf.nameservers.push(SocketAddr::new(ip, 53));
+                }
+            }
+            // the last `search` or `domain` line wins.
+            Some("search") | Some("domain") => {
+                conf.search = fields.map(str::to_owned).collect();
+            }
+            Some("options") => {
+                for option in fields {
+                    if let Some(ndots) = option
+                        .strip_prefix("ndots:")
+                        .and_then(|ndots| ndots.parse::<usize>().ok())
+                    {
+                        conf.ndots = ndots.min(15);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    conf
+}
+
+/// Returns the addresses of the hosts file, an unreadable file is treated as empty.
+fn read_hosts<P: AsRef<Path>>(path: P) -> HashMap<String, Vec<IpAddr>> {
+    let mut hosts = HashMap::<String, Vec<IpAddr>>::new();
+
+    let Ok(content) = std::fs::read_to_string(path) else {
+        return hosts;
+    };
+
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+
+        let mut fields = line.split_whitespace();
+
+        let Some(ip) = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok()) else {
+            continue;
+        };
+
+        for name in fields {
+            let addrs = hosts.entry(name.to_ascii_lowercase()).or_default();
+
+            if !addrs.contains(&ip) {
+                addrs.push(ip);
+            }
+        }
+    }
+
+    // IPv4 first, as the DNS lookups.
+    for addrs in hosts.values_mut() {
+        addrs.sort_by_key(IpAddr::is_ipv6);
+    }
+
+    hosts
+}
+
+#[cfg(feature = "global_reactor")]
+mod global {
+    use std::sync::OnceLock;
+
+    use super::*;
+    use crate::reactor::global_reactor;
+
+    static RESOLVER: OnceLock<Resolver> = OnceLock::new();
+
+    /// Fetch the global resolver instance, created from the system configuration.
+    ///
+    /// Falls back to the name server `127.0.0.1:53` if `/etc/resolv.conf` is unusable.
+    pub fn global_resolver() -> &'static Resolver {
+        RESOLVER.get_or_init(|| {
+            Resolver::with_system_conf(global_reactor().clone()).unwrap_or_else(|err| {
+                log::warn!(
+                    "Resolver: fallback to 127.0.0.1:53, unable to load /etc/resolv.conf, err={}",
+                    err
+                );
+
+                Resolver::with_hosts(
+                    vec![SocketAddr::from(([127, 0, 0, 1], 53))],
+                    read_hosts("/etc/hosts"),
+                    global_reactor().clone(),
+                )
+            })
+        })
+    }
+}
+
+#[cfg(feature = "global_reactor")]
+pub use global::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_query() {
+        let query = encode_query(0x1234, "a.bc", TYPE_A).unwrap();
+
+        assert_eq!(
+            query,
+            [
+                0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 1, b'a', 2, b'b', b'c', 0, 0, 1, 0,
+                1
+            ]
+        );
+
+        assert!(encode_query(1, "a..b", TYPE_A).is_err());
+        assert!(encode_query(1, &"a".repeat(64), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let mut response = encode_query(7, "a.bc", TYPE_A).unwrap();
+
+        // QR, RD, RA
+        response[2] = 0x81;
+        response[3] = 0x80;
+        // ANCOUNT = 3
+        response[7] = 3;
+
+        // CNAME to `b.bc`, pointer to the question name.
+        response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 4]);
+        response.extend_from_slice(&[1, b'b', 0xc0, 14]);
+
+        // A records of the target.
+        response.extend_from_slice(&[1, b'b', 0xc0, 14, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4]);
+        response.extend_from_slice(&[10, 0, 0, 1]);
+        response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 1, 0, 0, 4]);
+        response.extend_from_slice(&[10, 0, 0, 2]);
+
+        assert_eq!(
+            decode_response(7, TYPE_A, &response).unwrap(),
+            [
+                ("10.0.0.1".parse().unwrap(), 30),
+                ("10.0.0.2".parse().unwrap(), 256)
+            ]
+        );
+
+        assert_eq!(
+            decode_response(8, TYPE_A, &response).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        // NXDOMAIN
+        response[3] = 0x83;
+
+        assert_eq!(
+            decode_response(7, TYPE_A, &response).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        // truncated.
+        response[3] = 0x80;
+
+        assert!(decode_response(7, TYPE_A, &response[..response.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_parse_resolv_conf() {
+        let conf = parse_resolv_conf(
+            "# comment\ndomain example.com\nsearch ns.svc.cluster.local svc.cluster.local\nnameserver 10.0.0.53\nnameserver fe80::1%eth0\noptions ndots:5 timeout:1\n",
+        );
+
+        assert_eq!(
+            conf,
+            ResolvConf {
+                nameservers: vec![
+                    "10.0.0.53:53".parse::<SocketAddr>().unwrap(),
+                    "[fe80::1]:53".parse().unwrap()
+                ],
+                search: vec!["ns.svc.cluster.local".into(), "svc.cluster.local".into()],
+                ndots: 5,
+            }
+        );
+
+        assert_eq!(parse_resolv_conf("nameserver 10.0.0.53\n").ndots, 1);
+    }
+
+    #[cfg(feature = "global_reactor")]
+    #[futures_test::test]
+    async fn test_lookup_static() {
+        use crate::reactor::global_reactor;
+
+        let resolver = Resolver::new(vec![], global_reactor().clone());
+
+        assert_eq!(
+            resolver.resolve("[::1]", 443).await.unwrap(),
+            ["[::1]:443".parse::<SocketAddr>().unwrap()]
+        );
+
+        assert_eq!(
+            resolver.lookup("no.nameserver").await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    /// Answers the `A` queries of `name` with `ip` and the other names with `NXDOMAIN`.
+    #[cfg(feature = "global_reactor")]
+    fn mock_nameserver(name: &'static str, ip: Ipv4Addr) -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let laddr = socket.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let mut buf = [0; MAX_MESSAGE_LEN];
+
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                let query = &buf[..len];
+
+                let mut labels = vec![];
+                let mut pos = 12;
+
+                while query[pos] != 0 {
+                    let len = query[pos] as usize;
+                    labels.push(std::str::from_utf8(&query[pos + 1..pos + 1 + len]).unwrap());
+                    pos += 1 + len;
+                }
+
+                let qtype = u16::from_be_bytes([query[pos + 1], query[pos + 2]]);
+
+                // the header and the question.
+                let mut response = query[..pos + 5].to_vec();
+
+                response[2] = 0x81;
+
+                if labels.join(".") == name {
+                    response[3] = 0x80;
+
+                    if qtype == TYPE_A {
+                        response[7] = 1;
+                        response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                        response.extend_from_slice(&ip.octets());
+                    }
+                } else {
+                    response[3] = 0x83;
+                }
+
+                socket.send_to(&response, from).unwrap();
+            }
+        });
+
+        laddr
+    }
+
+    #[cfg(feature = "global_reactor")]
+    #[futures_test::test]
+    async fn test_lookup_relative_name() {
+        use crate::reactor::global_reactor;
+
+        let nameserver =
+            mock_nameserver("backend.ns.svc.cluster.local", Ipv4Addr::new(10, 0, 0, 1));
+
+        let resolver = Resolver::new(vec![nameserver], global_reactor().clone()).search(
+            vec!["svc.cluster.local".into(), "ns.svc.cluster.local.".into()],
+            5,
+        );
+
+        assert_eq!(
+            resolver.search_names("backend.ns", false),
+            [
+                "backend.ns.svc.cluster.local",
+                "backend.ns.ns.svc.cluster.local",
+                "backend.ns"
+            ]
+        );
+
+        assert_eq!(
+            resolver.search_names("a.b.c.d.e.f", false),
+            [
+                "a.b.c.d.e.f",
+                "a.b.c.d.e.f.svc.cluster.local",
+                "a.b.c.d.e.f.ns.svc.cluster.local"
+            ]
+        );
+
+        let addrs = [IpAddr::from([10, 0, 0, 1])];
+
+        assert_eq!(resolver.lookup("backend").await.unwrap().addrs, addrs);
+        assert_eq!(resolver.lookup("Backend.NS").await.unwrap().addrs, addrs);
+
+        // absolute names are never searched.
+        assert_eq!(
+            resolver.lookup("backend.").await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        assert_eq!(
+            resolver
+                .lookup("backend.ns.svc.cluster.local.")
+                .await
+                .unwrap()
+                .addrs,
+            addrs
+        );
+    }
+}
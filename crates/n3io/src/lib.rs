@@ -8,3 +8,4 @@ pub mod timeout;
 /// reexport mio library.
 pub use mio;
 pub mod copy;
+pub mod dns;
@@ -8,3 +8,6 @@ pub use tcp_listener::*;
 
 mod udp;
 pub use udp::*;
+
+#[cfg(target_os = "linux")]
+mod sys;
@@ -0,0 +1,446 @@
+//! Raw socket helpers for the options `std` and `mio` don't expose.
+
+use std::{
+    io::{Error, ErrorKind, Result},
+    mem::{size_of, zeroed},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr::null_mut,
+    time::Instant,
+};
+
+use super::{Ecn, RecvMeta, Transmit};
+
+/// The maximum number of datagrams moved by one `recvmmsg`/`sendmmsg` call.
+pub(crate) const MAX_BATCH: usize = 32;
+
+/// The maximum number of segments the kernel accepts in one GSO send.
+const MAX_GSO_SEGMENTS: usize = 64;
+
+/// The size of the control buffer of one datagram.
+const CONTROL_LEN: usize = 128;
+
+/// Space for the control messages of one datagram.
+#[repr(align(8))]
+#[derive(Clone, Copy)]
+struct Control([u8; CONTROL_LEN]);
+
+/// Convert `addr` to a raw socket address.
+pub(crate) fn to_raw_addr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // Safety: all-zero is a valid `sockaddr_storage`.
+    let mut storage: libc::sockaddr_storage = unsafe { zeroed() };
+
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            // Safety: `sockaddr_storage` is large and aligned enough for any address.
+            let raw = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+
+            raw.sin_family = libc::AF_INET as libc::sa_family_t;
+            raw.sin_port = addr.port().to_be();
+            raw.sin_addr = libc::in_addr {
+                s_addr: u32::from_ne_bytes(addr.ip().octets()),
+            };
+
+            size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            // Safety: `sockaddr_storage` is large and aligned enough for any address.
+            let raw = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+
+            raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            raw.sin6_port = addr.port().to_be();
+            raw.sin6_flowinfo = addr.flowinfo();
+            raw.sin6_addr = libc::in6_addr {
+                s6_addr: addr.ip().octets(),
+            };
+            raw.sin6_scope_id = addr.scope_id();
+
+            size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
+
+/// Convert a raw socket address filled by the kernel to [`SocketAddr`].
+pub(crate) fn from_raw_addr(storage: &libc::sockaddr_storage) -> Result<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // Safety: the family says `storage` holds a `sockaddr_in`.
+            let raw = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(raw.sin_addr.s_addr.to_ne_bytes()),
+                u16::from_be(raw.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // Safety: the family says `storage` holds a `sockaddr_in6`.
+            let raw = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(raw.sin6_addr.s6_addr),
+                u16::from_be(raw.sin6_port),
+                raw.sin6_flowinfo,
+                raw.sin6_scope_id,
+            )))
+        }
+        family => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported address family {}", family),
+        )),
+    }
+}
+
+/// Returns `-1` as the last os error.
+pub(crate) fn cvt(ret: libc::c_int) -> Result<libc::c_int> {
+    if ret == -1 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Set an integer socket option.
+pub(crate) fn setsockopt(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> Result<()> {
+    // Safety: `value` outlives the call.
+    cvt(unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    })
+    .map(|_| ())
+}
+
+/// Create a non-blocking udp socket bound to `addr` with `SO_REUSEPORT`.
+pub(crate) fn bind_reuse_port(addr: &SocketAddr) -> Result<OwnedFd> {
+    let domain = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+
+    // Safety: no pointer is passed.
+    let fd = cvt(unsafe {
+        libc::socket(
+            domain,
+            libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    })?;
+
+    // Safety: `fd` is a new socket owned by nobody else.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    setsockopt(fd.as_raw_fd(), libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+
+    let (storage, len) = to_raw_addr(addr);
+
+    // Safety: `storage` holds a valid address of `len` bytes.
+    cvt(unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &storage as *const _ as *const libc::sockaddr,
+            len,
+        )
+    })?;
+
+    Ok(fd)
+}
+
+/// Returns the number of segments the socket can send in one GSO datagram, `1` if `UDP_SEGMENT`
+/// is not supported by the kernel.
+pub(crate) fn max_gso_segments(fd: RawFd) -> usize {
+    let mut value: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+
+    // Safety: `value` and `len` outlive the call.
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if ret == -1 { 1 } else { MAX_GSO_SEGMENTS }
+}
+
+/// Enable `SO_TXTIME` with the `CLOCK_MONOTONIC` clock, see [`Transmit::txtime`].
+pub(crate) fn set_txtime(fd: RawFd) -> Result<()> {
+    let value = libc::sock_txtime {
+        clockid: libc::CLOCK_MONOTONIC,
+        flags: 0,
+    };
+
+    // Safety: `value` outlives the call.
+    cvt(unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TXTIME,
+            &value as *const _ as *const libc::c_void,
+            size_of::<libc::sock_txtime>() as libc::socklen_t,
+        )
+    })
+    .map(|_| ())
+}
+
+/// Convert `instant` to the nanoseconds of the `CLOCK_MONOTONIC` clock.
+fn monotonic_nanos(instant: Instant) -> u64 {
+    // Safety: all-zero is a valid `timespec`, which outlives the call.
+    let mut now: libc::timespec = unsafe { zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+
+    let now_nanos = now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64;
+
+    now_nanos + instant.saturating_duration_since(Instant::now()).as_nanos() as u64
+}
+
+/// Append a control message of `value` to `hdr`, whose control buffer is `control`.
+fn push_cmsg<T: Copy>(
+    hdr: &mut libc::msghdr,
+    control: &mut Control,
+    level: libc::c_int,
+    ty: libc::c_int,
+    value: T,
+) {
+    let offset: usize = hdr.msg_controllen as _;
+    // Safety: `CMSG_SPACE`/`CMSG_LEN` are pure computations.
+    let (space, len) = unsafe {
+        (
+            libc::CMSG_SPACE(size_of::<T>() as u32) as usize,
+            libc::CMSG_LEN(size_of::<T>() as u32) as usize,
+        )
+    };
+
+    assert!(
+        offset + space <= control.0.len(),
+        "control buffer is too short"
+    );
+
+    // Safety: the message fits in `control`, which is aligned for `cmsghdr`, the messages are
+    // packed by `CMSG_SPACE` as `CMSG_NXTHDR` expects.
+    unsafe {
+        let cmsg = control.0.as_mut_ptr().add(offset) as *mut libc::cmsghdr;
+
+        (*cmsg).cmsg_level = level;
+        (*cmsg).cmsg_type = ty;
+        (*cmsg).cmsg_len = len as _;
+
+        (libc::CMSG_DATA(cmsg) as *mut T).write_unaligned(value);
+    }
+
+    hdr.msg_control = control.0.as_mut_ptr() as *mut libc::c_void;
+    hdr.msg_controllen = (offset + space) as _;
+}
+
+/// Receives up to [`MAX_BATCH`] datagrams with one `recvmmsg` call.
+pub(crate) fn recv_batch(
+    fd: RawFd,
+    bufs: &mut [&mut [u8]],
+    metas: &mut [RecvMeta],
+) -> Result<usize> {
+    let batch = bufs.len().min(metas.len()).min(MAX_BATCH);
+
+    // Safety: all-zero is a valid value of these plain c structs.
+    let mut names: [libc::sockaddr_storage; MAX_BATCH] = unsafe { zeroed() };
+    let mut iovs: [libc::iovec; MAX_BATCH] = unsafe { zeroed() };
+    let mut hdrs: [libc::mmsghdr; MAX_BATCH] = unsafe { zeroed() };
+    let mut controls = [Control([0; CONTROL_LEN]); MAX_BATCH];
+
+    for i in 0..batch {
+        iovs[i].iov_base = bufs[i].as_mut_ptr() as *mut libc::c_void;
+        iovs[i].iov_len = bufs[i].len();
+
+        let hdr = &mut hdrs[i].msg_hdr;
+
+        hdr.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+        hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        hdr.msg_iov = &mut iovs[i];
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = controls[i].0.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = controls[i].0.len() as _;
+    }
+
+    // Safety: every header points to buffers living until the end of this function.
+    let received =
+        cvt(unsafe { libc::recvmmsg(fd, hdrs.as_mut_ptr(), batch as _, 0, null_mut()) })? as usize;
+
+    for i in 0..received {
+        let len = hdrs[i].msg_len as usize;
+
+        metas[i] = RecvMeta {
+            len,
+            from: from_raw_addr(&names[i])?,
+            stride: len,
+            dst_ip: None,
+            ecn: None,
+        };
+
+        let hdr = &hdrs[i].msg_hdr;
+
+        // Safety: `hdr` was filled by `recvmmsg` and its control buffer is still alive.
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(hdr) };
+
+        while !cmsg.is_null() {
+            // Safety: `cmsg` points into the control buffer of `hdr`.
+            let (level, ty) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+
+            if level == libc::SOL_UDP && ty == libc::UDP_GRO {
+                // Safety: the kernel passes the GRO segment size as a `c_int`.
+                let stride =
+                    unsafe { (libc::CMSG_DATA(cmsg) as *const libc::c_int).read_unaligned() };
+
+                metas[i].stride = stride as usize;
+            } else if level == libc::IPPROTO_IP && ty == libc::IP_PKTINFO {
+                // Safety: the kernel passes an `in_pktinfo`.
+                let info =
+                    unsafe { (libc::CMSG_DATA(cmsg) as *const libc::in_pktinfo).read_unaligned() };
+
+                let ip = Ipv4Addr::from(info.ipi_addr.s_addr.to_ne_bytes());
+
+                // a dual-stack socket reports the ipv4 peers as mapped addresses.
+                metas[i].dst_ip = Some(if metas[i].from.is_ipv6() {
+                    IpAddr::V6(ip.to_ipv6_mapped())
+                } else {
+                    IpAddr::V4(ip)
+                });
+            } else if level == libc::IPPROTO_IPV6 && ty == libc::IPV6_PKTINFO {
+                // Safety: the kernel passes an `in6_pktinfo`.
+                let info =
+                    unsafe { (libc::CMSG_DATA(cmsg) as *const libc::in6_pktinfo).read_unaligned() };
+
+                metas[i].dst_ip = Some(IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+            } else if level == libc::IPPROTO_IP && ty == libc::IP_TOS {
+                // Safety: the kernel passes the TOS byte as an `u8`.
+                let tos = unsafe { *libc::CMSG_DATA(cmsg) };
+
+                metas[i].ecn = Some(Ecn::from_bits(tos));
+            } else if level == libc::IPPROTO_IPV6 && ty == libc::IPV6_TCLASS {
+                // Safety: the kernel passes the traffic class as a `c_int`.
+                let tclass =
+                    unsafe { (libc::CMSG_DATA(cmsg) as *const libc::c_int).read_unaligned() };
+
+                metas[i].ecn = Some(Ecn::from_bits(tclass as u8));
+            }
+
+            // Safety: as above.
+            cmsg = unsafe { libc::CMSG_NXTHDR(hdr, cmsg) };
+        }
+    }
+
+    Ok(received)
+}
+
+/// Sends up to [`MAX_BATCH`] transmits with one `sendmmsg` call, returns the number of transmits
+/// sent.
+pub(crate) fn send_batch(fd: RawFd, transmits: &[Transmit<'_>]) -> Result<usize> {
+    let batch = transmits.len().min(MAX_BATCH);
+
+    // Safety: all-zero is a valid value of these plain c structs.
+    let mut names: [libc::sockaddr_storage; MAX_BATCH] = unsafe { zeroed() };
+    let mut iovs: [libc::iovec; MAX_BATCH] = unsafe { zeroed() };
+    let mut hdrs: [libc::mmsghdr; MAX_BATCH] = unsafe { zeroed() };
+    let mut controls = [Control([0; CONTROL_LEN]); MAX_BATCH];
+
+    for (i, transmit) in transmits[..batch].iter().enumerate() {
+        let (name, name_len) = to_raw_addr(&transmit.to);
+
+        names[i] = name;
+        iovs[i].iov_base = transmit.buf.as_ptr() as *mut libc::c_void;
+        iovs[i].iov_len = transmit.buf.len();
+
+        let hdr = &mut hdrs[i].msg_hdr;
+
+        hdr.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+        hdr.msg_namelen = name_len;
+        hdr.msg_iov = &mut iovs[i];
+        hdr.msg_iovlen = 1;
+
+        if let Some(segment_size) = transmit.segment_size {
+            push_cmsg(
+                hdr,
+                &mut controls[i],
+                libc::SOL_UDP,
+                libc::UDP_SEGMENT,
+                segment_size as u16,
+            );
+        }
+
+        if let Some(txtime) = transmit.txtime {
+            push_cmsg(
+                hdr,
+                &mut controls[i],
+                libc::SOL_SOCKET,
+                libc::SCM_TXTIME,
+                monotonic_nanos(txtime),
+            );
+        }
+
+        if let Some(src_ip) = transmit.src_ip {
+            let src_ip = match src_ip {
+                IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(src_ip, IpAddr::V4),
+                ip => ip,
+            };
+
+            match src_ip {
+                IpAddr::V4(ip) => push_cmsg(
+                    hdr,
+                    &mut controls[i],
+                    libc::IPPROTO_IP,
+                    libc::IP_PKTINFO,
+                    libc::in_pktinfo {
+                        ipi_ifindex: 0,
+                        ipi_spec_dst: libc::in_addr {
+                            s_addr: u32::from_ne_bytes(ip.octets()),
+                        },
+                        ipi_addr: libc::in_addr { s_addr: 0 },
+                    },
+                ),
+                IpAddr::V6(ip) => push_cmsg(
+                    hdr,
+                    &mut controls[i],
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_PKTINFO,
+                    libc::in6_pktinfo {
+                        ipi6_addr: libc::in6_addr {
+                            s6_addr: ip.octets(),
+                        },
+                        ipi6_ifindex: 0,
+                    },
+                ),
+            }
+        }
+
+        if let Some(ecn) = transmit.ecn {
+            // the ipv4 destinations of a dual-stack socket are sent by the ipv4 stack.
+            let is_ipv4 = match transmit.to {
+                SocketAddr::V4(_) => true,
+                SocketAddr::V6(addr) => addr.ip().to_ipv4_mapped().is_some(),
+            };
+
+            let (level, ty) = if is_ipv4 {
+                (libc::IPPROTO_IP, libc::IP_TOS)
+            } else {
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+            };
+
+            push_cmsg(hdr, &mut controls[i], level, ty, ecn as libc::c_int);
+        }
+    }
+
+    // Safety: every header points to buffers living until the end of this function.
+    cvt(unsafe { libc::sendmmsg(fd, hdrs.as_mut_ptr(), batch as _, 0) }).map(|sent| sent as usize)
+}
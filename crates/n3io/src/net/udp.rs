@@ -2,8 +2,12 @@ use std::{
     collections::HashMap,
     future::poll_fn,
     io::{Error, ErrorKind, Result},
-    net::{SocketAddr, ToSocketAddrs},
-    sync::Arc,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Instant,
 };
 
 use futures::stream::FuturesUnordered;
@@ -11,6 +15,91 @@ use mio::{Interest, Token};
 
 use crate::reactor::Reactor;
 
+/// The ECN codepoint of the IP header(RFC 3168).
+///
+/// Only the socket level is supported, see [`Transmit::ecn`] and [`RecvMeta::ecn`]. n3quic
+/// sends not-ECT packets until quiche accepts the received codepoints and reports the ECN counts
+/// in its ACK frames, which quiche 0.24 doesn't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Ecn {
+    /// Not ECN-capable transport.
+    NotEct = 0b00,
+    /// ECN-capable transport, `ECT(1)`.
+    Ect1 = 0b01,
+    /// ECN-capable transport, `ECT(0)`.
+    Ect0 = 0b10,
+    /// Congestion experienced.
+    Ce = 0b11,
+}
+
+impl Ecn {
+    /// Returns the codepoint of the two low bits of a TOS or traffic class byte.
+    pub fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b00 => Self::NotEct,
+            0b01 => Self::Ect1,
+            0b10 => Self::Ect0,
+            _ => Self::Ce,
+        }
+    }
+}
+
+/// The meta data of a datagram received by [`UdpSocket::recv_batch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvMeta {
+    /// The number of bytes received.
+    pub len: usize,
+    /// The address from whence the data came.
+    pub from: SocketAddr,
+    /// The size of the datagrams coalesced by GRO into the buffer, equals `len` if not coalesced.
+    pub stride: usize,
+    /// The destination address of the datagram, requires
+    /// [`set_recv_pktinfo`](UdpSocket::set_recv_pktinfo), `None` otherwise.
+    ///
+    /// The ipv4 destinations of a dual-stack socket are reported as ipv4-mapped addresses.
+    pub dst_ip: Option<IpAddr>,
+    /// The ECN codepoint of the datagram, requires [`set_recv_ecn`](UdpSocket::set_recv_ecn),
+    /// `None` otherwise.
+    pub ecn: Option<Ecn>,
+}
+
+impl Default for RecvMeta {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            from: (Ipv4Addr::UNSPECIFIED, 0).into(),
+            stride: 0,
+            dst_ip: None,
+            ecn: None,
+        }
+    }
+}
+
+/// A datagram sent by [`UdpSocket::send_batch`].
+#[derive(Debug, Clone, Copy)]
+pub struct Transmit<'a> {
+    /// The payload, a sequence of `segment_size` datagrams if `segment_size` is set.
+    pub buf: &'a [u8],
+    /// The destination address.
+    pub to: SocketAddr,
+    /// The size of the GSO segments, only the last one may be shorter.
+    ///
+    /// Must be `None` unless [`max_gso_segments`](UdpSocket::max_gso_segments) is greater than `1`.
+    pub segment_size: Option<usize>,
+    /// The earliest time to put the datagram on the wire, requires
+    /// [`set_txtime`](UdpSocket::set_txtime), ignored otherwise.
+    pub txtime: Option<Instant>,
+    /// The source address of the datagram, the address of the socket if `None`.
+    ///
+    /// Must be one of the local addresses, only useful if the socket is bound to a wildcard
+    /// address. Sending fails with [`ErrorKind::Unsupported`] if it's set on non-linux platforms.
+    pub src_ip: Option<IpAddr>,
+    /// The ECN codepoint set in the IP header, `Not-ECT` if `None`, ignored on non-linux
+    /// platforms.
+    pub ecn: Option<Ecn>,
+}
+
 /// An asynchronous [`UdpSocket`](std::net::UdpSocket)  based on `mio` library.
 #[derive(Debug)]
 pub struct UdpSocket {
@@ -20,9 +109,30 @@ pub struct UdpSocket {
     mio_udp_socket: mio::net::UdpSocket,
     /// reactor bound to this io.
     reactor: Reactor,
+    /// the number of segments one GSO send can carry.
+    gso_segments: AtomicUsize,
 }
 
 impl UdpSocket {
+    fn new(token: Token, mio_udp_socket: mio::net::UdpSocket, reactor: Reactor) -> Self {
+        #[cfg(target_os = "linux")]
+        let gso_segments = {
+            use std::os::fd::AsRawFd;
+
+            super::sys::max_gso_segments(mio_udp_socket.as_raw_fd())
+        };
+
+        #[cfg(not(target_os = "linux"))]
+        let gso_segments = 1;
+
+        Self {
+            token,
+            mio_udp_socket,
+            reactor,
+            gso_segments: AtomicUsize::new(gso_segments),
+        }
+    }
+
     /// shutdown the read and write of this udp socket.
     pub fn shutdown(&self) -> Result<()> {
         self.reactor
@@ -49,11 +159,33 @@ impl UdpSocket {
             Interest::READABLE.add(Interest::WRITABLE),
         )?;
 
-        Ok(Self {
-            token,
-            mio_udp_socket,
-            reactor,
-        })
+        Ok(Self::new(token, mio_udp_socket, reactor))
+    }
+
+    /// See [`bind_reuse_port_with`](Self::bind_reuse_port_with)
+    #[cfg(all(feature = "global_reactor", target_os = "linux"))]
+    pub async fn bind_reuse_port(addr: SocketAddr) -> Result<Self> {
+        use crate::reactor::global_reactor;
+
+        Self::bind_reuse_port_with(addr, global_reactor().clone()).await
+    }
+
+    /// Creates a UDP socket from the given address with `SO_REUSEPORT`.
+    ///
+    /// The kernel balances the datagrams between the sockets bound to the same address by the
+    /// hash of the 4-tuple.
+    #[cfg(target_os = "linux")]
+    pub async fn bind_reuse_port_with(addr: SocketAddr, reactor: Reactor) -> Result<Self> {
+        let fd = super::sys::bind_reuse_port(&addr)?;
+
+        let mut mio_udp_socket = mio::net::UdpSocket::from_std(std::net::UdpSocket::from(fd));
+
+        let token = reactor.register(
+            &mut mio_udp_socket,
+            Interest::READABLE.add(Interest::WRITABLE),
+        )?;
+
+        Ok(Self::new(token, mio_udp_socket, reactor))
     }
 
     /// Receives data from the socket. On success, returns the number of bytes read and the address from whence the data came.
@@ -77,6 +209,270 @@ impl UdpSocket {
         })
         .await
     }
+
+    /// Returns the number of datagrams one [`Transmit`] can carry, `1` if GSO is unavailable.
+    pub fn max_gso_segments(&self) -> usize {
+        self.gso_segments.load(Ordering::Relaxed)
+    }
+
+    /// Enable or disable UDP GRO, see [`RecvMeta::stride`].
+    ///
+    /// Returns [`ErrorKind::Unsupported`] on non-linux platforms.
+    pub fn set_gro(&self, enable: bool) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+
+            super::sys::setsockopt(
+                self.mio_udp_socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_GRO,
+                enable as libc::c_int,
+            )
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            _ = enable;
+            Err(Error::new(ErrorKind::Unsupported, "UDP GRO is linux only"))
+        }
+    }
+
+    /// Enable `SO_TXTIME`, the kernel holds each datagram until its [`Transmit::txtime`].
+    ///
+    /// The timestamps are of the `CLOCK_MONOTONIC` clock, which is honored by the `fq` qdisc.
+    /// Returns [`ErrorKind::Unsupported`] on non-linux platforms.
+    pub fn set_txtime(&self) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+
+            super::sys::set_txtime(self.mio_udp_socket.as_raw_fd())
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "SO_TXTIME is linux only",
+            ))
+        }
+    }
+
+    /// Enable or disable the reporting of the destination addresses, see [`RecvMeta::dst_ip`].
+    ///
+    /// Returns [`ErrorKind::Unsupported`] on non-linux platforms.
+    pub fn set_recv_pktinfo(&self, enable: bool) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+
+            let fd = self.mio_udp_socket.as_raw_fd();
+
+            // a dual-stack socket receives the ipv4 datagrams with the `IP_PKTINFO` message.
+            super::sys::setsockopt(
+                fd,
+                libc::IPPROTO_IP,
+                libc::IP_PKTINFO,
+                enable as libc::c_int,
+            )?;
+
+            if self.mio_udp_socket.local_addr()?.is_ipv6() {
+                super::sys::setsockopt(
+                    fd,
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_RECVPKTINFO,
+                    enable as libc::c_int,
+                )?;
+            }
+
+            Ok(())
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            _ = enable;
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "IP_PKTINFO is linux only",
+            ))
+        }
+    }
+
+    /// Enable or disable the reporting of the ECN codepoints, see [`RecvMeta::ecn`].
+    ///
+    /// Returns [`ErrorKind::Unsupported`] on non-linux platforms.
+    pub fn set_recv_ecn(&self, enable: bool) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+
+            let fd = self.mio_udp_socket.as_raw_fd();
+
+            // a dual-stack socket receives the ipv4 datagrams with the `IP_TOS` message.
+            super::sys::setsockopt(
+                fd,
+                libc::IPPROTO_IP,
+                libc::IP_RECVTOS,
+                enable as libc::c_int,
+            )?;
+
+            if self.mio_udp_socket.local_addr()?.is_ipv6() {
+                super::sys::setsockopt(
+                    fd,
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_RECVTCLASS,
+                    enable as libc::c_int,
+                )?;
+            }
+
+            Ok(())
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            _ = enable;
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "ECN reporting is linux only",
+            ))
+        }
+    }
+
+    /// Receives several datagrams from the socket, with one `recvmmsg` call on linux.
+    ///
+    /// Fills `bufs[i]` and `metas[i]` for each datagram and returns the number of datagrams
+    /// received.
+    pub async fn recv_batch(
+        &self,
+        bufs: &mut [&mut [u8]],
+        metas: &mut [RecvMeta],
+    ) -> Result<usize> {
+        poll_fn(|cx| {
+            self.reactor
+                .poll_io(cx, self.token, Interest::READABLE, |_| {
+                    self.try_recv_batch(bufs, metas)
+                })
+        })
+        .await
+    }
+
+    /// Sends all the `transmits`, with as few `sendmmsg` calls as possible on linux.
+    pub async fn send_batch(&self, transmits: &[Transmit<'_>]) -> Result<()> {
+        let mut sent = 0;
+
+        while sent < transmits.len() {
+            let n = poll_fn(|cx| {
+                self.reactor
+                    .poll_io(cx, self.token, Interest::WRITABLE, |_| {
+                        self.try_send_batch(&transmits[sent..])
+                    })
+            })
+            .await?;
+
+            sent += n;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn try_recv_batch(&self, bufs: &mut [&mut [u8]], metas: &mut [RecvMeta]) -> Result<usize> {
+        use std::os::fd::AsRawFd;
+
+        super::sys::recv_batch(self.mio_udp_socket.as_raw_fd(), bufs, metas)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn try_recv_batch(&self, bufs: &mut [&mut [u8]], metas: &mut [RecvMeta]) -> Result<usize> {
+        if bufs.is_empty() || metas.is_empty() {
+            return Ok(0);
+        }
+
+        let (len, from) = self.mio_udp_socket.recv_from(bufs[0])?;
+
+        metas[0] = RecvMeta {
+            len,
+            from,
+            stride: len,
+            dst_ip: None,
+            ecn: None,
+        };
+
+        Ok(1)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn try_send_batch(&self, transmits: &[Transmit<'_>]) -> Result<usize> {
+        use std::os::fd::AsRawFd;
+
+        match super::sys::send_batch(self.mio_udp_socket.as_raw_fd(), transmits) {
+            // the device can't do the checksum offload GSO requires.
+            Err(err)
+                if err.raw_os_error() == Some(libc::EIO) && transmits[0].segment_size.is_some() =>
+            {
+                log::warn!(
+                    "UdpSocket({:?}) disable GSO, err={}",
+                    self.mio_udp_socket.local_addr(),
+                    err
+                );
+
+                self.gso_segments.store(1, Ordering::Relaxed);
+
+                self.send_segments(&transmits[0])?;
+
+                Ok(1)
+            }
+            r => r,
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn try_send_batch(&self, transmits: &[Transmit<'_>]) -> Result<usize> {
+        let transmit = &transmits[0];
+
+        // sending from the wrong address is worse than not sending.
+        if transmit.src_ip.is_some() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "`Transmit::src_ip` is linux only",
+            ));
+        }
+
+        let segment_size = transmit.segment_size.unwrap_or(transmit.buf.len()).max(1);
+
+        for segment in transmit.buf.chunks(segment_size) {
+            self.mio_udp_socket.send_to(segment, transmit.to)?;
+        }
+
+        Ok(1)
+    }
+
+    /// Sends the segments of `transmit` one by one, each with the control messages of `transmit`.
+    #[cfg(target_os = "linux")]
+    fn send_segments(&self, transmit: &Transmit<'_>) -> Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let segment_size = transmit.segment_size.unwrap_or(transmit.buf.len()).max(1);
+
+        let segments = transmit
+            .buf
+            .chunks(segment_size)
+            .map(|buf| Transmit {
+                buf,
+                segment_size: None,
+                ..*transmit
+            })
+            .collect::<Vec<_>>();
+
+        let mut sent = 0;
+
+        while sent < segments.len() {
+            sent += super::sys::send_batch(self.mio_udp_socket.as_raw_fd(), &segments[sent..])?;
+        }
+
+        Ok(())
+    }
 }
 
 /// A group of udp sockets.
@@ -88,9 +484,16 @@ pub mod udp_group {
 
     use super::*;
 
+    /// The buffers of the pending [`UdpGroupReceiver::recv_batch`] call.
+    #[derive(Clone, Copy)]
+    struct RecvBufs {
+        bufs: *mut [*mut [u8]],
+        metas: *mut [RecvMeta],
+    }
+
     struct UdpGroupRecvFrom {
         addr: SocketAddr,
-        buf: Arc<UnsafeCell<MaybeUninit<*mut [u8]>>>,
+        bufs: Arc<UnsafeCell<MaybeUninit<RecvBufs>>>,
         socket: Arc<UdpSocket>,
     }
 
@@ -98,30 +501,35 @@ pub mod udp_group {
     unsafe impl Sync for UdpGroupRecvFrom {}
 
     impl Future for UdpGroupRecvFrom {
-        type Output = Result<(Self, usize, SocketAddr)>;
+        type Output = Result<(Self, usize)>;
 
         fn poll(
             self: std::pin::Pin<&mut Self>,
             cx: &mut std::task::Context<'_>,
         ) -> std::task::Poll<Self::Output> {
             // Safety: Tasks managed by `FuturesUnordered` are polled sequentially.
-            let buf = unsafe { &mut *(&mut *self.buf.get()).assume_init() };
+            let (bufs, metas) = unsafe {
+                let recv_bufs = (*self.bufs.get()).assume_init();
+                (
+                    &mut *(recv_bufs.bufs as *mut [&mut [u8]]),
+                    &mut *recv_bufs.metas,
+                )
+            };
 
             self.socket
                 .reactor
                 .clone()
                 .poll_io(cx, self.socket.token, Interest::READABLE, |_| {
-                    self.socket.clone().mio_udp_socket.recv_from(buf)
+                    self.socket.try_recv_batch(bufs, metas)
                 })
-                .map_ok(|(read_size, from)| {
+                .map_ok(|received| {
                     (
                         Self {
                             addr: self.addr,
                             socket: self.socket.clone(),
-                            buf: self.buf.clone(),
+                            bufs: self.bufs.clone(),
                         },
-                        read_size,
-                        from,
+                        received,
                     )
                 })
         }
@@ -129,8 +537,35 @@ pub mod udp_group {
 
     /// Create a udp socket group.
     pub async fn bind_with<S>(
+        laddrs: S,
+        max_recv_buf: usize,
+        reactor: Reactor,
+    ) -> Result<(UdpGroupSender, UdpGroupReceiver)>
+    where
+        S: ToSocketAddrs,
+    {
+        bind_prv(laddrs, max_recv_buf, false, reactor).await
+    }
+
+    /// Create a udp socket group with `SO_REUSEPORT`, see [`UdpSocket::bind_reuse_port_with`].
+    ///
+    /// Bind several groups to the same addresses to receive the datagrams on several tasks.
+    #[cfg(target_os = "linux")]
+    pub async fn bind_reuse_port_with<S>(
+        laddrs: S,
+        max_recv_buf: usize,
+        reactor: Reactor,
+    ) -> Result<(UdpGroupSender, UdpGroupReceiver)>
+    where
+        S: ToSocketAddrs,
+    {
+        bind_prv(laddrs, max_recv_buf, true, reactor).await
+    }
+
+    async fn bind_prv<S>(
         laddrs: S,
         _max_recv_buf: usize,
+        _reuse_port: bool,
         reactor: Reactor,
     ) -> Result<(UdpGroupSender, UdpGroupReceiver)>
     where
@@ -143,15 +578,31 @@ pub mod udp_group {
         );
 
         for laddr in laddrs.to_socket_addrs()? {
-            let socket = Arc::new(UdpSocket::bind_with(laddr, reactor.clone()).await?);
+            #[cfg(target_os = "linux")]
+            let socket = if _reuse_port {
+                UdpSocket::bind_reuse_port_with(laddr, reactor.clone()).await?
+            } else {
+                UdpSocket::bind_with(laddr, reactor.clone()).await?
+            };
+
+            #[cfg(not(target_os = "linux"))]
+            let socket = UdpSocket::bind_with(laddr, reactor.clone()).await?;
+
+            let socket = Arc::new(socket);
             let laddr = socket.mio_socket().local_addr()?;
 
+            // learn the destination of each datagram, the replies must be sent from it.
+            #[cfg(target_os = "linux")]
+            if laddr.ip().is_unspecified() {
+                socket.set_recv_pktinfo(true)?;
+            }
+
             sockets.insert(laddr, socket.clone());
 
             receiver.1.push(UdpGroupRecvFrom {
                 addr: laddr,
                 socket,
-                buf: receiver.0.clone(),
+                bufs: receiver.0.clone(),
             });
         }
 
@@ -169,22 +620,111 @@ pub mod udp_group {
         }
         /// Send datagram via path.
         pub async fn send(&self, buf: &[u8], from: SocketAddr, to: SocketAddr) -> Result<usize> {
-            let socket = self
-                .0
-                .get(&from)
+            let (socket, wildcard) = self.socket(from)?;
+
+            if !wildcard {
+                return socket.send_to(buf, to).await;
+            }
+
+            socket
+                .send_batch(&[Transmit {
+                    buf,
+                    to,
+                    segment_size: None,
+                    txtime: None,
+                    src_ip: Some(from.ip()),
+                    ecn: None,
+                }])
+                .await?;
+
+            Ok(buf.len())
+        }
+
+        /// Send the `transmits` via the socket bound to `from`, see [`UdpSocket::send_batch`].
+        ///
+        /// A `from` address received by a wildcard socket, see [`RecvMeta::dst_ip`], is sent by
+        /// that socket with [`Transmit::src_ip`] set.
+        pub async fn send_batch(&self, transmits: &[Transmit<'_>], from: SocketAddr) -> Result<()> {
+            let (socket, wildcard) = self.socket(from)?;
+
+            if !wildcard {
+                return socket.send_batch(transmits).await;
+            }
+
+            let transmits = transmits
+                .iter()
+                .map(|transmit| Transmit {
+                    src_ip: Some(from.ip()),
+                    ..*transmit
+                })
+                .collect::<Vec<_>>();
+
+            socket.send_batch(&transmits).await
+        }
+
+        /// Returns the socket bound to `from`, or the wildcard socket of the same port and family,
+        /// and whether it's the wildcard one.
+        fn socket(&self, from: SocketAddr) -> Result<(Arc<UdpSocket>, bool)> {
+            if let Some(socket) = self.0.get(&from) {
+                return Ok((socket.clone(), false));
+            }
+
+            let unspecified = match from {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            };
+
+            self.0
+                .get(&SocketAddr::new(unspecified, from.port()))
+                .map(|socket| (socket.clone(), true))
                 .ok_or(Error::new(
                     ErrorKind::AddrNotAvailable,
                     format!("UdpGroup: invalid from address `{}`", from),
-                ))?
-                .clone();
+                ))
+        }
+
+        /// Returns the number of datagrams one [`Transmit`] can carry on every socket of the
+        /// group.
+        pub fn max_gso_segments(&self) -> usize {
+            self.0
+                .values()
+                .map(|socket| socket.max_gso_segments())
+                .min()
+                .unwrap_or(1)
+        }
+
+        /// Enable `SO_TXTIME` on every socket of the group, see [`UdpSocket::set_txtime`].
+        pub fn set_txtime(&self) -> Result<()> {
+            for socket in self.0.values() {
+                socket.set_txtime()?;
+            }
+
+            Ok(())
+        }
+
+        /// Enable or disable UDP GRO on every socket of the group, see [`UdpSocket::set_gro`].
+        pub fn set_gro(&self, enable: bool) -> Result<()> {
+            for socket in self.0.values() {
+                socket.set_gro(enable)?;
+            }
+
+            Ok(())
+        }
+
+        /// Enable or disable the reporting of the ECN codepoints on every socket of the group,
+        /// see [`UdpSocket::set_recv_ecn`].
+        pub fn set_recv_ecn(&self, enable: bool) -> Result<()> {
+            for socket in self.0.values() {
+                socket.set_recv_ecn(enable)?;
+            }
 
-            socket.send_to(buf, to).await
+            Ok(())
         }
     }
 
     /// A receiver recieve data from socket group.
     pub struct UdpGroupReceiver(
-        Arc<UnsafeCell<MaybeUninit<*mut [u8]>>>,
+        Arc<UnsafeCell<MaybeUninit<RecvBufs>>>,
         FuturesUnordered<UdpGroupRecvFrom>,
     );
 
@@ -194,22 +734,292 @@ pub mod udp_group {
     impl UdpGroupReceiver {
         /// Receives data from the group.
         pub async fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr, SocketAddr)> {
+            let mut metas = [RecvMeta::default()];
+
+            let (_, laddr) = self.recv_batch(&mut [buf], &mut metas).await?;
+
+            let to = metas[0]
+                .dst_ip
+                .map_or(laddr, |ip| SocketAddr::new(ip, laddr.port()));
+
+            Ok((metas[0].len, metas[0].from, to))
+        }
+
+        /// Receives several datagrams from one socket of the group, see [`UdpSocket::recv_batch`].
+        ///
+        /// Returns the number of datagrams received and the local address of the socket, the
+        /// sockets bound to a wildcard address also report the destination of each datagram in
+        /// [`RecvMeta::dst_ip`].
+        pub async fn recv_batch(
+            &mut self,
+            bufs: &mut [&mut [u8]],
+            metas: &mut [RecvMeta],
+        ) -> Result<(usize, SocketAddr)> {
             // Safety:
             // - `FuturesUnordered` will not call poll on the submitted future until `FuturesUnordered::poll_next` is called.
             // - The receiver of this func is `&mut self`, thus only one caller can access this func at the same time.
-            unsafe { (&mut *self.0.get()).write(buf as *mut [u8]) };
-
-            while let Some((recv_from, read_size, from)) = self.1.try_next().await? {
-                assert!(!(buf.len() < read_size), "Buff too short");
+            unsafe {
+                (&mut *self.0.get()).write(RecvBufs {
+                    bufs: bufs as *mut [&mut [u8]] as *mut [*mut [u8]],
+                    metas: metas as *mut [RecvMeta],
+                })
+            };
 
+            while let Some((recv_from, received)) = self.1.try_next().await? {
                 let to = recv_from.addr;
 
                 self.1.push(recv_from);
 
-                return Ok((read_size, from, to));
+                return Ok((received, to));
             }
 
             unreachable!("FuturesUnordered: is empty.")
         }
     }
 }
+
+#[cfg(all(test, feature = "global_reactor", target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[futures_test::test]
+    async fn test_bind_reuse_port() {
+        let socket = UdpSocket::bind_reuse_port("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let laddr = socket.mio_socket().local_addr().unwrap();
+
+        let shared = UdpSocket::bind_reuse_port(laddr).await.unwrap();
+
+        assert_eq!(shared.mio_socket().local_addr().unwrap(), laddr);
+
+        assert!(UdpSocket::bind(laddr).await.is_err());
+
+        let client = UdpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        client.send_to(b"hello", laddr).await.unwrap();
+
+        let mut buf = [0; 16];
+
+        let (len, from) = futures::future::select(
+            Box::pin(socket.recv_from(&mut buf)),
+            Box::pin(async {
+                let mut buf = [0; 16];
+                shared.recv_from(&mut buf).await
+            }),
+        )
+        .await
+        .factor_first()
+        .0
+        .unwrap();
+
+        assert_eq!(len, 5);
+        assert_eq!(from, client.mio_socket().local_addr().unwrap());
+    }
+
+    #[futures_test::test]
+    async fn test_send_recv_batch() {
+        let server = UdpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let laddr = server.mio_socket().local_addr().unwrap();
+
+        let payload = [1u8; 30];
+
+        // the first datagram is split into three segments if GSO is available.
+        let segment_size = if client.max_gso_segments() > 1 {
+            Some(10)
+        } else {
+            None
+        };
+
+        // the kernel ignores the timestamp without the `fq` qdisc.
+        let txtime = client.set_txtime().ok().map(|_| Instant::now());
+
+        client
+            .send_batch(&[
+                Transmit {
+                    buf: &payload,
+                    to: laddr,
+                    segment_size,
+                    txtime: None,
+                    src_ip: None,
+                    ecn: None,
+                },
+                Transmit {
+                    buf: b"hello",
+                    to: laddr,
+                    segment_size: None,
+                    txtime,
+                    src_ip: None,
+                    ecn: None,
+                },
+            ])
+            .await
+            .unwrap();
+
+        let expected = if segment_size.is_some() {
+            vec![10, 10, 10, 5]
+        } else {
+            vec![30, 5]
+        };
+
+        let mut lens = vec![];
+        let mut storage = vec![[0u8; 64]; 8];
+
+        while lens.len() < expected.len() {
+            let mut bufs = storage
+                .iter_mut()
+                .map(|buf| &mut buf[..])
+                .collect::<Vec<_>>();
+            let mut metas = [RecvMeta::default(); 8];
+
+            let received = server.recv_batch(&mut bufs, &mut metas).await.unwrap();
+
+            for meta in &metas[..received] {
+                assert_eq!(meta.from, client.mio_socket().local_addr().unwrap());
+                lens.push(meta.len);
+            }
+        }
+
+        assert_eq!(lens, expected);
+    }
+
+    #[futures_test::test]
+    async fn test_send_segments() {
+        let server = UdpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let client = UdpSocket::bind("0.0.0.0:0".parse().unwrap()).await.unwrap();
+
+        let laddr = server.mio_socket().local_addr().unwrap();
+        let port = client.mio_socket().local_addr().unwrap().port();
+
+        // the GSO fallback keeps the source address of the transmit.
+        client
+            .send_segments(&Transmit {
+                buf: &[1u8; 25],
+                to: laddr,
+                segment_size: Some(10),
+                txtime: None,
+                src_ip: Some("127.0.0.2".parse().unwrap()),
+                ecn: None,
+            })
+            .unwrap();
+
+        let mut buf = [0u8; 64];
+        let mut lens = vec![];
+
+        for _ in 0..3 {
+            let (len, from) = server.recv_from(&mut buf).await.unwrap();
+
+            assert_eq!(
+                from,
+                SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), port)
+            );
+
+            lens.push(len);
+        }
+
+        assert_eq!(lens, [10, 10, 5]);
+    }
+
+    #[futures_test::test]
+    async fn test_group_wildcard() {
+        for (laddr, ip) in [
+            ("0.0.0.0:0", "127.0.0.1"),
+            ("[::]:0", "::1"),
+            ("[::]:0", "127.0.0.1"),
+        ] {
+            let (sender, mut receiver) =
+                udp_group::bind_with(laddr, 65527, crate::reactor::global_reactor().clone())
+                    .await
+                    .unwrap();
+
+            let port = sender.local_addrs().next().unwrap().port();
+            let ip: IpAddr = ip.parse().unwrap();
+
+            let client = UdpSocket::bind(SocketAddr::new(ip, 0)).await.unwrap();
+
+            client
+                .send_to(b"hello", SocketAddr::new(ip, port))
+                .await
+                .unwrap();
+
+            let mut buf = [0u8; 64];
+
+            let (len, from, to) = receiver.recv(&mut buf).await.unwrap();
+
+            assert_eq!(len, 5);
+
+            // a dual-stack socket reports the ipv4 addresses as ipv4-mapped addresses.
+            let expected = match (to, ip) {
+                (SocketAddr::V6(_), IpAddr::V4(ip)) => IpAddr::V6(ip.to_ipv6_mapped()),
+                _ => ip,
+            };
+
+            assert_eq!(to, SocketAddr::new(expected, port));
+            assert_eq!(from.ip(), expected);
+
+            sender.send(b"world", to, from).await.unwrap();
+
+            let (len, from) = client.recv_from(&mut buf).await.unwrap();
+
+            assert_eq!(&buf[..len], b"world");
+            assert_eq!(from, SocketAddr::new(ip, port));
+        }
+    }
+
+    #[futures_test::test]
+    async fn test_ecn() {
+        for addr in ["127.0.0.1:0", "[::1]:0"] {
+            let server = UdpSocket::bind(addr.parse().unwrap()).await.unwrap();
+            let client = UdpSocket::bind(addr.parse().unwrap()).await.unwrap();
+
+            server.set_recv_ecn(true).unwrap();
+
+            let laddr = server.mio_socket().local_addr().unwrap();
+
+            let codepoints = [Ecn::Ect0, Ecn::Ect1, Ecn::Ce, Ecn::NotEct];
+
+            let transmits = codepoints
+                .iter()
+                .map(|ecn| Transmit {
+                    buf: b"hello",
+                    to: laddr,
+                    segment_size: None,
+                    txtime: None,
+                    src_ip: None,
+                    ecn: Some(*ecn),
+                })
+                .collect::<Vec<_>>();
+
+            client.send_batch(&transmits).await.unwrap();
+
+            let mut received = vec![];
+            let mut storage = vec![[0u8; 64]; 4];
+
+            while received.len() < codepoints.len() {
+                let mut bufs = storage
+                    .iter_mut()
+                    .map(|buf| &mut buf[..])
+                    .collect::<Vec<_>>();
+                let mut metas = [RecvMeta::default(); 4];
+
+                let n = server.recv_batch(&mut bufs, &mut metas).await.unwrap();
+
+                received.extend(metas[..n].iter().map(|meta| meta.ecn));
+            }
+
+            assert_eq!(received, codepoints.map(Some));
+        }
+    }
+}
@@ -45,6 +45,8 @@ struct ReactorImpl {
     timing_wheel: TimeWheel<Token>,
     /// mio registry.
     registry: Registry,
+    /// the resolution of the timers.
+    tick_interval: Duration,
 }
 
 impl ReactorImpl {
@@ -55,6 +57,7 @@ impl ReactorImpl {
             io_writable_stats: Default::default(),
             timing_wheel: TimeWheel::new(tick_interval),
             registry,
+            tick_interval,
         }
     }
 
@@ -282,6 +285,11 @@ impl Reactor {
         self.0.registry.deregister(source)
     }
 
+    /// Returns the resolution of the timers, see [`new`](Self::new).
+    pub fn tick_interval(&self) -> Duration {
+        self.0.tick_interval
+    }
+
     /// Create a new `deadline` timer.
     pub fn deadline(&self, deadline: Instant) -> Token {
         let token = self.0.next_token(Interest::READABLE);
@@ -77,3 +77,55 @@ where
         }
     }
 }
+
+#[cfg(feature = "global_reactor")]
+/// See [`sleep_with`]
+pub fn sleep(duration: Duration) -> Sleep {
+    use crate::reactor::global_reactor;
+
+    sleep_with(duration, global_reactor().clone())
+}
+
+/// Create a future that completes after `duration` has elapsed.
+pub fn sleep_with(duration: Duration, reactor: Reactor) -> Sleep {
+    let timer = reactor.deadline(Instant::now() + duration);
+
+    Sleep { timer, reactor }
+}
+
+/// Future returned by [`sleep_with`]
+pub struct Sleep {
+    timer: Token,
+    reactor: Reactor,
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.reactor.deregister_timer(self.timer);
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+        self.reactor.poll_timeout(cx, self.timer).map(|_| ())
+    }
+}
+
+#[cfg(feature = "global_reactor")]
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[futures_test::test]
+    async fn test_sleep() {
+        let start = Instant::now();
+
+        sleep(Duration::from_millis(100)).await;
+
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+}
@@ -0,0 +1,71 @@
+use std::io::{Error, ErrorKind, Result};
+
+/// The header size of a [`FlowDatagram`].
+pub const FLOW_HEADER_LEN: usize = 4;
+
+/// A udp datagram carried in a QUIC DATAGRAM frame.
+///
+/// ```text
+/// +---------+---------+
+/// | flow id | payload |
+/// | u32     | ...     |
+/// +---------+---------+
+/// ```
+///
+/// The flow id is allocated by the agent for each local udp peer, big-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowDatagram<'a> {
+    /// The flow the payload belongs to.
+    pub flow_id: u32,
+    /// The udp payload.
+    pub payload: &'a [u8],
+}
+
+impl<'a> FlowDatagram<'a> {
+    /// Create a new datagram of `flow_id`.
+    pub fn new(flow_id: u32, payload: &'a [u8]) -> Self {
+        Self { flow_id, payload }
+    }
+
+    /// Encode this datagram into bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FLOW_HEADER_LEN + self.payload.len());
+
+        buf.extend_from_slice(&self.flow_id.to_be_bytes());
+        buf.extend_from_slice(self.payload);
+
+        buf
+    }
+
+    /// Decode a datagram from the payload of a DATAGRAM frame.
+    pub fn decode(buf: &'a [u8]) -> Result<Self> {
+        let Some((header, payload)) = buf.split_first_chunk::<FLOW_HEADER_LEN>() else {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "flow datagram is truncated",
+            ));
+        };
+
+        Ok(Self {
+            flow_id: u32::from_be_bytes(*header),
+            payload,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flow_datagram() {
+        let datagram = FlowDatagram::new(0x01020304, b"payload");
+
+        let buf = datagram.encode();
+
+        assert_eq!(&buf[..FLOW_HEADER_LEN], &[1, 2, 3, 4]);
+        assert_eq!(FlowDatagram::decode(&buf).unwrap(), datagram);
+        assert_eq!(FlowDatagram::decode(&buf[..4]).unwrap().payload, b"");
+        assert!(FlowDatagram::decode(&buf[..3]).is_err());
+    }
+}
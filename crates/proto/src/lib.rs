@@ -0,0 +1,7 @@
+//! The wire formats of the n3 tunnel protocol: stream preambles and udp flow datagrams.
+
+mod preamble;
+pub use preamble::*;
+
+mod datagram;
+pub use datagram::*;
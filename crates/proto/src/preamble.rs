@@ -0,0 +1,378 @@
+use std::io::{Error, ErrorKind, Result};
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The first bytes of every preamble.
+pub const PREAMBLE_MAGIC: &[u8; 2] = b"N3";
+
+/// The preamble version written by this crate.
+pub const PREAMBLE_VERSION: u8 = 1;
+
+/// The header size: magic, version and body length.
+const HEADER_LEN: usize = 5;
+
+/// The option asking the receiver to answer the preamble with a [`PreambleStatus`].
+pub const REPLY_OPTION: &str = "reply";
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, reason.into())
+}
+
+/// The first message of a tunnel stream, selects the target the stream is forwarded to.
+///
+/// ```text
+/// +-------+---------+-------------+------------------------------------------------+
+/// | "N3"  | version | body length | body                                           |
+/// | 2     | u8      | u16         | target(u8 len), token(u16 len), options(u8 n)  |
+/// +-------+---------+-------------+------------------------------------------------+
+/// ```
+///
+/// Each option is a key(u8 len) and a value(u16 len), integers are big-endian, an empty token
+/// means no token.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Preamble {
+    /// The target address, `host:port`.
+    pub target: String,
+    /// The authentication token.
+    pub token: Option<String>,
+    /// Extension options, unknown options are ignored by the receiver.
+    pub options: Vec<(String, String)>,
+}
+
+impl Preamble {
+    /// Create a new preamble to `target`(`host:port`).
+    pub fn new<T: Into<String>>(target: T) -> Self {
+        Self {
+            target: target.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Set the authentication token.
+    pub fn token<T: Into<String>>(mut self, token: T) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Append an extension option.
+    pub fn option<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.options.push((key.into(), value.into()));
+        self
+    }
+
+    /// Returns the value of option `key`.
+    pub fn get_option(&self, key: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Ask the receiver to answer this preamble with a [`PreambleStatus`].
+    pub fn reply(self) -> Self {
+        self.option(REPLY_OPTION, "1")
+    }
+
+    /// Returns true if the sender waits for a [`PreambleStatus`].
+    pub fn wants_reply(&self) -> bool {
+        self.get_option(REPLY_OPTION).is_some()
+    }
+
+    /// Split `target` into host and port, the brackets of an ipv6 host are removed.
+    pub fn host_port(&self) -> Result<(&str, u16)> {
+        let Some((host, port)) = self.target.rsplit_once(':') else {
+            return Err(invalid(format!("target `{}` has no port", self.target)));
+        };
+
+        let port = port
+            .parse::<u16>()
+            .map_err(|err| invalid(format!("target `{}`: {}", self.target, err)))?;
+
+        let host = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+
+        if host.is_empty() {
+            return Err(invalid(format!("target `{}` has no host", self.target)));
+        }
+
+        Ok((host, port))
+    }
+
+    /// Encode this preamble into bytes.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut body = vec![];
+
+        put_u8_bytes(&mut body, "target", self.target.as_bytes())?;
+        put_u16_bytes(
+            &mut body,
+            "token",
+            self.token.as_deref().unwrap_or_default().as_bytes(),
+        )?;
+
+        body.push(
+            u8::try_from(self.options.len()).map_err(|_| invalid("too many preamble options"))?,
+        );
+
+        for (key, value) in &self.options {
+            put_u8_bytes(&mut body, "option key", key.as_bytes())?;
+            put_u16_bytes(&mut body, "option value", value.as_bytes())?;
+        }
+
+        let len = u16::try_from(body.len()).map_err(|_| invalid("preamble is too long"))?;
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + body.len());
+
+        buf.extend_from_slice(PREAMBLE_MAGIC);
+        buf.push(PREAMBLE_VERSION);
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(&body);
+
+        Ok(buf)
+    }
+
+    /// Decode the body of a preamble.
+    fn decode_body(mut body: &[u8]) -> Result<Self> {
+        let target = get_string(&mut body, 1, "target")?;
+        let token = get_string(&mut body, 2, "token")?;
+
+        let count = get_bytes(&mut body, 1, "options")?[0];
+
+        let mut options = vec![];
+
+        for _ in 0..count {
+            let key = get_string(&mut body, 1, "option key")?;
+            let value = get_string(&mut body, 2, "option value")?;
+
+            options.push((key, value));
+        }
+
+        if !body.is_empty() {
+            return Err(invalid("trailing bytes after the preamble options"));
+        }
+
+        Ok(Self {
+            target,
+            token: if token.is_empty() { None } else { Some(token) },
+            options,
+        })
+    }
+
+    /// Write this preamble to `writer`.
+    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.encode()?).await?;
+        writer.flush().await
+    }
+
+    /// Read a preamble from `reader`, no bytes after it are consumed.
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
+        let mut header = [0u8; HEADER_LEN];
+
+        reader.read_exact(&mut header).await?;
+
+        if &header[..2] != PREAMBLE_MAGIC {
+            return Err(invalid("invalid preamble magic"));
+        }
+
+        if header[2] != PREAMBLE_VERSION {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported preamble version {}", header[2]),
+            ));
+        }
+
+        let mut body = vec![0u8; u16::from_be_bytes([header[3], header[4]]) as usize];
+
+        reader.read_exact(&mut body).await?;
+
+        Self::decode_body(&body)
+    }
+}
+
+/// The answer to a preamble with the [`REPLY_OPTION`], written once the target is connected or
+/// the preamble is rejected.
+///
+/// ```text
+/// +-------+---------+--------+
+/// | "N3"  | version | status |
+/// | 2     | u8      | u8     |
+/// +-------+---------+--------+
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PreambleStatus {
+    /// The target is connected, the stream is forwarded to it.
+    Connected = 0,
+    /// The token or the target is not allowed.
+    Denied = 1,
+    /// The target is not a valid `host:port`.
+    InvalidTarget = 2,
+    /// The target can't be resolved or connected.
+    Unreachable = 3,
+}
+
+impl PreambleStatus {
+    /// Write this status to `writer`.
+    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        writer
+            .write_all(&[
+                PREAMBLE_MAGIC[0],
+                PREAMBLE_MAGIC[1],
+                PREAMBLE_VERSION,
+                *self as u8,
+            ])
+            .await?;
+        writer.flush().await
+    }
+
+    /// Read a status from `reader`, no bytes after it are consumed.
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
+        let mut buf = [0u8; 4];
+
+        reader.read_exact(&mut buf).await?;
+
+        if &buf[..2] != PREAMBLE_MAGIC {
+            return Err(invalid("invalid preamble status magic"));
+        }
+
+        if buf[2] != PREAMBLE_VERSION {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported preamble version {}", buf[2]),
+            ));
+        }
+
+        match buf[3] {
+            0 => Ok(Self::Connected),
+            1 => Ok(Self::Denied),
+            2 => Ok(Self::InvalidTarget),
+            3 => Ok(Self::Unreachable),
+            status => Err(invalid(format!("unknown preamble status {}", status))),
+        }
+    }
+}
+
+fn put_u8_bytes(buf: &mut Vec<u8>, name: &str, bytes: &[u8]) -> Result<()> {
+    let len = u8::try_from(bytes.len()).map_err(|_| invalid(format!("{} is too long", name)))?;
+
+    buf.push(len);
+    buf.extend_from_slice(bytes);
+
+    Ok(())
+}
+
+fn put_u16_bytes(buf: &mut Vec<u8>, name: &str, bytes: &[u8]) -> Result<()> {
+    let len = u16::try_from(bytes.len()).map_err(|_| invalid(format!("{} is too long", name)))?;
+
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(bytes);
+
+    Ok(())
+}
+
+fn get_bytes<'a>(buf: &mut &'a [u8], len: usize, name: &str) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(invalid(format!("preamble is truncated at {}", name)));
+    }
+
+    let (bytes, rest) = buf.split_at(len);
+
+    *buf = rest;
+
+    Ok(bytes)
+}
+
+/// Read a string prefixed by a `len_size` bytes big-endian length.
+fn get_string(buf: &mut &[u8], len_size: usize, name: &str) -> Result<String> {
+    let len = get_bytes(buf, len_size, name)?
+        .iter()
+        .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+
+    let bytes = get_bytes(buf, len, name)?;
+
+    String::from_utf8(bytes.to_vec()).map_err(|err| invalid(format!("{}: {}", name, err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, io::Cursor};
+
+    use super::*;
+
+    #[test]
+    fn test_preamble() {
+        let preamble = Preamble::new("db.internal:5432")
+            .token("secret")
+            .option("proto", "tcp");
+
+        let mut buf = preamble.encode().unwrap();
+
+        buf.extend_from_slice(b"payload");
+
+        let mut reader = Cursor::new(buf);
+
+        assert_eq!(block_on(Preamble::read(&mut reader)).unwrap(), preamble);
+
+        let mut rest = vec![];
+
+        block_on(reader.read_to_end(&mut rest)).unwrap();
+
+        assert_eq!(rest, b"payload");
+
+        assert_eq!(preamble.host_port().unwrap(), ("db.internal", 5432));
+        assert_eq!(preamble.get_option("proto"), Some("tcp"));
+        assert_eq!(Preamble::new("[::1]:80").host_port().unwrap(), ("::1", 80));
+        assert!(Preamble::new("db.internal").host_port().is_err());
+    }
+
+    #[test]
+    fn test_invalid_preamble() {
+        let read = |buf: Vec<u8>| block_on(Preamble::read(&mut Cursor::new(buf)));
+
+        assert!(read(b"GET / HTTP/1.1\r\n".to_vec()).is_err());
+
+        let mut buf = Preamble::new("127.0.0.1:80").encode().unwrap();
+
+        buf[2] = 2;
+
+        assert_eq!(read(buf).unwrap_err().kind(), ErrorKind::Unsupported);
+
+        let mut buf = Preamble::new("127.0.0.1:80").encode().unwrap();
+
+        buf.pop();
+
+        assert!(read(buf).is_err());
+    }
+
+    #[test]
+    fn test_preamble_status() {
+        assert!(Preamble::new("db.internal:5432").reply().wants_reply());
+        assert!(!Preamble::new("db.internal:5432").wants_reply());
+
+        let mut buf = vec![];
+
+        block_on(PreambleStatus::Unreachable.write(&mut buf)).unwrap();
+
+        buf.extend_from_slice(b"payload");
+
+        let mut reader = Cursor::new(buf);
+
+        assert_eq!(
+            block_on(PreambleStatus::read(&mut reader)).unwrap(),
+            PreambleStatus::Unreachable
+        );
+
+        let mut rest = vec![];
+
+        block_on(reader.read_to_end(&mut rest)).unwrap();
+
+        assert_eq!(rest, b"payload");
+
+        let read = |buf: &[u8]| block_on(PreambleStatus::read(&mut Cursor::new(buf.to_vec())));
+
+        assert!(read(b"N3\x01\x04").is_err());
+        assert!(read(b"HTTP").is_err());
+        assert!(read(b"N3\x01").is_err());
+    }
+}
@@ -0,0 +1,254 @@
+//! Batching of the outgoing packets of a connection.
+
+use std::{io::Result, net::SocketAddr, time::Instant};
+
+use n3io::{net::Transmit, timeout::sleep_with};
+use quiche::SendInfo;
+
+use crate::{QuicConnDispatcher, QuicConnDispatcherExt, QuicPacing};
+
+/// The maximum number of packets written into one batch.
+const MAX_BATCH_PACKETS: usize = 64;
+
+/// The maximum payload of a udp datagram over IPv4, a GSO datagram must not exceed it.
+const MAX_GSO_DATAGRAM_LEN: usize = 65507;
+
+/// The packets written by one round of [`SendBatch::fill`], stored back to back.
+pub(crate) struct SendBatch {
+    /// the packets, grown with the maximum packet size of the connection.
+    buf: Vec<u8>,
+    /// `(offset, len, send_info)` of the packets in `buf`.
+    packets: Vec<(usize, usize, SendInfo)>,
+    /// the packet written by the last round, which is paced for a later batch.
+    deferred: Option<(usize, usize, SendInfo)>,
+    /// the send time passed to the kernel, set by [`QuicPacing::TxTime`].
+    txtime: Option<Instant>,
+}
+
+impl SendBatch {
+    /// Create a new empty batch.
+    pub(crate) fn new() -> Self {
+        Self {
+            buf: vec![],
+            packets: Vec::with_capacity(MAX_BATCH_PACKETS),
+            deferred: None,
+            txtime: None,
+        }
+    }
+
+    /// Waits for the next packet of `dispatcher`, then writes the packets that can be sent now,
+    /// up to quiche's `send_quantum` bytes.
+    ///
+    /// The batch is released at the `SendInfo::at` time of its first packet, as the pacing of the
+    /// connection requires, the packets due more than one reactor tick later are left to the next
+    /// batch.
+    pub(crate) async fn fill(&mut self, dispatcher: &QuicConnDispatcher) -> Result<()> {
+        self.packets.clear();
+        self.txtime = None;
+
+        if let Some((offset, send_size, send_info)) = self.deferred.take() {
+            self.buf.copy_within(offset..offset + send_size, 0);
+            self.packets.push((0, send_size, send_info));
+        } else {
+            let max = self.reserve(dispatcher);
+            let (send_size, send_info) = dispatcher.send(&mut self.buf[..max]).await?;
+            self.packets.push((0, send_size, send_info));
+        }
+
+        // the handshake may have raised the maximum packet size while waiting.
+        let max = self.reserve(dispatcher);
+
+        let (pacing, reactor) = {
+            let state = dispatcher.0.lock().unwrap();
+            (state.pacing, state.reactor.clone())
+        };
+
+        let release = self.packets[0].2.at;
+        let window = release + reactor.tick_interval();
+
+        let send_quantum = dispatcher.send_quantum();
+
+        let mut offset = self.packets[0].1;
+
+        while self.packets.len() < MAX_BATCH_PACKETS && offset < send_quantum {
+            let Some((send_size, send_info)) =
+                dispatcher.try_send(&mut self.buf[offset..offset + max])?
+            else {
+                break;
+            };
+
+            if pacing != QuicPacing::Off && send_info.at > window {
+                self.deferred = Some((offset, send_size, send_info));
+                break;
+            }
+
+            self.packets.push((offset, send_size, send_info));
+
+            offset += send_size;
+        }
+
+        let now = Instant::now();
+
+        if release > now {
+            match pacing {
+                QuicPacing::Off => {}
+                QuicPacing::Timer => sleep_with(release - now, reactor).await,
+                QuicPacing::TxTime => self.txtime = Some(release),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Grows `buf` to hold [`MAX_BATCH_PACKETS`] packets of the current maximum size, which is
+    /// only known once the peer's transport parameters are received.
+    ///
+    /// Returns the maximum size of one packet.
+    fn reserve(&mut self, dispatcher: &QuicConnDispatcher) -> usize {
+        let max = dispatcher.max_send_udp_payload_size();
+
+        if self.buf.len() < max * MAX_BATCH_PACKETS {
+            self.buf.resize(max * MAX_BATCH_PACKETS, 0);
+        }
+
+        max
+    }
+
+    /// Returns the packets grouped by source address, in the order they were written.
+    ///
+    /// Consecutive packets of the same path are coalesced into GSO segments, up to
+    /// `max_gso_segments` and [`MAX_GSO_DATAGRAM_LEN`] bytes per [`Transmit`].
+    pub(crate) fn transmits(
+        &self,
+        max_gso_segments: usize,
+    ) -> Vec<(SocketAddr, Vec<Transmit<'_>>)> {
+        let mut groups: Vec<(SocketAddr, Vec<Transmit<'_>>)> = vec![];
+        let mut pending: Option<Segments> = None;
+
+        for &(offset, len, send_info) in &self.packets {
+            if let Some(segments) = &mut pending {
+                if segments.from == send_info.from
+                    && segments.to == send_info.to
+                    && segments.count < max_gso_segments
+                    && segments.end - segments.offset + len <= MAX_GSO_DATAGRAM_LEN
+                    && len <= segments.segment_size
+                {
+                    segments.end += len;
+                    segments.count += 1;
+
+                    // only the last segment may be shorter.
+                    if len < segments.segment_size {
+                        self.push(&mut groups, pending.take().unwrap());
+                    }
+
+                    continue;
+                }
+
+                self.push(&mut groups, pending.take().unwrap());
+            }
+
+            pending = Some(Segments {
+                from: send_info.from,
+                to: send_info.to,
+                offset,
+                end: offset + len,
+                segment_size: len,
+                count: 1,
+            });
+        }
+
+        if let Some(segments) = pending {
+            self.push(&mut groups, segments);
+        }
+
+        groups
+    }
+
+    fn push<'a>(&'a self, groups: &mut Vec<(SocketAddr, Vec<Transmit<'a>>)>, segments: Segments) {
+        let transmit = Transmit {
+            buf: &self.buf[segments.offset..segments.end],
+            to: segments.to,
+            segment_size: if segments.count > 1 {
+                Some(segments.segment_size)
+            } else {
+                None
+            },
+            txtime: self.txtime,
+            // set by `UdpGroupSender` if `from` is received by a wildcard socket.
+            src_ip: None,
+            // quiche doesn't report the ECN counts in its ACK frames, so the peer can't react to
+            // the congestion marks, the packets are sent as not ECN-capable.
+            ecn: None,
+        };
+
+        match groups.last_mut() {
+            Some((from, transmits)) if *from == segments.from => transmits.push(transmit),
+            _ => groups.push((segments.from, vec![transmit])),
+        }
+    }
+}
+
+/// Consecutive packets of one path, sent as a single [`Transmit`].
+struct Segments {
+    from: SocketAddr,
+    to: SocketAddr,
+    offset: usize,
+    end: usize,
+    segment_size: usize,
+    count: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transmits() {
+        let from = "127.0.0.1:1000".parse().unwrap();
+        let to = "127.0.0.1:1001".parse().unwrap();
+        let at = Instant::now();
+
+        let len = 1350;
+
+        let batch = SendBatch {
+            buf: vec![0; len * MAX_BATCH_PACKETS],
+            packets: (0..MAX_BATCH_PACKETS)
+                .map(|i| (i * len, len, SendInfo { from, to, at }))
+                .collect(),
+            deferred: None,
+            txtime: None,
+        };
+
+        let groups = batch.transmits(MAX_BATCH_PACKETS);
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].0, from);
+
+        let transmits = &groups[0].1;
+
+        // 64 segments of 1350 bytes exceed the maximum udp payload.
+        let segments = MAX_GSO_DATAGRAM_LEN / len;
+
+        assert_eq!(transmits.len(), 2);
+        assert_eq!(transmits[0].buf.len(), segments * len);
+        assert_eq!(transmits[1].buf.len(), (MAX_BATCH_PACKETS - segments) * len);
+
+        for transmit in transmits {
+            assert_eq!(transmit.to, to);
+            assert_eq!(transmit.segment_size, Some(len));
+        }
+
+        // a short packet ends the segments.
+        let mut batch = batch;
+        batch.packets.truncate(3);
+        batch.packets[1].1 = 100;
+
+        let groups = batch.transmits(MAX_BATCH_PACKETS);
+        let transmits = &groups[0].1;
+
+        assert_eq!(transmits.len(), 2);
+        assert_eq!(transmits[0].buf.len(), len + 100);
+        assert_eq!(transmits[1].buf.len(), len);
+        assert_eq!(transmits[1].segment_size, None);
+    }
+}
@@ -0,0 +1,257 @@
+//! Generators of the source [`ConnectionId`]s, including the [`QUIC-LB`] encodings.
+//!
+//! [`QUIC-LB`]: https://datatracker.ietf.org/doc/html/draft-ietf-quic-load-balancers
+
+use std::io::{Error, ErrorKind, Result};
+
+use boring::symm::{Cipher, Crypter, Mode};
+use quiche::ConnectionId;
+
+use crate::random_conn_id;
+
+/// The maximum length of a connection id.
+const MAX_CONN_ID_LEN: usize = 20;
+
+/// The config rotation bits `0b111` are reserved for unroutable connection ids.
+const MAX_CONFIG_ID: u8 = 6;
+
+/// Connection id generation trait.
+pub trait ConnectionIdGenerator {
+    /// Create a new source connection id.
+    fn generate(&self) -> ConnectionId<'static>;
+
+    /// The length of the generated ids, used to parse the short header packets.
+    fn conn_id_len(&self) -> usize {
+        MAX_CONN_ID_LEN
+    }
+}
+
+/// A default implementation for [`ConnectionIdGenerator`], creates random 20 bytes ids.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RandomConnectionIdGenerator;
+
+impl ConnectionIdGenerator for RandomConnectionIdGenerator {
+    fn generate(&self) -> ConnectionId<'static> {
+        random_conn_id()
+    }
+}
+
+/// The encoding mode of [`QuicLbConnectionIdGenerator`].
+#[derive(Clone, PartialEq, Eq)]
+enum QuicLbMode {
+    /// `first octet | server id | nonce`.
+    Plaintext,
+    /// `first octet | encrypted nonce | encrypted server id`, with a 16 bytes AES key.
+    StreamCipher([u8; 16]),
+}
+
+/// A `QUIC-LB` connection id generator, the ids encode the server id of this instance, so a
+/// load balancer sharing the config can route the packets back to it.
+///
+/// The first octet holds the 3 config rotation bits and the length of the rest of the id.
+#[derive(Clone)]
+pub struct QuicLbConnectionIdGenerator {
+    config_id: u8,
+    server_id: Vec<u8>,
+    nonce_len: usize,
+    mode: QuicLbMode,
+}
+
+impl std::fmt::Debug for QuicLbConnectionIdGenerator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QuicLbConnectionIdGenerator")
+            .field("config_id", &self.config_id)
+            .field("server_id", &self.server_id)
+            .field("nonce_len", &self.nonce_len)
+            .field("encrypted", &(self.mode != QuicLbMode::Plaintext))
+            .finish()
+    }
+}
+
+impl QuicLbConnectionIdGenerator {
+    /// Create a plaintext mode generator, the server id is visible on the wire.
+    ///
+    /// `config_id` is in `0..=6`, `server_id` and the random nonce fit in 19 bytes.
+    pub fn plaintext(config_id: u8, server_id: &[u8], nonce_len: usize) -> Result<Self> {
+        Self::new(config_id, server_id, nonce_len, QuicLbMode::Plaintext)
+    }
+
+    /// Create a stream cipher mode generator, the server id is encrypted with `key`.
+    ///
+    /// `nonce_len` is in `8..=16`.
+    pub fn stream_cipher(
+        config_id: u8,
+        server_id: &[u8],
+        nonce_len: usize,
+        key: [u8; 16],
+    ) -> Result<Self> {
+        if !(8..=16).contains(&nonce_len) {
+            return Err(invalid("stream cipher nonce length must be in `8..=16`"));
+        }
+
+        Self::new(
+            config_id,
+            server_id,
+            nonce_len,
+            QuicLbMode::StreamCipher(key),
+        )
+    }
+
+    fn new(config_id: u8, server_id: &[u8], nonce_len: usize, mode: QuicLbMode) -> Result<Self> {
+        if config_id > MAX_CONFIG_ID {
+            return Err(invalid("config id must be in `0..=6`"));
+        }
+
+        if server_id.is_empty() || nonce_len == 0 {
+            return Err(invalid("server id and nonce must not be empty"));
+        }
+
+        if 1 + server_id.len() + nonce_len > MAX_CONN_ID_LEN {
+            return Err(invalid("connection id is longer than 20 bytes"));
+        }
+
+        Ok(Self {
+            config_id,
+            server_id: server_id.to_vec(),
+            nonce_len,
+            mode,
+        })
+    }
+
+    /// Extract the server id from `conn_id`, as the load balancer does.
+    ///
+    /// Returns `None` if `conn_id` is not encoded by this config.
+    pub fn decode_server_id(&self, conn_id: &[u8]) -> Option<Vec<u8>> {
+        let first = *conn_id.first()?;
+
+        if first >> 5 != self.config_id || conn_id.len() < self.conn_id_len() {
+            return None;
+        }
+
+        let body = &conn_id[1..self.conn_id_len()];
+
+        match &self.mode {
+            QuicLbMode::Plaintext => Some(body[..self.server_id.len()].to_vec()),
+            QuicLbMode::StreamCipher(key) => {
+                let (nonce, server_id) = body.split_at(self.nonce_len);
+
+                let mut nonce = nonce.to_vec();
+                let mut server_id = server_id.to_vec();
+
+                // reverse of the three passes of `generate`.
+                xor_pad(&mut server_id, &aes_ecb(key, &nonce));
+                xor_pad(&mut nonce, &aes_ecb(key, &server_id));
+                xor_pad(&mut server_id, &aes_ecb(key, &nonce));
+
+                Some(server_id)
+            }
+        }
+    }
+}
+
+impl ConnectionIdGenerator for QuicLbConnectionIdGenerator {
+    fn conn_id_len(&self) -> usize {
+        1 + self.server_id.len() + self.nonce_len
+    }
+
+    fn generate(&self) -> ConnectionId<'static> {
+        let mut nonce = vec![0; self.nonce_len];
+        boring::rand::rand_bytes(&mut nonce).unwrap();
+
+        let first = self.config_id << 5 | (self.conn_id_len() - 1) as u8;
+
+        let mut buf = Vec::with_capacity(self.conn_id_len());
+
+        buf.push(first);
+
+        match &self.mode {
+            QuicLbMode::Plaintext => {
+                buf.extend_from_slice(&self.server_id);
+                buf.extend_from_slice(&nonce);
+            }
+            QuicLbMode::StreamCipher(key) => {
+                let mut server_id = self.server_id.clone();
+
+                xor_pad(&mut server_id, &aes_ecb(key, &nonce));
+                xor_pad(&mut nonce, &aes_ecb(key, &server_id));
+                xor_pad(&mut server_id, &aes_ecb(key, &nonce));
+
+                buf.extend_from_slice(&nonce);
+                buf.extend_from_slice(&server_id);
+            }
+        }
+
+        ConnectionId::from_vec(buf)
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("QUIC-LB: {}", reason))
+}
+
+/// XOR `buf` with the leading bytes of `pad`.
+fn xor_pad(buf: &mut [u8], pad: &[u8; 16]) {
+    for (byte, pad) in buf.iter_mut().zip(pad) {
+        *byte ^= pad;
+    }
+}
+
+/// Encrypt `input` zero-padded to a single block with AES-128-ECB.
+fn aes_ecb(key: &[u8; 16], input: &[u8]) -> [u8; 16] {
+    let mut block = [0; 16];
+    block[..input.len()].copy_from_slice(input);
+
+    let mut crypter = Crypter::new(Cipher::aes_128_ecb(), Mode::Encrypt, key, None).unwrap();
+    crypter.pad(false);
+
+    let mut out = [0; 32];
+    let len = crypter.update(&block, &mut out).unwrap();
+
+    out[..len].try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quic_lb_plaintext() {
+        let generator = QuicLbConnectionIdGenerator::plaintext(1, &[0x0a, 0x0b], 8).unwrap();
+
+        let conn_id = generator.generate();
+
+        assert_eq!(conn_id.len(), 11);
+        assert_eq!(generator.conn_id_len(), 11);
+        assert_eq!(conn_id[0], 1 << 5 | 10);
+        assert_eq!(&conn_id[1..3], &[0x0a, 0x0b]);
+        assert_eq!(generator.decode_server_id(&conn_id), Some(vec![0x0a, 0x0b]));
+
+        // another config.
+        let other = QuicLbConnectionIdGenerator::plaintext(2, &[0x0a, 0x0b], 8).unwrap();
+        assert_eq!(other.decode_server_id(&conn_id), None);
+
+        assert!(QuicLbConnectionIdGenerator::plaintext(7, &[1], 8).is_err());
+        assert!(QuicLbConnectionIdGenerator::plaintext(0, &[1; 15], 8).is_err());
+        assert!(QuicLbConnectionIdGenerator::plaintext(0, &[], 8).is_err());
+    }
+
+    #[test]
+    fn test_quic_lb_stream_cipher() {
+        let key = [0x4d; 16];
+        let server_id = [0x12, 0x34, 0x56];
+
+        let generator = QuicLbConnectionIdGenerator::stream_cipher(0, &server_id, 10, key).unwrap();
+
+        let conn_id = generator.generate();
+
+        assert_eq!(conn_id.len(), 14);
+        assert_eq!(conn_id[0], 13);
+        assert_eq!(
+            generator.decode_server_id(&conn_id),
+            Some(server_id.to_vec())
+        );
+
+        assert!(QuicLbConnectionIdGenerator::stream_cipher(0, &server_id, 7, key).is_err());
+        assert!(QuicLbConnectionIdGenerator::stream_cipher(0, &[1; 4], 16, key).is_err());
+    }
+}
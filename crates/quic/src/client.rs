@@ -1,20 +1,89 @@
 use std::{
+    collections::HashMap,
+    future::poll_fn,
     io::{Error, ErrorKind, Result},
     net::{SocketAddr, ToSocketAddrs},
-    sync::Arc,
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
+use futures::{
+    StreamExt,
+    future::{Either, select},
+    stream::FuturesUnordered,
+};
 use n3_spawner::spawn;
-use n3io::{net::UdpSocket, reactor::Reactor, timeout::TimeoutExt};
+use n3io::{
+    dns::Resolver,
+    net::UdpSocket,
+    reactor::Reactor,
+    timeout::{TimeoutExt, sleep_with},
+};
 use quiche::{ConnectionId, RecvInfo};
 use rand::{rng, seq::SliceRandom};
 
-use crate::{QuicConn, QuicConnDispatcher, QuicConnDispatcherExt, random_conn_id};
+use crate::{
+    ConnectionIdGenerator, QuicConn, QuicConnDispatcher, QuicConnDispatcherExt, QuicPacing,
+    QuicSessionCache, RandomConnectionIdGenerator, batch::SendBatch, conn::active_path,
+    reset_token,
+};
 
 struct QuicConnectConfig {
     quiche_config: quiche::Config,
     server_name: Option<String>,
     raddrs: Vec<SocketAddr>,
+    /// the host name of `raddrs`, which is resolved again when the TTL expires.
+    host: Option<(String, u16)>,
+    /// the TTL expiration of `raddrs`.
+    resolved_until: Option<Instant>,
+    resolver: Option<Resolver>,
+    conn_id_generator: Arc<dyn ConnectionIdGenerator + Sync + Send>,
+    session_cache: Option<QuicSessionCache>,
+    early_data: bool,
+    /// pacing of the connections.
+    pacing: QuicPacing,
+    /// delay between the racing connection attempts.
+    attempt_delay: Duration,
+    /// how long a failed address is tried after the others.
+    blacklist_timeout: Duration,
+    /// failed addresses and their expiration.
+    blacklist: HashMap<SocketAddr, Instant>,
+}
+
+/// Returns the addresses in the order of attempts.
+///
+/// The addresses are shuffled, the families are interleaved with IPv6 first (RFC 8305),
+/// the blacklisted addresses are tried last.
+fn attempt_order(
+    raddrs: &mut [SocketAddr],
+    blacklist: &mut HashMap<SocketAddr, Instant>,
+) -> Vec<SocketAddr> {
+    let now = Instant::now();
+
+    blacklist.retain(|_, expiration| *expiration > now);
+
+    raddrs.shuffle(&mut rng());
+
+    let (blacklisted, raddrs): (Vec<_>, Vec<_>) = raddrs
+        .iter()
+        .partition(|raddr| blacklist.contains_key(raddr));
+
+    let (mut ipv6, mut ipv4): (Vec<_>, Vec<_>) = raddrs.into_iter().partition(SocketAddr::is_ipv6);
+
+    let mut attempts = Vec::with_capacity(ipv6.len() + ipv4.len() + blacklisted.len());
+
+    ipv6.reverse();
+    ipv4.reverse();
+
+    while !ipv6.is_empty() || !ipv4.is_empty() {
+        attempts.extend(ipv6.pop());
+        attempts.extend(ipv4.pop());
+    }
+
+    attempts.extend(blacklisted);
+
+    attempts
 }
 
 /// A builder for quic client sockets.
@@ -26,9 +95,19 @@ impl QuicConnector {
         Self(raddrs.to_socket_addrs().and_then(|iter| {
             Ok(QuicConnectConfig {
                 raddrs: iter.collect(),
+                host: None,
+                resolved_until: None,
+                resolver: None,
                 quiche_config: quiche::Config::new(quiche::PROTOCOL_VERSION)
                     .map_err(Error::other)?,
                 server_name: None,
+                conn_id_generator: Arc::new(RandomConnectionIdGenerator),
+                session_cache: None,
+                early_data: false,
+                pacing: QuicPacing::default(),
+                attempt_delay: Duration::from_millis(250),
+                blacklist_timeout: Duration::from_secs(10),
+                blacklist: Default::default(),
             })
         }))
     }
@@ -38,12 +117,83 @@ impl QuicConnector {
         Self(raddrs.to_socket_addrs().and_then(|iter| {
             Ok(QuicConnectConfig {
                 raddrs: iter.collect(),
+                host: None,
+                resolved_until: None,
+                resolver: None,
                 quiche_config,
                 server_name: None,
+                conn_id_generator: Arc::new(RandomConnectionIdGenerator),
+                session_cache: None,
+                early_data: false,
+                pacing: QuicPacing::default(),
+                attempt_delay: Duration::from_millis(250),
+                blacklist_timeout: Duration::from_secs(10),
+                blacklist: Default::default(),
             })
         }))
     }
 
+    /// Create a new `QuicConnector` instance of `host`, which is resolved asynchronously by
+    /// [`connect_with`](Self::connect_with) and again when the TTL of its records expires.
+    ///
+    /// `host` is also used as the default `server_name`.
+    pub fn with_host(host: impl AsRef<str>, port: u16) -> Self {
+        let host = host.as_ref();
+
+        Self::new_with_host(
+            host,
+            port,
+            quiche::Config::new(quiche::PROTOCOL_VERSION).map_err(Error::other),
+        )
+    }
+
+    /// Create a new `QuicConnector` instance of `host`, see [`with_host`](Self::with_host).
+    pub fn with_host_and_config(
+        host: impl AsRef<str>,
+        port: u16,
+        quiche_config: quiche::Config,
+    ) -> Self {
+        Self::new_with_host(host.as_ref(), port, Ok(quiche_config))
+    }
+
+    fn new_with_host(host: &str, port: u16, quiche_config: Result<quiche::Config>) -> Self {
+        Self(quiche_config.map(|quiche_config| {
+            // an ip literal is not a valid server name.
+            let server_name = host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<std::net::IpAddr>()
+                .is_err()
+                .then(|| host.to_owned());
+
+            QuicConnectConfig {
+                raddrs: vec![],
+                host: Some((host.to_owned(), port)),
+                resolved_until: None,
+                resolver: None,
+                quiche_config,
+                server_name,
+                conn_id_generator: Arc::new(RandomConnectionIdGenerator),
+                session_cache: None,
+                early_data: false,
+                pacing: QuicPacing::default(),
+                attempt_delay: Duration::from_millis(250),
+                blacklist_timeout: Duration::from_secs(10),
+                blacklist: Default::default(),
+            }
+        }))
+    }
+
+    /// Update the DNS resolver of [`with_host`](Self::with_host) instances.
+    ///
+    /// The default one is created from the system configuration on the first connection.
+    pub fn resolver(self, resolver: Resolver) -> Self {
+        Self(self.0.and_then(|mut config| {
+            config.resolver = Some(resolver);
+            Ok(config)
+        }))
+    }
+
     /// Configure the `server_name` parameter, which is used to verify the peer's
     /// certificate.
     pub fn server_name(self, name: impl AsRef<str>) -> Self {
@@ -53,6 +203,69 @@ impl QuicConnector {
         }))
     }
 
+    /// Update the generator of source connection ids, default is [`RandomConnectionIdGenerator`].
+    pub fn conn_id_generator<G>(self, generator: G) -> Self
+    where
+        G: ConnectionIdGenerator + Sync + Send + 'static,
+    {
+        Self(self.0.and_then(|mut config| {
+            config.conn_id_generator = Arc::new(generator);
+            Ok(config)
+        }))
+    }
+
+    /// Update the TLS session cache, default is `None`, a full handshake for each connection.
+    ///
+    /// Set to a [`QuicSessionCache`] to resume the sessions of the previous connections.
+    pub fn session_cache(self, cache: Option<QuicSessionCache>) -> Self {
+        Self(self.0.and_then(|mut config| {
+            config.session_cache = cache;
+            Ok(config)
+        }))
+    }
+
+    /// Send `0-RTT` data on the resumed connections, default is `false`.
+    ///
+    /// The connection is returned once the server answers, before the handshake completes, the
+    /// early data may be replayed by an attacker, see [`ZeroRttPolicy`](crate::ZeroRttPolicy) of
+    /// the server side. Requires a [`session_cache`](Self::session_cache).
+    pub fn early_data(self, enable: bool) -> Self {
+        Self(self.0.and_then(|mut config| {
+            if enable {
+                config.quiche_config.enable_early_data();
+            }
+
+            config.early_data = enable;
+            Ok(config)
+        }))
+    }
+
+    /// Update the pacing of the connections, default is [`QuicPacing::Off`].
+    pub fn pacing(self, pacing: QuicPacing) -> Self {
+        Self(self.0.and_then(|mut config| {
+            config.pacing = pacing;
+            Ok(config)
+        }))
+    }
+
+    /// Update the delay before racing the next address, default is `250ms`.
+    ///
+    /// The next address is tried at once if the previous attempts failed.
+    pub fn attempt_delay(self, delay: Duration) -> Self {
+        Self(self.0.and_then(|mut config| {
+            config.attempt_delay = delay;
+            Ok(config)
+        }))
+    }
+
+    /// Update how long a failed address is tried after the others, default is `10s`.
+    pub fn blacklist_timeout(self, timeout: Duration) -> Self {
+        Self(self.0.and_then(|mut config| {
+            config.blacklist_timeout = timeout;
+            Ok(config)
+        }))
+    }
+
     /// Update quic config.
     pub fn quiche_config<F>(self, f: F) -> Self
     where
@@ -73,7 +286,11 @@ impl QuicConnector {
         self.connect_with(global_reactor().clone()).await
     }
 
-    /// Create a new client socket and issue a non-blocking connect to a random address in the address pool.
+    /// Create a new client socket and race the connection attempts to the addresses in the pool.
+    ///
+    /// The attempts are started one by one with [`attempt_delay`](Self::attempt_delay), the first
+    /// established connection is returned and the others are cancelled. Failed addresses are
+    /// blacklisted for [`blacklist_timeout`](Self::blacklist_timeout).
     ///
     /// see [`QuicConn::connect`]
     pub async fn connect_with(&mut self, reactor: Reactor) -> Result<QuicConn> {
@@ -82,15 +299,158 @@ impl QuicConnector {
             .as_mut()
             .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?;
 
-        config.raddrs.shuffle(&mut rng());
+        config.resolve(&reactor).await?;
 
-        QuicConn::connect_with(
-            config.server_name.as_deref(),
-            config.raddrs[0],
-            &mut config.quiche_config,
-            reactor,
-        )
-        .await
+        let mut raddrs = attempt_order(&mut config.raddrs, &mut config.blacklist)
+            .into_iter()
+            .peekable();
+
+        if raddrs.peek().is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "QuicConnector: empty address pool",
+            ));
+        }
+
+        let options = ConnectOptions {
+            conn_id_generator: config.conn_id_generator.clone(),
+            session_cache: config.session_cache.clone(),
+            early_data: config.early_data,
+            pacing: config.pacing,
+        };
+
+        let server_name = config.server_name.as_deref();
+        let quiche_config = Mutex::new(&mut config.quiche_config);
+
+        let attempt = |raddr: SocketAddr| {
+            let reactor = reactor.clone();
+            let quiche_config = &quiche_config;
+            let options = &options;
+
+            async move {
+                let result =
+                    QuicConn::connect_prv(server_name, raddr, quiche_config, reactor, options)
+                        .await;
+
+                (raddr, result)
+            }
+        };
+
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
+
+        loop {
+            if attempts.is_empty() {
+                match raddrs.next() {
+                    Some(raddr) => attempts.push(attempt(raddr)),
+                    None => break,
+                }
+            }
+
+            let next = if raddrs.peek().is_some() {
+                match select(
+                    attempts.next(),
+                    pin!(sleep_with(config.attempt_delay, reactor.clone())),
+                )
+                .await
+                {
+                    Either::Left((next, _)) => next,
+                    Either::Right(_) => {
+                        // the pending attempts are too slow, race the next address.
+                        attempts.extend(raddrs.next().map(attempt));
+                        continue;
+                    }
+                }
+            } else {
+                attempts.next().await
+            };
+
+            // Safety: `attempts` is not empty.
+            let (raddr, result) = next.unwrap();
+
+            match result {
+                Ok(conn) => {
+                    config.blacklist.remove(&raddr);
+                    return Ok(conn);
+                }
+                Err(err) => {
+                    log::warn!(
+                        "QuicConnector(connect) attempt failed, raddr={}, err={}",
+                        raddr,
+                        err
+                    );
+
+                    config
+                        .blacklist
+                        .insert(raddr, Instant::now() + config.blacklist_timeout);
+
+                    last_err = Some(err);
+
+                    // start the next attempt at once.
+                    attempts.extend(raddrs.next().map(attempt));
+                }
+            }
+        }
+
+        // Safety: at least one attempt is made.
+        Err(last_err.unwrap())
+    }
+}
+
+impl QuicConnectConfig {
+    /// Resolve the host again if the TTL of `raddrs` has expired.
+    ///
+    /// On failure the stale addresses are kept, if there are any.
+    async fn resolve(&mut self, reactor: &Reactor) -> Result<()> {
+        let Some((host, port)) = &self.host else {
+            return Ok(());
+        };
+
+        if self
+            .resolved_until
+            .is_some_and(|deadline| deadline > Instant::now())
+        {
+            return Ok(());
+        }
+
+        let resolver = match &self.resolver {
+            Some(resolver) => resolver.clone(),
+            None => {
+                let resolver = Resolver::with_system_conf(reactor.clone())?;
+                self.resolver = Some(resolver.clone());
+                resolver
+            }
+        };
+
+        match resolver.lookup(host).await {
+            Ok(lookup) => {
+                log::trace!(
+                    "QuicConnector(resolve) host={}, addrs={:?}",
+                    host,
+                    lookup.addrs
+                );
+
+                self.raddrs = lookup
+                    .addrs
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, *port))
+                    .collect();
+
+                self.resolved_until = Some(lookup.valid_until);
+
+                Ok(())
+            }
+            Err(err) if !self.raddrs.is_empty() => {
+                log::warn!(
+                    "QuicConnector(resolve) use the stale addresses, host={}, err={}",
+                    host,
+                    err
+                );
+
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
     }
 }
 
@@ -113,6 +473,24 @@ impl QuicConn {
         raddr: SocketAddr,
         config: &mut quiche::Config,
         reactor: Reactor,
+    ) -> Result<Self> {
+        Self::connect_prv(
+            server_name,
+            raddr,
+            &Mutex::new(config),
+            reactor,
+            &Default::default(),
+        )
+        .await
+    }
+
+    /// `config` is shared by the racing attempts, which lock it to create the connection.
+    async fn connect_prv(
+        server_name: Option<&str>,
+        raddr: SocketAddr,
+        config: &Mutex<&mut quiche::Config>,
+        reactor: Reactor,
+        options: &ConnectOptions,
     ) -> Result<Self> {
         let laddr: SocketAddr = if raddr.is_ipv4() {
             "0.0.0.0:0".parse().unwrap()
@@ -124,23 +502,51 @@ impl QuicConn {
 
         let laddr = udp_socket.mio_socket().local_addr()?;
 
-        let scid = random_conn_id();
-
-        let quiche_conn =
-            quiche::connect(server_name, &scid, laddr, raddr, config).map_err(Error::other)?;
+        let scid = options.conn_id_generator.generate();
 
-        let max_send_udp_payload_size = quiche_conn.max_send_udp_payload_size();
+        let mut quiche_conn = quiche::connect(
+            server_name,
+            &scid,
+            laddr,
+            raddr,
+            &mut config.lock().unwrap(),
+        )
+        .map_err(Error::other)?;
+
+        // the key of the session, a ticket is only valid for the server that issued it.
+        let server = server_name
+            .map(str::to_owned)
+            .unwrap_or_else(|| raddr.to_string());
+
+        if let Some(session_cache) = &options.session_cache
+            && let Some(session) = session_cache.get(&server)
+        {
+            if let Err(err) = quiche_conn.set_session(&session) {
+                log::warn!(
+                    "QuicConnector(connect) ignore session, server={}, err={}",
+                    server,
+                    err
+                );
+            } else {
+                log::trace!("QuicConnector(connect) resume session, server={}", server);
+            }
+        }
 
-        log::trace!(
-            "quiche conn, id={}, max_send_udp_payload_size={}",
-            quiche_conn.trace_id(),
-            max_send_udp_payload_size
-        );
+        log::trace!("quiche conn, id={}", quiche_conn.trace_id());
 
-        let mut buf = vec![0; max_send_udp_payload_size];
+        // the server may send packets up to our `max_recv_udp_payload_size`, which is larger than
+        // the 1200 bytes the handshake starts with.
+        let mut buf = vec![0; 65527];
 
         let dispatcher = QuicConnDispatcher::new(quiche_conn, reactor.clone());
 
+        {
+            let mut state = dispatcher.0.lock().unwrap();
+            state.conn_id_generator = options.conn_id_generator.clone();
+            state.session_cache = options.session_cache.clone().map(|cache| (cache, server));
+            state.pacing = pacing(&udp_socket, options.pacing);
+        }
+
         loop {
             let (send_size, send_info) = dispatcher.send(&mut buf).await?;
 
@@ -172,52 +578,235 @@ impl QuicConn {
                 .await?;
 
             if dispatcher.is_established() {
-                let state = dispatcher.0.lock().unwrap();
-                let scid = state.quiche_conn.source_id().clone().into_owned();
-                let dcid = state.quiche_conn.destination_id().clone().into_owned();
                 log::info!(
-                    "QuicConnector(connect) established, from={}, to={}, scid={:?}, dcid={:?}",
+                    "QuicConnector(connect) established, from={}, to={}, resumed={}",
                     laddr,
                     raddr,
-                    scid,
-                    dcid
+                    dispatcher.0.lock().unwrap().quiche_conn.is_resumed()
                 );
 
-                let udp_socket = Arc::new(udp_socket);
+                save_session(&dispatcher);
 
-                spawn(client_recv_loop(
+                return start(laddr, udp_socket, dispatcher);
+            }
+
+            // the server has answered, the streams are sent as 0-RTT data until the handshake
+            // completes. returning before any answer would let a dead address win the race.
+            if options.early_data && dispatcher.0.lock().unwrap().quiche_conn.is_in_early_data() {
+                log::info!(
+                    "QuicConnector(connect) early data, from={}, to={}",
                     laddr,
-                    udp_socket.clone(),
-                    scid.clone(),
-                    dcid.clone(),
-                    dispatcher.clone(),
-                    max_send_udp_payload_size,
-                ))?;
-
-                spawn(client_send_loop(
-                    udp_socket,
-                    scid,
-                    dcid,
-                    dispatcher.clone(),
-                    max_send_udp_payload_size,
-                ))?;
-
-                return Ok(QuicConn(dispatcher.0.clone()));
+                    raddr,
+                );
+
+                return start(laddr, udp_socket, dispatcher);
             }
         }
     }
 }
 
+/// Options of the client connection, set by [`QuicConnector`].
+struct ConnectOptions {
+    conn_id_generator: Arc<dyn ConnectionIdGenerator + Sync + Send>,
+    session_cache: Option<QuicSessionCache>,
+    early_data: bool,
+    pacing: QuicPacing,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            conn_id_generator: Arc::new(RandomConnectionIdGenerator),
+            session_cache: None,
+            early_data: false,
+            pacing: QuicPacing::default(),
+        }
+    }
+}
+
+/// Start the io loops of a connected `udp_socket`.
+fn start(
+    laddr: SocketAddr,
+    udp_socket: UdpSocket,
+    dispatcher: QuicConnDispatcher,
+) -> Result<QuicConn> {
+    let state = dispatcher.0.lock().unwrap();
+    let scid = state.quiche_conn.source_id().clone().into_owned();
+    let dcid = state.quiche_conn.destination_id().clone().into_owned();
+    drop(state);
+
+    let udp_socket = Arc::new(udp_socket);
+
+    dispatcher
+        .0
+        .lock()
+        .unwrap()
+        .client_sockets
+        .insert(laddr, udp_socket.clone());
+
+    spawn(client_recv_loop(
+        laddr,
+        udp_socket,
+        scid.clone(),
+        dcid.clone(),
+        dispatcher.clone(),
+    ))?;
+
+    spawn(client_send_loop(scid, dcid, dispatcher.clone()))?;
+
+    Ok(QuicConn(dispatcher.0))
+}
+
+/// Returns the pacing of the connection, after enabling `SO_TXTIME` on `udp_socket` if required.
+fn pacing(udp_socket: &UdpSocket, pacing: QuicPacing) -> QuicPacing {
+    if pacing != QuicPacing::TxTime {
+        return pacing;
+    }
+
+    if let Err(err) = udp_socket.set_txtime() {
+        log::warn!("QuicConn(client): fall back to timer pacing, err={}", err);
+        return QuicPacing::Timer;
+    }
+
+    pacing
+}
+
+/// Update the session cache with the latest ticket of quiche, the server sends the tickets
+/// after the handshake.
+///
+/// [`QuicSessionCache::put`] compares the ticket bytes, an unchanged ticket is not written again.
+fn save_session(dispatcher: &QuicConnDispatcher) {
+    let state = dispatcher.0.lock().unwrap();
+
+    if let (Some((session_cache, server)), Some(session)) =
+        (&state.session_cache, state.quiche_conn.session())
+    {
+        session_cache.put(server, session);
+    }
+}
+
+impl QuicConn {
+    /// Bind a new udp socket to `laddr` and validate the path from it to the current peer address.
+    ///
+    /// Returns the bound local address once the path is validated, call [`migrate`](Self::migrate)
+    /// to move the connection to it. Probing requires a spare connection id issued by the peer.
+    pub async fn probe_path(&self, laddr: SocketAddr) -> Result<SocketAddr> {
+        let (reactor, peer, scid, dcid) = {
+            let state = self.0.lock().unwrap();
+
+            if state.quiche_conn.is_server() {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "only the client can probe new paths",
+                ));
+            }
+
+            let (_, peer) = active_path(&state.quiche_conn)
+                .ok_or_else(|| Error::new(ErrorKind::NotConnected, "no active path"))?;
+
+            (
+                state.reactor.clone(),
+                peer,
+                state.quiche_conn.source_id().into_owned(),
+                state.quiche_conn.destination_id().into_owned(),
+            )
+        };
+
+        let udp_socket = Arc::new(UdpSocket::bind_with(laddr, reactor).await?);
+
+        let laddr = udp_socket.mio_socket().local_addr()?;
+
+        {
+            let mut state = self.0.lock().unwrap();
+
+            if let Err(err) = state.quiche_conn.probe_path(laddr, peer) {
+                drop(state);
+                _ = udp_socket.shutdown();
+
+                return Err(Error::other(err));
+            }
+
+            state.client_sockets.insert(laddr, udp_socket.clone());
+            state.pacing = pacing(&udp_socket, state.pacing);
+
+            log::info!(
+                "QuicConn(client): probe path, from={}, to={}, trace_id={}",
+                laddr,
+                peer,
+                state.quiche_conn.trace_id()
+            );
+
+            let waker = state.send_waker.take();
+
+            drop(state);
+
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+
+        spawn(client_recv_loop(
+            laddr,
+            udp_socket.clone(),
+            scid,
+            dcid,
+            QuicConnDispatcher(self.0.clone()),
+        ))?;
+
+        if let Err(err) = poll_fn(|cx| self.poll_path_validated(cx, laddr, peer)).await {
+            self.0.lock().unwrap().client_sockets.remove(&laddr);
+            _ = udp_socket.shutdown();
+
+            return Err(err);
+        }
+
+        Ok(laddr)
+    }
+
+    /// Move the connection to the path from `laddr`, which is validated by
+    /// [`probe_path`](Self::probe_path).
+    ///
+    /// The streams are not affected, the socket of the previous path is kept to receive the
+    /// packets in flight.
+    pub fn migrate(&self, laddr: SocketAddr) -> Result<()> {
+        let mut state = self.0.lock().unwrap();
+
+        if !state.client_sockets.contains_key(&laddr) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("path from `{}` is not probed", laddr),
+            ));
+        }
+
+        state
+            .quiche_conn
+            .migrate_source(laddr)
+            .map_err(Error::other)?;
+
+        log::info!(
+            "QuicConn(client): migrate, from={}, trace_id={}",
+            laddr,
+            state.quiche_conn.trace_id()
+        );
+
+        let waker = state.send_waker.take();
+
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+
+        Ok(())
+    }
+}
+
 async fn client_send_loop(
-    udp_socket: Arc<UdpSocket>,
     scid: ConnectionId<'static>,
     dcid: ConnectionId<'static>,
     dispatcher: QuicConnDispatcher,
-    max_send_udp_payload_size: usize,
 ) {
-    if let Err(err) =
-        client_send_loop_prv(&udp_socket, &dispatcher, max_send_udp_payload_size).await
-    {
+    if let Err(err) = client_send_loop_prv(&dispatcher).await {
         log::error!(
             "QuicConn(client) send loop stopped, scid={:?}, dcid={:?}, err={}",
             scid,
@@ -232,26 +821,60 @@ async fn client_send_loop(
         );
     }
 
-    if let Err(err) = udp_socket.shutdown() {
-        log::error!(
-            "QuicConn(client): shutdown udp socket, scid={:?}, dcid={:?}, err={}",
-            scid,
-            dcid,
-            err
-        );
+    let udp_sockets = std::mem::take(&mut dispatcher.0.lock().unwrap().client_sockets);
+
+    for (laddr, udp_socket) in udp_sockets {
+        if let Err(err) = udp_socket.shutdown() {
+            log::error!(
+                "QuicConn(client): shutdown udp socket, scid={:?}, dcid={:?}, laddr={}, err={}",
+                scid,
+                dcid,
+                laddr,
+                err
+            );
+        }
     }
 }
 
-async fn client_send_loop_prv(
-    udp_socket: &UdpSocket,
-    dispatcher: &QuicConnDispatcher,
-    max_send_udp_payload_size: usize,
-) -> Result<()> {
-    let mut buf = vec![0; max_send_udp_payload_size];
+async fn client_send_loop_prv(dispatcher: &QuicConnDispatcher) -> Result<()> {
+    let mut batch = SendBatch::new();
     loop {
-        let (send_size, send_info) = dispatcher.send(&mut buf).await?;
+        batch.fill(dispatcher).await?;
+
+        let max_gso_segments = dispatcher
+            .0
+            .lock()
+            .unwrap()
+            .client_sockets
+            .values()
+            .map(|udp_socket| udp_socket.max_gso_segments())
+            .min()
+            .unwrap_or(1);
+
+        // each path is sent from the socket bound to its local address.
+        for (from, transmits) in batch.transmits(max_gso_segments) {
+            let udp_socket = dispatcher
+                .0
+                .lock()
+                .unwrap()
+                .client_sockets
+                .get(&from)
+                .cloned();
+
+            let Some(udp_socket) = udp_socket else {
+                log::warn!(
+                    "QuicConn(client): drop packets of closed path, from={}, transmits={}",
+                    from,
+                    transmits.len()
+                );
+                continue;
+            };
 
-        udp_socket.send_to(&buf[..send_size], send_info.to).await?;
+            // a broken path must not stop the others, the connection times out if all are broken.
+            if let Err(err) = udp_socket.send_batch(&transmits).await {
+                log::error!("QuicConn(client): send packets, from={}, err={}", from, err);
+            }
+        }
     }
 }
 
@@ -261,11 +884,8 @@ async fn client_recv_loop(
     scid: ConnectionId<'static>,
     dcid: ConnectionId<'static>,
     dispatcher: QuicConnDispatcher,
-    max_send_udp_payload_size: usize,
 ) {
-    if let Err(err) =
-        client_recv_loop_prv(laddr, udp_socket, &dispatcher, max_send_udp_payload_size).await
-    {
+    if let Err(err) = client_recv_loop_prv(laddr, udp_socket, &dispatcher).await {
         log::error!(
             "QuicConn(client) recv loop stopped, scid={:?}, dcid={:?}, err={}",
             scid,
@@ -285,14 +905,96 @@ async fn client_recv_loop_prv(
     laddr: SocketAddr,
     udp_socket: Arc<UdpSocket>,
     dispatcher: &QuicConnDispatcher,
-    max_send_udp_payload_size: usize,
 ) -> Result<()> {
-    let mut buf = vec![0; max_send_udp_payload_size];
+    let mut buf = vec![0; 65527];
     loop {
         let (recv_size, from) = udp_socket.recv_from(&mut buf).await?;
 
         dispatcher
             .recv(&mut buf[..recv_size], RecvInfo { from, to: laddr })
             .await?;
+
+        issue_conn_ids(dispatcher);
+
+        save_session(dispatcher);
+    }
+}
+
+/// Issue spare source connection ids, which the server needs to validate the probed paths.
+fn issue_conn_ids(dispatcher: &QuicConnDispatcher) {
+    let mut state = dispatcher.0.lock().unwrap();
+
+    // packets are received on the client's own sockets, the retired ids need no cleanup.
+    while state.quiche_conn.retired_scid_next().is_some() {}
+
+    let mut issued = false;
+
+    while state.quiche_conn.scids_left() > 0 {
+        let id = state.conn_id_generator.generate();
+
+        if let Err(err) = state.quiche_conn.new_scid(&id, reset_token(), false) {
+            log::error!(
+                "QuicConn(client): issue new connection id, trace_id={}, err={}",
+                state.quiche_conn.trace_id(),
+                err
+            );
+            break;
+        }
+
+        issued = true;
+    }
+
+    let waker = if issued {
+        state.send_waker.take()
+    } else {
+        None
+    };
+
+    drop(state);
+
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attempt_order() {
+        let mut raddrs = [
+            "10.0.0.1:443",
+            "10.0.0.2:443",
+            "10.0.0.3:443",
+            "[::1]:443",
+            "[::2]:443",
+        ]
+        .map(|raddr| raddr.parse::<SocketAddr>().unwrap());
+
+        let blacklisted = raddrs[0];
+        let expired = raddrs[3];
+
+        let mut blacklist = HashMap::new();
+
+        blacklist.insert(blacklisted, Instant::now() + Duration::from_secs(10));
+        blacklist.insert(expired, Instant::now());
+
+        for _ in 0..10 {
+            let attempts = attempt_order(&mut raddrs, &mut blacklist);
+
+            assert_eq!(attempts.len(), 5);
+
+            let families = attempts
+                .iter()
+                .take(4)
+                .map(SocketAddr::is_ipv6)
+                .collect::<Vec<_>>();
+
+            assert_eq!(families, [true, false, true, false]);
+            assert_eq!(attempts[4], blacklisted);
+        }
+
+        assert!(!blacklist.contains_key(&expired));
     }
 }
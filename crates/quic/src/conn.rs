@@ -1,20 +1,26 @@
 use std::{
     cmp::Reverse,
-    collections::{BinaryHeap, HashMap, VecDeque},
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
     fmt::Debug,
     io::{Error, ErrorKind, Result},
+    net::SocketAddr,
     sync::{Arc, Mutex},
     task::{Context, Poll, Waker},
     time::Instant,
 };
 
 use futures::{AsyncRead, AsyncWrite};
-use n3io::{mio::Token, reactor::Reactor};
-use quiche::{RecvInfo, SendInfo};
+use n3io::{mio::Token, net::UdpSocket, reactor::Reactor};
+use quiche::{PathEvent, RecvInfo, SendInfo};
+
+use crate::{
+    ConnectionIdGenerator, QuicPacing, QuicServerMetrics, QuicSessionCache,
+    RandomConnectionIdGenerator,
+};
 
 pub(crate) struct QuicConnState {
     /// reactor for IOs.
-    reactor: Reactor,
+    pub(crate) reactor: Reactor,
     /// underlying quiche connection object.
     pub(crate) quiche_conn: quiche::Connection,
     /// generator for outbound bidirectional stream id.
@@ -24,11 +30,11 @@ pub(crate) struct QuicConnState {
     /// fifo queue for first seen inbound stream IDs.
     incoming_stream_id_fifo: VecDeque<u64>,
     /// wakers for stream reading events.
-    stream_readable_wakers: HashMap<u64, Waker>,
+    pub(crate) stream_readable_wakers: HashMap<u64, Waker>,
     /// wakers for stream writting events.
-    stream_writable_wakers: HashMap<u64, Waker>,
+    pub(crate) stream_writable_wakers: HashMap<u64, Waker>,
     /// waker for `poll_send`
-    send_waker: Option<Waker>,
+    pub(crate) send_waker: Option<Waker>,
     /// waker for fifo receiver.
     fifo_waker: Option<Waker>,
     /// open stream waker.
@@ -39,9 +45,36 @@ pub(crate) struct QuicConnState {
     closing_stream_set: HashMap<u64, Instant>,
     /// pre-allocated recv buf for closing stream receiving.
     closing_recv_buf: Vec<u8>,
+    /// http/3 connection attached by [`H3Conn`](crate::H3Conn).
+    pub(crate) h3_conn: Option<quiche::h3::Connection>,
+    /// waker for http/3 event polling.
+    pub(crate) h3_event_waker: Option<Waker>,
+    /// waker for DATAGRAM receiving.
+    dgram_waker: Option<Waker>,
+    /// counters of the listener, only set for server side connections.
+    pub(crate) metrics: Option<Arc<QuicServerMetrics>>,
+    /// udp sockets of the client side paths, keyed by local address.
+    pub(crate) client_sockets: HashMap<SocketAddr, Arc<UdpSocket>>,
+    /// wakers for path validation, keyed by `(local, peer)`.
+    path_wakers: HashMap<(SocketAddr, SocketAddr), Waker>,
+    /// paths that failed validation or were closed.
+    failed_paths: HashSet<(SocketAddr, SocketAddr)>,
+    /// generator for the spare source connection ids.
+    pub(crate) conn_id_generator: Arc<dyn ConnectionIdGenerator + Sync + Send>,
+    /// TLS session cache and the server key, only set for client side connections.
+    pub(crate) session_cache: Option<(QuicSessionCache, String)>,
+    /// pacing of the send loop.
+    pub(crate) pacing: QuicPacing,
 }
 
 impl QuicConnState {
+    /// Returns true if the negotiated application protocol is `h3`.
+    ///
+    /// The stream events of http/3 connections are consumed by `quiche::h3::Connection::poll`.
+    pub(crate) fn is_h3(&self) -> bool {
+        quiche::h3::APPLICATION_PROTOCOL.contains(&self.quiche_conn.application_proto())
+    }
+
     fn closing_recv(&mut self, id: u64) -> bool {
         loop {
             match self.quiche_conn.stream_recv(id, &mut self.closing_recv_buf) {
@@ -88,14 +121,46 @@ impl QuicConnState {
 
         while let Some(event) = self.quiche_conn.path_event_next() {
             log::info!(
-                "path event, scid={}, event={:?}",
+                "QuicConn({}): path event, trace_id={}, event={:?}",
+                self.quiche_conn.is_server(),
                 self.quiche_conn.trace_id(),
                 event
             );
+
+            match event {
+                // the server probes the new paths by itself.
+                PathEvent::New(..) | PathEvent::ReusedSourceConnectionId(..) => {}
+                PathEvent::Validated(local, peer) => {
+                    self.failed_paths.remove(&(local, peer));
+                    wakers.extend(self.path_wakers.remove(&(local, peer)));
+                }
+                PathEvent::FailedValidation(local, peer) | PathEvent::Closed(local, peer) => {
+                    self.failed_paths.insert((local, peer));
+                    wakers.extend(self.path_wakers.remove(&(local, peer)));
+                }
+                PathEvent::PeerMigrated(..) => {
+                    if let Some(metrics) = &self.metrics {
+                        metrics.migrations.inc();
+                    }
+                }
+            }
         }
 
-        while let Some(id) = self.quiche_conn.stream_readable_next() {
-            ordering_readable_id_set.push(Reverse(id));
+        if self.is_h3() {
+            if self.quiche_conn.is_readable()
+                && let Some(waker) = self.h3_event_waker.take()
+            {
+                log::trace!(
+                    "QuicConn({}): wakeup h3 event polling, trace_id={}",
+                    self.quiche_conn.is_server(),
+                    self.quiche_conn.trace_id()
+                );
+                wakers.push(waker);
+            }
+        } else {
+            while let Some(id) = self.quiche_conn.stream_readable_next() {
+                ordering_readable_id_set.push(Reverse(id));
+            }
         }
 
         while let Some(Reverse(id)) = ordering_readable_id_set.pop() {
@@ -154,6 +219,17 @@ impl QuicConnState {
             }
         }
 
+        if self.quiche_conn.dgram_recv_queue_len() > 0
+            && let Some(waker) = self.dgram_waker.take()
+        {
+            log::trace!(
+                "QuicConn({}): wakeup dgram receiving, trace_id={}",
+                self.quiche_conn.is_server(),
+                self.quiche_conn.trace_id()
+            );
+            wakers.push(waker);
+        }
+
         while let Some(id) = self.quiche_conn.stream_writable_next() {
             if let Some(waker) = self.stream_writable_wakers.remove(&id) {
                 log::trace!(
@@ -199,6 +275,35 @@ impl QuicConnState {
             wakers.push(waker);
         }
 
+        if let Some(waker) = self.h3_event_waker.take() {
+            log::trace!(
+                "QuicConn({}): finalize wake up `h3` task, trace_id={}",
+                self.quiche_conn.is_server(),
+                trace_id,
+            );
+            wakers.push(waker);
+        }
+
+        if let Some(waker) = self.dgram_waker.take() {
+            log::trace!(
+                "QuicConn({}): finalize wake up `dgram` task, trace_id={}",
+                self.quiche_conn.is_server(),
+                trace_id,
+            );
+            wakers.push(waker);
+        }
+
+        for (path, waker) in self.path_wakers.drain() {
+            log::trace!(
+                "QuicConn({}): finalize wake up path probing task, path={:?}, trace_id={}",
+                self.quiche_conn.is_server(),
+                path,
+                trace_id,
+            );
+
+            wakers.push(waker);
+        }
+
         for (stream_id, waker) in self.stream_readable_wakers.drain() {
             log::trace!(
                 "QuicConn({}): finalize wake up stream reading task, stream_id={}, trace_id={}",
@@ -225,6 +330,13 @@ impl QuicConnState {
     }
 }
 
+/// Returns the `(local, peer)` addresses of the active path of `conn`.
+pub(crate) fn active_path(conn: &quiche::Connection) -> Option<(SocketAddr, SocketAddr)> {
+    conn.path_stats()
+        .find(|stats| stats.active)
+        .map(|stats| (stats.local_addr, stats.peer_addr))
+}
+
 /// Returns true if the stream was created locally.
 fn is_local(stream_id: u64, is_server: bool) -> bool {
     (stream_id & 0x1) == (is_server as u64)
@@ -258,11 +370,27 @@ impl QuicConnDispatcher {
             open_stream_waker: Default::default(),
             closing_stream_set: Default::default(),
             closing_recv_buf: vec![0; 1200],
+            h3_conn: None,
+            h3_event_waker: None,
+            dgram_waker: None,
+            metrics: None,
+            client_sockets: Default::default(),
+            path_wakers: Default::default(),
+            failed_paths: Default::default(),
+            conn_id_generator: Arc::new(RandomConnectionIdGenerator),
+            session_cache: None,
+            pacing: QuicPacing::default(),
         }));
 
         QuicConnDispatcher(state)
     }
 
+    /// Close the connection, see [`QuicConn::close`].
+    pub(crate) fn close(&self, err: u64, reason: &[u8]) -> Result<()> {
+        // the `Drop` of `QuicConn` calls `close` again, which returns `Done`.
+        QuicConn(self.0.clone()).close(err, reason)
+    }
+
     /// Return true if the connection handshake is complete.
     pub(crate) fn is_established(&self) -> bool {
         self.0.lock().unwrap().quiche_conn.is_established()
@@ -418,6 +546,61 @@ impl QuicConnDispatcher {
         }
     }
 
+    /// Writes a single QUIC packet without waiting, returns `None` if there is nothing to send now.
+    ///
+    /// Used to fill a batch after [`poll_send`](Self::poll_send) returned a packet, the
+    /// timers are left to the next `poll_send` call.
+    pub fn try_send(&self, out: &mut [u8]) -> Result<Option<(usize, SendInfo)>> {
+        let mut state = self.0.lock().unwrap();
+
+        match state.quiche_conn.send(out) {
+            Ok((send_size, send_info)) => {
+                log::trace!(
+                    "QuicConn({}) try_send, send_size={}, send_info={:?}, trace_id={}",
+                    state.quiche_conn.is_server(),
+                    send_size,
+                    send_info,
+                    state.quiche_conn.trace_id(),
+                );
+
+                let wakers = state.poll_conn_stat_events();
+
+                drop(state);
+
+                for waker in wakers {
+                    waker.wake();
+                }
+
+                Ok(Some((send_size, send_info)))
+            }
+            Err(quiche::Error::Done) => Ok(None),
+            Err(err) => {
+                log::error!(
+                    "QuicConn({}): try_send data, trace_id={:?}, err={}",
+                    state.quiche_conn.is_server(),
+                    state.quiche_conn.trace_id(),
+                    err
+                );
+
+                Err(Error::other(err))
+            }
+        }
+    }
+
+    /// Returns the number of bytes the congestion controller allows to send in one batch.
+    pub fn send_quantum(&self) -> usize {
+        self.0.lock().unwrap().quiche_conn.send_quantum()
+    }
+
+    /// Returns the maximum size of the outgoing packets, 1200 bytes until the handshake completes.
+    pub fn max_send_udp_payload_size(&self) -> usize {
+        self.0
+            .lock()
+            .unwrap()
+            .quiche_conn
+            .max_send_udp_payload_size()
+    }
+
     /// Processes QUIC packets received from the peer.
     pub fn poll_recv(
         &self,
@@ -623,6 +806,77 @@ impl QuicConn {
         Ok(())
     }
 
+    /// Receives a DATAGRAM frame into `buf`, returns the frame length.
+    pub fn poll_dgram_recv(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize>> {
+        let mut state = self.0.lock().unwrap();
+
+        match state.quiche_conn.dgram_recv(buf) {
+            Ok(recv_size) => Poll::Ready(Ok(recv_size)),
+            Err(quiche::Error::Done) => {
+                if state.quiche_conn.is_closed() {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::BrokenPipe,
+                        format!(
+                            "quic connection is closed, id={}",
+                            state.quiche_conn.trace_id()
+                        ),
+                    )));
+                }
+
+                state.dgram_waker = Some(cx.waker().clone());
+
+                Poll::Pending
+            }
+            Err(err) => Poll::Ready(Err(Error::other(err))),
+        }
+    }
+
+    /// Returns the `(local, peer)` addresses of the active path.
+    pub fn active_path(&self) -> Option<(SocketAddr, SocketAddr)> {
+        self.quiche_conn(active_path)
+    }
+
+    /// Polls the validation result of the path between `local` and `peer`.
+    pub fn poll_path_validated(
+        &self,
+        cx: &mut Context<'_>,
+        local: SocketAddr,
+        peer: SocketAddr,
+    ) -> Poll<Result<()>> {
+        let mut state = self.0.lock().unwrap();
+
+        if state.quiche_conn.is_closed() {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::BrokenPipe,
+                format!(
+                    "quic connection is closed, id={}",
+                    state.quiche_conn.trace_id()
+                ),
+            )));
+        }
+
+        if state.failed_paths.contains(&(local, peer)) {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("failed to validate path, local={}, peer={}", local, peer),
+            )));
+        }
+
+        match state.quiche_conn.is_path_validated(local, peer) {
+            Ok(true) => Poll::Ready(Ok(())),
+            Ok(false) => {
+                state.path_wakers.insert((local, peer), cx.waker().clone());
+                Poll::Pending
+            }
+            Err(err) => Poll::Ready(Err(Error::new(ErrorKind::NotFound, err))),
+        }
+    }
+
+    /// Returns a handle to send DATAGRAM frames on this connection.
+    pub fn dgram_sender(&self) -> QuicDgramSender {
+        QuicDgramSender(self.0.clone())
+    }
+
     /// Accepts a new `QUIC` stream.
     pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<Result<QuicStream>> {
         let mut state = self.0.lock().unwrap();
@@ -733,6 +987,9 @@ pub trait QuicConnExt {
 
     /// Open a new outbound stream.
     fn open(&self) -> OpenStream<'_>;
+
+    /// Receive a DATAGRAM frame.
+    fn dgram_recv<'a>(&'a self, buf: &'a mut [u8]) -> DgramRecv<'a>;
 }
 
 impl QuicConnExt for QuicConn {
@@ -743,6 +1000,86 @@ impl QuicConnExt for QuicConn {
     fn open(&self) -> OpenStream<'_> {
         OpenStream(self)
     }
+
+    fn dgram_recv<'a>(&'a self, buf: &'a mut [u8]) -> DgramRecv<'a> {
+        DgramRecv { conn: self, buf }
+    }
+}
+
+/// A future created by [`dgram_recv`](QuicConnExt::dgram_recv) func.
+pub struct DgramRecv<'a> {
+    conn: &'a QuicConn,
+    buf: &'a mut [u8],
+}
+
+impl<'a> Future for DgramRecv<'a> {
+    type Output = Result<usize>;
+
+    fn poll(mut self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        this.conn.poll_dgram_recv(cx, this.buf)
+    }
+}
+
+/// A cloneable handle to send DATAGRAM frames, created by [`QuicConn::dgram_sender`].
+#[derive(Clone)]
+pub struct QuicDgramSender(Arc<Mutex<QuicConnState>>);
+
+impl QuicDgramSender {
+    /// Queue a DATAGRAM frame without waiting.
+    ///
+    /// Returns `WouldBlock` if the send queue is full, or `InvalidInput` if `buf` is larger than
+    /// [`max_writable_len`](Self::max_writable_len). DATAGRAM frames are unreliable, the caller is
+    /// expected to drop the payload in both cases.
+    pub fn send(&self, buf: &[u8]) -> Result<()> {
+        let mut state = self.0.lock().unwrap();
+
+        if state.quiche_conn.is_closed() {
+            return Err(Error::new(
+                ErrorKind::BrokenPipe,
+                format!(
+                    "quic connection is closed, id={}",
+                    state.quiche_conn.trace_id()
+                ),
+            ));
+        }
+
+        match state.quiche_conn.dgram_send(buf) {
+            Ok(()) => {}
+            Err(quiche::Error::Done) => {
+                return Err(Error::new(
+                    ErrorKind::WouldBlock,
+                    "dgram send queue is full",
+                ));
+            }
+            Err(quiche::Error::InvalidState) => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "DATAGRAM is not supported by the peer",
+                ));
+            }
+            Err(quiche::Error::BufferTooShort) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("dgram payload is too large, len={}", buf.len()),
+                ));
+            }
+            Err(err) => return Err(Error::other(err)),
+        }
+
+        if let Some(waker) = state.send_waker.take() {
+            drop(state);
+            waker.wake();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the maximum DATAGRAM payload that can be sent, `None` if it's not supported.
+    pub fn max_writable_len(&self) -> Option<usize> {
+        self.0.lock().unwrap().quiche_conn.dgram_max_writable_len()
+    }
 }
 
 /// A future created by [`accept`](QuicConnExt::accept) func.
@@ -0,0 +1,423 @@
+//! `HTTP/3` support based on the [`quiche::h3`] module.
+
+use std::{
+    fmt::Debug,
+    future::poll_fn,
+    io::{Error, ErrorKind, Result},
+    sync::{Arc, MutexGuard},
+    task::{Context, Poll},
+};
+
+use futures::{AsyncRead, AsyncWrite};
+use quiche::h3::{self, Event, NameValue};
+
+use crate::{QuicConn, conn::QuicConnState};
+
+/// A `HTTP/3` connection over [`QuicConn`].
+///
+/// Cloned instances share the same underlying connection, the connection is closed
+/// when the last instance is dropped.
+#[derive(Clone)]
+pub struct H3Conn(Arc<QuicConn>);
+
+impl Debug for H3Conn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("H3Conn").field(&self.0).finish()
+    }
+}
+
+impl H3Conn {
+    /// Attach a `HTTP/3` layer to the established `conn`.
+    ///
+    /// Returns error if the negotiated application protocol of `conn` is not `h3`.
+    pub fn new(conn: QuicConn, config: &h3::Config) -> Result<Self> {
+        let mut state = conn.0.lock().unwrap();
+
+        if !state.is_h3() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "H3Conn: the application protocol is not `h3`, trace_id={}",
+                    state.quiche_conn.trace_id()
+                ),
+            ));
+        }
+
+        let h3_conn =
+            h3::Connection::with_transport(&mut state.quiche_conn, config).map_err(Error::other)?;
+
+        log::trace!(
+            "H3Conn({}): create http/3 connection, trace_id={}",
+            state.quiche_conn.is_server(),
+            state.quiche_conn.trace_id()
+        );
+
+        state.h3_conn = Some(h3_conn);
+
+        // flush control streams.
+        let waker = state.send_waker.take();
+
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+
+        Ok(Self(Arc::new(conn)))
+    }
+
+    /// Returns the underlying quic connection.
+    pub fn quic_conn(&self) -> &QuicConn {
+        &self.0
+    }
+
+    /// Sends a `GOAWAY` frame, requests on streams greater than or equal to `id` won't be processed.
+    pub fn send_goaway(&self, id: u64) -> Result<()> {
+        let mut guard = self.lock();
+        let state = &mut *guard;
+
+        let h3_conn = state.h3_conn.as_mut().expect("h3_conn");
+
+        h3_conn
+            .send_goaway(&mut state.quiche_conn, id)
+            .map_err(Error::other)?;
+
+        let waker = state.send_waker.take();
+
+        drop(guard);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+
+        Ok(())
+    }
+
+    /// Resets both directions of the request stream `stream_id` with the application error `err`,
+    /// e.g. `H3_REQUEST_REJECTED` for a request received after `GOAWAY`.
+    pub fn reset_stream(&self, stream_id: u64, err: u64) -> Result<()> {
+        let mut state = self.lock();
+
+        for direction in [quiche::Shutdown::Read, quiche::Shutdown::Write] {
+            match state.quiche_conn.stream_shutdown(stream_id, direction, err) {
+                // the stream is already closed.
+                Ok(()) | Err(quiche::Error::Done) => {}
+                Err(err) => return Err(Error::other(err)),
+            }
+        }
+
+        let waker = state.send_waker.take();
+
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QuicConnState> {
+        self.0.0.lock().unwrap()
+    }
+
+    /// Polls the next `HTTP/3` event.
+    ///
+    /// `Data`/`Finished`/`Reset` events also wake up the pending body readers of the stream.
+    pub fn poll_event(&self, cx: &mut Context<'_>) -> Poll<Result<(u64, Event)>> {
+        let mut guard = self.lock();
+        let state = &mut *guard;
+
+        let h3_conn = state.h3_conn.as_mut().expect("h3_conn");
+
+        match h3_conn.poll(&mut state.quiche_conn) {
+            Ok((stream_id, event)) => {
+                log::trace!(
+                    "H3Conn({}): poll event, stream_id={}, event={:?}, trace_id={}",
+                    state.quiche_conn.is_server(),
+                    stream_id,
+                    event,
+                    state.quiche_conn.trace_id()
+                );
+
+                let mut wakers = vec![];
+
+                if let Event::Data | Event::Finished | Event::Reset(_) = &event
+                    && let Some(waker) = state.stream_readable_wakers.remove(&stream_id)
+                {
+                    wakers.push(waker);
+                }
+
+                if let Some(waker) = state.send_waker.take() {
+                    wakers.push(waker);
+                }
+
+                drop(guard);
+
+                for waker in wakers {
+                    waker.wake();
+                }
+
+                Poll::Ready(Ok((stream_id, event)))
+            }
+            Err(h3::Error::Done) => {
+                if state.quiche_conn.is_closed() || state.quiche_conn.is_draining() {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::BrokenPipe,
+                        format!(
+                            "quic connection is closed, id={}",
+                            state.quiche_conn.trace_id()
+                        ),
+                    )));
+                }
+
+                state.h3_event_waker = Some(cx.waker().clone());
+
+                Poll::Pending
+            }
+            Err(err) => {
+                log::error!(
+                    "H3Conn({}): poll event, trace_id={}, err={}",
+                    state.quiche_conn.is_server(),
+                    state.quiche_conn.trace_id(),
+                    err
+                );
+
+                Poll::Ready(Err(Error::other(err)))
+            }
+        }
+    }
+
+    /// Sends the response headers on `stream_id`.
+    pub fn poll_send_response<T: NameValue>(
+        &self,
+        cx: &mut Context<'_>,
+        stream_id: u64,
+        headers: &[T],
+        fin: bool,
+    ) -> Poll<Result<()>> {
+        let mut guard = self.lock();
+        let state = &mut *guard;
+
+        let h3_conn = state.h3_conn.as_mut().expect("h3_conn");
+
+        match h3_conn.send_response(&mut state.quiche_conn, stream_id, headers, fin) {
+            Ok(_) => {
+                let waker = state.send_waker.take();
+
+                drop(guard);
+
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+
+                Poll::Ready(Ok(()))
+            }
+            Err(h3::Error::StreamBlocked) | Err(h3::Error::Done) => {
+                log::trace!(
+                    "H3Conn({}): send response, stream_id={}, trace_id={}, pending",
+                    state.quiche_conn.is_server(),
+                    stream_id,
+                    state.quiche_conn.trace_id()
+                );
+
+                state
+                    .stream_writable_wakers
+                    .insert(stream_id, cx.waker().clone());
+
+                Poll::Pending
+            }
+            Err(err) => Poll::Ready(Err(Error::other(err))),
+        }
+    }
+
+    /// Sends a body chunk on `stream_id`, returns the number of written bytes.
+    pub fn poll_send_body(
+        &self,
+        cx: &mut Context<'_>,
+        stream_id: u64,
+        body: &[u8],
+        fin: bool,
+    ) -> Poll<Result<usize>> {
+        let mut guard = self.lock();
+        let state = &mut *guard;
+
+        if state.quiche_conn.is_closed() || state.quiche_conn.is_draining() {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "Connection is closed or is draining.",
+            )));
+        }
+
+        let h3_conn = state.h3_conn.as_mut().expect("h3_conn");
+
+        match h3_conn.send_body(&mut state.quiche_conn, stream_id, body, fin) {
+            Ok(written_size) => {
+                log::trace!(
+                    "H3Conn({}): send body, stream_id={}, trace_id={}, send_size={}, fin={}",
+                    state.quiche_conn.is_server(),
+                    stream_id,
+                    state.quiche_conn.trace_id(),
+                    written_size,
+                    fin
+                );
+
+                let waker = state.send_waker.take();
+
+                drop(guard);
+
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+
+                Poll::Ready(Ok(written_size))
+            }
+            Err(h3::Error::Done) => {
+                log::trace!(
+                    "H3Conn({}): send body, stream_id={}, trace_id={}, pending",
+                    state.quiche_conn.is_server(),
+                    stream_id,
+                    state.quiche_conn.trace_id()
+                );
+
+                state
+                    .stream_writable_wakers
+                    .insert(stream_id, cx.waker().clone());
+
+                Poll::Pending
+            }
+            Err(err) => Poll::Ready(Err(Error::other(err))),
+        }
+    }
+
+    /// Reads request/response body data from `stream_id`.
+    ///
+    /// Returns `0` if all the body data has been read.
+    pub fn poll_recv_body(
+        &self,
+        cx: &mut Context<'_>,
+        stream_id: u64,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        let mut guard = self.lock();
+        let state = &mut *guard;
+
+        let h3_conn = state.h3_conn.as_mut().expect("h3_conn");
+
+        match h3_conn.recv_body(&mut state.quiche_conn, stream_id, buf) {
+            Ok(read_size) => {
+                log::trace!(
+                    "H3Conn({}): recv body, stream_id={}, trace_id={}, read_size={}",
+                    state.quiche_conn.is_server(),
+                    stream_id,
+                    state.quiche_conn.trace_id(),
+                    read_size
+                );
+
+                let mut wakers = vec![];
+
+                // flow control updating.
+                if let Some(waker) = state.send_waker.take() {
+                    wakers.push(waker);
+                }
+
+                // `recv_body` may re-arm the `Data`/`Finished` events.
+                if let Some(waker) = state.h3_event_waker.take() {
+                    wakers.push(waker);
+                }
+
+                drop(guard);
+
+                for waker in wakers {
+                    waker.wake();
+                }
+
+                Poll::Ready(Ok(read_size))
+            }
+            Err(h3::Error::Done) => {
+                if state.quiche_conn.stream_finished(stream_id)
+                    || state.quiche_conn.is_closed()
+                    || state.quiche_conn.is_draining()
+                {
+                    return Poll::Ready(Ok(0));
+                }
+
+                state
+                    .stream_readable_wakers
+                    .insert(stream_id, cx.waker().clone());
+
+                Poll::Pending
+            }
+            Err(err) => Poll::Ready(Err(Error::other(err))),
+        }
+    }
+
+    /// Polls the next `HTTP/3` event.
+    pub async fn event(&self) -> Result<(u64, Event)> {
+        poll_fn(|cx| self.poll_event(cx)).await
+    }
+
+    /// Sends the response headers on `stream_id`.
+    pub async fn send_response<T: NameValue>(
+        &self,
+        stream_id: u64,
+        headers: &[T],
+        fin: bool,
+    ) -> Result<()> {
+        poll_fn(|cx| self.poll_send_response(cx, stream_id, headers, fin)).await
+    }
+
+    /// Create a body io for `stream_id`.
+    pub fn stream(&self, stream_id: u64) -> H3Stream {
+        H3Stream {
+            stream_id,
+            conn: self.clone(),
+        }
+    }
+}
+
+/// The body io of one `HTTP/3` request stream.
+///
+/// Reading from it returns the peer's body data, writing to it sends body data to the peer,
+/// and closing it sends the `fin` flag.
+pub struct H3Stream {
+    stream_id: u64,
+    conn: H3Conn,
+}
+
+impl H3Stream {
+    /// Returns id value of this stream.
+    pub fn id(&self) -> u64 {
+        self.stream_id
+    }
+}
+
+impl AsyncRead for H3Stream {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        self.conn.poll_recv_body(cx, self.stream_id, buf)
+    }
+}
+
+impl AsyncWrite for H3Stream {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        self.conn.poll_send_body(cx, self.stream_id, buf, false)
+    }
+
+    fn poll_flush(self: std::pin::Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.conn
+            .poll_send_body(cx, self.stream_id, b"", true)
+            .map_ok(|_| ())
+    }
+}
@@ -11,13 +11,39 @@ pub use utils::*;
 mod validator;
 pub use validator::*;
 
+mod cid;
+pub use cid::*;
+
+mod reset;
+pub use reset::*;
+
+mod session;
+pub use session::*;
+
 mod conn;
 pub use conn::*;
 
 mod client;
 pub use client::*;
 
+mod h3;
+pub use h3::*;
+
+mod tuning;
+pub use tuning::*;
+
+mod metrics;
+pub use metrics::*;
+
+mod shutdown;
+pub use shutdown::*;
+
+mod pacing;
+pub use pacing::*;
+
 /// re-export quiche.
 pub use quiche;
 
 mod mutex;
+
+mod batch;
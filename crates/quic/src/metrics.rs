@@ -0,0 +1,100 @@
+use n3_metrics::{Collector, Counter, Encoder, Gauge};
+
+/// Counters of a `QuicServer`, shared with its [`QuicListener`](crate::QuicListener).
+#[derive(Debug, Default)]
+pub struct QuicServerMetrics {
+    /// Connections that have completed the handshake.
+    pub handshakes: Counter,
+    /// Sent retry packets.
+    pub retries: Counter,
+    /// Sent version negotiation packets.
+    pub version_negotiations: Counter,
+    /// New connections dropped because the `max_active_conn_size` is reached.
+    pub max_active_conn_drops: Counter,
+    /// Established connections dropped because the incoming queue is full.
+    pub incoming_queue_full_drops: Counter,
+    /// Handshaking and established connections, see [`QuicListener::active_conns`](crate::QuicListener::active_conns).
+    pub active_conns: Gauge,
+    /// Connections whose peer moved to a new validated address.
+    pub migrations: Counter,
+    /// Sent stateless reset packets.
+    pub stateless_resets: Counter,
+    /// Connections accepted before the handshake completes, see [`ZeroRttPolicy::Accept`](crate::ZeroRttPolicy::Accept).
+    pub early_data_accepts: Counter,
+    /// Replayed `0-RTT` handshakes that were dropped.
+    pub early_data_replays: Counter,
+}
+
+impl Collector for QuicServerMetrics {
+    fn collect(&self, encoder: &mut Encoder) {
+        encoder.counter(
+            "n3_quic_handshakes_total",
+            "QUIC connections that have completed the handshake.",
+            &[],
+            self.handshakes.get(),
+        );
+
+        encoder.counter(
+            "n3_quic_retries_total",
+            "Sent QUIC retry packets.",
+            &[],
+            self.retries.get(),
+        );
+
+        encoder.counter(
+            "n3_quic_version_negotiations_total",
+            "Sent QUIC version negotiation packets.",
+            &[],
+            self.version_negotiations.get(),
+        );
+
+        encoder.counter(
+            "n3_quic_dropped_conns_total",
+            "QUIC connections dropped by the server.",
+            &[("reason", "max_active_conn_size")],
+            self.max_active_conn_drops.get(),
+        );
+
+        encoder.counter(
+            "n3_quic_dropped_conns_total",
+            "QUIC connections dropped by the server.",
+            &[("reason", "incoming_queue_full")],
+            self.incoming_queue_full_drops.get(),
+        );
+
+        encoder.gauge(
+            "n3_quic_active_conns",
+            "Handshaking and established QUIC connections.",
+            &[],
+            self.active_conns.get(),
+        );
+
+        encoder.counter(
+            "n3_quic_migrations_total",
+            "QUIC connections whose peer moved to a new validated address.",
+            &[],
+            self.migrations.get(),
+        );
+
+        encoder.counter(
+            "n3_quic_stateless_resets_total",
+            "Sent QUIC stateless reset packets.",
+            &[],
+            self.stateless_resets.get(),
+        );
+
+        encoder.counter(
+            "n3_quic_early_data_accepts_total",
+            "QUIC connections accepted in 0-RTT, before the handshake completes.",
+            &[],
+            self.early_data_accepts.get(),
+        );
+
+        encoder.counter(
+            "n3_quic_early_data_replays_total",
+            "Dropped replays of QUIC 0-RTT handshakes.",
+            &[],
+            self.early_data_replays.get(),
+        );
+    }
+}
@@ -0,0 +1,55 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    str::FromStr,
+};
+
+/// How the send loops pace the packets, by the `SendInfo::at` time of quiche.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum QuicPacing {
+    /// Send the packets as soon as quiche writes them.
+    #[default]
+    Off,
+    /// Hold the packets until their send time with the timers of the `Reactor`.
+    ///
+    /// The packets due within one tick of the reactor are sent together.
+    Timer,
+    /// Pass the send time to the kernel with `SO_TXTIME`, which requires the `fq` qdisc.
+    ///
+    /// Falls back to `Timer` if the socket option is not supported.
+    TxTime,
+}
+
+impl FromStr for QuicPacing {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "timer" => Ok(Self::Timer),
+            "txtime" => Ok(Self::TxTime),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown pacing `{}`, expect `off`, `timer` or `txtime`", s),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pacing() {
+        assert_eq!(QuicPacing::default(), QuicPacing::Off);
+        assert_eq!("off".parse::<QuicPacing>().unwrap(), QuicPacing::Off);
+        assert_eq!("timer".parse::<QuicPacing>().unwrap(), QuicPacing::Timer);
+        assert_eq!("txtime".parse::<QuicPacing>().unwrap(), QuicPacing::TxTime);
+        assert_eq!(
+            "fq".parse::<QuicPacing>().unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+}
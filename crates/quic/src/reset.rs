@@ -0,0 +1,101 @@
+//! [`Stateless reset`] tokens and packets.
+//!
+//! [`Stateless reset`]: https://datatracker.ietf.org/doc/html/rfc9000#name-stateless-reset
+
+use boring::{hash::MessageDigest, pkey::PKey, sign::Signer};
+
+/// The minimum length of a stateless reset packet: 5 unpredictable bytes and the 16 bytes token.
+const MIN_STATELESS_RESET_LEN: usize = 21;
+
+/// The stateless reset packet is made hard to distinguish from a short header packet of this
+/// length.
+const MAX_STATELESS_RESET_LEN: usize = 43;
+
+/// A static key, derives the stateless reset token of a connection id.
+///
+/// Keep the key unchanged across restarts, so the peers of a restarted server can be reset.
+#[derive(Clone)]
+pub struct StatelessResetKey(Vec<u8>);
+
+impl StatelessResetKey {
+    /// Create a new key from the secret `key` bytes.
+    pub fn new(key: impl AsRef<[u8]>) -> Self {
+        Self(key.as_ref().to_vec())
+    }
+
+    /// Create a random key, the tokens are invalidated when the process restarts.
+    pub fn random() -> Self {
+        let mut key = vec![0; 32];
+        boring::rand::rand_bytes(&mut key).unwrap();
+        Self(key)
+    }
+
+    /// Returns the stateless reset token of `conn_id`: the leading 16 bytes of
+    /// `HMAC-SHA256(key, conn_id)`.
+    pub fn token(&self, conn_id: &[u8]) -> u128 {
+        let key = PKey::hmac(&self.0).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        signer.update(conn_id).unwrap();
+
+        let digest = signer.sign_to_vec().unwrap();
+
+        u128::from_be_bytes(digest[..16].try_into().unwrap())
+    }
+
+    /// Write the stateless reset packet of `conn_id` into `buf`, in reply to a packet of
+    /// `recv_len` bytes.
+    ///
+    /// Returns `None` if the received packet is too small, the reset is always shorter than the
+    /// packet that triggers it to avoid the reset loops.
+    pub(crate) fn write_reset(
+        &self,
+        conn_id: &[u8],
+        recv_len: usize,
+        buf: &mut [u8],
+    ) -> Option<usize> {
+        let len = recv_len
+            .checked_sub(1)?
+            .min(MAX_STATELESS_RESET_LEN)
+            .min(buf.len());
+
+        if len < MIN_STATELESS_RESET_LEN {
+            return None;
+        }
+
+        let (unpredictable, token) = buf[..len].split_at_mut(len - 16);
+
+        boring::rand::rand_bytes(unpredictable).unwrap();
+
+        // short header form: `0b01xx_xxxx`.
+        unpredictable[0] = 0b0100_0000 | (unpredictable[0] & 0b0011_1111);
+
+        token.copy_from_slice(&self.token(conn_id).to_be_bytes());
+
+        Some(len)
+    }
+}
+
+impl std::fmt::Debug for StatelessResetKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("StatelessResetKey").field(&"..").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_reset() {
+        let key = StatelessResetKey::new(b"n3");
+
+        let mut buf = vec![0; 1500];
+
+        assert_eq!(key.write_reset(b"cid", 21, &mut buf), None);
+        assert_eq!(key.write_reset(b"cid", 22, &mut buf), Some(21));
+        assert_eq!(key.write_reset(b"cid", 1200, &mut buf), Some(43));
+
+        assert_eq!(buf[0] & 0b1100_0000, 0b0100_0000);
+        assert_eq!(&buf[27..43], &key.token(b"cid").to_be_bytes());
+    }
+}
@@ -1,30 +1,99 @@
 use std::{
+    collections::HashMap,
     fmt::Debug,
     io::{Error, ErrorKind, Result},
     net::{SocketAddr, ToSocketAddrs},
-    sync::Arc,
-    time::Duration,
+    pin::pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use dashmap::{DashMap, DashSet};
-use futures::{StreamExt, channel::mpsc};
+use futures::{
+    StreamExt,
+    channel::{mpsc, oneshot},
+    future::{Either, select},
+};
 use n3_spawner::spawn;
 use n3io::{
-    net::udp_group::{self, UdpGroupReceiver, UdpGroupSender},
+    net::{
+        RecvMeta,
+        udp_group::{self, UdpGroupReceiver, UdpGroupSender},
+    },
     reactor::Reactor,
+    timeout::sleep_with,
 };
 use quiche::{ConnectionId, Header, RecvInfo};
 
 use crate::{
-    AddressValidator, QuicConn, QuicConnDispatcher, QuicConnDispatcherExt, SimpleAddressValidator,
-    random_conn_id,
+    AddressValidator, ConnectionIdGenerator, QuicConn, QuicConnDispatcher, QuicConnDispatcherExt,
+    QuicPacing, QuicServerMetrics, QuicShutdown, RandomConnectionIdGenerator,
+    SimpleAddressValidator, StatelessResetKey, batch::SendBatch,
 };
 
+/// The interval of checking whether all the connections are closed during shutdown.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The number of datagrams received by one `recv_batch` call of a listener driver.
+const RECV_BATCH: usize = 16;
+
+/// A handle to replace the `quiche::Config` of a running [`QuicListener`].
+///
+/// The new config is used by the subsequent handshakes, established connections are not affected.
+#[derive(Clone, Default)]
+pub struct QuicConfigReloader(Arc<Mutex<Option<quiche::Config>>>);
+
+impl Debug for QuicConfigReloader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("QuicConfigReloader").finish()
+    }
+}
+
+impl QuicConfigReloader {
+    /// Create a new reloader, which is not attached to any listener.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the `quiche::Config` of the attached listener.
+    ///
+    /// The `verify_peer` flag of `config` should be set as [`QuicServer::verify_peer`] did.
+    pub fn reload(&self, config: quiche::Config) {
+        *self.0.lock().unwrap() = Some(config);
+    }
+
+    fn take(&self) -> Option<quiche::Config> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// The connection tables of a listener shard.
+#[derive(Default)]
+struct ShardConns {
+    /// handshaking connections.
+    handshaking_conn_set: DashSet<ConnectionId<'static>>,
+    /// aliving quic connections.
+    quiche_conn_set: DashMap<ConnectionId<'static>, QuicConnDispatcher>,
+    /// all the active source connection ids of the connections, used to route the packets.
+    conn_ids: DashMap<ConnectionId<'static>, QuicConnDispatcher>,
+}
+
+/// Returns the count of the active connections of all the shards.
+fn active_conns(shards: &[ShardConns]) -> usize {
+    shards.iter().map(|shard| shard.quiche_conn_set.len()).sum()
+}
+
 /// Server socket for quic.
 pub struct QuicListener {
     incoming: mpsc::Receiver<QuicConn>,
     laddrs: Vec<SocketAddr>,
-    quiche_conn_set: Arc<DashMap<ConnectionId<'static>, QuicConnDispatcher>>,
+    shards: Arc<[ShardConns]>,
+    reloader: QuicConfigReloader,
+    metrics: Arc<QuicServerMetrics>,
+    shutdown: QuicShutdown,
+    /// completed when all the driver tasks exit.
+    closed: oneshot::Receiver<()>,
 }
 
 impl QuicListener {
@@ -35,14 +104,42 @@ impl QuicListener {
 
     /// Returns the count of the active connections.
     pub fn active_conns(&self) -> usize {
-        self.quiche_conn_set.len()
+        active_conns(&self.shards)
+    }
+
+    /// Returns the handle to replace the `quiche::Config` of this listener at runtime.
+    pub fn config_reloader(&self) -> QuicConfigReloader {
+        self.reloader.clone()
+    }
+
+    /// Returns the counters of this listener.
+    pub fn metrics(&self) -> Arc<QuicServerMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Returns the handle to shutdown this listener gracefully.
+    pub fn shutdown_handle(&self) -> QuicShutdown {
+        self.shutdown.clone()
+    }
+
+    /// Wait for the listener to stop, after the shutdown is started, this is when all the
+    /// connections are closed.
+    pub async fn wait_closed(&mut self) {
+        _ = (&mut self.closed).await;
     }
 
     /// Accepts a new `QUIC` connection.
     ///
     /// If an accepted stream is returned, the remote address of the peer is returned along with it.
+    ///
+    /// Returns error once the shutdown is started.
     pub async fn accept(&mut self) -> Result<QuicConn> {
-        if let Some(next) = self.incoming.next().await {
+        let next = match select(self.incoming.next(), pin!(self.shutdown.wait())).await {
+            Either::Left((next, _)) => next,
+            Either::Right(_) => None,
+        };
+
+        if let Some(next) = next {
             Ok(next)
         } else {
             Err(Error::new(
@@ -53,19 +150,74 @@ impl QuicListener {
     }
 }
 
+/// The server side handling of the `0-RTT` data, which may be replayed by an attacker.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum ZeroRttPolicy {
+    /// Reject the early data, the clients resume the sessions with a `1-RTT` handshake.
+    #[default]
+    Disabled,
+    /// Buffer the early data, the connection is accepted after the handshake completes.
+    ///
+    /// A replayed handshake never completes, so the data is never delivered twice.
+    Deferred,
+    /// Accept the connection in early data, the streams are served before the handshake completes.
+    ///
+    /// A handshake replayed within the retry token timeout is dropped, so the replay window is
+    /// bounded by the expiration of the address validation tokens. Falls back to `Deferred` if
+    /// the peer's certificate is verified.
+    Accept,
+}
+
+impl FromStr for ZeroRttPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "disabled" => Ok(Self::Disabled),
+            "deferred" => Ok(Self::Deferred),
+            "accept" => Ok(Self::Accept),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "unknown 0-RTT policy `{}`, expect `disabled`, `deferred` or `accept`",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
 struct QuicServerConfig {
     /// quic server-side config.
     config: quiche::Config,
     /// validator for retry packet.
     validator: Option<Box<dyn AddressValidator + Sync + Send>>,
+    /// generator for the source connection ids.
+    conn_id_generator: Arc<dyn ConnectionIdGenerator + Sync + Send>,
+    /// key of the stateless reset tokens.
+    stateless_reset_key: StatelessResetKey,
     /// expiration interval for retry token.
     retry_token_timeout: Duration,
+    /// handling of the `0-RTT` data.
+    zero_rtt: ZeroRttPolicy,
     /// The maximum unhandle incoming quic connection length.
     incoming_queue_size: usize,
     /// The maximum number of active connections of this server can handles.
     max_active_conn_size: usize,
+    /// The number of listener shards.
+    shards: usize,
+    /// pacing of the connections.
+    pacing: QuicPacing,
     /// Configures wether to verify the peer’s certificate.
     verify_peer: bool,
+    /// runtime `quiche::Config` replacement.
+    reloader: QuicConfigReloader,
+    /// server counters.
+    metrics: Arc<QuicServerMetrics>,
+    /// graceful shutdown handle.
+    shutdown: QuicShutdown,
 }
 
 impl Debug for QuicServerConfig {
@@ -74,6 +226,8 @@ impl Debug for QuicServerConfig {
             .field("retry_token_timeout", &self.retry_token_timeout)
             .field("incoming_queue_size", &self.incoming_queue_size)
             .field("max_active_conn_size", &self.max_active_conn_size)
+            .field("shards", &self.shards)
+            .field("pacing", &self.pacing)
             .finish()
     }
 }
@@ -87,10 +241,18 @@ impl QuicServer {
         Self(Ok(QuicServerConfig {
             config: quiche::Config::new(quiche::PROTOCOL_VERSION).expect("quiche_config"),
             validator: None,
+            conn_id_generator: Arc::new(RandomConnectionIdGenerator),
+            stateless_reset_key: StatelessResetKey::random(),
             retry_token_timeout: Duration::from_secs(60),
+            zero_rtt: ZeroRttPolicy::Disabled,
             incoming_queue_size: 100,
             max_active_conn_size: 500,
+            shards: 1,
+            pacing: QuicPacing::default(),
             verify_peer: false,
+            reloader: QuicConfigReloader::new(),
+            metrics: Default::default(),
+            shutdown: QuicShutdown::new(),
         }))
     }
 
@@ -99,10 +261,18 @@ impl QuicServer {
         Self(Ok(QuicServerConfig {
             config,
             validator: None,
+            conn_id_generator: Arc::new(RandomConnectionIdGenerator),
+            stateless_reset_key: StatelessResetKey::random(),
             retry_token_timeout: Duration::from_secs(60),
+            zero_rtt: ZeroRttPolicy::Disabled,
             incoming_queue_size: 100,
             max_active_conn_size: 500,
+            shards: 1,
+            pacing: QuicPacing::default(),
             verify_peer: false,
+            reloader: QuicConfigReloader::new(),
+            metrics: Default::default(),
+            shutdown: QuicShutdown::new(),
         }))
     }
 
@@ -150,6 +320,23 @@ impl QuicServer {
         }))
     }
 
+    /// Set the number of listener shards, the default value is `1`.
+    ///
+    /// Each shard binds its own `SO_REUSEPORT` sockets to the listening addresses, and runs its
+    /// own receiving task and connection table, the kernel balances the datagrams between the
+    /// shards by the hash of the 4-tuple. A packet that reaches another shard than its connection,
+    /// e.g. after a migration, is routed by the connection id. More than one shard is only
+    /// supported on Linux.
+    pub fn shards(self, value: usize) -> Self {
+        assert!(value > 0, "`shards` is set to `0`");
+
+        Self(self.0.and_then(|mut config| {
+            config.shards = value;
+
+            Ok(config)
+        }))
+    }
+
     /// Update expiration interval for retry token, the default value `60s`.
     pub fn retry_token_timeout(self, duration: Duration) -> Self {
         Self(self.0.and_then(|mut config| {
@@ -171,6 +358,83 @@ impl QuicServer {
         }))
     }
 
+    /// Update the generator of source connection ids, default is [`RandomConnectionIdGenerator`].
+    ///
+    /// Use [`QuicLbConnectionIdGenerator`](crate::QuicLbConnectionIdGenerator) to route the
+    /// packets by a `QUIC-LB` load balancer.
+    pub fn conn_id_generator<G>(self, generator: G) -> Self
+    where
+        G: ConnectionIdGenerator + Sync + Send + 'static,
+    {
+        Self(self.0.and_then(|mut config| {
+            config.conn_id_generator = Arc::new(generator);
+
+            Ok(config)
+        }))
+    }
+
+    /// Update the handling of the `0-RTT` data, default is [`ZeroRttPolicy::Disabled`].
+    pub fn zero_rtt(self, policy: ZeroRttPolicy) -> Self {
+        Self(self.0.and_then(|mut config| {
+            if policy != ZeroRttPolicy::Disabled {
+                config.config.enable_early_data();
+            }
+
+            config.zero_rtt = policy;
+
+            Ok(config)
+        }))
+    }
+
+    /// Update the pacing of the connections, default is [`QuicPacing::Off`].
+    pub fn pacing(self, pacing: QuicPacing) -> Self {
+        Self(self.0.and_then(|mut config| {
+            config.pacing = pacing;
+
+            Ok(config)
+        }))
+    }
+
+    /// Update the key of the stateless reset tokens, default is a random one.
+    ///
+    /// Set a static key to reset the connections of the peers after a restart.
+    pub fn stateless_reset_key(self, key: StatelessResetKey) -> Self {
+        Self(self.0.and_then(|mut config| {
+            config.stateless_reset_key = key;
+
+            Ok(config)
+        }))
+    }
+
+    /// Attach `reloader` to the listener, the default is a new one.
+    ///
+    /// Use it to create the reload handle before the listener is bound.
+    pub fn config_reloader(self, reloader: QuicConfigReloader) -> Self {
+        Self(self.0.and_then(|mut config| {
+            config.reloader = reloader;
+
+            Ok(config)
+        }))
+    }
+
+    /// Record the server counters to `metrics`, the default is a new one.
+    pub fn metrics(self, metrics: Arc<QuicServerMetrics>) -> Self {
+        Self(self.0.and_then(|mut config| {
+            config.metrics = metrics;
+
+            Ok(config)
+        }))
+    }
+
+    /// Attach the graceful shutdown `handle` to the listener, the default is a new one.
+    pub fn shutdown_handle(self, handle: QuicShutdown) -> Self {
+        Self(self.0.and_then(|mut config| {
+            config.shutdown = handle;
+
+            Ok(config)
+        }))
+    }
+
     /// See [`bind_with`](Self::bind_with).
     #[cfg(feature = "global_reactor")]
     pub async fn bind<S>(self, laddrs: S) -> Result<QuicListener>
@@ -188,47 +452,128 @@ impl QuicServer {
         S: ToSocketAddrs,
     {
         let this = self.0?;
-        let (udp_group_sender, udp_group_receiver) =
-            udp_group::bind_with(laddrs, 65527, reactor.clone()).await?;
 
-        let laddrs = udp_group_sender.local_addrs().copied().collect::<_>();
+        let groups = Self::bind_shards(laddrs, this.shards, reactor.clone()).await?;
 
-        let validator = this
-            .validator
-            .unwrap_or_else(|| Box::new(SimpleAddressValidator::new(this.retry_token_timeout)));
+        let mut pacing = this.pacing;
 
-        let (incoming_sender, incoming_receiver) = mpsc::channel(this.incoming_queue_size);
+        if pacing == QuicPacing::TxTime
+            && let Err(err) = groups
+                .iter()
+                .try_for_each(|(udp_group_sender, _)| udp_group_sender.set_txtime())
+        {
+            log::warn!("QuicServer: fall back to timer pacing, err={}", err);
+            pacing = QuicPacing::Timer;
+        }
 
-        let quiche_conn_set: Arc<DashMap<ConnectionId<'static>, QuicConnDispatcher>> =
-            Default::default();
+        // Safety: at least one shard is bound.
+        let laddrs = groups[0].0.local_addrs().copied().collect::<Vec<_>>();
 
-        let server = QuicListenerDriver {
-            reactor,
-            udp_group_sender,
-            udp_group_receiver,
-            config: this.config,
-            validator,
-            incoming_sender,
-            quiche_conn_set: quiche_conn_set.clone(),
-            handshaking_conn_set: Default::default(),
-            max_active_conn_size: this.max_active_conn_size,
-            verify_peer: this.verify_peer,
+        let validator: Arc<dyn AddressValidator + Sync + Send> = match this.validator {
+            Some(validator) => validator.into(),
+            None => Arc::new(SimpleAddressValidator::new(this.retry_token_timeout)),
         };
 
-        spawn(async move {
-            if let Err(err) = server.run().await {
-                log::error!("listener stopped with error: {}", err);
-            } else {
-                log::trace!("listener stopped.",);
-            }
-        })?;
+        let (incoming_sender, incoming_receiver) = mpsc::channel(this.incoming_queue_size);
+
+        let (closed, closed_receiver) = oneshot::channel();
+
+        // dropped when the last driver task exits.
+        let closed = Arc::new(closed);
+
+        let config = Arc::new(Mutex::new(this.config));
+
+        let early_data_conn_ids: Arc<Mutex<HashMap<ConnectionId<'static>, Instant>>> =
+            Default::default();
+
+        let shards: Arc<[ShardConns]> = (0..groups.len()).map(|_| Default::default()).collect();
+
+        for (index, (udp_group_sender, udp_group_receiver)) in groups.into_iter().enumerate() {
+            let server = QuicListenerDriver {
+                reactor: reactor.clone(),
+                udp_group_sender,
+                udp_group_receiver,
+                config: config.clone(),
+                validator: validator.clone(),
+                conn_id_generator: this.conn_id_generator.clone(),
+                stateless_reset_key: this.stateless_reset_key.clone(),
+                zero_rtt: this.zero_rtt,
+                pacing,
+                retry_token_timeout: this.retry_token_timeout,
+                early_data_conn_ids: early_data_conn_ids.clone(),
+                incoming_sender: incoming_sender.clone(),
+                index,
+                shards: shards.clone(),
+                max_active_conn_size: this.max_active_conn_size,
+                verify_peer: this.verify_peer,
+                reloader: this.reloader.clone(),
+                metrics: this.metrics.clone(),
+                shutdown: this.shutdown.clone(),
+                closing: false,
+                _closed: closed.clone(),
+            };
+
+            spawn(async move {
+                if let Err(err) = server.run().await {
+                    log::error!("listener stopped with error: {}, shard={}", err, index);
+                } else {
+                    log::trace!("listener stopped, shard={}", index);
+                }
+            })?;
+        }
 
         Ok(QuicListener {
             incoming: incoming_receiver,
             laddrs,
-            quiche_conn_set,
+            shards,
+            reloader: this.reloader,
+            metrics: this.metrics,
+            shutdown: this.shutdown,
+            closed: closed_receiver,
         })
     }
+
+    /// Bind the udp groups of `shards` to `laddrs`.
+    async fn bind_shards<S>(
+        laddrs: S,
+        shards: usize,
+        reactor: Reactor,
+    ) -> Result<Vec<(UdpGroupSender, UdpGroupReceiver)>>
+    where
+        S: ToSocketAddrs,
+    {
+        if shards == 1 {
+            return Ok(vec![udp_group::bind_with(laddrs, 65527, reactor).await?]);
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let mut groups =
+                vec![udp_group::bind_reuse_port_with(laddrs, 65527, reactor.clone()).await?];
+
+            // the other shards share the ports assigned to the first one.
+            let laddrs = groups[0].0.local_addrs().copied().collect::<Vec<_>>();
+
+            for _ in 1..shards {
+                groups.push(
+                    udp_group::bind_reuse_port_with(laddrs.as_slice(), 65527, reactor.clone())
+                        .await?,
+                );
+            }
+
+            Ok(groups)
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            _ = (laddrs, reactor);
+
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "QuicServer: more than one shard requires linux `SO_REUSEPORT`",
+            ))
+        }
+    }
 }
 
 /// Listener driver.
@@ -238,20 +583,43 @@ struct QuicListenerDriver {
     udp_group_sender: UdpGroupSender,
     /// udp sockets group.
     udp_group_receiver: UdpGroupReceiver,
-    /// quic server-side config.
-    config: quiche::Config,
+    /// quic server-side config, shared by the shards.
+    config: Arc<Mutex<quiche::Config>>,
     /// validator for retry packet.
-    validator: Box<dyn AddressValidator + Sync + Send>,
+    validator: Arc<dyn AddressValidator + Sync + Send>,
+    /// generator for the source connection ids.
+    conn_id_generator: Arc<dyn ConnectionIdGenerator + Sync + Send>,
+    /// key of the stateless reset tokens.
+    stateless_reset_key: StatelessResetKey,
+    /// handling of the `0-RTT` data.
+    zero_rtt: ZeroRttPolicy,
+    /// pacing of the connections.
+    pacing: QuicPacing,
+    /// expiration interval for retry token, the replay window of the `0-RTT` data.
+    retry_token_timeout: Duration,
+    /// initial source connection ids of the connections accepted in early data, shared by the
+    /// shards, a replayed handshake may reach any of them.
+    early_data_conn_ids: Arc<Mutex<HashMap<ConnectionId<'static>, Instant>>>,
     /// incoming connection sender.
     incoming_sender: mpsc::Sender<QuicConn>,
-    /// handshaking connections.
-    handshaking_conn_set: Arc<DashSet<ConnectionId<'static>>>,
-    /// aliving quic streams.
-    quiche_conn_set: Arc<DashMap<ConnectionId<'static>, QuicConnDispatcher>>,
+    /// the shard of this driver.
+    index: usize,
+    /// connection tables of all the shards.
+    shards: Arc<[ShardConns]>,
     /// The maximum number of active connections that this server handles.
     max_active_conn_size: usize,
     /// Wether to verify the peer’s certificate.
     verify_peer: bool,
+    /// runtime `quiche::Config` replacement.
+    reloader: QuicConfigReloader,
+    /// server counters.
+    metrics: Arc<QuicServerMetrics>,
+    /// graceful shutdown handle.
+    shutdown: QuicShutdown,
+    /// Whether the remaining connections are closed after the grace period.
+    closing: bool,
+    /// dropped when the driver task exits.
+    _closed: Arc<oneshot::Sender<()>>,
 }
 
 impl QuicListenerDriver {
@@ -294,13 +662,35 @@ impl QuicListenerDriver {
             }
         };
 
-        let mut quiche_conn = match quiche::accept(
-            &header.dcid,
-            Some(&odcid),
-            recv_info.to,
-            recv_info.from,
-            &mut self.config,
-        ) {
+        let accepted = {
+            let mut config = self.config.lock().unwrap();
+
+            if let Some(reloaded) = self.reloader.take() {
+                log::info!(
+                    "QuicServer: reload quiche config, laddrs={:?}",
+                    self.udp_group_sender.local_addrs().collect::<Vec<_>>()
+                );
+
+                *config = reloaded;
+
+                if self.zero_rtt != ZeroRttPolicy::Disabled {
+                    config.enable_early_data();
+                }
+            }
+
+            // the token of the initial source connection id is sent in the transport parameters.
+            config.set_stateless_reset_token(Some(self.stateless_reset_key.token(&header.dcid)));
+
+            quiche::accept(
+                &header.dcid,
+                Some(&odcid),
+                recv_info.to,
+                recv_info.from,
+                &mut config,
+            )
+        };
+
+        let mut quiche_conn = match accepted {
             Ok(conn) => {
                 log::trace!(
                     "QuicServer(initial) accept new conn, from={:?}, to={}, scid={:?}, dcid={:?}, odcid={:?}",
@@ -337,8 +727,22 @@ impl QuicListenerDriver {
             return Ok(());
         }
 
+        if quiche_conn.is_in_early_data() && self.is_replayed(&header.dcid) {
+            self.metrics.early_data_replays.inc();
+
+            log::warn!(
+                "QuicServer: drop replayed early data, trace_id={}, from={}, to={}",
+                quiche_conn.trace_id(),
+                recv_info.from,
+                recv_info.to
+            );
+            return Ok(());
+        }
+
         // check `max_active_conn_size` condition.
-        if !(self.quiche_conn_set.len() < self.max_active_conn_size) {
+        if !(active_conns(&self.shards) < self.max_active_conn_size) {
+            self.metrics.max_active_conn_drops.inc();
+
             log::warn!(
                 "QuicServer: the `max_active_conn_size` reached, trace_id={}, from={}, to={}",
                 quiche_conn.trace_id(),
@@ -348,9 +752,12 @@ impl QuicListenerDriver {
             return Ok(());
         }
 
+        let shard = &self.shards[self.index];
+
         // add to handshaking set.
         if !quiche_conn.is_established() {
-            self.handshaking_conn_set
+            shard
+                .handshaking_conn_set
                 .insert(header.dcid.clone().into_owned());
 
             log::trace!(
@@ -361,6 +768,8 @@ impl QuicListenerDriver {
                 header.dcid,
             );
         } else {
+            self.metrics.handshakes.inc();
+
             log::trace!(
                 "QuicServer(initial) established, from={:?}, to={}, scid={:?}, dcid={:?}",
                 recv_info.from,
@@ -370,24 +779,37 @@ impl QuicListenerDriver {
             );
         }
 
-        let max_send_udp_payload_size = quiche_conn.max_send_udp_payload_size();
-
         let dispatcher = QuicConnDispatcher::new(quiche_conn, self.reactor.clone());
 
-        self.quiche_conn_set
+        {
+            let mut state = dispatcher.0.lock().unwrap();
+            state.metrics = Some(self.metrics.clone());
+            state.conn_id_generator = self.conn_id_generator.clone();
+            state.pacing = self.pacing;
+        }
+
+        shard
+            .quiche_conn_set
             .insert(header.dcid.clone().into_owned(), dispatcher.clone());
 
+        shard
+            .conn_ids
+            .insert(header.dcid.clone().into_owned(), dispatcher.clone());
+
+        self.metrics
+            .active_conns
+            .set(active_conns(&self.shards) as i64);
+
         let scid = header.dcid.into_owned();
 
-        let quiche_conn_set = self.quiche_conn_set.clone();
-        let handshaking_conn_set = self.handshaking_conn_set.clone();
+        let index = self.index;
+        let shards = self.shards.clone();
+        let metrics = self.metrics.clone();
         let udp_group_sender = self.udp_group_sender.clone();
 
         // io sending task.
         spawn(async move {
-            if let Err(err) =
-                Self::conn_send_loop(udp_group_sender, dispatcher, max_send_udp_payload_size).await
-            {
+            if let Err(err) = Self::conn_send_loop(udp_group_sender, dispatcher.clone()).await {
                 log::error!(
                     "QuicConn(Server) sending loop is stopped, scid={:?},err={}",
                     scid,
@@ -398,8 +820,16 @@ impl QuicListenerDriver {
             // clearup:
             // - try remove from handshaking set.
             // - try remove from established set.
-            handshaking_conn_set.remove(&scid);
-            quiche_conn_set.remove(&scid);
+            let shard = &shards[index];
+
+            shard.handshaking_conn_set.remove(&scid);
+            shard.quiche_conn_set.remove(&scid);
+
+            for id in Self::remove_conn_ids(&dispatcher) {
+                shard.conn_ids.remove(&id);
+            }
+
+            metrics.active_conns.set(active_conns(&shards) as i64);
 
             log::trace!(
                 "QuicConn(Server) remove connection from set, scid={:?}",
@@ -408,31 +838,107 @@ impl QuicListenerDriver {
         })
     }
 
+    /// Issue new source connection ids to the peer of `dispatcher` and index them in `shard`, the
+    /// owner of the connection, unindex the ids retired by the peer.
+    fn update_conn_ids(&self, shard: &ShardConns, dispatcher: &QuicConnDispatcher) {
+        let mut issued = vec![];
+        let mut retired = vec![];
+
+        let mut state = dispatcher.0.lock().unwrap();
+
+        while let Some(id) = state.quiche_conn.retired_scid_next() {
+            retired.push(id);
+        }
+
+        // new ids are only issued after the handshake, the peer keeps the initial one until then.
+        while state.quiche_conn.is_established() && state.quiche_conn.scids_left() > 0 {
+            let id = state.conn_id_generator.generate();
+
+            match state
+                .quiche_conn
+                .new_scid(&id, self.stateless_reset_key.token(&id), false)
+            {
+                Ok(_) => issued.push(id),
+                Err(err) => {
+                    log::error!(
+                        "QuicServer: issue new connection id, trace_id={}, err={}",
+                        state.quiche_conn.trace_id(),
+                        err
+                    );
+                    break;
+                }
+            }
+        }
+
+        if issued.is_empty() && retired.is_empty() {
+            return;
+        }
+
+        log::trace!(
+            "QuicServer: update connection ids, trace_id={}, issued={:?}, retired={:?}",
+            state.quiche_conn.trace_id(),
+            issued,
+            retired
+        );
+
+        // send the `NEW_CONNECTION_ID` frames.
+        let waker = state.send_waker.take();
+
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+
+        for id in retired {
+            shard.conn_ids.remove(&id);
+        }
+
+        for id in issued {
+            shard.conn_ids.insert(id, dispatcher.clone());
+        }
+    }
+
+    /// Returns all the source connection ids of a closed connection.
+    fn remove_conn_ids(dispatcher: &QuicConnDispatcher) -> Vec<ConnectionId<'static>> {
+        let mut state = dispatcher.0.lock().unwrap();
+
+        let mut ids = state
+            .quiche_conn
+            .source_ids()
+            .map(|id| id.clone().into_owned())
+            .collect::<Vec<_>>();
+
+        while let Some(id) = state.quiche_conn.retired_scid_next() {
+            ids.push(id);
+        }
+
+        ids
+    }
+
     async fn conn_send_loop(
         udp_group_sender: UdpGroupSender,
         dispatcher: QuicConnDispatcher,
-        max_send_udp_payload_size: usize,
     ) -> Result<()> {
-        let mut buf = vec![0; max_send_udp_payload_size];
+        let mut batch = SendBatch::new();
 
         loop {
-            let (send_size, send_info) = dispatcher.send(&mut buf).await?;
+            batch.fill(&dispatcher).await?;
 
-            log::trace!(
-                "QuicServer(send_loop) send data {}, from={}, to={}",
-                send_size,
-                send_info.from,
-                send_info.to
-            );
+            for (from, transmits) in batch.transmits(udp_group_sender.max_gso_segments()) {
+                log::trace!(
+                    "QuicServer(send_loop) send data, from={}, transmits={}",
+                    from,
+                    transmits.len()
+                );
 
-            udp_group_sender
-                .send(&buf[..send_size], send_info.from, send_info.to)
-                .await?;
+                udp_group_sender.send_batch(&transmits, from).await?;
+            }
         }
     }
 
     async fn retry(&self, header: Header<'_>, buf: &mut [u8], recv_info: RecvInfo) -> Result<()> {
-        let new_scid = random_conn_id();
+        let new_scid = self.conn_id_generator.generate();
 
         log::trace!(
             "retry, from={:?}, to={}, scid={:?}, dcid={:?}, new_scid={:?}",
@@ -472,6 +978,8 @@ impl QuicListenerDriver {
             }
         };
 
+        self.metrics.retries.inc();
+
         self.udp_group_sender
             .send(&buf[..send_size], recv_info.to, recv_info.from)
             .await
@@ -507,107 +1015,336 @@ impl QuicListenerDriver {
             }
         };
 
+        self.metrics.version_negotiations.inc();
+
         self.udp_group_sender
             .send(&buf[..send_size], recv_info.to, recv_info.from)
             .await
             .map(|_| ())
     }
 
-    /// run udp recv loop
-    async fn run(mut self) -> Result<()> {
-        let mut buf = vec![0; 65527];
+    /// Whether the connection is accepted in early data, see [`ZeroRttPolicy::Accept`].
+    fn accept_early_data(&self, dispatcher: &QuicConnDispatcher) -> bool {
+        self.zero_rtt == ZeroRttPolicy::Accept
+            && !self.verify_peer
+            && dispatcher.0.lock().unwrap().quiche_conn.is_in_early_data()
+    }
 
-        loop {
-            let (read_size, from, to) = self.udp_group_receiver.recv(&mut buf).await?;
+    /// Check and record the initial source connection id of an early data connection.
+    ///
+    /// The address validation token binds the id, a replayed handshake reuses it until the
+    /// token expires.
+    fn is_replayed(&self, scid: &ConnectionId<'_>) -> bool {
+        if self.zero_rtt != ZeroRttPolicy::Accept {
+            return false;
+        }
 
-            let recv_info = RecvInfo { from, to };
+        let now = Instant::now();
+        let window = self.retry_token_timeout;
 
-            let header = quiche::Header::from_slice(&mut buf[..read_size], quiche::MAX_CONN_ID_LEN)
-                .map_err(Error::other)?;
+        let mut early_data_conn_ids = self.early_data_conn_ids.lock().unwrap();
+
+        early_data_conn_ids.retain(|_, accepted| now.duration_since(*accepted) < window);
 
+        early_data_conn_ids
+            .insert(scid.clone().into_owned(), now)
+            .is_some()
+    }
+
+    /// Find the connection of `dcid` in the tables of the other shards.
+    ///
+    /// The kernel steers the packets by the 4-tuple, the packets of a migrated connection may
+    /// reach another shard.
+    fn route_other_shards(&self, dcid: &ConnectionId<'_>) -> Option<(usize, QuicConnDispatcher)> {
+        self.shards
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != self.index)
+            .find_map(|(index, shard)| {
+                shard
+                    .conn_ids
+                    .get(dcid)
+                    .map(|dispatcher| (index, dispatcher.clone()))
+            })
+    }
+
+    /// Reset the peer of an unknown connection, e.g. a connection of the process before restart.
+    async fn stateless_reset(
+        &self,
+        header: Header<'_>,
+        buf: &mut [u8],
+        read_size: usize,
+        recv_info: RecvInfo,
+    ) -> Result<()> {
+        let Some(send_size) = self
+            .stateless_reset_key
+            .write_reset(&header.dcid, read_size, buf)
+        else {
             log::trace!(
-                "QuicServer(run) dispatch, scid={:?}, dcid={:?}, from={}, to={}, len={}",
-                header.scid,
-                header.dcid,
+                "QuicServer: packet is too short to reset, from={:?}, to={}, dcid={:?}, len={}",
                 recv_info.from,
                 recv_info.to,
+                header.dcid,
                 read_size
             );
+            return Ok(());
+        };
 
-            let dispatcher = self
-                .quiche_conn_set
-                .get(&header.dcid)
-                .map(|conn| conn.clone());
+        log::trace!(
+            "stateless_reset, from={:?}, to={}, dcid={:?}",
+            recv_info.from,
+            recv_info.to,
+            header.dcid
+        );
 
-            if let Some(dispatcher) = dispatcher {
-                if let Err(err) = dispatcher.recv(&mut buf[..read_size], recv_info).await {
-                    log::error!(
-                        "Failed to dispatch received packet, trace_id={:?}, err={}",
-                        header.dcid,
+        self.metrics.stateless_resets.inc();
+
+        self.udp_group_sender
+            .send(&buf[..send_size], recv_info.to, recv_info.from)
+            .await
+            .map(|_| ())
+    }
+
+    /// Close the remaining connections of this shard when the grace period expires.
+    ///
+    /// Returns true if all the connections are closed.
+    fn drain(&mut self, deadline: Instant) -> bool {
+        let shard = &self.shards[self.index];
+
+        if shard.quiche_conn_set.is_empty() {
+            log::info!(
+                "QuicServer: shutdown, laddrs={:?}",
+                self.udp_group_sender.local_addrs().collect::<Vec<_>>()
+            );
+
+            return true;
+        }
+
+        if !self.closing && Instant::now() >= deadline {
+            log::warn!(
+                "QuicServer: grace period expired, close {} connections, laddrs={:?}",
+                shard.quiche_conn_set.len(),
+                self.udp_group_sender.local_addrs().collect::<Vec<_>>()
+            );
+
+            for dispatcher in shard.quiche_conn_set.iter() {
+                if let Err(err) = dispatcher.close(0x0, b"shutdown") {
+                    log::trace!(
+                        "QuicServer: failed to close, trace_id={:?}, err={}",
+                        dispatcher.key(),
                         err
                     );
                 }
+            }
+
+            self.closing = true;
+        }
+
+        false
+    }
+
+    /// run udp recv loop
+    async fn run(mut self) -> Result<()> {
+        if let Err(err) = self.udp_group_sender.set_gro(true) {
+            log::debug!("QuicServer: UDP GRO is unavailable, err={}", err);
+        }
+
+        let mut buf = vec![0; RECV_BATCH * 65527];
+        let mut bufs = buf.chunks_mut(65527).collect::<Vec<_>>();
+        let mut metas = [RecvMeta::default(); RECV_BATCH];
+
+        loop {
+            let recv = {
+                let recv = pin!(self.udp_group_receiver.recv_batch(&mut bufs, &mut metas));
+
+                if self.shutdown.is_shutdown() {
+                    // re-check the connection set periodically when draining.
+                    let tick = pin!(sleep_with(DRAIN_CHECK_INTERVAL, self.reactor.clone()));
+
+                    match select(recv, tick).await {
+                        Either::Left((recv, _)) => Some(recv?),
+                        Either::Right(_) => None,
+                    }
+                } else {
+                    match select(recv, pin!(self.shutdown.wait())).await {
+                        Either::Left((recv, _)) => Some(recv?),
+                        Either::Right((deadline, _)) => {
+                            log::info!(
+                                "QuicServer: start shutdown, laddrs={:?}, active_conns={}, grace={:?}",
+                                self.udp_group_sender.local_addrs().collect::<Vec<_>>(),
+                                self.shards[self.index].quiche_conn_set.len(),
+                                deadline.saturating_duration_since(Instant::now())
+                            );
+
+                            None
+                        }
+                    }
+                }
+            };
+
+            if let Some(deadline) = self.shutdown.deadline()
+                && self.drain(deadline)
+            {
+                return Ok(());
+            }
+
+            let Some((received, laddr)) = recv else {
+                continue;
+            };
+
+            for (buf, meta) in bufs.iter_mut().zip(metas.iter()).take(received) {
+                // the wildcard sockets report the address the client sent to.
+                let recv_info = RecvInfo {
+                    from: meta.from,
+                    to: meta
+                        .dst_ip
+                        .map_or(laddr, |ip| SocketAddr::new(ip, laddr.port())),
+                };
+
+                // a GRO buffer holds several datagrams of `stride` bytes, the last may be shorter.
+                for datagram in buf[..meta.len].chunks_mut(meta.stride.max(1)) {
+                    let read_size = datagram.len();
+
+                    self.dispatch(datagram, read_size, recv_info).await?;
+                }
+            }
+        }
+    }
+
+    /// Dispatch one received datagram.
+    async fn dispatch(
+        &mut self,
+        buf: &mut [u8],
+        read_size: usize,
+        recv_info: RecvInfo,
+    ) -> Result<()> {
+        let header =
+            quiche::Header::from_slice(&mut buf[..read_size], self.conn_id_generator.conn_id_len())
+                .map_err(Error::other)?;
+
+        log::trace!(
+            "QuicServer(run) dispatch, scid={:?}, dcid={:?}, from={}, to={}, len={}",
+            header.scid,
+            header.dcid,
+            recv_info.from,
+            recv_info.to,
+            read_size
+        );
+
+        let routed = self.shards[self.index]
+            .conn_ids
+            .get(&header.dcid)
+            .map(|conn| (self.index, conn.clone()))
+            .or_else(|| self.route_other_shards(&header.dcid));
+
+        if let Some((index, dispatcher)) = routed {
+            let shard = &self.shards[index];
+
+            if let Err(err) = dispatcher.recv(&mut buf[..read_size], recv_info).await {
+                log::error!(
+                    "Failed to dispatch received packet, trace_id={:?}, err={}",
+                    header.dcid,
+                    err
+                );
+            }
 
-                if self.handshaking_conn_set.contains(&header.dcid) {
-                    if dispatcher.is_established() {
-                        log::trace!(
-                            "QuicServer(dispatch) established, trace_id={:?}, from={}, to={}",
+            self.update_conn_ids(shard, &dispatcher);
+
+            if shard.handshaking_conn_set.contains(&header.dcid) {
+                let established = dispatcher.is_established();
+
+                if established || self.accept_early_data(&dispatcher) {
+                    log::trace!(
+                        "QuicServer(dispatch) established, trace_id={:?}, from={}, to={}, early_data={}",
+                        header.dcid,
+                        recv_info.from,
+                        recv_info.to,
+                        !established
+                    );
+
+                    shard.handshaking_conn_set.remove(&header.dcid);
+
+                    if established {
+                        self.metrics.handshakes.inc();
+                    } else {
+                        self.metrics.early_data_accepts.inc();
+                    }
+
+                    // Safety: if `try_send` func returns error, the `QuicConn` instance will
+                    // automatic drop connection resources, includes:
+                    //
+                    // - stop send/recv io tasks.
+                    // - remove associated dispatcher from tracking table.
+                    let conn = QuicConn(dispatcher.0.clone());
+
+                    if self.verify_peer && conn.quiche_conn(|conn| conn.peer_cert().is_none()) {
+                        log::warn!(
+                            "QuicServer: failed to verfy peer, trace_id={:?}, from={}, to={}, err=anonymous client",
                             header.dcid,
                             recv_info.from,
                             recv_info.to
                         );
+                        return Ok(());
+                    }
 
-                        self.handshaking_conn_set.remove(&header.dcid);
+                    if self.shutdown.is_shutdown() {
+                        log::warn!(
+                            "QuicServer: shutting down, drop new conn, trace_id={:?}, from={}, to={}",
+                            header.dcid,
+                            recv_info.from,
+                            recv_info.to
+                        );
+                        return Ok(());
+                    }
 
-                        // Safety: if `try_send` func returns error, the `QuicConn` instance will
-                        // automatic drop connection resources, includes:
-                        //
-                        // - stop send/recv io tasks.
-                        // - remove associated dispatcher from tracking table.
-                        let conn = QuicConn(dispatcher.0.clone());
+                    if let Err(err) = self.incoming_sender.try_send(conn) {
+                        if err.is_full() {
+                            self.metrics.incoming_queue_full_drops.inc();
 
-                        if self.verify_peer && conn.quiche_conn(|conn| conn.peer_cert().is_none()) {
                             log::warn!(
-                                "QuicServer: failed to verfy peer, trace_id={:?}, from={}, to={}, err=anonymous client",
+                                "QuicServer: incoming queue is full, drop new conn, trace_id={:?}, from={}, to={}",
                                 header.dcid,
                                 recv_info.from,
                                 recv_info.to
                             );
-                            continue;
+                            return Ok(());
                         }
 
-                        if let Err(err) = self.incoming_sender.try_send(conn) {
-                            if err.is_full() {
-                                log::warn!(
-                                    "QuicServer: incoming queue is full, drop new conn, trace_id={:?}, from={}, to={}",
-                                    header.dcid,
-                                    recv_info.from,
-                                    recv_info.to
-                                );
-                                continue;
-                            }
-
-                            return Err(Error::other(err.into_send_error()));
-                        }
+                        return Err(Error::other(err.into_send_error()));
                     }
                 }
-            } else {
-                match header.ty {
-                    quiche::Type::Initial => {
-                        self.initial(header, &mut buf, read_size, recv_info).await?;
-                    }
-                    _ => {
-                        log::error!(
-                            "QuicServer(run) recv unsupport packet, scid={:?}, dcid={:?}, from={}, to={}, ty={:?}, ",
-                            header.scid,
-                            header.dcid,
-                            recv_info.from,
-                            recv_info.to,
-                            header.ty
-                        );
-                    }
+            }
+        } else {
+            match header.ty {
+                quiche::Type::Initial if self.shutdown.is_shutdown() => {
+                    log::trace!(
+                        "QuicServer(run) shutting down, ignore initial packet, scid={:?}, dcid={:?}, from={}, to={}",
+                        header.scid,
+                        header.dcid,
+                        recv_info.from,
+                        recv_info.to,
+                    );
+                }
+                quiche::Type::Initial => {
+                    self.initial(header, buf, read_size, recv_info).await?;
+                }
+                quiche::Type::Short => {
+                    self.stateless_reset(header, buf, read_size, recv_info)
+                        .await?;
+                }
+                _ => {
+                    log::error!(
+                        "QuicServer(run) recv unsupport packet, scid={:?}, dcid={:?}, from={}, to={}, ty={:?}, ",
+                        header.scid,
+                        header.dcid,
+                        recv_info.from,
+                        recv_info.to,
+                        header.ty
+                    );
                 }
             }
         }
+
+        Ok(())
     }
 }
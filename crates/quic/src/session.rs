@@ -0,0 +1,194 @@
+//! TLS session cache for the resumption and `0-RTT` of [`QuicConnector`](crate::QuicConnector).
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::{Error, ErrorKind, Result, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Default)]
+struct QuicSessionCacheState {
+    /// sessions keyed by server name or address.
+    sessions: HashMap<String, Vec<u8>>,
+    /// file that persists the sessions.
+    path: Option<PathBuf>,
+}
+
+/// The latest TLS session of each server, shared by the clones.
+///
+/// Sessions are persisted as `{server} {hex session}` lines if a file is attached.
+#[derive(Clone, Default)]
+pub struct QuicSessionCache(Arc<Mutex<QuicSessionCacheState>>);
+
+impl QuicSessionCache {
+    /// Create an in-memory cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a cache persisted to `path`, loads the sessions saved by the previous process.
+    ///
+    /// A missing or broken file is treated as an empty cache, the sessions are only an
+    /// optimization of the handshakes.
+    pub fn with_file<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref();
+
+        let sessions = match load(path) {
+            Ok(sessions) => sessions,
+            Err(err) => {
+                if err.kind() != ErrorKind::NotFound {
+                    log::warn!(
+                        "QuicSessionCache: ignore session file, path={:?}, err={}",
+                        path,
+                        err
+                    );
+                }
+
+                Default::default()
+            }
+        };
+
+        Self(Arc::new(Mutex::new(QuicSessionCacheState {
+            sessions,
+            path: Some(path.to_owned()),
+        })))
+    }
+
+    /// Returns the session of `server`.
+    pub fn get(&self, server: &str) -> Option<Vec<u8>> {
+        self.0.lock().unwrap().sessions.get(server).cloned()
+    }
+
+    /// Update the session of `server`, and write the file if attached.
+    pub fn put(&self, server: &str, session: &[u8]) {
+        let mut state = self.0.lock().unwrap();
+
+        if state.sessions.get(server).map(Vec::as_slice) == Some(session) {
+            return;
+        }
+
+        state.sessions.insert(server.to_owned(), session.to_vec());
+
+        if let Some(path) = &state.path
+            && let Err(err) = save(path, &state.sessions)
+        {
+            log::error!(
+                "QuicSessionCache: write session file, path={:?}, err={}",
+                path,
+                err
+            );
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<HashMap<String, Vec<u8>>> {
+    let content = std::fs::read_to_string(path)?;
+
+    let mut sessions = HashMap::new();
+
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let (server, session) = line
+            .split_once(' ')
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "expect `{server} {session}`"))?;
+
+        sessions.insert(server.to_owned(), decode_hex(session.trim())?);
+    }
+
+    Ok(sessions)
+}
+
+fn save(path: &Path, sessions: &HashMap<String, Vec<u8>>) -> Result<()> {
+    let mut content = String::new();
+
+    for (server, session) in sessions {
+        content.push_str(server);
+        content.push(' ');
+
+        for byte in session {
+            _ = write!(content, "{:02x}", byte);
+        }
+
+        content.push('\n');
+    }
+
+    // replace the file atomically, a crash never leaves a truncated file.
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    // the mode only applies to a new file, a leftover one is replaced.
+    _ = std::fs::remove_file(&tmp);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    // the tickets are resumption secrets, only readable by the owner.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&tmp)?;
+
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+
+    drop(file);
+
+    std::fs::rename(&tmp, path)
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(Error::new(ErrorKind::InvalidData, "odd hex length"));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(hex.get(i..i + 2).unwrap_or_default(), 16)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_file() {
+        let path = std::env::temp_dir().join(format!("n3quic-session-{}", std::process::id()));
+
+        let cache = QuicSessionCache::with_file(&path);
+
+        assert_eq!(cache.get("example.com"), None);
+
+        cache.put("example.com", &[0x00, 0x1f, 0xff]);
+        cache.put("127.0.0.1:443", b"ticket");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let cache = QuicSessionCache::with_file(&path);
+
+        assert_eq!(cache.get("example.com"), Some(vec![0x00, 0x1f, 0xff]));
+        assert_eq!(cache.get("127.0.0.1:443"), Some(b"ticket".to_vec()));
+
+        std::fs::write(&path, "broken").unwrap();
+
+        assert_eq!(QuicSessionCache::with_file(&path).get("example.com"), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    FutureExt,
+    channel::oneshot,
+    future::{Shared, pending},
+};
+
+#[derive(Default)]
+struct ShutdownState {
+    sender: Option<oneshot::Sender<()>>,
+    deadline: Option<Instant>,
+}
+
+/// A handle to shutdown a [`QuicListener`](crate::QuicListener) gracefully.
+///
+/// After [`shutdown`](Self::shutdown) is called, the listener stops accepting new connections,
+/// the established ones are closed when the grace period expires.
+#[derive(Clone)]
+pub struct QuicShutdown {
+    state: Arc<Mutex<ShutdownState>>,
+    signal: Shared<oneshot::Receiver<()>>,
+}
+
+impl Default for QuicShutdown {
+    fn default() -> Self {
+        let (sender, receiver) = oneshot::channel();
+
+        Self {
+            state: Arc::new(Mutex::new(ShutdownState {
+                sender: Some(sender),
+                deadline: None,
+            })),
+            signal: receiver.shared(),
+        }
+    }
+}
+
+impl Debug for QuicShutdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QuicShutdown")
+            .field("deadline", &self.deadline())
+            .finish()
+    }
+}
+
+impl QuicShutdown {
+    /// Create a new handle, which is not attached to any listener.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start the graceful shutdown, connections still alive after `grace` are closed.
+    ///
+    /// Only the first call takes effect.
+    pub fn shutdown(&self, grace: Duration) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(sender) = state.sender.take() {
+            state.deadline = Some(Instant::now() + grace);
+            _ = sender.send(());
+        }
+    }
+
+    /// Returns the end of the grace period, if the shutdown is started.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.state.lock().unwrap().deadline
+    }
+
+    /// Returns true if the shutdown is started.
+    pub fn is_shutdown(&self) -> bool {
+        self.deadline().is_some()
+    }
+
+    /// Wait for the shutdown to start, returns the end of the grace period.
+    pub async fn wait(&self) -> Instant {
+        if self.signal.clone().await.is_err() {
+            // unreachable, `self` holds the sender.
+            pending::<()>().await;
+        }
+
+        self.deadline().expect("deadline")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[futures_test::test]
+    async fn test_shutdown() {
+        let shutdown = QuicShutdown::new();
+        let cloned = shutdown.clone();
+
+        assert!(!cloned.is_shutdown());
+
+        shutdown.shutdown(Duration::from_secs(10));
+
+        let deadline = cloned.wait().await;
+
+        assert!(cloned.is_shutdown());
+        assert_eq!(cloned.deadline(), Some(deadline));
+
+        // only the first call takes effect.
+        shutdown.shutdown(Duration::ZERO);
+
+        assert_eq!(shutdown.wait().await, deadline);
+    }
+}
@@ -0,0 +1,387 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    str::FromStr,
+};
+
+/// Congestion control algorithms of [`QuicTuning::cc_algorithm`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum QuicCongestionControl {
+    /// CUBIC, the default of quiche.
+    Cubic,
+    /// New Reno.
+    Reno,
+    /// BBRv2.
+    Bbr2,
+}
+
+impl FromStr for QuicCongestionControl {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cubic" => Ok(Self::Cubic),
+            "reno" => Ok(Self::Reno),
+            "bbr2" => Ok(Self::Bbr2),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "unknown congestion control `{}`, expect `cubic`, `reno` or `bbr2`",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+impl From<QuicCongestionControl> for quiche::CongestionControlAlgorithm {
+    fn from(value: QuicCongestionControl) -> Self {
+        match value {
+            QuicCongestionControl::Cubic => Self::CUBIC,
+            QuicCongestionControl::Reno => Self::Reno,
+            QuicCongestionControl::Bbr2 => Self::BBR2,
+        }
+    }
+}
+
+/// Transport parameters of `quiche::Config`, unset fields leave the config unchanged.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct QuicTuning {
+    /// Sets the `initial_max_streams_bidi` and `initial_max_streams_uni` transport parameters.
+    pub initial_max_streams: Option<u64>,
+    /// Sets the `initial_max_stream_data_*` transport parameters.
+    ///
+    /// The `initial_max_data` is set to `initial_max_streams * initial_max_stream_data`.
+    pub initial_max_stream_data: Option<u64>,
+    /// Sets the `max_idle_timeout` transport parameter, in milliseconds.
+    pub max_idle_timeout: Option<u64>,
+    /// Sets the `max_ack_delay` transport parameter, in milliseconds.
+    pub max_ack_delay: Option<u64>,
+    /// Sets the `ack_delay_exponent` transport parameter.
+    pub ack_delay_exponent: Option<u64>,
+    /// Enables the DATAGRAM extension, with receive and send queues of this length.
+    pub dgram_queue_len: Option<usize>,
+    /// Sets the congestion control algorithm.
+    pub cc_algorithm: Option<QuicCongestionControl>,
+    /// Sets the initial congestion window, in `1..=1000` packets.
+    pub initial_congestion_window_packets: Option<usize>,
+    /// Sets the maximum outgoing and incoming UDP payload size, in `1200..=65527` bytes.
+    pub max_udp_payload_size: Option<usize>,
+    /// Enables the path MTU discovery, probing up to `max_udp_payload_size`.
+    pub discover_pmtu: Option<bool>,
+    /// Enables the HyStart++ slow start of the congestion controller.
+    pub hystart: Option<bool>,
+    /// Enables the pacing of quiche, the send time of each packet is computed by the congestion
+    /// controller, see [`QuicPacing`](crate::QuicPacing).
+    pub enable_pacing: Option<bool>,
+    /// Sets the maximum pacing rate, in bytes per second.
+    pub max_pacing_rate: Option<u64>,
+    /// Sets the `active_connection_id_limit` transport parameter, at least `2`.
+    pub active_connection_id_limit: Option<u64>,
+}
+
+impl QuicTuning {
+    /// Returns a copy of `self` with unset fields taken from `base`.
+    pub fn or(&self, base: &QuicTuning) -> QuicTuning {
+        QuicTuning {
+            initial_max_streams: self.initial_max_streams.or(base.initial_max_streams),
+            initial_max_stream_data: self
+                .initial_max_stream_data
+                .or(base.initial_max_stream_data),
+            max_idle_timeout: self.max_idle_timeout.or(base.max_idle_timeout),
+            max_ack_delay: self.max_ack_delay.or(base.max_ack_delay),
+            ack_delay_exponent: self.ack_delay_exponent.or(base.ack_delay_exponent),
+            dgram_queue_len: self.dgram_queue_len.or(base.dgram_queue_len),
+            cc_algorithm: self.cc_algorithm.or(base.cc_algorithm),
+            initial_congestion_window_packets: self
+                .initial_congestion_window_packets
+                .or(base.initial_congestion_window_packets),
+            max_udp_payload_size: self.max_udp_payload_size.or(base.max_udp_payload_size),
+            discover_pmtu: self.discover_pmtu.or(base.discover_pmtu),
+            hystart: self.hystart.or(base.hystart),
+            enable_pacing: self.enable_pacing.or(base.enable_pacing),
+            max_pacing_rate: self.max_pacing_rate.or(base.max_pacing_rate),
+            active_connection_id_limit: self
+                .active_connection_id_limit
+                .or(base.active_connection_id_limit),
+        }
+    }
+
+    /// Check the ranges of the set fields.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: &str| Err(Error::new(ErrorKind::InvalidInput, reason.to_owned()));
+
+        if let Some(packets) = self.initial_congestion_window_packets
+            && !(1..=1000).contains(&packets)
+        {
+            return invalid("`initial_congestion_window_packets` must be in `1..=1000`");
+        }
+
+        if let Some(size) = self.max_udp_payload_size
+            && !(1200..=65527).contains(&size)
+        {
+            return invalid("`max_udp_payload_size` must be in `1200..=65527`");
+        }
+
+        if self.max_pacing_rate == Some(0) {
+            return invalid("`max_pacing_rate` must be greater than 0");
+        }
+
+        if let Some(limit) = self.active_connection_id_limit
+            && limit < 2
+        {
+            return invalid("`active_connection_id_limit` must be at least 2");
+        }
+
+        Ok(())
+    }
+
+    /// Write the set fields into `config`.
+    pub fn apply(&self, config: &mut quiche::Config) {
+        if let Some(streams) = self.initial_max_streams {
+            config.set_initial_max_streams_bidi(streams);
+            config.set_initial_max_streams_uni(streams);
+        }
+
+        if let Some(size) = self.initial_max_stream_data {
+            config.set_initial_max_stream_data_bidi_local(size);
+            config.set_initial_max_stream_data_bidi_remote(size);
+            config.set_initial_max_stream_data_uni(size);
+        }
+
+        if let (Some(streams), Some(size)) =
+            (self.initial_max_streams, self.initial_max_stream_data)
+        {
+            config.set_initial_max_data(streams.saturating_mul(size));
+        }
+
+        if let Some(timeout) = self.max_idle_timeout {
+            config.set_max_idle_timeout(timeout);
+        }
+
+        if let Some(delay) = self.max_ack_delay {
+            config.set_max_ack_delay(delay);
+        }
+
+        if let Some(exponent) = self.ack_delay_exponent {
+            config.set_ack_delay_exponent(exponent);
+        }
+
+        if let Some(len) = self.dgram_queue_len {
+            config.enable_dgram(len > 0, len, len);
+        }
+
+        if let Some(algorithm) = self.cc_algorithm {
+            config.set_cc_algorithm(algorithm.into());
+        }
+
+        if let Some(packets) = self.initial_congestion_window_packets {
+            config.set_initial_congestion_window_packets(packets);
+        }
+
+        if let Some(size) = self.max_udp_payload_size {
+            config.set_max_send_udp_payload_size(size);
+            config.set_max_recv_udp_payload_size(size);
+        }
+
+        if let Some(discover) = self.discover_pmtu {
+            config.discover_pmtu(discover);
+        }
+
+        if let Some(enable) = self.hystart {
+            config.enable_hystart(enable);
+        }
+
+        if let Some(enable) = self.enable_pacing {
+            config.enable_pacing(enable);
+        }
+
+        if let Some(rate) = self.max_pacing_rate {
+            config.set_max_pacing_rate(rate);
+        }
+
+        if let Some(limit) = self.active_connection_id_limit {
+            config.set_active_connection_id_limit(limit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(QuicTuning::default().validate().is_ok());
+
+        let valid = QuicTuning {
+            initial_congestion_window_packets: Some(10),
+            max_udp_payload_size: Some(1200),
+            max_pacing_rate: Some(1),
+            active_connection_id_limit: Some(2),
+            ..Default::default()
+        };
+
+        assert!(valid.validate().is_ok());
+
+        assert!(
+            QuicTuning {
+                initial_congestion_window_packets: Some(1000),
+                max_udp_payload_size: Some(65527),
+                ..Default::default()
+            }
+            .validate()
+            .is_ok()
+        );
+
+        for invalid in [
+            QuicTuning {
+                initial_congestion_window_packets: Some(0),
+                ..Default::default()
+            },
+            QuicTuning {
+                initial_congestion_window_packets: Some(1001),
+                ..Default::default()
+            },
+            QuicTuning {
+                max_udp_payload_size: Some(1199),
+                ..Default::default()
+            },
+            QuicTuning {
+                max_udp_payload_size: Some(65528),
+                ..Default::default()
+            },
+            QuicTuning {
+                max_pacing_rate: Some(0),
+                ..Default::default()
+            },
+            QuicTuning {
+                active_connection_id_limit: Some(1),
+                ..Default::default()
+            },
+        ] {
+            assert_eq!(
+                invalid.validate().unwrap_err().kind(),
+                ErrorKind::InvalidInput,
+                "{:?}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_or() {
+        let base = QuicTuning {
+            initial_max_streams: Some(100),
+            max_idle_timeout: Some(30_000),
+            cc_algorithm: Some(QuicCongestionControl::Cubic),
+            ..Default::default()
+        };
+
+        let tuning = QuicTuning {
+            max_idle_timeout: Some(5_000),
+            cc_algorithm: Some(QuicCongestionControl::Bbr2),
+            hystart: Some(false),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            tuning.or(&base),
+            QuicTuning {
+                initial_max_streams: Some(100),
+                max_idle_timeout: Some(5_000),
+                cc_algorithm: Some(QuicCongestionControl::Bbr2),
+                hystart: Some(false),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(QuicTuning::default().or(&base), base);
+        assert_eq!(tuning.or(&QuicTuning::default()), tuning);
+    }
+
+    #[test]
+    fn test_apply() {
+        let tuning = QuicTuning {
+            initial_max_streams: Some(100),
+            initial_max_stream_data: Some(1024 * 1024),
+            max_idle_timeout: Some(5_000),
+            max_ack_delay: Some(50),
+            ack_delay_exponent: Some(4),
+            dgram_queue_len: Some(16),
+            cc_algorithm: Some(QuicCongestionControl::Bbr2),
+            max_udp_payload_size: Some(1452),
+            active_connection_id_limit: Some(4),
+            ..Default::default()
+        };
+
+        let mut client_config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+        client_config.set_application_protos(&[b"test"]).unwrap();
+        tuning.apply(&mut client_config);
+
+        let root_path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"));
+
+        let mut server_config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+        server_config.set_application_protos(&[b"test"]).unwrap();
+        server_config
+            .load_cert_chain_from_pem_file(root_path.join("cert/server.crt").to_str().unwrap())
+            .unwrap();
+        server_config
+            .load_priv_key_from_pem_file(root_path.join("cert/server.key").to_str().unwrap())
+            .unwrap();
+
+        // the config is write only, the server reads the applied transport parameters.
+        let client_addr = "127.0.0.1:1".parse().unwrap();
+        let server_addr = "127.0.0.1:2".parse().unwrap();
+
+        let mut client = quiche::connect(
+            None,
+            &quiche::ConnectionId::from_ref(&[1; 16]),
+            client_addr,
+            server_addr,
+            &mut client_config,
+        )
+        .unwrap();
+
+        let mut server = quiche::accept(
+            &quiche::ConnectionId::from_ref(&[2; 16]),
+            None,
+            server_addr,
+            client_addr,
+            &mut server_config,
+        )
+        .unwrap();
+
+        let mut buf = vec![0; 65527];
+
+        let (send_size, _) = client.send(&mut buf).unwrap();
+
+        server
+            .recv(
+                &mut buf[..send_size],
+                quiche::RecvInfo {
+                    from: client_addr,
+                    to: server_addr,
+                },
+            )
+            .unwrap();
+
+        let params = server.peer_transport_params().unwrap();
+
+        assert_eq!(params.initial_max_streams_bidi, 100);
+        assert_eq!(params.initial_max_streams_uni, 100);
+        assert_eq!(params.initial_max_stream_data_bidi_local, 1024 * 1024);
+        assert_eq!(params.initial_max_stream_data_bidi_remote, 1024 * 1024);
+        assert_eq!(params.initial_max_stream_data_uni, 1024 * 1024);
+        assert_eq!(params.initial_max_data, 100 * 1024 * 1024);
+        assert_eq!(params.max_idle_timeout, 5_000);
+        assert_eq!(params.max_ack_delay, 50);
+        assert_eq!(params.ack_delay_exponent, 4);
+        assert_eq!(params.max_udp_payload_size, 1452);
+        assert_eq!(params.active_conn_id_limit, 4);
+        assert!(params.max_datagram_frame_size.is_some());
+    }
+}
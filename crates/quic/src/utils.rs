@@ -7,3 +7,11 @@ pub fn random_conn_id() -> ConnectionId<'static> {
 
     ConnectionId::from_vec(buf)
 }
+
+/// Create a random stateless reset token for a new connection id.
+pub(crate) fn reset_token() -> u128 {
+    let mut buf = [0; 16];
+    boring::rand::rand_bytes(&mut buf).unwrap();
+
+    u128::from_be_bytes(buf)
+}
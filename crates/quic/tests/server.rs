@@ -3,7 +3,9 @@ use std::{io::ErrorKind, iter::repeat, thread::sleep, time::Duration, vec};
 use futures::{AsyncReadExt, AsyncWriteExt};
 
 use n3io::timeout::TimeoutExt;
-use n3quic::{QuicConnExt, QuicConnector, QuicServer};
+use n3quic::{
+    QuicConnExt, QuicConnector, QuicPacing, QuicServer, QuicSessionCache, QuicTuning, ZeroRttPolicy,
+};
 use quiche::Config;
 
 fn mock_config(is_server: bool) -> Config {
@@ -152,6 +154,140 @@ async fn server_drop_conn() {
     assert_eq!(listener.active_conns(), 0);
 }
 
+#[cfg(target_os = "linux")]
+#[futures_test::test]
+async fn sharded_listener() {
+    let mut listener = QuicServer::with_quiche_config(mock_config(true))
+        .shards(4)
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+
+    let raddrs = listener.local_addrs().copied().collect::<Vec<_>>();
+
+    assert_eq!(raddrs.len(), 1);
+
+    let mut connector = QuicConnector::new_with_config(raddrs.as_slice(), mock_config(false));
+
+    let mut conns = vec![];
+
+    // the client ports are spread over the shards.
+    for _ in 0..8 {
+        let outbound = connector.connect().await.unwrap();
+        let inbound = listener.accept().await.unwrap();
+
+        let mut stream = outbound.open().await.unwrap();
+
+        stream.write_all(b"hello").await.unwrap();
+
+        let mut inbound_stream = inbound.accept().await.unwrap();
+
+        let mut buf = [0; 5];
+
+        inbound_stream.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(&buf, b"hello");
+
+        conns.push((outbound, inbound));
+    }
+
+    assert_eq!(listener.active_conns(), 8);
+}
+
+#[futures_test::test]
+async fn pacing_modes() {
+    for pacing in [QuicPacing::Off, QuicPacing::Timer, QuicPacing::TxTime] {
+        let mut listener = QuicServer::with_quiche_config(mock_config(true))
+            .pacing(pacing)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+
+        let raddrs = listener.local_addrs().copied().collect::<Vec<_>>();
+
+        let mut connector =
+            QuicConnector::new_with_config(raddrs.as_slice(), mock_config(false)).pacing(pacing);
+
+        let outbound = connector.connect().await.unwrap();
+        let inbound = listener.accept().await.unwrap();
+
+        let mut outbound_stream = outbound.open().await.unwrap();
+
+        // large enough to be sent in several batches.
+        let data = vec![7u8; 512 * 1024];
+
+        outbound_stream.write_all(&data).await.unwrap();
+
+        let mut inbound_stream = inbound.accept().await.unwrap();
+
+        let mut buf = vec![0; data.len()];
+
+        inbound_stream.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(buf, data);
+    }
+}
+
+#[futures_test::test]
+async fn large_udp_payload() {
+    let tuning = QuicTuning {
+        max_udp_payload_size: Some(1452),
+        ..Default::default()
+    };
+
+    let mut server_config = mock_config(true);
+    tuning.apply(&mut server_config);
+
+    let mut client_config = mock_config(false);
+    tuning.apply(&mut client_config);
+
+    let mut listener = QuicServer::with_quiche_config(server_config)
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+
+    let raddrs = listener.local_addrs().copied().collect::<Vec<_>>();
+
+    let mut connector = QuicConnector::new_with_config(raddrs.as_slice(), client_config);
+
+    let outbound = connector.connect().await.unwrap();
+    let inbound = listener.accept().await.unwrap();
+
+    let mut outbound_stream = outbound.open().await.unwrap();
+
+    outbound_stream.write_all(b"hello").await.unwrap();
+
+    let mut inbound_stream = inbound.accept().await.unwrap();
+
+    let mut buf = [0; 5];
+    inbound_stream.read_exact(&mut buf).await.unwrap();
+
+    // packets above 1200 bytes in both directions.
+    let data = vec![7u8; 512 * 1024];
+
+    inbound_stream.write_all(&data).await.unwrap();
+
+    let mut buf = vec![0; data.len()];
+
+    outbound_stream
+        .read_exact(&mut buf)
+        .timeout(Duration::from_secs(5))
+        .await
+        .unwrap();
+
+    assert_eq!(buf, data);
+
+    outbound_stream.write_all(&data).await.unwrap();
+
+    inbound_stream
+        .read_exact(&mut buf)
+        .timeout(Duration::from_secs(5))
+        .await
+        .unwrap();
+
+    assert_eq!(buf, data);
+}
+
 #[futures_test::test]
 async fn drop_inbound_stream() {
     // _ = pretty_env_logger::try_init_timed();
@@ -191,3 +327,283 @@ async fn drop_inbound_stream() {
 
     assert!(outbound_stream.is_finished());
 }
+
+#[futures_test::test]
+async fn client_path_probing() {
+    let laddrs = repeat("127.0.0.1:0".parse().unwrap())
+        .take(20)
+        .collect::<Vec<_>>();
+
+    let mut listener = QuicServer::with_quiche_config(mock_config(true))
+        .bind(laddrs.as_slice())
+        .await
+        .unwrap();
+
+    let raddrs = listener.local_addrs().copied().collect::<Vec<_>>();
+
+    let mut connector = QuicConnector::new_with_config(raddrs.as_slice(), mock_config(false));
+
+    let outbound = connector.connect().await.unwrap();
+    let inbound = listener.accept().await.unwrap();
+
+    let (laddr, raddr) = outbound.active_path().unwrap();
+
+    assert!(raddrs.contains(&raddr));
+    assert_eq!(inbound.active_path().unwrap().0, raddr);
+
+    // only the client probes new paths.
+    assert_eq!(
+        inbound
+            .probe_path("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap_err()
+            .kind(),
+        ErrorKind::Unsupported
+    );
+
+    // a path must be probed before the migration.
+    assert_eq!(
+        outbound
+            .migrate("127.0.0.1:1".parse().unwrap())
+            .unwrap_err()
+            .kind(),
+        ErrorKind::InvalidInput
+    );
+
+    assert_eq!(outbound.active_path().unwrap(), (laddr, raddr));
+
+    // the connection is not affected.
+    let mut outbound_stream = outbound.open().await.unwrap();
+
+    outbound_stream.write_all(b"hello").await.unwrap();
+
+    let mut inbound_stream = inbound.accept().await.unwrap();
+
+    let mut buf = vec![0; 5];
+
+    inbound_stream.read_exact(&mut buf).await.unwrap();
+
+    assert_eq!(buf, b"hello");
+}
+
+#[futures_test::test]
+async fn dgram_send_recv() {
+    let laddrs = repeat("127.0.0.1:0".parse().unwrap())
+        .take(20)
+        .collect::<Vec<_>>();
+
+    let mut server_config = mock_config(true);
+    server_config.enable_dgram(true, 10, 10);
+
+    let mut listener = QuicServer::with_quiche_config(server_config)
+        .bind(laddrs.as_slice())
+        .await
+        .unwrap();
+
+    let raddrs = listener.local_addrs().copied().collect::<Vec<_>>();
+
+    let mut client_config = mock_config(false);
+    client_config.enable_dgram(true, 10, 10);
+
+    let mut connector = QuicConnector::new_with_config(raddrs.as_slice(), client_config);
+
+    let outbound = connector.connect().await.unwrap();
+    let inbound = listener.accept().await.unwrap();
+
+    let max_writable_len = outbound.dgram_sender().max_writable_len().unwrap();
+
+    assert_eq!(
+        outbound
+            .dgram_sender()
+            .send(&vec![0; max_writable_len + 1])
+            .unwrap_err()
+            .kind(),
+        ErrorKind::InvalidInput
+    );
+
+    outbound.dgram_sender().send(b"hello").unwrap();
+
+    let mut buf = vec![0; 100];
+
+    let len = inbound.dgram_recv(&mut buf).await.unwrap();
+
+    assert_eq!(&buf[..len], b"hello");
+
+    inbound.dgram_sender().send(b"world").unwrap();
+
+    let len = outbound.dgram_recv(&mut buf).await.unwrap();
+
+    assert_eq!(&buf[..len], b"world");
+}
+
+#[futures_test::test]
+async fn client_migration() {
+    let laddrs = repeat("127.0.0.1:0".parse().unwrap())
+        .take(20)
+        .collect::<Vec<_>>();
+
+    let mut listener = QuicServer::with_quiche_config(mock_config(true))
+        .bind(laddrs.as_slice())
+        .await
+        .unwrap();
+
+    let raddrs = listener.local_addrs().copied().collect::<Vec<_>>();
+
+    let mut connector = QuicConnector::new_with_config(raddrs.as_slice(), mock_config(false));
+
+    let outbound = connector.connect().await.unwrap();
+    let inbound = listener.accept().await.unwrap();
+
+    let mut outbound_stream = outbound.open().await.unwrap();
+
+    outbound_stream.write_all(b"hello").await.unwrap();
+
+    let mut inbound_stream = inbound.accept().await.unwrap();
+
+    let mut buf = vec![0; 5];
+
+    inbound_stream.read_exact(&mut buf).await.unwrap();
+
+    assert_eq!(buf, b"hello");
+
+    // the server issues spare connection ids after the handshake.
+    while outbound.quiche_conn(|conn| conn.available_dcids()) == 0 {
+        sleep(Duration::from_millis(10));
+    }
+
+    let (old_laddr, _) = outbound.active_path().unwrap();
+
+    let new_laddr = outbound
+        .probe_path("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+
+    assert_ne!(old_laddr, new_laddr);
+
+    outbound.migrate(new_laddr).unwrap();
+
+    assert_eq!(outbound.active_path().unwrap().0, new_laddr);
+
+    // the running stream is not affected.
+    outbound_stream.write_all(b"world").await.unwrap();
+
+    inbound_stream.read_exact(&mut buf).await.unwrap();
+
+    assert_eq!(buf, b"world");
+
+    // the server switches to the new path after validating it.
+    while listener.metrics().migrations.get() == 0 {
+        sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(inbound.active_path().unwrap().1, new_laddr);
+}
+
+#[futures_test::test]
+async fn zero_rtt_resumption() {
+    let mut listener = QuicServer::with_quiche_config(mock_config(true))
+        .zero_rtt(ZeroRttPolicy::Accept)
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+
+    let raddr = *listener.local_addrs().next().unwrap();
+
+    let sessions = QuicSessionCache::new();
+
+    let mut connector = QuicConnector::new_with_config(raddr, mock_config(false))
+        .session_cache(Some(sessions.clone()))
+        .early_data(true);
+
+    let outbound = connector.connect().await.unwrap();
+    let inbound = listener.accept().await.unwrap();
+
+    // the session ticket is sent after the handshake.
+    while sessions.get(&raddr.to_string()).is_none() {
+        sleep(Duration::from_millis(10));
+    }
+
+    drop(inbound);
+    drop(outbound);
+
+    let outbound = connector.connect().await.unwrap();
+
+    let mut outbound_stream = outbound.open().await.unwrap();
+
+    outbound_stream.write_all(b"hello").await.unwrap();
+
+    let inbound = listener.accept().await.unwrap();
+
+    let mut inbound_stream = inbound.accept().await.unwrap();
+
+    let mut buf = vec![0; 5];
+
+    inbound_stream.read_exact(&mut buf).await.unwrap();
+
+    assert_eq!(buf, b"hello");
+
+    assert!(outbound.quiche_conn(|conn| conn.is_resumed()));
+}
+
+#[futures_test::test]
+async fn zero_rtt_race_dead_address() {
+    let mut listener = QuicServer::with_quiche_config(mock_config(true))
+        .zero_rtt(ZeroRttPolicy::Accept)
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+
+    let raddr = *listener.local_addrs().next().unwrap();
+
+    let sessions = QuicSessionCache::new();
+
+    let mut connector = QuicConnector::new_with_config(raddr, mock_config(false))
+        .session_cache(Some(sessions.clone()))
+        .early_data(true);
+
+    let outbound = connector.connect().await.unwrap();
+    let inbound = listener.accept().await.unwrap();
+
+    while sessions.get(&raddr.to_string()).is_none() {
+        sleep(Duration::from_millis(10));
+    }
+
+    drop(inbound);
+    drop(outbound);
+
+    // never answers, but has a resumable session.
+    let dead = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dead_addr = dead.local_addr().unwrap();
+
+    sessions.put(
+        &dead_addr.to_string(),
+        &sessions.get(&raddr.to_string()).unwrap(),
+    );
+
+    let mut connector =
+        QuicConnector::new_with_config([dead_addr, raddr].as_slice(), mock_config(false))
+            .session_cache(Some(sessions.clone()))
+            .early_data(true)
+            .attempt_delay(Duration::from_millis(100));
+
+    // the attempts are shuffled, the dead address is tried first in some of them.
+    for _ in 0..5 {
+        let outbound = connector.connect().await.unwrap();
+
+        assert_eq!(outbound.active_path().unwrap().1, raddr);
+
+        let mut outbound_stream = outbound.open().await.unwrap();
+
+        outbound_stream.write_all(b"hello").await.unwrap();
+
+        let inbound = listener.accept().await.unwrap();
+
+        let mut inbound_stream = inbound.accept().await.unwrap();
+
+        let mut buf = vec![0; 5];
+
+        inbound_stream.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(buf, b"hello");
+    }
+}